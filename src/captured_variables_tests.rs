@@ -0,0 +1,24 @@
+/*--[sql-tests]
+# Captured variables
+```SQL,non-transactional
+CREATE TEMP TABLE users(id int GENERATED ALWAYS AS IDENTITY, name text);
+```
+
+```SQL,non-transactional,capture(id)
+INSERT INTO users(name) VALUES ('alice') RETURNING id;
+```
+```output
+ id
+----
+  1
+```
+
+```SQL
+SELECT name FROM users WHERE id = :id;
+```
+```output
+ name
+-------
+ alice
+```
+*/
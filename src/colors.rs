@@ -1,4 +1,9 @@
-use termcolor::ColorSpec;
+use std::{
+    cell::RefCell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use termcolor::{Buffer, BufferWriter, ColorSpec};
 
 use once_cell::sync::Lazy;
 
@@ -9,11 +14,10 @@ macro_rules! cprintln {
             use std::io::Write;
             #[allow(unused_imports)]
             use termcolor::{ColorSpec, WriteColor};
-            let bufwtr = termcolor::BufferWriter::stdout(*$crate::colors::STDOUT_COLOR_CHOICE);
-            let mut buffer = bufwtr.buffer();
-            $crate::format_colors!(buffer @ $($rest)*);
-            let _ = write!(&mut buffer, "\n");
-            let _ = bufwtr.print(&buffer);
+            $crate::colors::write_stdout(|buffer| {
+                $crate::format_colors!(buffer @ $($rest)*);
+                let _ = write!(buffer, "\n");
+            });
         }
     };
 }
@@ -25,10 +29,9 @@ macro_rules! cprint {
             use std::io::Write;
             #[allow(unused_imports)]
             use termcolor::{ColorSpec,  WriteColor};
-            let bufwtr = termcolor::BufferWriter::stdout(*$crate::colors::STDOUT_COLOR_CHOICE);
-            let mut buffer = bufwtr.buffer();
-            $crate::format_colors!(buffer @ $($rest)*);
-            let _ = bufwtr.print(&buffer);
+            $crate::colors::write_stdout(|buffer| {
+                $crate::format_colors!(buffer @ $($rest)*);
+            });
         }
     };
 }
@@ -43,11 +46,10 @@ macro_rules! ecprintln {
             use std::io::Write;
             #[allow(unused_imports)]
             use termcolor::{ColorSpec, WriteColor};
-            let bufwtr = termcolor::BufferWriter::stderr(*$crate::colors::STDERR_COLOR_CHOICE);
-            let mut buffer = bufwtr.buffer();
-            $crate::format_colors!(buffer @ $($rest)*);
-            let _ = write!(&mut buffer, "\n");
-            let _ = bufwtr.print(&buffer);
+            $crate::colors::write_stderr(|buffer| {
+                $crate::format_colors!(buffer @ $($rest)*);
+                let _ = write!(buffer, "\n");
+            });
         }
     };
 }
@@ -57,11 +59,23 @@ macro_rules! ecprint {
     ($($rest:tt)*) => {
         {
             use std::io::Write;
+            #[allow(unused_imports)]
             use termcolor::{ColorSpec, WriteColor};
-            let bufwtr = termcolor::BufferWriter::stderr(*$crate::colors::STDERR_COLOR_CHOICE);
-            let mut buffer = bufwtr.buffer();
-            $crate::format_colors!(buffer @ $($rest)*);
-            let _ = bufwtr.print(&buffer);
+            $crate::colors::write_stderr(|buffer| {
+                $crate::format_colors!(buffer @ $($rest)*);
+            });
+        }
+    };
+}
+
+/// Prints a warning to stderr, counting it towards
+/// `--fail-on-warning-count`.
+#[macro_export]
+macro_rules! warning {
+    ($($rest:tt)*) => {
+        {
+            $crate::colors::count_warning();
+            $crate::ecprintln!("Warning" bold yellow, ": ", $($rest)*);
         }
     };
 }
@@ -75,17 +89,93 @@ macro_rules! format_colors {
             $crate::colors::add_to_color_spec(&mut color, $crate::colors::ColoringOption::$color);
         )+
         let _ = $buffer.set_color(&color);
-        let _ = write!(&mut $buffer, $fmt);
+        let _ = write!($buffer, $fmt);
         let _ = $buffer.reset();
         $crate::format_colors!($buffer @ $($($rest)*)?)
     };
     ($buffer:ident @ $fmt:literal $(, $($rest:tt)* )? ) => {
-        let _ = write!(&mut $buffer, $fmt);
+        let _ = write!($buffer, $fmt);
         $crate::format_colors!($buffer @ $($($rest)*)?)
     };
     ($buffer:ident @ ) => {};
 }
 
+thread_local! {
+    /// where `cprint!` and `cprintln!` write on this thread instead of
+    /// stdout, if anywhere
+    static STDOUT_SINK: RefCell<Option<Buffer>> = const { RefCell::new(None) };
+    /// where `ecprint!`, `ecprintln!` and `warning!` write on this thread
+    /// instead of stderr, if anywhere
+    static STDERR_SINK: RefCell<Option<Buffer>> = const { RefCell::new(None) };
+}
+
+/// Writes to the thread's stdout sink if it has one, or to stdout through a
+/// buffer otherwise.
+#[doc(hidden)]
+pub fn write_stdout(write: impl FnOnce(&mut Buffer)) {
+    STDOUT_SINK.with(|sink| {
+        if let Some(buffer) = sink.borrow_mut().as_mut() {
+            return write(buffer);
+        }
+        let bufwtr = BufferWriter::stdout(*STDOUT_COLOR_CHOICE);
+        let mut buffer = bufwtr.buffer();
+        write(&mut buffer);
+        let _ = bufwtr.print(&buffer);
+    })
+}
+
+/// Writes to the thread's stderr sink if it has one, or to stderr through a
+/// buffer otherwise.
+#[doc(hidden)]
+pub fn write_stderr(write: impl FnOnce(&mut Buffer)) {
+    STDERR_SINK.with(|sink| {
+        if let Some(buffer) = sink.borrow_mut().as_mut() {
+            return write(buffer);
+        }
+        let bufwtr = BufferWriter::stderr(*STDERR_COLOR_CHOICE);
+        let mut buffer = bufwtr.buffer();
+        write(&mut buffer);
+        let _ = bufwtr.print(&buffer);
+    })
+}
+
+/// Runs `f` with what this thread prints to stdout written into `sink`
+/// instead, returning it along with the result.
+#[cfg(test)]
+pub fn capture_stdout<T>(sink: Buffer, f: impl FnOnce() -> T) -> (T, Buffer) {
+    let outer = STDOUT_SINK.with(|current| current.replace(Some(sink)));
+    let result = f();
+    let sink = STDOUT_SINK.with(|current| current.replace(outer));
+    (result, sink.expect("the stdout sink was removed"))
+}
+
+/// Runs `f` with what this thread prints to stderr written into `sink`
+/// instead, returning it along with the result.
+#[cfg(test)]
+pub fn capture_stderr<T>(sink: Buffer, f: impl FnOnce() -> T) -> (T, Buffer) {
+    let outer = STDERR_SINK.with(|current| current.replace(Some(sink)));
+    let result = f();
+    let sink = STDERR_SINK.with(|current| current.replace(outer));
+    (result, sink.expect("the stderr sink was removed"))
+}
+
+/// How many warnings have been printed with `warning!`.
+static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+
+#[doc(hidden)]
+pub fn count_warning() {
+    WARNINGS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn warnings() -> usize {
+    WARNINGS.load(Ordering::Relaxed)
+}
+
+/// Starts counting warnings from zero again, for a new run.
+pub fn reset_warnings() {
+    WARNINGS.store(0, Ordering::Relaxed);
+}
+
 pub static STDOUT_COLOR_CHOICE: Lazy<termcolor::ColorChoice>  = Lazy::new(|| {
     if atty::is(atty::Stream::Stdout) {
         termcolor::ColorChoice::Auto
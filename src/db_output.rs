@@ -1,8 +1,10 @@
+use std::{borrow::Cow, cmp::Ordering};
 
 use tokio_postgres::SimpleQueryMessage;
 
 use crate::cprintln;
-use crate::Test;
+use crate::statements::command_tags;
+use crate::{AlternativeOutput, Args, Test};
 
 use self::FailureInfo::*;
 use self::TestResult::*;
@@ -14,99 +16,895 @@ pub enum TestResult {
 
 pub enum FailureInfo {
     QueryError(tokio_postgres::Error),
+    /// a test run through psql failed with this error
+    PsqlError(String),
+    /// the test's `compare-with` query failed with this error
+    ReferenceError(tokio_postgres::Error),
+    /// the test's file couldn't connect to its database, with this error
+    ConnectionError(String),
     WrongNumberOfRows {
         received: Vec<Vec<String>>,
+        headers: Vec<String>,
         expected: usize,
         found: usize,
     },
-    MismatchedValues(Vec<Vec<String>>),
+    MismatchedValues {
+        received: Vec<Vec<String>>,
+        headers: Vec<String>,
+    },
+    MismatchedHeaders {
+        expected: Vec<String>,
+        received: Vec<String>,
+    },
+    WrongNotices {
+        expected: Vec<String>,
+        received: Vec<String>,
+    },
+    /// the statements' command tags weren't those of the `tags` block
+    WrongCommandTags {
+        expected: Vec<String>,
+        received: Vec<String>,
+    },
+    /// the query didn't fail with the error from an `output, error` block
+    WrongError {
+        expected: String,
+        /// the error as psql would show it, `None` if the query succeeded
+        received: Option<String>,
+    },
+    /// the query didn't return the number of columns from `cols`
+    WrongColumnCount {
+        expected: usize,
+        received: usize,
+    },
+    /// an `assert-true` column wasn't true in these rows, or there were none
+    NotTrue {
+        column: String,
+        received: Vec<Vec<String>>,
+        headers: Vec<String>,
+    },
 }
 
-pub(crate) fn validate_output(output: Vec<SimpleQueryMessage>, test: &Test) -> TestResult {
-    use SimpleQueryMessage::*;
+pub(crate) fn validate_output(
+    output: &[SimpleQueryMessage],
+    test: &Test,
+    args: &Args,
+) -> TestResult {
+    let (headers, received) = received_rows(output, args.first_result_only, null_text(test));
+    validate_rows(test, headers, received, args)
+}
 
+/// Checks the column names and rows a test received, however it was run,
+/// against the ones it expects.
+pub(crate) fn validate_rows(
+    test: &Test,
+    headers: Vec<String>,
+    received: Vec<Vec<String>>,
+    args: &Args,
+) -> TestResult {
+    if let Some(expected) = &test.expected_error {
+        return Failed(WrongError {
+            expected: expected.clone(),
+            received: None,
+        });
+    }
     if test.ignore_output {
         return Passed;
     }
 
-    let mut received = Vec::with_capacity(test.output.len());
+    if test.column_count.is_some() {
+        return check_column_count(test, headers.len());
+    }
+    if let Some(column) = &test.assert_true {
+        return check_all_true(column, headers, received);
+    }
+    let (headers, received) = without_columns(headers, received, &test.ignore_cols);
+    compare_output(test, headers, received, args)
+}
+
+/// Checks the number of columns the query returned against the test's
+/// `cols`, if it has one.
+pub(crate) fn check_column_count(test: &Test, received: usize) -> TestResult {
+    match test.column_count {
+        Some(expected) if expected != received => Failed(WrongColumnCount { expected, received }),
+        _ => Passed,
+    }
+}
+
+/// Checks that `column`, by name or 1-based index, is true in every row, and
+/// that there's at least one.
+fn check_all_true(column: &str, headers: Vec<String>, received: Vec<Vec<String>>) -> TestResult {
+    let not_true = |received| NotTrue {
+        column: column.to_string(),
+        received,
+        headers: headers.clone(),
+    };
+    if received.is_empty() {
+        return Failed(not_true(vec![]));
+    }
+    let index = match column.parse::<usize>() {
+        Ok(n) => n.checked_sub(1).filter(|&i| i < headers.len()),
+        Err(_) => headers.iter().position(|h| h == column),
+    };
+    let Some(index) = index else {
+        return Failed(MismatchedHeaders {
+            expected: vec![column.to_string()],
+            received: headers,
+        });
+    };
+    let failing: Vec<_> = received
+        .into_iter()
+        .filter(|row| row.get(index).map(String::as_str) != Some("t"))
+        .collect();
+    match failing.is_empty() {
+        true => Passed,
+        false => Failed(not_true(failing)),
+    }
+}
+
+/// The received output with the 0-based `columns` left out.
+fn without_columns(
+    headers: Vec<String>,
+    rows: Vec<Vec<String>>,
+    columns: &[usize],
+) -> (Vec<String>, Vec<Vec<String>>) {
+    if columns.is_empty() {
+        return (headers, rows);
+    }
+    let keep = |row: Vec<String>| -> Vec<String> {
+        row.into_iter()
+            .enumerate()
+            .filter(|(i, _)| !columns.contains(i))
+            .map(|(_, value)| value)
+            .collect()
+    };
+    (keep(headers), rows.into_iter().map(keep).collect())
+}
+
+/// Checks the error a query failed with against the one the test expects,
+/// which passes if every line expected is part of the error as psql would
+/// show it, ignoring differences in whitespace. Tests that don't expect an
+/// error fail with it.
+pub(crate) fn validate_error(test: &Test, error: tokio_postgres::Error) -> TestResult {
+    let Some(expected) = &test.expected_error else {
+        return Failed(QueryError(error));
+    };
+    check_error(expected, error_text(&error))
+}
+
+/// Like [`validate_error`] for the error psql printed for a test run through
+/// it.
+pub(crate) fn validate_psql_error(test: &Test, error: String) -> TestResult {
+    let Some(expected) = &test.expected_error else {
+        return Failed(PsqlError(error));
+    };
+    check_error(expected, error)
+}
+
+fn check_error(expected: &str, received: String) -> TestResult {
+    match error_matches(expected, &received) {
+        true => Passed,
+        false => Failed(WrongError {
+            expected: expected.to_string(),
+            received: Some(received),
+        }),
+    }
+}
+
+/// Whether each line of `expected` is part of a line of `received`.
+fn error_matches(expected: &str, received: &str) -> bool {
+    let normalize = |line: &str| line.split_whitespace().collect::<Vec<_>>().join(" ");
+    let received: Vec<String> = received.lines().map(normalize).collect();
+    expected.lines().all(|line| {
+        let line = normalize(line);
+        received.iter().any(|received| received.contains(&line))
+    })
+}
+
+/// An error as psql shows it, `ERROR:  message` followed by any `DETAIL:`
+/// and `HINT:` lines.
+pub(crate) fn error_text(error: &tokio_postgres::Error) -> String {
+    let Some(error) = error.as_db_error() else {
+        return error.to_string();
+    };
+    let mut text = format!("{}:  {}", error.severity(), error.message());
+    if let Some(detail) = error.detail() {
+        text += &format!("\nDETAIL:  {detail}");
+    }
+    if let Some(hint) = error.hint() {
+        text += &format!("\nHINT:  {hint}");
+    }
+    text
+}
+
+/// An error, as psql shows it, in a few words: its first line without the
+/// severity, or `timeout` if the query ran past `--timeout`.
+fn error_reason(error: &str) -> String {
+    let line = error.lines().next().unwrap_or_default();
+    if line.contains("canceling statement due to statement timeout") {
+        return "timeout".to_string();
+    }
+    let message = line
+        .split_once("ERROR:")
+        .map_or(line, |(_, message)| message);
+    format!("error: {}", message.trim())
+}
+
+/// The rows one statement of a query returned.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct ResultSet {
+    /// empty if there are no rows, since the simple query protocol only
+    /// reports the column names along with a row
+    pub headers: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+/// How a test writes NULLs in its expected output.
+pub(crate) fn null_text(test: &Test) -> &str {
+    test.null_as.as_deref().unwrap_or("")
+}
+
+/// Splits a query's output into the results of each of its statements,
+/// writing NULLs as `null`.
+pub(crate) fn result_sets(output: &[SimpleQueryMessage], null: &str) -> Vec<ResultSet> {
+    use SimpleQueryMessage::*;
+
+    let mut sets = vec![];
+    let mut current = ResultSet::default();
     for r in output {
         match r {
             Row(r) => {
+                if current.headers.is_empty() {
+                    current.headers = r.columns().iter().map(|c| c.name().to_string()).collect();
+                }
                 let mut row: Vec<String> = Vec::with_capacity(r.len());
                 for i in 0..r.len() {
-                    row.push(r.get(i).unwrap_or("").to_string())
+                    row.push(r.get(i).unwrap_or(null).to_string())
                 }
-                received.push(row);
+                current.rows.push(row);
             }
-            CommandComplete(..) => break,
+            CommandComplete(..) => sets.push(std::mem::take(&mut current)),
             _ => unreachable!(),
         }
     }
+    sets
+}
+
+/// The column names and rows of a query's output: those of its first
+/// statement if `first_result_only` is set, otherwise the rows of every
+/// statement one after the other, named by the first statement that
+/// returned any. NULLs are written as `null`.
+pub(crate) fn received_rows(
+    output: &[SimpleQueryMessage],
+    first_result_only: bool,
+    null: &str,
+) -> (Vec<String>, Vec<Vec<String>>) {
+    combine_results(result_sets(output, null), first_result_only)
+}
+
+fn combine_results(
+    sets: Vec<ResultSet>,
+    first_result_only: bool,
+) -> (Vec<String>, Vec<Vec<String>>) {
+    let num_sets = match first_result_only {
+        true => 1,
+        false => sets.len(),
+    };
+    let mut headers = vec![];
+    let mut rows = vec![];
+    for set in sets.into_iter().take(num_sets) {
+        if headers.is_empty() {
+            headers = set.headers;
+        }
+        rows.extend(set.rows);
+    }
+    (headers, rows)
+}
+
+/// Checks the rows, and column names if requested, received from a query
+/// against those the test expects. `headers` is empty if no rows were
+/// received, since the simple query protocol only reports them with a row.
+fn compare_output(
+    test: &Test,
+    headers: Vec<String>,
+    received: Vec<Vec<String>>,
+    args: &Args,
+) -> TestResult {
+    let check_headers = test.check_headers.unwrap_or(args.check_headers);
+    let mut received = received;
+    if test.as_set {
+        distinct_rows(&mut received);
+    }
+    if !test.sort_by.is_empty() {
+        sort_rows(&mut received, &test.sort_by);
+    }
+    // lines the groups up with the expected rows, for the report
+    if !test.grouped_by.is_empty() {
+        group_rows(&mut received, &test.output, &test.grouped_by);
+    }
+
+    let mismatch = match find_mismatch(
+        test,
+        &test.output_headers,
+        &test.output,
+        &headers,
+        &received,
+        check_headers,
+    ) {
+        None => return Passed,
+        Some(mismatch) => mismatch,
+    };
+    let alternative_matches = test.alternative_outputs.iter().any(|alternative| {
+        let AlternativeOutput {
+            output_headers,
+            output,
+        } = alternative;
+        find_mismatch(
+            test,
+            output_headers,
+            output,
+            &headers,
+            &received,
+            check_headers,
+        )
+        .is_none()
+    });
+    if alternative_matches {
+        return Passed;
+    }
 
-    if test.output.len() != received.len() {
-        return Failed(WrongNumberOfRows {
-            expected: test.output.len(),
+    if mismatch == Mismatch::Headers {
+        return Failed(MismatchedHeaders {
+            expected: test.output_headers.clone(),
+            received: headers,
+        });
+    }
+    // the simple query protocol only reports the column names along with a row
+    let headers = match headers.is_empty() {
+        true => test.output_headers.clone(),
+        false => headers,
+    };
+    match mismatch {
+        Mismatch::NumberOfRows => Failed(WrongNumberOfRows {
+            expected: num_expected_rows(test),
             found: received.len(),
             received,
-        });
+            headers,
+        }),
+        _ => Failed(MismatchedValues { received, headers }),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Mismatch {
+    Headers,
+    NumberOfRows,
+    Values,
+}
+
+/// How the received output, with its rows already sorted if the test sorts
+/// them, differs from one the test expects, if it does. Rows grouped by the
+/// test are arranged to match `expected`'s groups here.
+fn find_mismatch(
+    test: &Test,
+    expected_headers: &[String],
+    expected: &[Vec<String>],
+    headers: &[String],
+    received: &[Vec<String>],
+    check_headers: bool,
+) -> Option<Mismatch> {
+    if check_headers && !headers.is_empty() && expected_headers != headers {
+        return Some(Mismatch::Headers);
+    }
+
+    let mut expected = Cow::Borrowed(expected);
+    if test.as_set {
+        distinct_rows(expected.to_mut());
+    }
+    if expected.len() != received.len() {
+        return Some(Mismatch::NumberOfRows);
+    }
+
+    if !test.sort_by.is_empty() {
+        sort_rows(expected.to_mut(), &test.sort_by);
+    }
+    let mut received = Cow::Borrowed(received);
+    if !test.grouped_by.is_empty() {
+        group_rows(received.to_mut(), &expected, &test.grouped_by);
+    }
+
+    let rows_match = expected
+        .iter()
+        .zip(received.iter())
+        .all(|(expected, received)| rows_match(test, expected, received));
+    (!rows_match).then_some(Mismatch::Values)
+}
+
+/// Sorts `rows` and removes the duplicates, for comparing them as sets.
+fn distinct_rows(rows: &mut Vec<Vec<String>>) {
+    rows.sort();
+    rows.dedup();
+}
+
+/// The number of rows the test expects, those that are distinct if it
+/// compares them as sets.
+fn num_expected_rows(test: &Test) -> usize {
+    match test.as_set {
+        true => {
+            let mut expected = test.output.clone();
+            distinct_rows(&mut expected);
+            expected.len()
+        }
+        false => test.output.len(),
+    }
+}
+
+/// Stably sorts `rows` by the values in `columns`, the first being the most
+/// significant.
+fn sort_rows(rows: &mut [Vec<String>], columns: &[usize]) {
+    rows.sort_by(|a, b| {
+        columns
+            .iter()
+            .map(|&c| a.get(c).cmp(&b.get(c)))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(Ordering::Equal)
+    })
+}
+
+/// Moves each group of `rows`, those with the same values in `columns`, to
+/// where its group first appears in `expected`, keeping the order of the rows
+/// within each group. Groups `expected` doesn't have go last.
+fn group_rows(rows: &mut [Vec<String>], expected: &[Vec<String>], columns: &[usize]) {
+    let same_group = |a: &[String], b: &[String]| columns.iter().all(|&c| a.get(c) == b.get(c));
+    // sorting by cached keys is stable
+    rows.sort_by_cached_key(|row| {
+        expected
+            .iter()
+            .position(|expected| same_group(expected, row))
+            .unwrap_or(expected.len())
+    });
+}
+
+fn rows_match(test: &Test, expected: &[String], received: &[String]) -> bool {
+    if (test.tolerances.is_empty() && test.wildcard.is_none() && test.bool_cols.is_empty())
+        || expected.len() != received.len()
+    {
+        return expected == received;
     }
+    expected
+        .iter()
+        .zip(received)
+        .enumerate()
+        .all(|(column, (expected, received))| {
+            if test.wildcard.as_ref() == Some(expected) {
+                return true;
+            }
+            if test.bool_cols.contains(&column) {
+                return match (parse_bool(expected), parse_bool(received)) {
+                    (Some(expected), Some(received)) => expected == received,
+                    _ => expected == received,
+                };
+            }
+            match test.tolerances.iter().find(|(c, _)| *c == column) {
+                Some((_, tolerance)) => tolerance.accepts(expected, received),
+                None => expected == received,
+            }
+        })
+}
+
+/// The boolean a value of a `bool` column is, ignoring case.
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "t" | "true" | "1" => Some(true),
+        "f" | "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// The messages of the notices a test expects its query to raise, one per
+/// line of a `notices` block.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ExpectedNotices {
+    pub messages: Vec<String>,
+    /// the notices may be raised in any order
+    pub unordered: bool,
+    /// other notices may be raised as well
+    pub contains: bool,
+}
+
+impl ExpectedNotices {
+    pub fn accepts(&self, received: &[String]) -> bool {
+        match (self.unordered, self.contains) {
+            (false, false) => self.messages == received,
+            (false, true) => {
+                let mut received = received.iter();
+                self.messages
+                    .iter()
+                    .all(|expected| received.any(|r| r == expected))
+            }
+            (true, contains) => {
+                let mut remaining = received.to_vec();
+                let all_found = self.messages.iter().all(|expected| {
+                    match remaining.iter().position(|r| r == expected) {
+                        Some(i) => {
+                            remaining.swap_remove(i);
+                            true
+                        }
+                        None => false,
+                    }
+                });
+                all_found && (contains || remaining.is_empty())
+            }
+        }
+    }
+}
+
+/// Checks the notices a query raised against those the test expects, if it
+/// expects any.
+pub(crate) fn validate_notices(test: &Test, received: Vec<String>) -> TestResult {
+    match &test.notices {
+        Some(expected) if !expected.accepts(&received) => Failed(WrongNotices {
+            expected: expected.messages.clone(),
+            received,
+        }),
+        _ => Passed,
+    }
+}
+
+/// Checks the row counts of the query's statements against those ending the
+/// command tags the test expects, if it expects any. tokio-postgres only
+/// reports each statement's row count, so the rest of a tag isn't checked,
+/// and neither are tags that don't end in a count, such as `CREATE TABLE`.
+pub(crate) fn validate_command_tags(test: &Test, output: &[SimpleQueryMessage]) -> TestResult {
+    let Some(expected) = &test.command_tags else {
+        return Passed;
+    };
+    let rows: Vec<u64> = output
+        .iter()
+        .filter_map(|message| match message {
+            SimpleQueryMessage::CommandComplete(rows) => Some(*rows),
+            _ => None,
+        })
+        .collect();
+    // the expected tags with the counts received, so they only differ where
+    // the counts do, and a rebuilt tag for any statement past them
+    let received: Vec<String> = command_tags(&test.text, &rows)
+        .into_iter()
+        .zip(&rows)
+        .enumerate()
+        .map(|(i, (rebuilt, &rows))| match expected.get(i) {
+            Some(expected) => with_count(expected, rows),
+            None => rebuilt,
+        })
+        .collect();
+    match *expected == received {
+        true => Passed,
+        false => Failed(WrongCommandTags {
+            expected: expected.clone(),
+            received,
+        }),
+    }
+}
 
-    // let all_eq = iter::zip(test.output.iter(), received.iter())
-    //     .all(|(expected, received)| expected == received);
+/// `tag` with the row count ending it, if it ends in one, replaced by `rows`.
+fn with_count(tag: &str, rows: u64) -> String {
+    match tag.rsplit_once(' ') {
+        Some((verb, count)) if count.parse::<u64>().is_ok() => format!("{verb} {rows}"),
+        _ => tag.to_string(),
+    }
+}
+
+/// How far a numeric value may be from the expected one, set per-column with
+/// the `approx`, `approx-rel` and `precision` output attributes.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Tolerance {
+    /// `|expected - received| <= tolerance`
+    Absolute(f64),
+    /// `|expected - received| <= tolerance * max(|expected|, |received|)`
+    Relative(f64),
+    /// both values are equal once rounded to this many significant digits of
+    /// the expected value
+    Precision(usize),
+}
 
-    // TODO we'll need a more complicated version later
-    if test.output != received {
-        return Failed(MismatchedValues(received));
+impl Tolerance {
+    /// Values that aren't numbers, such as NULLs, must match exactly.
+    fn accepts(self, expected: &str, received: &str) -> bool {
+        if expected == received {
+            return true;
+        }
+        let (Ok(e), Ok(r)) = (expected.parse::<f64>(), received.parse::<f64>()) else {
+            return false;
+        };
+        let difference = (e - r).abs();
+        match self {
+            Tolerance::Absolute(tolerance) => difference <= tolerance,
+            Tolerance::Relative(tolerance) => difference <= tolerance * e.abs().max(r.abs()),
+            Tolerance::Precision(digits) => {
+                let magnitude = if e != 0.0 { e } else { r };
+                if magnitude == 0.0 {
+                    return true;
+                }
+                // the decimal places of the last significant digit, negative
+                // when it's left of the decimal point
+                let places = digits as i32 - 1 - magnitude.abs().log10().floor() as i32;
+                let scale = 10f64.powi(places);
+                (e * scale).round() == (r * scale).round()
+            }
+        }
     }
+}
+
+/// How mismatched output is shown.
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiffStyle {
+    /// The expected and received tables followed by a cell-by-cell diff.
+    Inline,
+    /// A plain-text unified diff of the tables, for diff-aware tools.
+    Unified,
+}
 
-    Passed
+/// Limits the cell-by-cell diff to the columns with changed values, and the
+/// `key` column, by name or 1-based index, for context.
+#[derive(Clone, Copy, Debug)]
+pub struct ChangedCols<'a> {
+    pub key: Option<&'a str>,
 }
 
 impl FailureInfo {
-    pub(crate) fn print(&self, test: &Test) {
+    /// The failure on one line, `line header — reason`, for
+    /// `--compact-failures` to print after the test's file.
+    pub(crate) fn summary_line(&self, test: &Test) -> String {
+        let reason = match self {
+            QueryError(error) => error_reason(&error_text(error)),
+            PsqlError(error) => error_reason(error),
+            ReferenceError(error) => format!("compare-with {}", error_reason(&error_text(error))),
+            ConnectionError(error) => {
+                let error = error.lines().next().unwrap_or_default();
+                format!("not run, connection error: {error}")
+            }
+            WrongNumberOfRows {
+                expected, found, ..
+            } => format!("wrong rows (expected {expected} got {found})"),
+            MismatchedValues { .. } => "mismatch".to_string(),
+            MismatchedHeaders { .. } => "mismatched column names".to_string(),
+            WrongNotices { .. } => "wrong notices".to_string(),
+            WrongCommandTags { .. } => "wrong command tags".to_string(),
+            WrongError { received: None, .. } => "expected an error".to_string(),
+            WrongError {
+                received: Some(error),
+                ..
+            } => format!("wrong {}", error_reason(error)),
+            WrongColumnCount { expected, received } => {
+                format!("wrong columns (expected {expected} got {received})")
+            }
+            NotTrue { column, .. } => format!("`{column}` not true"),
+        };
+        format!("{} {} — {reason}", test.line, test.header)
+    }
+
+    pub(crate) fn print(
+        &self,
+        test: &Test,
+        diff_style: DiffStyle,
+        changed_cols: Option<ChangedCols>,
+    ) {
         let test_name = &test.header;
-        let received = match self {
-            WrongNumberOfRows { received, .. } => {
+        let (received, headers) = match self {
+            WrongNumberOfRows { received, headers, .. } => {
                 cprintln!("{test_name}" bold, " failed with:\n");
-                received
+                (received, headers)
             }
-            MismatchedValues(received) => {
+            MismatchedValues { received, headers } => {
                 cprintln!("{test_name}" bold," failed with:\n");
-                received
+                (received, headers)
             }
             QueryError(error) => {
                 cprintln!("{test_name}" bold, " failed due to ", "error" red, ":\n{error}\n");
                 return;
             }
+            PsqlError(error) => {
+                cprintln!("{test_name}" bold, " failed in psql due to ", "error" red, ":\n{error}\n");
+                return;
+            }
+            WrongColumnCount { expected, received } => {
+                cprintln!("{test_name}" bold, " failed with {received} columns, expected {expected}\n");
+                return;
+            }
+            ConnectionError(error) => {
+                cprintln!("{test_name}" bold, " didn't run, its file couldn't connect due to ", "error" red, ":\n{error}\n");
+                return;
+            }
+            ReferenceError(error) => {
+                cprintln!("{test_name}" bold, "'s compare-with query failed due to ", "error" red, ":\n{error}\n");
+                return;
+            }
+            MismatchedHeaders { expected, received } => {
+                let expected = expected.join(" | ");
+                let received = received.join(" | ");
+                cprintln!(
+                    "{test_name}" bold,
+                    " failed with mismatched column names:\n\n",
+                    "Expected\n" blue,
+                    "{expected}\n\n",
+                    "Received\n" blue,
+                    "{received}\n",
+                );
+                return;
+            }
+            WrongError { expected, received } => {
+                let received = received.as_deref().unwrap_or("no error");
+                cprintln!(
+                    "{test_name}" bold,
+                    " failed with the wrong error:\n\n",
+                    "Expected\n" blue,
+                    "{expected}\n\n",
+                    "Received\n" blue,
+                    "{received}\n",
+                );
+                return;
+            }
+            NotTrue {
+                column,
+                received,
+                headers,
+            } => {
+                if received.is_empty() {
+                    cprintln!(
+                        "{test_name}" bold,
+                        " failed, expected `{column}` to be true but no rows were returned\n"
+                    );
+                } else {
+                    let rows = render_psql_table(received, headers);
+                    cprintln!(
+                        "{test_name}" bold,
+                        " failed, expected `{column}` to be true in every row but it wasn't in:\n\n",
+                        "{rows}"
+                    );
+                }
+                return;
+            }
+            WrongNotices { expected, received } | WrongCommandTags { expected, received } => {
+                let what = match self {
+                    WrongNotices { .. } => "notices",
+                    _ => "command tags",
+                };
+                let expected: Vec<&str> = expected.iter().map(String::as_str).collect();
+                let received: Vec<&str> = received.iter().map(String::as_str).collect();
+                let diff = unified_line_diff(&expected, &received);
+                let (expected, received) = (expected.join("\n"), received.join("\n"));
+                cprintln!(
+                    "{test_name}" bold,
+                    " failed with the wrong {what}:\n\n",
+                    "Expected\n" blue,
+                    "{expected}\n\n",
+                    "Received\n" blue,
+                    "{received}\n\n",
+                    "Diff\n" blue,
+                    "{diff}",
+                );
+                return;
+            }
         };
 
+        let separator = test.separator.as_deref().unwrap_or("|");
+        if diff_style == DiffStyle::Unified {
+            let wildcard = test.wildcard.as_deref();
+            let diff = unified_diff(&test.output, received, wildcard, separator);
+            cprintln!("{diff}");
+            for alternative in &test.alternative_outputs {
+                let diff = unified_diff(&alternative.output, received, wildcard, separator);
+                cprintln!("Or" blue);
+                cprintln!("{diff}");
+            }
+            return;
+        }
+
         let expected_rows = test.output.len();
-        let expected_vals = stringify_table(&test.output);
+        let expected_vals = stringify_table(&test.output, separator);
 
         let received_rows = received.len();
-        let received_vals = stringify_table(&received);
+        let received_vals = stringify_table(received, separator);
 
         cprintln!(
             "Expected\n" blue,
             "{expected_vals}\n",
             "({expected_rows} rows)\n" dimmed,
+        );
+        for alternative in &test.alternative_outputs {
+            let alternative_rows = alternative.output.len();
+            let alternative_vals = stringify_table(&alternative.output, separator);
+            cprintln!(
+                "Or\n" blue,
+                "{alternative_vals}\n",
+                "({alternative_rows} rows)\n" dimmed,
+            );
+        }
+        cprintln!(
             "Received\n" blue,
             "{received_vals}\n",
             "({received_rows} rows)\n" dimmed,
         );
 
-        print_diff(&test.output, &received);
+        let wildcard = test.wildcard.as_deref();
+        let shown = changed_cols.map(|ChangedCols { key }| {
+            let key = key.and_then(|key| match headers.iter().position(|h| h == key) {
+                Some(index) => Some(index),
+                None => key.parse::<usize>().ok()?.checked_sub(1),
+            });
+            changed_columns(&test.output, received, wildcard, key)
+        });
+        print_diff(
+            &test.output,
+            received,
+            wildcard,
+            shown.as_deref(),
+            separator,
+        );
     }
 }
 
-fn stringify_table(table: &[Vec<String>]) -> String {
-    use std::{cmp::max, fmt::Write};
+/// Renders `table` with its columns split by `separator`, as the output
+/// blocks they're compared with are written.
+fn stringify_table(table: &[Vec<String>], separator: &str) -> String {
     if table.is_empty() {
         return "---".to_string();
     }
-    let mut width = vec![0; table[0].len()];
-    for row in table {
+    stringify_table_with_widths(table, &column_widths(&[table]), separator)
+}
+
+/// Renders `rows` as psql would, below a header of the column names. Columns
+/// without a name are called `?column?`, as psql does for expressions.
+/// Numbers are right-aligned and everything else left-aligned.
+pub(crate) fn render_psql_table(rows: &[Vec<String>], headers: &[String]) -> String {
+    use std::fmt::Write;
+
+    let num_cols = rows
+        .iter()
+        .map(Vec::len)
+        .chain([headers.len()])
+        .max()
+        .unwrap_or(0);
+    if num_cols == 0 {
+        return "---\n".to_string();
+    }
+    let headers: Vec<String> = (0..num_cols)
+        .map(|i| {
+            headers
+                .get(i)
+                .cloned()
+                .unwrap_or_else(|| "?column?".to_string())
+        })
+        .collect();
+    let width = column_widths(&[std::slice::from_ref(&headers), rows]);
+
+    let mut output = String::new();
+    let header: Vec<String> = headers
+        .iter()
+        .zip(&width)
+        .map(|(name, &width)| format!(" {name:^width$} "))
+        .collect();
+    let _ = writeln!(output, "{}", header.join("|").trim_end());
+    let separator: Vec<String> = width.iter().map(|width| "-".repeat(width + 2)).collect();
+    let _ = writeln!(output, "{}", separator.join("+"));
+    for row in rows {
+        let row: Vec<String> = row
+            .iter()
+            .zip(&width)
+            .map(|(value, &width)| match value.parse::<f64>() {
+                Ok(_) => format!(" {value:>width$} "),
+                Err(_) => format!(" {value:<width$} "),
+            })
+            .collect();
+        let _ = writeln!(output, "{}", row.join("|").trim_end());
+    }
+    output
+}
+
+/// The width needed by each column to fit every value in `tables`.
+fn column_widths(tables: &[&[Vec<String>]]) -> Vec<usize> {
+    use std::cmp::max;
+    let mut width = vec![];
+    for row in tables.iter().flat_map(|table| table.iter()) {
         // Ensure that we have width for every column
         // TODO this shouldn't be needed, but somtimes is?
         if width.len() < row.len() {
@@ -116,11 +914,16 @@ fn stringify_table(table: &[Vec<String>]) -> String {
             width[i] = max(width[i], value.len())
         }
     }
+    width
+}
+
+fn stringify_table_with_widths(table: &[Vec<String>], width: &[usize], separator: &str) -> String {
+    use std::fmt::Write;
     let mut output = String::with_capacity(width.iter().sum::<usize>() + width.len() * 3);
     for row in table {
         for (i, value) in row.iter().enumerate() {
             if i != 0 {
-                output.push_str(" | ")
+                let _ = write!(&mut output, " {separator} ");
             }
             let _ = write!(&mut output, "{:>width$}", value, width = width[i]);
         }
@@ -130,69 +933,1035 @@ fn stringify_table(table: &[Vec<String>]) -> String {
     output
 }
 
-fn print_diff(left: &[Vec<String>], right: &[Vec<String>]) {
-    use std::{cmp::max, io::Write};
-    use termcolor::{Color, ColorSpec, WriteColor};
+/// Renders a unified diff of the two tables, aligned to a shared set of
+/// column widths so that only rows that actually differ show up as changes.
+/// Expected values that are the `wildcard` show the value received in their
+/// place, so they aren't reported as changes.
+fn unified_diff(
+    expected: &[Vec<String>],
+    received: &[Vec<String>],
+    wildcard: Option<&str>,
+    separator: &str,
+) -> String {
+    let expected = fill_wildcards(expected, received, wildcard);
+    let expected = &expected[..];
+    let width = column_widths(&[expected, received]);
+    let expected = stringify_table_with_widths(expected, &width, separator);
+    let received = stringify_table_with_widths(received, &width, separator);
+    let expected: Vec<_> = expected.lines().collect();
+    let received: Vec<_> = received.lines().collect();
+    unified_line_diff(&expected, &received)
+}
+
+/// `expected` with the values that are the `wildcard` replaced by the ones
+/// received in the same place.
+fn fill_wildcards<'e>(
+    expected: &'e [Vec<String>],
+    received: &[Vec<String>],
+    wildcard: Option<&str>,
+) -> Cow<'e, [Vec<String>]> {
+    let Some(wildcard) = wildcard else {
+        return Cow::Borrowed(expected);
+    };
+    let filled = expected
+        .iter()
+        .enumerate()
+        .map(|(i, row)| {
+            row.iter()
+                .enumerate()
+                .map(
+                    |(j, value)| match received.get(i).and_then(|row| row.get(j)) {
+                        Some(received) if value == wildcard => received.clone(),
+                        _ => value.clone(),
+                    },
+                )
+                .collect()
+        })
+        .collect();
+    Cow::Owned(filled)
+}
+
+/// A unified diff, with 3 lines of context, turning `expected` into
+/// `received`.
+pub(crate) fn unified_line_diff(expected: &[&str], received: &[&str]) -> String {
+    use std::fmt::Write;
+    const CONTEXT: usize = 3;
+
+    let edits = diff_lines(expected, received);
+
+    let mut output = "--- expected\n+++ received\n".to_string();
+    let changes: Vec<_> = (0..edits.len())
+        .filter(|&i| !matches!(edits[i].0, Edit::Same))
+        .collect();
+    let mut changes = changes.iter().peekable();
+    while let Some(&first) = changes.next() {
+        let mut last = first;
+        while let Some(&&next) = changes.peek() {
+            if next - last > 2 * CONTEXT {
+                break;
+            }
+            last = next;
+            changes.next();
+        }
+
+        let hunk = &edits[first.saturating_sub(CONTEXT)..(last + CONTEXT + 1).min(edits.len())];
+        let (_, expected_start, received_start) = hunk[0];
+        let expected_len = hunk.iter().filter(|e| e.0 != Edit::Added).count();
+        let received_len = hunk.iter().filter(|e| e.0 != Edit::Removed).count();
+        let range = |start: usize, len: usize| match len {
+            0 => format!("{start},0"),
+            _ => format!("{},{len}", start + 1),
+        };
+        let _ = writeln!(
+            &mut output,
+            "@@ -{} +{} @@",
+            range(expected_start, expected_len),
+            range(received_start, received_len)
+        );
+        for &(edit, e, r) in hunk {
+            let _ = match edit {
+                Edit::Same => writeln!(&mut output, " {}", expected[e]),
+                Edit::Removed => writeln!(&mut output, "-{}", expected[e]),
+                Edit::Added => writeln!(&mut output, "+{}", received[r]),
+            };
+        }
+    }
+    output
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Edit {
+    Same,
+    Removed,
+    Added,
+}
+
+/// Computes a minimal line diff via the longest common subsequence. Each
+/// edit is paired with the index it's at in the left and right inputs.
+fn diff_lines(left: &[&str], right: &[&str]) -> Vec<(Edit, usize, usize)> {
+    let (n, m) = (left.len(), right.len());
+    // common[i][j] is the length of the LCS of left[i..] and right[j..]
+    let mut common = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            common[i][j] = if left[i] == right[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut edits = Vec::with_capacity(n + m);
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && left[i] == right[j] {
+            edits.push((Edit::Same, i, j));
+            i += 1;
+            j += 1;
+        } else if i < n && (j == m || common[i + 1][j] >= common[i][j + 1]) {
+            edits.push((Edit::Removed, i, j));
+            i += 1;
+        } else {
+            edits.push((Edit::Added, i, j));
+            j += 1;
+        }
+    }
+    edits
+}
+
+/// The columns with a value that differs between the tables in some row, and
+/// the `key` column.
+fn changed_columns(
+    left: &[Vec<String>],
+    right: &[Vec<String>],
+    wildcard: Option<&str>,
+    key: Option<usize>,
+) -> Vec<usize> {
+    let num_rows = left.len().max(right.len());
+    let num_cols = left.iter().chain(right).map(Vec::len).max().unwrap_or(0);
+    fn cell(rows: &[Vec<String>], i: usize, j: usize) -> Option<&str> {
+        rows.get(i)?.get(j).map(String::as_str)
+    }
+    (0..num_cols)
+        .filter(|&j| {
+            key == Some(j)
+                || (0..num_rows).any(|i| {
+                    let (left, right) = (cell(left, i, j), cell(right, i, j));
+                    let matches_wildcard = right.is_some() && left == wildcard;
+                    !matches_wildcard && left.unwrap_or("") != right.unwrap_or("")
+                })
+        })
+        .collect()
+}
 
+/// Prints the tables cell by cell, showing the changed values. Values the
+/// `wildcard` matched are shown as received, in a color of their own. With
+/// `shown`, only those columns are, the others collapsed into `…`.
+fn print_diff(
+    left: &[Vec<String>],
+    right: &[Vec<String>],
+    wildcard: Option<&str>,
+    shown: Option<&[usize]>,
+    separator: &str,
+) {
     cprintln!("Diff" blue);
 
+    crate::colors::write_stdout(|output| {
+        write_diff(output, left, right, wildcard, shown, separator)
+    });
+}
+
+fn write_diff(
+    output: &mut impl termcolor::WriteColor,
+    left: &[Vec<String>],
+    right: &[Vec<String>],
+    wildcard: Option<&str>,
+    shown: Option<&[usize]>,
+    separator: &str,
+) {
+    use std::cmp::max;
+    use termcolor::{Color, ColorSpec};
+
     static EMPTY_ROW: Vec<String> = vec![];
     static EMPTY_VAL: String = String::new();
 
     let num_rows = max(left.len(), right.len());
-    let mut width = vec![
-        0;
-        max(
-            left.get(0).map(Vec::len).unwrap_or(0),
-            right.get(0).map(Vec::len).unwrap_or(0),
-        )
-    ];
+    let num_cols = left.iter().chain(right).map(Vec::len).max().unwrap_or(0);
+    let mut width = vec![0; num_cols];
     for i in 0..num_rows {
         let left = left.get(i).unwrap_or(&EMPTY_ROW);
         let right = right.get(i).unwrap_or(&EMPTY_ROW);
         let cols = max(left.len(), right.len());
-        for j in 0..cols {
+        for (j, width) in width.iter_mut().enumerate().take(cols) {
+            let matches_wildcard = j < right.len() && left.get(j).map(|v| &**v) == wildcard;
             let left = left.get(j).unwrap_or(&EMPTY_VAL);
             let right = right.get(j).unwrap_or(&EMPTY_VAL);
-            if left == right {
-                width[j] = max(width[j], left.len())
+            if matches_wildcard {
+                *width = max(*width, right.len())
+            } else if left == right {
+                *width = max(*width, left.len())
             } else {
-                width[j] = max(width[j], left.len() + right.len() + 2)
+                *width = max(*width, left.len() + right.len() + 2)
             }
         }
     }
 
-    let bufwtr = termcolor::BufferWriter::stdout(*crate::colors::STDOUT_COLOR_CHOICE);
-    let mut output = bufwtr.buffer();
+    // the columns in the order they're written, `None` for hidden ones
+    let columns: Vec<Option<usize>> = match shown {
+        None => (0..num_cols).map(Some).collect(),
+        Some(shown) => {
+            let mut columns = vec![];
+            let mut next = 0;
+            for &j in shown {
+                if j > next {
+                    columns.push(None);
+                }
+                columns.push(Some(j));
+                next = j + 1;
+            }
+            if next < num_cols {
+                columns.push(None);
+            }
+            columns
+        }
+    };
+
     for i in 0..num_rows {
         let left = left.get(i).unwrap_or(&EMPTY_ROW);
         let right = right.get(i).unwrap_or(&EMPTY_ROW);
         let cols = max(left.len(), right.len());
-        for j in 0..cols {
+        let columns = columns.iter().filter(|j| j.is_none_or(|j| j < cols));
+        for (written, &column) in columns.enumerate() {
+            if written != 0 {
+                let _ = write!(output, " {separator} ");
+            }
+            let Some(j) = column else {
+                let _ = write!(output, "…");
+                continue;
+            };
+            let width = width[j];
+            let matches_wildcard = j < right.len() && left.get(j).map(|v| &**v) == wildcard;
             let left = left.get(j).unwrap_or(&EMPTY_VAL);
             let right = right.get(j).unwrap_or(&EMPTY_VAL);
-            if j != 0 {
-                let _ = write!(&mut output, " | ");
-            }
-            if left == right {
+            if matches_wildcard {
+                let _ = write!(output, "{:>padding$}", "", padding = width - right.len());
+                let _ = output.set_color(ColorSpec::new().set_fg(Some(Color::Cyan)));
+                let _ = write!(output, "{right}");
+                let _ = output.reset();
+            } else if left == right {
                 let _ = write!(
-                    &mut output,
+                    output,
                     "{:>padding$}{left}",
                     "",
-                    padding = width[j] - left.len()
+                    padding = width - left.len()
                 );
             } else {
-                let padding = width[j] - (left.len() + right.len() + 2);
-                let _ = write!(&mut output, "{:>padding$}", "", padding = padding);
+                let padding = width - (left.len() + right.len() + 2);
+                let _ = write!(output, "{:>padding$}", "", padding = padding);
                 let _ = output.set_color(ColorSpec::new().set_fg(Some(Color::Magenta)));
-                let _ = write!(&mut output, "-{left}");
+                let _ = write!(output, "-{left}");
                 let _ = output.set_color(ColorSpec::new().set_fg(Some(Color::Yellow)));
-                let _ = write!(&mut output, "+{right}");
+                let _ = write!(output, "+{right}");
                 let _ = output.reset();
             };
         }
-        let _ = writeln!(&mut output);
+        let _ = writeln!(output);
+    }
+    let _ = writeln!(output);
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    fn table(rows: &[&[&str]]) -> Vec<Vec<String>> {
+        rows.iter()
+            .map(|row| row.iter().map(|v| v.to_string()).collect())
+            .collect()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn nulls_are_written_as_the_tests_token() {
+        use super::{validate_output, TestResult::*};
+        use crate::{Args, Test};
+        use clap::Parser;
+
+        let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL is not set");
+        let (client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        let output = client
+            .simple_query("SELECT NULL::text a, '' b, 1 c UNION ALL SELECT 'x', NULL, NULL")
+            .await
+            .unwrap();
+
+        let args = Args::parse_from(["test", "."]);
+        let test = Test {
+            output: table(&[&["∅", "", "1"], &["x", "∅", "∅"]]),
+            null_as: Some("∅".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(validate_output(&output, &test, &args), Passed));
+
+        // without it NULLs are empty, like the empty string
+        let test = Test {
+            output: table(&[&["", "", "1"], &["x", "", ""]]),
+            ..Default::default()
+        };
+        assert!(matches!(validate_output(&output, &test, &args), Passed));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn command_tags_are_checked_in_order() {
+        use super::{validate_command_tags, FailureInfo::*, TestResult::*};
+        use crate::Test;
+
+        let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL is not set");
+        let (client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        let sql = "CREATE TEMP TABLE tags_test(a int);\n\
+            INSERT INTO tags_test VALUES (1), (2), (3);\n\
+            UPDATE tags_test SET a = a + 1 WHERE a > 1;\n\
+            DELETE FROM tags_test;\n\
+            PREPARE tags_insert AS INSERT INTO tags_test VALUES (1);\n\
+            EXECUTE tags_insert;\n\
+            LOCK tags_test;\n\
+            DECLARE tags_cursor CURSOR FOR SELECT * FROM tags_test;\n\
+            CLOSE tags_cursor;";
+        let output = client.simple_query(sql).await.unwrap();
+
+        let expected = |tags: &[&str]| Test {
+            text: sql.to_string(),
+            command_tags: Some(tags.iter().map(|tag| tag.to_string()).collect()),
+            ..Default::default()
+        };
+        // the tags the server sends, whichever of them can't be guessed from
+        // the statements
+        let tags = [
+            "CREATE TABLE",
+            "INSERT 0 3",
+            "UPDATE 2",
+            "DELETE 3",
+            "PREPARE",
+            "INSERT 0 1",
+            "LOCK TABLE",
+            "DECLARE CURSOR",
+            "CLOSE CURSOR",
+        ];
+        assert!(matches!(
+            validate_command_tags(&expected(&tags), &output),
+            Passed
+        ));
+
+        let mut wrong = tags;
+        (wrong[2], wrong[5]) = ("UPDATE 3", "INSERT 0 2");
+        match validate_command_tags(&expected(&wrong), &output) {
+            Failed(WrongCommandTags { received, .. }) => assert_eq!(received, tags),
+            _ => panic!("expected the tags to differ"),
+        }
+        assert!(matches!(
+            validate_command_tags(&expected(&tags[..3]), &output),
+            Failed(WrongCommandTags { .. })
+        ));
+    }
+
+    #[test]
+    fn later_statements_rows_are_kept() {
+        use super::{combine_results, ResultSet};
+
+        let sets = || {
+            vec![
+                ResultSet::default(),
+                ResultSet {
+                    headers: vec!["a".to_string()],
+                    rows: table(&[&["1"]]),
+                },
+                ResultSet {
+                    headers: vec!["b".to_string()],
+                    rows: table(&[&["2"], &["3"]]),
+                },
+            ]
+        };
+        assert_eq!(
+            combine_results(sets(), false),
+            (vec!["a".to_string()], table(&[&["1"], &["2"], &["3"]]))
+        );
+        assert_eq!(combine_results(sets(), true), (vec![], vec![]));
+        assert_eq!(
+            combine_results(sets().split_off(1), true),
+            (vec!["a".to_string()], table(&[&["1"]]))
+        );
+    }
+
+    #[test]
+    fn ignore_headers_wins_over_check_headers() {
+        use super::{compare_output, FailureInfo::MismatchedHeaders, TestResult::*};
+        use crate::{Args, Test};
+        use clap::Parser;
+
+        let args = Args::parse_from(["test", "--check-headers", "."]);
+        let mut test = Test {
+            text: "select 1".to_string(),
+            output: table(&[&["1"]]),
+            output_headers: vec!["one".to_string()],
+            ..Default::default()
+        };
+        let headers = || vec!["?column?".to_string()];
+
+        let result = compare_output(&test, headers(), table(&[&["1"]]), &args);
+        assert!(matches!(result, Failed(MismatchedHeaders { .. })));
+
+        test.check_headers = Some(false);
+        let result = compare_output(&test, headers(), table(&[&["1"]]), &args);
+        assert!(matches!(result, Passed));
+
+        test.check_headers = None;
+        let args = Args::parse_from(["test", "."]);
+        let result = compare_output(&test, headers(), table(&[&["1"]]), &args);
+        assert!(matches!(result, Passed));
+    }
+
+    #[test]
+    fn tolerances_allow_approximate_values() {
+        use super::{compare_output, TestResult::*, Tolerance::*};
+        use crate::{Args, Test};
+        use clap::Parser;
+
+        let args = Args::parse_from(["test", "."]);
+        let mut test = Test {
+            output: table(&[&["a", "1.0", "1000"]]),
+            tolerances: vec![(1, Absolute(1e-3)), (2, Relative(1e-3))],
+            ..Default::default()
+        };
+        let passes = |test: &Test, row: &[&str]| {
+            matches!(compare_output(test, vec![], table(&[row]), &args), Passed)
+        };
+
+        assert!(passes(&test, &["a", "1.0005", "1000.9"]));
+        assert!(passes(&test, &["a", "0.9991", "999.1"]));
+        // just outside of tolerance
+        assert!(!passes(&test, &["a", "1.0011", "1000"]));
+        assert!(!passes(&test, &["a", "1.0", "1001.1"]));
+        // other columns are still compared exactly
+        assert!(!passes(&test, &["b", "1.0", "1000"]));
+        // as are values that aren't numbers
+        assert!(!passes(&test, &["a", "", "1000"]));
+
+        test.tolerances = vec![(1, Precision(2))];
+        assert!(passes(&test, &["a", "1.04", "1000"]));
+        assert!(!passes(&test, &["a", "1.06", "1000"]));
+        // digits are counted from the first significant one
+        test.output = table(&[&["a", "12345.6", "0.0012345"]]);
+        test.tolerances = vec![(1, Precision(3)), (2, Precision(3))];
+        assert!(passes(&test, &["a", "12340", "0.001234"]));
+        assert!(!passes(&test, &["a", "12351", "0.0012345"]));
+        assert!(!passes(&test, &["a", "12345.6", "0.001236"]));
+        test.output = table(&[&["a", "1.0", "1000"]]);
+
+        test.tolerances.clear();
+        assert!(!passes(&test, &["a", "1.0005", "1000"]));
+    }
+
+    #[test]
+    fn bool_columns_match_however_booleans_are_written() {
+        use super::{compare_output, TestResult::*};
+        use crate::{Args, Test};
+        use clap::Parser;
+
+        let args = Args::parse_from(["test", "."]);
+        let mut test = Test {
+            output: table(&[&["true", "False", "t"], &["0", "TRUE", "yes"]]),
+            bool_cols: vec![0, 1, 2],
+            ..Default::default()
+        };
+        let passes = |test: &Test, rows: &[&[&str]]| {
+            matches!(compare_output(test, vec![], table(rows), &args), Passed)
+        };
+
+        assert!(passes(&test, &[&["t", "f", "t"], &["f", "t", "yes"]]));
+        assert!(!passes(&test, &[&["f", "f", "t"], &["f", "t", "yes"]]));
+        // values that aren't booleans are still compared exactly
+        assert!(!passes(&test, &[&["t", "f", "t"], &["f", "t", "y"]]));
+
+        test.bool_cols = vec![1, 2];
+        assert!(!passes(&test, &[&["t", "f", "t"], &["0", "t", "yes"]]));
+        assert!(passes(&test, &[&["true", "f", "t"], &["0", "t", "yes"]]));
+    }
+
+    #[test]
+    fn assert_true_needs_every_row_true() {
+        use super::{check_all_true, FailureInfo::*, TestResult::*};
+
+        let headers = || ["name", "ok"].map(String::from).to_vec();
+        let rows = table(&[&["a", "t"], &["b", "f"], &["c", "t"]]);
+        match check_all_true("ok", headers(), rows) {
+            Failed(NotTrue { received, .. }) => assert_eq!(received, table(&[&["b", "f"]])),
+            _ => panic!("a false row should fail"),
+        }
+        let rows = table(&[&["a", "t"], &["c", "t"]]);
+        assert!(matches!(check_all_true("2", headers(), rows), Passed));
+        assert!(matches!(
+            check_all_true("ok", vec![], vec![]),
+            Failed(NotTrue { .. })
+        ));
+        let rows = table(&[&["a", "t"]]);
+        assert!(matches!(
+            check_all_true("result", headers(), rows),
+            Failed(MismatchedHeaders { .. })
+        ));
+    }
+
+    #[test]
+    fn ignored_columns_are_left_out() {
+        use super::{compare_output, without_columns, TestResult::*};
+        use crate::{Args, Test};
+        use clap::Parser;
+
+        let args = Args::parse_from(["test", "."]);
+        let test = Test {
+            output_headers: vec!["name".to_string(), "count".to_string()],
+            output: table(&[&["a", "1"], &["b", "2"]]),
+            ignore_cols: vec![1],
+            check_headers: Some(true),
+            ..Default::default()
+        };
+        let headers = ["name", "id", "count"].map(String::from).to_vec();
+        let received = table(&[&["a", "17", "1"], &["b", "42", "2"]]);
+        let (headers, received) = without_columns(headers, received, &test.ignore_cols);
+        assert_eq!(headers, ["name", "count"]);
+        assert_eq!(received, table(&[&["a", "1"], &["b", "2"]]));
+        assert!(matches!(
+            compare_output(&test, headers, received, &args),
+            Passed
+        ));
+
+        let (_, received) = without_columns(vec![], table(&[&["a", "17", "2"]]), &[1]);
+        assert!(!matches!(
+            compare_output(&test, vec![], received, &args),
+            Passed
+        ));
+    }
+
+    #[test]
+    fn wildcards_match_any_value() {
+        use super::{compare_output, unified_diff, TestResult::*};
+        use crate::{Args, Test};
+        use clap::Parser;
+
+        let args = Args::parse_from(["test", "."]);
+        let test = Test {
+            output: table(&[&["1", "...", "a"], &["2", "...", "b"]]),
+            wildcard: Some("...".to_string()),
+            ..Default::default()
+        };
+        let passes =
+            |rows: &[&[&str]]| matches!(compare_output(&test, vec![], table(rows), &args), Passed);
+
+        assert!(passes(&[
+            &["1", "2022-07-01 12:00", "a"],
+            &["2", "2022-07-02 08:30", "b"]
+        ]));
+        assert!(passes(&[&["1", "", "a"], &["2", "...", "b"]]));
+        // the other columns are still compared exactly
+        assert!(!passes(&[&["1", "x", "a"], &["2", "y", "c"]]));
+        // a wildcard only stands for a single value
+        assert!(!passes(&[&["1", "x", "a"]]));
+
+        let received = table(&[&["1", "x", "a"], &["2", "y", "c"]]);
+        assert_eq!(
+            unified_diff(&test.output, &received, Some("..."), "|"),
+            "--- expected\n+++ received\n@@ -1,2 +1,2 @@\n 1 | x | a\n-2 | y | b\n+2 | y | c\n"
+        );
+    }
+
+    #[test]
+    fn expected_errors_are_matched_by_line() {
+        use super::error_matches;
+
+        let received = "ERROR:  duplicate key value violates unique constraint \"t_pkey\"\nDETAIL:  Key (id)=(1) already exists.";
+        assert!(error_matches(
+            "ERROR: duplicate key value violates unique constraint\nDETAIL:  Key (id)=(1) already exists.",
+            received
+        ));
+        assert!(error_matches("unique constraint \"t_pkey\"", received));
+        assert!(!error_matches("ERROR:  division by zero", received));
+        assert!(!error_matches(
+            "ERROR:  duplicate key value\nHINT:  try again",
+            received
+        ));
+    }
+
+    #[test]
+    fn any_alternative_output_is_accepted() {
+        use super::{compare_output, FailureInfo::*, TestResult::*};
+        use crate::{AlternativeOutput, Args, Test};
+        use clap::Parser;
+
+        let args = Args::parse_from(["test", "--check-headers", "."]);
+        let alternative = |headers: &[&str], rows: &[&[&str]]| AlternativeOutput {
+            output_headers: headers.iter().map(|h| h.to_string()).collect(),
+            output: table(rows),
+        };
+        let test = Test {
+            output: table(&[&["1.0"]]),
+            output_headers: vec!["x".to_string()],
+            alternative_outputs: vec![
+                alternative(&["x"], &[&["1"]]),
+                alternative(&["y"], &[&["1.00"], &["2"]]),
+            ],
+            ..Default::default()
+        };
+        let result = |headers: &[&str], rows: &[&[&str]]| {
+            let headers = headers.iter().map(|h| h.to_string()).collect();
+            compare_output(&test, headers, table(rows), &args)
+        };
+
+        assert!(matches!(result(&["x"], &[&["1.0"]]), Passed));
+        assert!(matches!(result(&["x"], &[&["1"]]), Passed));
+        assert!(matches!(result(&["y"], &[&["1.00"], &["2"]]), Passed));
+        // failures are reported against the test's own output
+        assert!(matches!(
+            result(&["x"], &[&["1.00"], &["2"]]),
+            Failed(WrongNumberOfRows { expected: 1, .. })
+        ));
+        assert!(matches!(
+            result(&["x"], &[&["2"]]),
+            Failed(MismatchedValues { .. })
+        ));
+        assert!(matches!(
+            result(&["z"], &[&["1"]]),
+            Failed(MismatchedHeaders { .. })
+        ));
+    }
+
+    #[test]
+    fn notices_are_compared_in_order_unless_unordered() {
+        use super::{validate_notices, ExpectedNotices, FailureInfo::WrongNotices, TestResult::*};
+        use crate::Test;
+
+        let strings = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let expected = |unordered, contains| ExpectedNotices {
+            messages: strings(&["one", "two"]),
+            unordered,
+            contains,
+        };
+        let accepts =
+            |expected: &ExpectedNotices, received: &[&str]| expected.accepts(&strings(received));
+
+        let exact = expected(false, false);
+        assert!(accepts(&exact, &["one", "two"]));
+        assert!(!accepts(&exact, &["two", "one"]));
+        assert!(!accepts(&exact, &["one", "two", "three"]));
+
+        let unordered = expected(true, false);
+        assert!(accepts(&unordered, &["two", "one"]));
+        assert!(!accepts(&unordered, &["two", "one", "one"]));
+        assert!(!accepts(&unordered, &["one", "one"]));
+
+        let contains = expected(false, true);
+        assert!(accepts(&contains, &["zero", "one", "half", "two"]));
+        assert!(!accepts(&contains, &["two", "one"]));
+
+        let unordered_contains = expected(true, true);
+        assert!(accepts(&unordered_contains, &["two", "zero", "one"]));
+        assert!(!accepts(&unordered_contains, &["two", "two"]));
+
+        let test = Test {
+            notices: Some(exact),
+            ..Default::default()
+        };
+        assert!(matches!(
+            validate_notices(&test, strings(&["one", "two"])),
+            Passed
+        ));
+        assert!(matches!(
+            validate_notices(&test, strings(&["one"])),
+            Failed(WrongNotices { .. })
+        ));
+        assert!(matches!(
+            validate_notices(&Test::default(), strings(&["one"])),
+            Passed
+        ));
+    }
+
+    #[test]
+    fn sort_by_sorts_rows_before_comparing() {
+        use super::{compare_output, FailureInfo::MismatchedValues, TestResult::*};
+        use crate::{Args, Test};
+        use clap::Parser;
+
+        let args = Args::parse_from(["test", "."]);
+        let test = Test {
+            output: table(&[&["1", "a"], &["2", "a"], &["1", "b"]]),
+            sort_by: vec![1, 0],
+            ..Default::default()
+        };
+
+        let received = table(&[&["1", "b"], &["2", "a"], &["1", "a"]]);
+        let result = compare_output(&test, vec![], received, &args);
+        assert!(matches!(result, Passed));
+
+        // mismatches are reported sorted, to line up with the expected rows
+        let received = table(&[&["1", "b"], &["3", "a"], &["1", "a"]]);
+        match compare_output(&test, vec![], received, &args) {
+            Failed(MismatchedValues { received, .. }) => {
+                assert_eq!(received, table(&[&["1", "a"], &["3", "a"], &["1", "b"]]))
+            }
+            _ => panic!("expected mismatched values"),
+        }
+    }
+
+    #[test]
+    fn failures_are_summarized_in_a_line() {
+        use super::FailureInfo::{self, *};
+        use crate::Test;
+
+        let test = Test {
+            line: 12,
+            header: "`Totals`".to_string(),
+            ..Default::default()
+        };
+        let summary = |failure: FailureInfo| failure.summary_line(&test);
+        let strings = |values: &[&str]| values.iter().map(|v| v.to_string()).collect();
+
+        let rows = |expected, found| WrongNumberOfRows {
+            received: vec![],
+            headers: vec![],
+            expected,
+            found,
+        };
+        assert_eq!(
+            summary(rows(3, 1)),
+            "12 `Totals` — wrong rows (expected 3 got 1)"
+        );
+        let values = MismatchedValues {
+            received: vec![],
+            headers: vec![],
+        };
+        assert_eq!(summary(values), "12 `Totals` — mismatch");
+        let error =
+            "psql:<stdin>:3: ERROR:  relation \"t\" does not exist\nLINE 1: select * from t";
+        assert_eq!(
+            summary(PsqlError(error.to_string())),
+            "12 `Totals` — error: relation \"t\" does not exist"
+        );
+        let timeout = "ERROR:  canceling statement due to statement timeout";
+        assert_eq!(
+            summary(PsqlError(timeout.to_string())),
+            "12 `Totals` — timeout"
+        );
+
+        let reason = |failure: FailureInfo| {
+            let summary = summary(failure);
+            summary.split_once(" — ").unwrap().1.to_string()
+        };
+        assert_eq!(
+            reason(ConnectionError("connection refused\nretrying".to_string())),
+            "not run, connection error: connection refused"
+        );
+        let headers = MismatchedHeaders {
+            expected: strings(&["a"]),
+            received: strings(&["b"]),
+        };
+        assert_eq!(reason(headers), "mismatched column names");
+        let notices = WrongNotices {
+            expected: strings(&["hi"]),
+            received: vec![],
+        };
+        assert_eq!(reason(notices), "wrong notices");
+        let tags = WrongCommandTags {
+            expected: strings(&["SELECT 1"]),
+            received: strings(&["SELECT 2"]),
+        };
+        assert_eq!(reason(tags), "wrong command tags");
+        let no_error = WrongError {
+            expected: "division by zero".to_string(),
+            received: None,
+        };
+        assert_eq!(reason(no_error), "expected an error");
+        let wrong_error = WrongError {
+            expected: "division by zero".to_string(),
+            received: Some("ERROR:  syntax error at end of input".to_string()),
+        };
+        assert_eq!(
+            reason(wrong_error),
+            "wrong error: syntax error at end of input"
+        );
+        let columns = WrongColumnCount {
+            expected: 2,
+            received: 3,
+        };
+        assert_eq!(reason(columns), "wrong columns (expected 2 got 3)");
+        let not_true = NotTrue {
+            column: "ok".to_string(),
+            received: vec![],
+            headers: vec![],
+        };
+        assert_eq!(reason(not_true), "`ok` not true");
+    }
+
+    #[test]
+    fn set_ignores_order_and_duplicates() {
+        use super::{compare_output, FailureInfo::WrongNumberOfRows, TestResult::*};
+        use crate::{Args, Test};
+        use clap::Parser;
+
+        let args = Args::parse_from(["test", "."]);
+        let test = Test {
+            output: table(&[&["b"], &["a"]]),
+            as_set: true,
+            ..Default::default()
+        };
+
+        let received = table(&[&["a"], &["b"], &["a"]]);
+        let result = compare_output(&test, vec![], received.clone(), &args);
+        assert!(matches!(result, Passed));
+        // without `set` the duplicate is an extra row
+        let multiset = Test {
+            as_set: false,
+            ..test.clone()
+        };
+        let result = compare_output(&multiset, vec![], received, &args);
+        assert!(matches!(result, Failed(WrongNumberOfRows { .. })));
+
+        let received = table(&[&["a"], &["a"]]);
+        match compare_output(&test, vec![], received, &args) {
+            Failed(WrongNumberOfRows {
+                expected, found, ..
+            }) => assert_eq!((expected, found), (2, 1)),
+            _ => panic!("expected the wrong number of rows"),
+        }
+    }
+
+    #[test]
+    fn grouped_by_accepts_groups_in_any_order() {
+        use super::{compare_output, FailureInfo::MismatchedValues, TestResult::*};
+        use crate::{Args, Test};
+        use clap::Parser;
+
+        let args = Args::parse_from(["test", "."]);
+        let test = Test {
+            output: table(&[&["a", "1"], &["a", "2"], &["b", "3"], &["b", "1"]]),
+            grouped_by: vec![0],
+            ..Default::default()
+        };
+
+        let received = table(&[&["b", "3"], &["b", "1"], &["a", "1"], &["a", "2"]]);
+        let result = compare_output(&test, vec![], received, &args);
+        assert!(matches!(result, Passed));
+
+        // the rows within a group are still compared in order
+        let received = table(&[&["b", "1"], &["b", "3"], &["a", "1"], &["a", "2"]]);
+        match compare_output(&test, vec![], received, &args) {
+            Failed(MismatchedValues { received, .. }) => assert_eq!(
+                received,
+                table(&[&["a", "1"], &["a", "2"], &["b", "1"], &["b", "3"]])
+            ),
+            _ => panic!("expected mismatched values"),
+        }
+    }
+
+    #[test]
+    fn expect_empty_fails_on_rows() {
+        use super::{compare_output, FailureInfo::WrongNumberOfRows, TestResult::*};
+        use crate::{Args, Test};
+        use clap::Parser;
+
+        let args = Args::parse_from(["test", "--check-headers", "."]);
+        let test = Test {
+            text: "select 1".to_string(),
+            expect_empty: true,
+            check_headers: Some(false),
+            ..Default::default()
+        };
+        let headers = vec!["?column?".to_string()];
+
+        let result = compare_output(&test, headers, table(&[&["1"]]), &args);
+        assert!(matches!(result, Failed(WrongNumberOfRows { found: 1, .. })));
+
+        let result = compare_output(&test, vec![], vec![], &args);
+        assert!(matches!(result, Passed));
+    }
+
+    #[test]
+    fn psql_tables_are_aligned() {
+        let rows = table(&[&["1", "alpha"], &["100", "b"]]);
+        let headers = ["id".to_string(), "name".to_string()];
+        let expected = [
+            " id  | name",
+            "-----+-------",
+            "   1 | alpha",
+            " 100 | b",
+            "",
+        ];
+        assert_eq!(
+            super::render_psql_table(&rows, &headers),
+            expected.join("\n")
+        );
+
+        // missing names are inferred
+        let expected = [
+            " ?column? | ?column?",
+            "----------+----------",
+            "        1 | alpha",
+            "      100 | b",
+            "",
+        ];
+        assert_eq!(super::render_psql_table(&rows, &[]), expected.join("\n"));
+
+        let expected = " count\n-------\n";
+        assert_eq!(
+            super::render_psql_table(&[], &["count".to_string()]),
+            expected
+        );
+        assert_eq!(super::render_psql_table(&[], &[]), "---\n");
+    }
+
+    #[test]
+    fn diff_can_show_only_changed_columns() {
+        use super::{changed_columns, write_diff};
+
+        let expected = table(&[&["1", "a", "x", "10", "p"], &["2", "b", "y", "20", "q"]]);
+        let received = table(&[&["1", "a", "x", "11", "p"], &["2", "b", "y", "20", "q"]]);
+        let diff = |shown: Option<&[usize]>| {
+            let mut output = termcolor::Buffer::no_color();
+            write_diff(&mut output, &expected, &received, None, shown, "|");
+            String::from_utf8(output.into_inner()).unwrap()
+        };
+
+        let shown = changed_columns(&expected, &received, None, Some(0));
+        assert_eq!(shown, [0, 3]);
+        assert_eq!(
+            diff(Some(&shown)),
+            "1 | … | -10+11 | …\n2 | … |     20 | …\n\n"
+        );
+        assert_eq!(changed_columns(&expected, &received, None, None), [3]);
+        assert_eq!(
+            diff(None),
+            "1 | a | x | -10+11 | p\n2 | b | y |     20 | q\n\n"
+        );
+    }
+
+    #[test]
+    fn unified_diff_of_small_mismatch() {
+        let expected = table(&[&["1", "a"], &["2", "b"], &["3", "c"]]);
+        let received = table(&[&["1", "a"], &["20", "b"], &["3", "c"], &["4", "d"]]);
+        let diff = super::unified_diff(&expected, &received, None, "|");
+        let expected_diff = [
+            "--- expected",
+            "+++ received",
+            "@@ -1,3 +1,4 @@",
+            "  1 | a",
+            "- 2 | b",
+            "+20 | b",
+            "  3 | c",
+            "+ 4 | d",
+        ];
+        assert_eq!(diff.lines().collect::<Vec<_>>(), expected_diff);
+    }
+
+    #[test]
+    fn diffs_use_the_output_blocks_separator() {
+        let expected = table(&[&["1", "a"], &["2", "b"]]);
+        let received = table(&[&["1", "a"], &["2", "c"]]);
+        let diff = super::unified_diff(&expected, &received, None, ";");
+        assert!(diff.ends_with(" 1 ; a\n-2 ; b\n+2 ; c\n"));
+        assert_eq!(super::stringify_table(&expected, ";"), "1 ; a\n2 ; b\n");
+    }
+
+    #[test]
+    fn unified_diff_splits_distant_changes_into_hunks() {
+        let expected: Vec<_> = (0..20).map(|i| vec![format!("{i:02}")]).collect();
+        let mut received = expected.clone();
+        received[1][0] = "xx".to_string();
+        received[18][0] = "yy".to_string();
+        let diff = super::unified_diff(&expected, &received, None, "|");
+        let headers: Vec<_> = diff.lines().filter(|l| l.starts_with("@@")).collect();
+        assert_eq!(headers, ["@@ -1,5 +1,5 @@", "@@ -16,5 +16,5 @@"]);
+        assert!(diff.contains("\n-01\n+xx\n"));
+        assert!(diff.contains("\n-18\n+yy\n"));
+    }
+
+    #[test]
+    fn unified_diff_of_identical_tables_is_empty() {
+        let rows = table(&[&["1"]]);
+        assert_eq!(
+            super::unified_diff(&rows, &rows, None, "|"),
+            "--- expected\n+++ received\n"
+        );
+    }
+
+    #[test]
+    fn failures_can_be_captured() {
+        use super::{DiffStyle, FailureInfo::*};
+        use crate::colors::capture_stdout;
+        use crate::Test;
+
+        let test = Test {
+            header: "`a.md:3`".to_string(),
+            output_headers: vec!["a".to_string()],
+            output: table(&[&["1"]]),
+            ..Default::default()
+        };
+        let failure = WrongNumberOfRows {
+            received: table(&[&["1"], &["2"]]),
+            headers: vec!["a".to_string()],
+            expected: 1,
+            found: 2,
+        };
+        let sink = termcolor::Buffer::no_color();
+        let ((), sink) = capture_stdout(sink, || failure.print(&test, DiffStyle::Unified, None));
+        let printed = String::from_utf8(sink.into_inner()).unwrap();
+        assert_eq!(
+            printed,
+            "`a.md:3` failed with:\n\n\
+            --- expected\n+++ received\n@@ -1,1 +1,2 @@\n 1\n+2\n\n"
+        );
     }
-    let _ = writeln!(&mut output);
-    let _ = bufwtr.print(&output);
 }
@@ -0,0 +1,228 @@
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use xshell::{cmd, Shell};
+
+use crate::runner::PGPORT;
+use crate::{cprintln, Args};
+
+/// The binaries from `pg_config --bindir` the runner uses.
+const BINARIES: &[&str] = &["initdb", "postgres", "psql", "createdb", "dropdb"];
+
+/// One of the checks `--doctor` makes, with what it found or why it failed.
+struct Check {
+    name: String,
+    outcome: Result<String, String>,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, outcome: Result<String, String>) -> Self {
+        Self {
+            name: name.into(),
+            outcome,
+        }
+    }
+}
+
+/// Checks that the environment can run the tests with `args`, printing the
+/// result of each check and failing if any did.
+pub(crate) fn run(args: &Args) -> Result<()> {
+    let checks = checks(args)?;
+    let mut num_failed = 0;
+    for Check { name, outcome } in &checks {
+        match outcome {
+            Ok(found) => cprintln!("{name} ... ", "ok" green, " ({found})"),
+            Err(reason) => {
+                num_failed += 1;
+                cprintln!("{name} ... ", "FAILED" bold red, ": {reason}")
+            }
+        }
+    }
+    if num_failed > 0 {
+        bail!("{num_failed} checks failed")
+    }
+    Ok(())
+}
+
+fn checks(args: &Args) -> Result<Vec<Check>> {
+    let sh = Shell::new()?;
+    let mut checks = vec![];
+
+    #[cfg(unix)]
+    checks.push(Check::new(
+        "not running as root",
+        match nix::unistd::geteuid().is_root() {
+            true => Err("postgres refuses to run as root".to_string()),
+            false => Ok("running as a regular user".to_string()),
+        },
+    ));
+
+    let pg_configs = match &*args.pg_configs {
+        [] => vec!["pg_config".to_string()],
+        pg_configs => pg_configs.to_vec(),
+    };
+    for pg_config in &pg_configs {
+        checks.extend(check_pg_config(&sh, pg_config));
+    }
+
+    checks.push(Check::new(
+        format!("port {PGPORT}"),
+        match TcpListener::bind(("localhost", PGPORT.parse::<u16>()?)) {
+            Ok(_) => Ok("available".to_string()),
+            // the server is started on another port when it can't use this one
+            Err(_) if args.startup_retries > 0 => {
+                Ok("in use, `--startup-retries` will pick another".to_string())
+            }
+            Err(e) => Err(format!("could not bind it: {e}")),
+        },
+    ));
+
+    let temp_base = std::env::temp_dir();
+    checks.push(Check::new(
+        "temporary directory",
+        match tempfile::tempdir() {
+            Ok(_) => Ok(format!("{} is writable", temp_base.display())),
+            Err(e) => Err(format!("could not write to {}: {e}", temp_base.display())),
+        },
+    ));
+
+    Ok(checks)
+}
+
+/// Checks `pg_config` runs, and that the binaries in its bindir do.
+fn check_pg_config(sh: &Shell, pg_config: &str) -> Vec<Check> {
+    let mut checks = vec![];
+    let bindir = cmd!(sh, "{pg_config} --bindir")
+        .quiet()
+        .ignore_stderr()
+        .read()
+        .map(PathBuf::from)
+        .map_err(|e| format!("could not run `{pg_config}`, is it on the PATH? {e}"));
+    checks.push(Check::new(
+        pg_config,
+        bindir
+            .clone()
+            .map(|dir| format!("bindir {}", dir.display())),
+    ));
+    if let Ok(bindir) = &bindir {
+        checks.extend(check_binaries(bindir));
+        let postgres = bindir.join("postgres");
+        let version = cmd!(sh, "{postgres} --version")
+            .quiet()
+            .ignore_stderr()
+            .read()
+            .map_err(|e| format!("could not get the version: {e}"));
+        checks.push(Check::new("server version", version));
+    }
+    checks
+}
+
+/// Checks each binary the runner needs is in `bindir` and executable.
+fn check_binaries(bindir: &Path) -> Vec<Check> {
+    BINARIES
+        .iter()
+        .map(|binary| {
+            let path = bindir.join(binary);
+            let location = path.display();
+            let outcome = match is_executable(&path) {
+                Ok(true) => Ok(location.to_string()),
+                Ok(false) => Err(format!("{location} is not executable")),
+                Err(e) => Err(format!("{location} not found: {e}")),
+            };
+            Check::new(*binary, outcome)
+        })
+        .collect()
+}
+
+fn is_executable(path: &Path) -> std::io::Result<bool> {
+    let metadata = std::fs::metadata(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        Ok(metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+    }
+    #[cfg(not(unix))]
+    Ok(metadata.is_file())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use pretty_assertions::assert_eq;
+
+    use super::{check_binaries, check_pg_config, BINARIES};
+
+    #[cfg(unix)]
+    #[test]
+    fn missing_binaries_are_reported() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        for binary in BINARIES.iter().filter(|b| **b != "createdb") {
+            let path = dir.path().join(binary);
+            fs::write(&path, "").unwrap();
+            let mode = if *binary == "dropdb" { 0o644 } else { 0o755 };
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        }
+
+        let bindir = dir.path().to_string_lossy();
+        let failed: Vec<(String, String)> = check_binaries(dir.path())
+            .into_iter()
+            .filter_map(|check| {
+                let reason = check.outcome.err()?.replace(&*bindir, "<bindir>");
+                Some((check.name, reason))
+            })
+            .collect();
+        assert_eq!(failed.len(), 2);
+        assert_eq!(failed[0].0, "createdb");
+        assert!(failed[0].1.starts_with("<bindir>/createdb not found"));
+        assert_eq!(
+            failed[1],
+            (
+                "dropdb".to_string(),
+                "<bindir>/dropdb is not executable".to_string()
+            )
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn the_given_pg_config_is_checked() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let bindir = dir.path().display();
+        let scripts = [
+            ("my_pg_config", format!("#!/bin/sh\necho {bindir}\n")),
+            (
+                "postgres",
+                "#!/bin/sh\necho 'postgres (PostgreSQL) 15.1'\n".to_string(),
+            ),
+        ];
+        for (name, script) in scripts {
+            let path = dir.path().join(name);
+            fs::write(&path, script).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        let sh = xshell::Shell::new().unwrap();
+        let pg_config = dir.path().join("my_pg_config");
+        let pg_config = pg_config.to_str().unwrap();
+        let checks = check_pg_config(&sh, pg_config);
+        assert_eq!(checks[0].name, pg_config);
+        assert_eq!(checks[0].outcome, Ok(format!("bindir {bindir}")));
+        let version = checks.last().unwrap();
+        assert_eq!(version.name, "server version");
+        assert_eq!(
+            version.outcome,
+            Ok("postgres (PostgreSQL) 15.1".to_string())
+        );
+
+        let missing = dir.path().join("missing");
+        let checks = check_pg_config(&sh, missing.to_str().unwrap());
+        assert_eq!(checks.len(), 1);
+        assert!(checks[0].outcome.is_err());
+    }
+}
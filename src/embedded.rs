@@ -0,0 +1,219 @@
+//! Running the tests in a string against a client the caller connected, so
+//! they can be checked from a `#[tokio::test]` without the binary managing a
+//! server.
+
+use std::collections::HashMap;
+
+use clap::Parser;
+use tokio_postgres::Client;
+
+use crate::db_output::{DiffStyle, FailureInfo, TestResult};
+use crate::parser::{self, ParseError};
+use crate::runner::{substitute_variables, validate_ran, with_reference, Ran};
+use crate::{Args, Test};
+
+/// The outcome of each test run by [`run_tests_in_str`], in order.
+pub struct RunReport {
+    pub tests: Vec<TestReport>,
+}
+
+pub struct TestReport {
+    pub test: Test,
+    /// why the test failed, `None` if it passed
+    pub failure: Option<FailureInfo>,
+}
+
+impl TestReport {
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+impl RunReport {
+    pub fn num_passed(&self) -> usize {
+        self.tests.iter().filter(|t| t.passed()).count()
+    }
+
+    pub fn num_failed(&self) -> usize {
+        self.tests.len() - self.num_passed()
+    }
+
+    pub fn all_passed(&self) -> bool {
+        self.num_failed() == 0
+    }
+
+    /// Prints each failure as the binary would.
+    pub fn print_failures(&self) {
+        for TestReport { test, failure } in &self.tests {
+            if let Some(failure) = failure {
+                failure.print(test, DiffStyle::Inline, None)
+            }
+        }
+    }
+}
+
+/// Runs the tests in `tests`, written as in a markdown file, one after the
+/// other on `client`. As in a stateful file, transactional tests are rolled
+/// back, other tests keep their effects for the tests after them, and
+/// captured values are substituted into later tests. The notices tests raise
+/// go to `client`'s connection, so `notices` blocks aren't checked.
+pub async fn run_tests_in_str(tests: &str, client: &Client) -> Result<RunReport, ParseError> {
+    let args = Args::parse_from(["test-embeded-sql"]);
+    let tests = parser::extract_tests_from_string(tests)?;
+
+    let mut reports = Vec::with_capacity(tests.len());
+    let mut variables = HashMap::new();
+    for mut test in tests {
+        let text = substitute_variables(&test.text, &variables);
+        let ran = if test.transactional {
+            let ran = match client.simple_query("BEGIN").await {
+                Ok(_) => run_test(client, &test, &text, true).await,
+                Err(e) => Ran::Query(Err(e)),
+            };
+            let _ = client.simple_query("ROLLBACK").await;
+            ran
+        } else {
+            run_test(client, &test, &text, false).await
+        };
+        if let (Some(name), Some(value)) = (&test.capture, ran.first_value()) {
+            variables.insert(name.clone(), value);
+        }
+        let result = validate_ran(&args, &mut test, ran);
+        let failure = match result {
+            TestResult::Passed => None,
+            TestResult::Failed(failure) => Some(failure),
+        };
+        reports.push(TestReport { test, failure });
+    }
+    Ok(RunReport { tests: reports })
+}
+
+/// Runs `text`, a test's SQL, followed by the query it's compared with or,
+/// if it checks the columns of a result with no rows, the description of its
+/// statements.
+async fn run_test(client: &Client, test: &Test, text: &str, in_transaction: bool) -> Ran {
+    let result = client.simple_query(text).await;
+    with_reference(client, test, false, in_transaction, result).await
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::run_tests_in_str;
+
+    static TESTS: &str = r#"
+# Setup
+```SQL,stateful
+CREATE TABLE nums(n int)
+```
+
+# Insert
+```SQL
+INSERT INTO nums VALUES (1), (2) RETURNING n
+```
+```output
+ n
+---
+ 1
+ 2
+```
+
+# Rolled back
+```SQL
+SELECT count(*) FROM nums
+```
+```output
+ count
+-------
+ 0
+```
+
+# Wrong
+```SQL
+SELECT 1 AS one
+```
+```output
+ one
+-----
+ 2
+```
+
+# Wrong count
+```SQL
+INSERT INTO nums VALUES (3)
+```
+```tags
+INSERT 0 2
+```
+
+# Compared
+```SQL
+INSERT INTO nums VALUES (3) RETURNING n
+```
+```SQL,compare-with
+SELECT n FROM nums
+```
+
+# Compared wrong
+```SQL
+SELECT 1 AS n
+```
+```SQL,compare-with
+SELECT 2 AS n
+```
+
+# No rows
+```SQL
+SELECT n, n AS m FROM nums
+```
+```output, cols(2)
+```
+
+# No rows wrong
+```SQL
+SELECT n, n AS m FROM nums
+```
+```output, cols(3)
+```
+"#;
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn tests_run_against_a_client() {
+        let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL is not set");
+        let (client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        client
+            .simple_query("DROP TABLE IF EXISTS nums")
+            .await
+            .unwrap();
+
+        let report = run_tests_in_str(TESTS, &client).await.unwrap();
+        let results: Vec<(&str, bool)> = report
+            .tests
+            .iter()
+            .map(|t| (&*t.test.header, t.passed()))
+            .collect();
+        assert_eq!(
+            results,
+            [
+                ("`Setup`", true),
+                ("`Insert`", true),
+                ("`Rolled back`", true),
+                ("`Wrong`", false),
+                ("`Wrong count`", false),
+                ("`Compared`", true),
+                ("`Compared wrong`", false),
+                ("`No rows`", true),
+                ("`No rows wrong`", false)
+            ]
+        );
+        assert_eq!((report.num_passed(), report.num_failed()), (5, 4));
+        assert!(!report.all_passed());
+
+        client.simple_query("DROP TABLE nums").await.unwrap();
+    }
+}
@@ -0,0 +1,124 @@
+use tokio_postgres::{Client, SimpleQueryMessage};
+
+use crate::statements::split_statements;
+
+/// The statements that `EXPLAIN` accepts, by their first keyword.
+const EXPLAINABLE: &[&str] = &[
+    "select", "with", "insert", "update", "delete", "merge", "values", "table", "execute",
+];
+
+/// The plans of the statements of `sql` that can be explained, one line per
+/// element as `EXPLAIN` prints them. With `analyze` the statements are run
+/// again, so they're explained in a savepoint, or a transaction if
+/// `in_transaction` isn't set, that's rolled back afterwards.
+pub(crate) async fn explain(
+    client: &Client,
+    sql: &str,
+    in_transaction: bool,
+    analyze: bool,
+) -> Result<Vec<String>, tokio_postgres::Error> {
+    let (begin, end) = match in_transaction {
+        true => ("SAVEPOINT explain", "ROLLBACK TO SAVEPOINT explain"),
+        false => ("BEGIN", "ROLLBACK"),
+    };
+    client.simple_query(begin).await?;
+    let mut plan = vec![];
+    let mut result = Ok(());
+    for statement in explained_statements(sql, analyze) {
+        match client.simple_query(&statement).await {
+            Ok(messages) => plan.extend(messages.iter().filter_map(|message| match message {
+                SimpleQueryMessage::Row(row) => row.get(0).map(str::to_string),
+                _ => None,
+            })),
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        }
+    }
+    client.simple_query(end).await?;
+    result.map(|()| plan)
+}
+
+/// The `EXPLAIN` statements for the statements of `sql` that can be
+/// explained.
+fn explained_statements(sql: &str, analyze: bool) -> Vec<String> {
+    let explain = match analyze {
+        true => "EXPLAIN (ANALYZE)",
+        false => "EXPLAIN",
+    };
+    split_statements(sql)
+        .into_iter()
+        .filter(|statement| is_explainable(statement.text))
+        .map(|statement| format!("{explain} {}", statement.text))
+        .collect()
+}
+
+fn is_explainable(statement: &str) -> bool {
+    let mut rest = statement.trim_start();
+    // skip the comments before the first keyword
+    loop {
+        if let Some(comment) = rest.strip_prefix("--") {
+            rest = comment.split_once('\n').map_or("", |(_, rest)| rest);
+        } else if let Some(comment) = rest.strip_prefix("/*") {
+            rest = comment.split_once("*/").map_or("", |(_, rest)| rest);
+        } else {
+            break;
+        }
+        rest = rest.trim_start();
+    }
+    let keyword: String = rest
+        .chars()
+        .take_while(char::is_ascii_alphabetic)
+        .collect::<String>()
+        .to_ascii_lowercase();
+    EXPLAINABLE.contains(&&*keyword)
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::explained_statements;
+
+    #[test]
+    fn only_explainable_statements_are_explained() {
+        let sql = "CREATE TABLE t(a int);\nINSERT INTO t VALUES (1);\n-- the rows\nselect * from t;\nSET x = 1";
+        assert_eq!(
+            explained_statements(sql, false),
+            [
+                "EXPLAIN INSERT INTO t VALUES (1)",
+                "EXPLAIN -- the rows\nselect * from t"
+            ]
+        );
+        assert_eq!(
+            explained_statements("/* one */ VALUES (1)", true),
+            ["EXPLAIN (ANALYZE) /* one */ VALUES (1)"]
+        );
+        assert!(explained_statements("VACUUM t; tablespace", false).is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn plans_are_captured() {
+        let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL is not set");
+        let (client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+
+        let plan = super::explain(&client, "SELECT 1", false, false)
+            .await
+            .unwrap();
+        assert_eq!(plan.len(), 1);
+        assert!(plan[0].starts_with("Result  (cost="), "{plan:?}");
+
+        let plan = super::explain(&client, "SELECT 1", false, true)
+            .await
+            .unwrap();
+        assert!(
+            plan.iter().any(|line| line.starts_with("Execution Time")),
+            "{plan:?}"
+        );
+    }
+}
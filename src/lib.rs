@@ -0,0 +1,2039 @@
+use std::{
+    borrow::Cow,
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+
+use parser::ParseErrorKind::UnterminatedMarker;
+use serde::{Deserialize, Serialize};
+use xshell::{cmd, Shell};
+
+mod parser;
+mod runner;
+mod colors;
+mod db_output;
+mod watch;
+mod timing;
+mod snapshot;
+mod statements;
+mod doctor;
+mod embedded;
+mod explain;
+mod psql;
+mod rerun;
+mod progress;
+mod xfail;
+
+pub use db_output::FailureInfo;
+pub use embedded::{run_tests_in_str, RunReport, TestReport};
+pub use parser::{ParseError, ParseErrorKind};
+pub use runner::{run, TestResultRecord, TestStatus};
+
+#[derive(clap::Parser, Clone, Debug)]
+pub struct Args {
+    #[clap(short, long)]
+    host: Option<String>,
+
+    #[clap(short, long)]
+    port: Option<u16>,
+
+    #[clap(short = 'a', long)]
+    password: Option<String>,
+
+    #[clap(short, long, default_value = "/*--[sql-tests]")]
+    start_marker: String,
+
+    #[clap(short, long, default_value = "*/")]
+    end_marker: String,
+
+    /// Only run the `k`th of `n` disjoint partitions of the tests, e.g. `1/4`
+    #[clap(long, value_name = "k/n")]
+    shard: Option<runner::Shard>,
+
+    /// Run the stateless tests, and the stateful files, in a random order to
+    /// find tests that depend on the ones run before them. The tests of a
+    /// stateful, serial or single-transaction file keep their order. Results
+    /// are still printed file by file.
+    #[clap(long)]
+    shuffle: bool,
+
+    /// The seed `--shuffle` orders the tests with, printed at the start of
+    /// the run, to repeat its order
+    #[clap(long, value_name = "N", requires = "shuffle")]
+    seed: Option<u64>,
+
+    /// Role that should own the scratch databases, created if it doesn't
+    /// exist unless `--no-create-role` is given. Tests connect as this role.
+    #[clap(long, value_name = "ROLE")]
+    db_owner: Option<String>,
+
+    /// Compare the column names of the results with those of the expected
+    /// output. Can be set per-test with the `headers` and `ignore-headers`
+    /// output attributes.
+    #[clap(long)]
+    check_headers: bool,
+
+    /// How to display mismatched output
+    #[clap(long, arg_enum, default_value = "inline")]
+    diff_style: db_output::DiffStyle,
+
+    /// Only show the columns with changed values in the cell-by-cell diff,
+    /// collapsing the others into `…`. The output is still compared in full
+    #[clap(long)]
+    diff_only_changed_cols: bool,
+
+    /// A column, by name or 1-based index, the diff always shows for context
+    /// with `--diff-only-changed-cols`
+    #[clap(long, value_name = "COL", requires = "diff-only-changed-cols")]
+    diff_key_col: Option<String>,
+
+    /// Print each failure on one line, `FAIL file:line header — reason`,
+    /// instead of with its diff
+    #[clap(long)]
+    compact_failures: bool,
+
+    /// Print the files tests would be extracted from, without parsing or
+    /// running them
+    #[clap(long)]
+    list_files: bool,
+
+    /// Print the tests that would be run, as `file:line:header`, without
+    /// running them
+    #[clap(long)]
+    list: bool,
+
+    /// With `--list`, follow each test with whether it passed or failed in the
+    /// last run, as recorded in `--failures-file`, or is unknown
+    #[clap(long, requires = "list")]
+    with_status: bool,
+
+    /// Always run initdb instead of copying a cluster cached by an earlier run
+    #[clap(long)]
+    no_cache: bool,
+
+    /// Write the SQL of the tests, in order, to a script that can be run with
+    /// psql instead of running them. `-` writes to stdout.
+    #[clap(long, value_name = "PATH")]
+    emit_script: Option<PathBuf>,
+
+    /// After running the tests, rerun those in each source file as it changes
+    #[clap(long)]
+    watch: bool,
+
+    /// SQL to run after each stateful file's tests, failing the file if it
+    /// returns any rows, e.g. `SELECT gid FROM pg_prepared_xacts`
+    #[clap(long, value_name = "SQL")]
+    assert_after_file: Option<String>,
+
+    /// Don't create the role tests connect as, it must already exist
+    #[clap(long)]
+    no_create_role: bool,
+
+    /// Print percentiles and a histogram of how long the tests' queries took
+    #[clap(long)]
+    timing_report: bool,
+
+    /// Only use the files git reports as changed since this ref
+    #[clap(long, value_name = "REF")]
+    changed_since: Option<String>,
+
+    /// Print the parsed tests as JSON instead of running them
+    #[clap(long)]
+    dump_parsed: bool,
+
+    /// Only end a block of tests at an end marker at the start of a line, so
+    /// the marker can appear within the tests' SQL
+    #[clap(long)]
+    anchored_end_marker: bool,
+
+    /// Match the start and end markers whatever their case
+    #[clap(long)]
+    case_insensitive_markers: bool,
+
+    /// Only keep the postmaster's logs if the run fails
+    #[clap(long)]
+    quiet_server: bool,
+
+    /// Compare a report of which tests passed to the one in this file
+    #[clap(long, value_name = "PATH")]
+    snapshot: Option<PathBuf>,
+
+    /// Update the `--snapshot` file instead of comparing against it
+    #[clap(long, requires = "snapshot")]
+    bless: bool,
+
+    /// Set a `postgresql.conf` parameter, replacing the runner's default for
+    /// it if there is one. Can be given more than once.
+    #[clap(long = "set", value_name = "NAME=VALUE")]
+    settings: Vec<runner::Setting>,
+
+    /// Replace the `{{NAME}}` placeholders of the tests' SQL with VALUE. A
+    /// placeholder without one fails the run. Can be given more than once
+    #[clap(long = "define", value_name = "NAME=VALUE")]
+    defines: Vec<runner::Define>,
+
+    /// Only run tests with this tag, set with the `tags(...)` SQL attribute.
+    /// When given more than once, tests with any of the tags are run.
+    #[clap(long = "tag", value_name = "TAG")]
+    tags: Vec<String>,
+
+    /// Don't run tests with this tag, even if selected by `--tag`. When given
+    /// more than once, tests with any of the tags are skipped.
+    #[clap(long = "skip-tag", value_name = "TAG")]
+    skip_tags: Vec<String>,
+
+    /// Before running the tests, prepare each of their statements without
+    /// running them, reporting all the syntax errors at once
+    #[clap(long)]
+    validate_sql: bool,
+
+    /// Commit the transactions of failing stateless tests instead of rolling
+    /// them back, so their state can be inspected while `--watch` keeps the
+    /// server running. Other stateless tests share the database, so they may
+    /// see the state and fail too.
+    #[clap(long, requires = "watch")]
+    no_rollback_on_failure: bool,
+
+    /// Write the output each test received to `DIR/<file>/<line>.out`,
+    /// formatted as psql would, whether the test passed or not
+    #[clap(long, value_name = "DIR")]
+    out_dir: Option<PathBuf>,
+
+    /// Check that the environment can run the tests, such as that the
+    /// postgres binaries of each `--pg-config` can be found, instead of
+    /// running them
+    #[clap(long)]
+    doctor: bool,
+
+    /// Start the server and a scratch database, print how to connect to it,
+    /// and keep it running until Ctrl-C. No files are searched for or parsed.
+    #[clap(long)]
+    serve_only: bool,
+
+    /// Only compare the rows of the first statement in each test, as if the
+    /// rows of any later statements weren't there. By default the rows of
+    /// all the statements are compared, one result after the other.
+    #[clap(long)]
+    first_result_only: bool,
+
+    /// Fail on files that aren't markdown but contain SQL blocks without
+    /// any start markers, which usually means they were meant to be parsed
+    /// whole as markdown
+    #[clap(long)]
+    strict_markers: bool,
+
+    /// Fail on SQL blocks that are empty or only comments instead of warning
+    /// about them
+    #[clap(long)]
+    strict_empty_sql: bool,
+
+    /// How test names are built from the headings they're under
+    #[clap(long, arg_enum, default_value = "nested")]
+    name_style: parser::NameStyle,
+
+    /// Run stateless tests directly instead of in a transaction that's rolled
+    /// back, for read-only suites where there's nothing to roll back. The
+    /// tests are trusted not to change the database they share.
+    #[clap(long, conflicts_with = "no-rollback-on-failure")]
+    stateless_no_transaction: bool,
+
+    /// SQL run in each stateless test's transaction right after it begins,
+    /// such as `SET LOCAL timezone = 'UTC'`, so it's rolled back along with
+    /// the test. Runs before the statements of a file's `<!-- prelude ... -->`
+    /// lines.
+    #[clap(long, value_name = "SQL", conflicts_with = "stateless-no-transaction")]
+    stateless_prelude: Option<String>,
+
+    /// Warn about each file searched that has no tests, such as one whose
+    /// markers don't match `--start-marker`
+    #[clap(long)]
+    warn_empty_files: bool,
+
+    /// Fail the run if more than N warnings are printed, from parsing the
+    /// files or running the tests
+    #[clap(long, value_name = "N")]
+    fail_on_warning_count: Option<usize>,
+
+    /// Fail without running anything if any test checks nothing about its
+    /// query, having no output, notices, tags, error or `compare-with` block
+    #[clap(long)]
+    require_assertions: bool,
+
+    /// Database to connect to when creating and dropping the scratch
+    /// databases and the role that owns them
+    #[clap(long, value_name = "DB", default_value = "postgres")]
+    maintenance_db: String,
+
+    /// Print the plan of each test's statements, from `EXPLAIN`, below its
+    /// result
+    #[clap(long)]
+    explain: bool,
+
+    /// Like `--explain` but with `EXPLAIN ANALYZE`, which runs each statement
+    /// again in a transaction that's rolled back
+    #[clap(long)]
+    explain_analyze: bool,
+
+    /// When to drop the scratch databases and remove the cluster's directory,
+    /// `on-success` keeps them for inspection if any test failed
+    #[clap(long, arg_enum, value_name = "WHEN", default_value = "always")]
+    teardown: runner::Teardown,
+
+    /// Write an example test file, `example_test.md`, into the current
+    /// directory to start from. Nothing is written if it already exists.
+    #[clap(long)]
+    init: bool,
+
+    /// Fail instead of exiting successfully when no tests are found
+    #[clap(long)]
+    fail_on_empty: bool,
+
+    /// How many connections run tests at once, the number of CPUs by default
+    #[clap(
+        short,
+        long,
+        value_name = "N",
+        env = "SQL_TESTER_JOBS",
+        default_value_t = default_jobs()
+    )]
+    jobs: std::num::NonZeroUsize,
+
+    /// Run each stateless file's tests one after the other on a connection of
+    /// its own, as `<!-- serial -->` files are, so session state such as
+    /// prepared statements carries over between them
+    #[clap(long)]
+    connection_per_file: bool,
+
+    /// Print the results of stateless tests in file and line order, holding
+    /// back those that finish before the tests ahead of them, so the output
+    /// is the same from run to run
+    #[clap(long)]
+    ordered_output: bool,
+
+    /// Name markdown files by their first `# heading` in reports, instead of
+    /// by their path
+    #[clap(long)]
+    suite_name_from_h1: bool,
+
+    /// How many times to try dropping a scratch database that's still in use
+    /// before giving up with a warning
+    #[clap(long, value_name = "N", default_value = "5")]
+    drop_attempts: std::num::NonZeroU32,
+
+    /// The `pg_config` of the PostgreSQL to test against, found on the PATH
+    /// by default. When given more than once, the tests are run against each
+    /// version in turn, each with its own cluster.
+    #[clap(long = "pg-config", value_name = "PATH")]
+    pg_configs: Vec<String>,
+
+    /// Where which tests passed and failed is recorded after each run, for
+    /// `--only-failed` and `--with-status`
+    #[clap(long, value_name = "PATH", default_value = "failed-tests.txt")]
+    failures_file: PathBuf,
+
+    /// A file listing the tests that are expected to fail, one per line, as
+    /// the `file:line` of the test or a regex matched against test headers.
+    /// Their failures are reported as XFAIL and not counted, while their
+    /// passes are reported as XPASS with a warning.
+    #[clap(long, value_name = "PATH")]
+    expected_failures: Option<PathBuf>,
+
+    /// Only run the tests that failed in the last run, as recorded in
+    /// `--failures-file`. Stateful files are run whole if any of their tests
+    /// failed.
+    #[clap(long)]
+    only_failed: bool,
+
+    /// Only run the test at `FILE:LINE`, the line failures report it at. In a
+    /// stateful file, the tests before it are run first to set up its state.
+    #[clap(long, value_name = "FILE:LINE")]
+    only: Option<rerun::Location>,
+
+    /// Write an event as a line of JSON as each file and test starts and
+    /// finishes, and when the run does, for tools following along. `-` writes
+    /// to stderr.
+    #[clap(long, value_name = "PATH")]
+    progress_json: Option<PathBuf>,
+
+    /// The most connections to the server open at once, counting those of
+    /// the `psql`, `createdb` and `dropdb` commands. One is kept for the
+    /// commands, so `--jobs` is capped at one less
+    #[clap(long, value_name = "N", default_value = "90")]
+    max_connections: std::num::NonZeroUsize,
+
+    /// Print the settings `postgresql.conf` ends up with, after the runner's
+    /// and `--set`'s are added to it, before starting the server. Written to
+    /// PATH if given, as `--dump-conf=PATH`, and to stderr otherwise
+    #[clap(long, value_name = "PATH", require_equals = true)]
+    dump_conf: Option<Option<PathBuf>>,
+
+    /// Fail the statements of tests that run for longer than this many
+    /// seconds, with the server's `statement_timeout`
+    #[clap(long, value_name = "SECS", env = "SQL_TESTER_TIMEOUT")]
+    timeout: Option<std::num::NonZeroU64>,
+
+    /// How many seconds to wait for the server to start accepting connections
+    #[clap(
+        long,
+        value_name = "SECS",
+        env = "SQL_TESTER_STARTUP_TIMEOUT",
+        default_value = "60"
+    )]
+    startup_timeout: std::num::NonZeroU64,
+
+    /// How many times to create and start a new server, on a new port, when
+    /// one doesn't start within `--startup-timeout`
+    #[clap(long, value_name = "N", default_value = "0")]
+    startup_retries: u32,
+
+    // #[clap(short = 'x', long, default_value_t = vec!["rs".to_string(), "c".to_string(), "h".to_string()])]
+    // extensions: Vec<String>,
+    input_paths: Vec<PathBuf>,
+}
+
+pub async fn main_with_args(args: &Args) -> Result<()> {
+    colors::reset_warnings();
+    let result = run_with_args(args).await;
+    let warnings = check_warning_count(args.fail_on_warning_count, colors::warnings());
+    result.and(warnings)
+}
+
+/// Fails if more warnings were printed than `--fail-on-warning-count`
+/// allows.
+fn check_warning_count(limit: Option<usize>, warnings: usize) -> Result<()> {
+    match limit {
+        Some(limit) if warnings > limit => {
+            bail!("{warnings} warnings were printed, more than the {limit} allowed by `--fail-on-warning-count`")
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn run_with_args(args: &Args) -> Result<()> {
+    if args.doctor {
+        return doctor::run(args);
+    }
+    if args.init {
+        for path in init(Path::new("."))? {
+            let path = path.display();
+            ecprintln!("Created" bold green, " {path}");
+        }
+        return Ok(());
+    }
+    if args.serve_only {
+        return runner::run(args, std::iter::empty(), None).await;
+    }
+    if args.input_paths.is_empty() {
+        bail!("no input files provided")
+    }
+    let source_files: Vec<Result<PathBuf>> = match &args.changed_since {
+        None => args
+            .input_paths
+            .iter()
+            .flat_map(|p| find_source_files(p))
+            .collect(),
+        Some(git_ref) => {
+            let changed = changed_since(&Shell::new()?, git_ref)?;
+            watch::changed_source_files(&args.input_paths, &changed)
+                .into_iter()
+                .map(Ok)
+                .collect()
+        }
+    };
+    if args.list_files {
+        for file in source_files {
+            println!("{}", file?.display());
+        }
+        return Ok(());
+    }
+    let (tests, errors): (Vec<_>, Vec<_>) = source_files
+        .into_iter()
+        .map(|path| extract_tests_from_file(&path?, &ExtractOptions::from(args)))
+        .partition(|t| t.is_ok());
+
+    if !errors.is_empty() {
+        let errors: Vec<String> = errors
+            .into_iter()
+            .map(|e| format!("{}", e.unwrap_err()))
+            .collect();
+        bail!("{}", errors.join("\n"));
+    }
+
+    if args.warn_empty_files {
+        for name in empty_files(tests.iter().map(|t| t.as_ref().unwrap())) {
+            warning!("no tests found in `{name}`");
+        }
+    }
+
+    let empty = empty_sql_tests(tests.iter().map(|t| t.as_ref().unwrap()));
+    if args.strict_empty_sql && !empty.is_empty() {
+        bail!(
+            "{}\n{} tests have no SQL to run",
+            empty.join("\n"),
+            empty.len()
+        );
+    }
+    for test in empty {
+        warning!("{test} has no SQL to run");
+    }
+
+    if args.require_assertions {
+        let unasserted = unasserted_tests(tests.iter().map(|t| t.as_ref().unwrap()));
+        if !unasserted.is_empty() {
+            bail!(
+                "{}\n{} tests don't check their output",
+                unasserted.join("\n"),
+                unasserted.len()
+            );
+        }
+    }
+
+    let stateful_hooks = tests.iter().map(|t| t.as_ref().unwrap()).find(|file| {
+        !file.stateless && (!file.before_all.is_empty() || !file.after_all.is_empty())
+    });
+    if let Some(file) = stateful_hooks {
+        bail!(
+            "`{}` has `before-all` or `after-all` blocks, which only stateless files can have; \
+            its first and last tests already run before and after the others",
+            file.name
+        )
+    }
+
+    if args.dump_parsed {
+        let files: Vec<TestFile> = tests.into_iter().map(|t| t.unwrap()).collect();
+        println!("{}", serde_json::to_string_pretty(&files)?);
+        return Ok(());
+    }
+
+    if let Some(path) = &args.emit_script {
+        let scripts: Vec<String> = tests.into_iter().map(|t| t.unwrap().to_script()).collect();
+        let script = scripts.join("\n");
+        if path.as_os_str() == "-" {
+            print!("{script}");
+        } else {
+            fs::write(path, script)
+                .with_context(|| format!("could not write script to `{}`", path.display()))?;
+        }
+        return Ok(());
+    }
+
+    let mut files: Vec<TestFile> = tests.into_iter().map(|t| t.unwrap()).collect();
+    if args.only_failed {
+        let failed = rerun::read_failed(&args.failures_file)?;
+        if failed.is_empty() {
+            ecprintln!("No failed tests" bold green, " to rerun");
+            return Ok(());
+        }
+        files = rerun::select_failed(files, &failed);
+    }
+    if let Some(location) = &args.only {
+        files = rerun::select_only(files, location)?;
+    }
+    let selected = runner::select_tests(args, files.clone());
+    if args.list {
+        let results = match args.with_status {
+            true => Some(rerun::read_results(&args.failures_file)?),
+            false => None,
+        };
+        print!("{}", rerun::listing(&selected, results.as_ref()));
+        return Ok(());
+    }
+    if !has_tests_to_run(args, &files, &selected)? {
+        return Ok(());
+    }
+
+    runner::run(args, files.into_iter(), None).await?;
+    // let tests = parsed;
+    // dbg!(tests);
+    Ok(())
+}
+
+/// How tests are found in files, from the [`Args`] of the same names.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ExtractOptions<'a> {
+    start_marker: &'a str,
+    end_marker: &'a str,
+    anchored_end_marker: bool,
+    case_insensitive_markers: bool,
+    strict_markers: bool,
+    name_style: parser::NameStyle,
+}
+
+impl<'a> From<&'a Args> for ExtractOptions<'a> {
+    fn from(args: &'a Args) -> Self {
+        Self {
+            start_marker: &args.start_marker,
+            end_marker: &args.end_marker,
+            anchored_end_marker: args.anchored_end_marker,
+            case_insensitive_markers: args.case_insensitive_markers,
+            strict_markers: args.strict_markers,
+            name_style: args.name_style,
+        }
+    }
+}
+
+/// The tests of each of the files under `path`, for passing to [`run`].
+pub fn extract_tests_from_path(path: &Path, options: &ExtractOptions) -> Vec<Result<TestFile>> {
+    find_source_files(path)
+        .into_iter()
+        .map(|path| extract_tests_from_file(&path?, options))
+        .collect()
+}
+
+fn extract_tests_from_file(path: &Path, options: &ExtractOptions) -> Result<TestFile> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("could not read file `{}`", path.display()))?;
+
+    let file = if path.extension().map(|e| e.to_str().unwrap()) == Some("md") {
+        extract_all_tests_from_file(&path.to_string_lossy(), &contents, options.name_style)
+    } else {
+        if options.strict_markers {
+            let path = path.to_string_lossy();
+            let (start_marker, case_insensitive) =
+                (options.start_marker, options.case_insensitive_markers);
+            check_markers(&path, &contents, start_marker, case_insensitive)?;
+        }
+        extract_marked_tests_from_file(&path.to_string_lossy(), &contents, options)
+    };
+    Ok(file?)
+}
+
+/// Fails if a file to be marker-parsed has SQL blocks but no start markers,
+/// since it would silently have no tests.
+fn check_markers(
+    path: &str,
+    contents: &str,
+    start_marker: &str,
+    case_insensitive: bool,
+) -> Result<(), ParseError> {
+    let has_marker = match case_insensitive {
+        true => contents
+            .to_ascii_lowercase()
+            .contains(&start_marker.to_ascii_lowercase()),
+        false => contents.contains(start_marker),
+    };
+    if has_marker {
+        return Ok(());
+    }
+    match parser::first_sql_block(contents) {
+        None => Ok(()),
+        Some((line, col)) => {
+            let kind = parser::ParseErrorKind::UnmarkedTests {
+                start_marker: start_marker.to_string(),
+            };
+            Err(ParseError::new(line, col, kind).in_file(path, 1))
+        }
+    }
+}
+
+/// The files git reports as changed since `git_ref`, including untracked ones.
+fn changed_since(sh: &Shell, git_ref: &str) -> Result<Vec<PathBuf>> {
+    let root = cmd!(sh, "git rev-parse --show-toplevel")
+        .quiet()
+        .ignore_stderr()
+        .read()
+        .context("`--changed-since` must be run from within a git repository")?;
+    let changed = cmd!(sh, "git diff --name-only {git_ref} --")
+        .quiet()
+        .read()
+        .with_context(|| format!("could not diff against `{git_ref}`"))?;
+    let untracked = cmd!(sh, "git ls-files --others --exclude-standard --full-name")
+        .quiet()
+        .read()?;
+    let root = Path::new(&root);
+    Ok(changed
+        .lines()
+        .chain(untracked.lines())
+        .map(|path| root.join(path))
+        .collect())
+}
+
+/// the files under `path` that tests will be extracted from, with symlinks
+/// resolved
+fn find_source_files(path: &Path) -> Vec<Result<PathBuf>> {
+    ignore::WalkBuilder::new(path)
+        .follow_links(true)
+        .sort_by_file_path(|a, b| a.cmp(b))
+        .build()
+        .filter(|entry| {
+            // TODO user plugable extension filter
+            entry
+                .as_ref()
+                .map(|e| {
+                    e.file_type().map(|t| t.is_file()).unwrap_or(false)
+                        && (matches!(
+                            e.path().extension().map(|e| e.to_str().unwrap()),
+                            Some("rs") | Some("h") | Some("c") | Some("md")
+                        ) || e.path() == path)
+                })
+                .unwrap_or(false)
+        })
+        .map(|entry| -> Result<PathBuf> {
+            let entry =
+                entry.with_context(|| format!("could not read file `{}`", path.display()))?;
+
+            if let Some(true) = entry.file_type().map(|f| f.is_symlink()) {
+                Ok(fs::read_link(entry.path()).unwrap())
+            } else {
+                Ok(entry.into_path())
+            }
+        })
+        .collect()
+}
+
+fn extract_all_tests_from_file(
+    path: &str,
+    contents: &str,
+    name_style: parser::NameStyle,
+) -> Result<TestFile, ParseError> {
+    let tests = parser::extract_tests_with_name_style(contents, name_style)
+        .map_err(|e| e.in_file(path, 1))?;
+    warn_nonstandard_casing(path, contents, 1);
+    let commit_transactional = parser::commits_transactional(contents);
+    let (before_all, after_all) = parser::file_hooks(contents);
+    let file = TestFile {
+        name: path.to_string(),
+        stateless: is_stateless(&tests) && !commit_transactional,
+        serial: parser::is_serial(contents),
+        commit_transactional,
+        single_transaction: parser::is_single_transaction(contents),
+        settings: parser::file_settings(contents),
+        prelude: parser::file_prelude(contents),
+        before_all,
+        after_all,
+        title: parser::first_h1(contents),
+        tests,
+    };
+    Ok(file)
+}
+
+fn extract_marked_tests_from_file(
+    path: &str,
+    contents: &str,
+    options: &ExtractOptions,
+) -> Result<TestFile, ParseError> {
+    let ExtractOptions {
+        start_marker,
+        end_marker,
+        anchored_end_marker: anchored_end,
+        case_insensitive_markers: case_insensitive,
+        name_style,
+        ..
+    } = *options;
+    let mut tests = vec![];
+    let mut serial = false;
+    let mut commit_transactional = false;
+    let mut single_transaction = false;
+    let mut settings = vec![];
+    let mut prelude = vec![];
+    let (mut before_all, mut after_all) = (vec![], vec![]);
+
+    let test_blocks = find_marked_tests_blocks(
+        contents,
+        start_marker,
+        end_marker,
+        anchored_end,
+        case_insensitive,
+    )
+    .map_err(|e| e.in_file(path, 1))?;
+    for (start, test_block) in test_blocks {
+        serial |= parser::is_serial(test_block);
+        commit_transactional |= parser::commits_transactional(test_block);
+        single_transaction |= parser::is_single_transaction(test_block);
+        settings.extend(parser::file_settings(test_block));
+        prelude.extend(parser::file_prelude(test_block));
+        let (before, after) = parser::file_hooks(test_block);
+        before_all.extend(before);
+        after_all.extend(after);
+        let mut test =
+            parser::extract_tests_with_name_style(test_block, name_style).map_err(|e| {
+                let (first_line, _) = line_col(contents, start);
+                e.in_file(path, first_line)
+            })?;
+        warn_nonstandard_casing(path, test_block, line_col(contents, start).0);
+        // spans are found within the block, not the file
+        let block_start = start + start_marker.len();
+        for t in &mut test {
+            t.line += line_col(contents, start).0 - 1;
+            t.sql_span = t.sql_span.start + block_start..t.sql_span.end + block_start;
+            t.output_span =
+                (t.output_span.take()).map(|span| span.start + block_start..span.end + block_start);
+        }
+        tests.extend(test);
+    }
+    let file = TestFile {
+        name: path.to_string(),
+        stateless: is_stateless(&tests) && !commit_transactional,
+        serial,
+        commit_transactional,
+        single_transaction,
+        settings,
+        prelude,
+        before_all,
+        after_all,
+        title: None,
+        tests,
+    };
+    Ok(file)
+}
+
+/// The default `--jobs`, one connection per CPU.
+fn default_jobs() -> std::num::NonZeroUsize {
+    std::thread::available_parallelism().unwrap_or(std::num::NonZeroUsize::new(4).unwrap())
+}
+
+/// Whether the cluster needs to be started for the tests `selected` from
+/// `files`. Without any tests there's nothing to start it for, unless
+/// `--watch` is waiting for tests to be written, which is reported as a
+/// warning or, with `--fail-on-empty`, an error.
+fn has_tests_to_run(args: &Args, files: &[TestFile], selected: &[TestFile]) -> Result<bool> {
+    let has_tests = |files: &[TestFile]| files.iter().any(|file| !file.tests.is_empty());
+    if args.watch || has_tests(selected) {
+        return Ok(true);
+    }
+    let message = match has_tests(files) {
+        true => "no tests selected by `--tag`, `--skip-tag` or `--shard`".to_string(),
+        false => format!(
+            "no tests found in {} files; check the start markers and file extensions",
+            files.len()
+        ),
+    };
+    if args.fail_on_empty {
+        bail!("{message}")
+    }
+    warning!("{message}");
+    Ok(false)
+}
+
+/// The files `--init` writes, by name.
+const STARTER_FILES: &[(&str, &str)] = &[("example_test.md", include_str!("example_test.md"))];
+
+/// Writes the starter files into `dir`, returning their paths. Fails without
+/// writing anything if any of them already exist.
+fn init(dir: &Path) -> Result<Vec<PathBuf>> {
+    let paths: Vec<PathBuf> = STARTER_FILES
+        .iter()
+        .map(|(name, _)| dir.join(name))
+        .collect();
+    if let Some(existing) = paths.iter().find(|path| path.exists()) {
+        bail!(
+            "`{}` already exists, not overwriting it",
+            existing.display()
+        )
+    }
+    for (path, (_, contents)) in paths.iter().zip(STARTER_FILES) {
+        fs::write(path, contents)
+            .with_context(|| format!("could not write `{}`", path.display()))?;
+    }
+    Ok(paths)
+}
+
+/// The names of the files without any tests.
+fn empty_files<'f>(files: impl IntoIterator<Item = &'f TestFile>) -> Vec<&'f str> {
+    files
+        .into_iter()
+        .filter(|file| file.tests.is_empty())
+        .map(|file| &*file.name)
+        .collect()
+}
+
+/// The locations, as `file:line: name`, of the tests that check nothing about
+/// what their query did: neither its output nor its notices, command tags,
+/// column count, error or agreement with a `compare-with` query.
+fn unasserted_tests<'f>(files: impl IntoIterator<Item = &'f TestFile>) -> Vec<String> {
+    let unasserted = |test: &&Test| {
+        test.ignore_output
+            && test.notices.is_none()
+            && test.command_tags.is_none()
+            && test.column_count.is_none()
+            && test.compare_with.is_none()
+            && test.expected_error.is_none()
+    };
+    files
+        .into_iter()
+        .flat_map(|file| {
+            file.tests
+                .iter()
+                .filter(unasserted)
+                .map(|test| format!("{}:{}: {}", file.name, test.line, test.header))
+        })
+        .collect()
+}
+
+/// The locations, as `file:line: name`, of the tests whose SQL is empty.
+fn empty_sql_tests<'f>(files: impl IntoIterator<Item = &'f TestFile>) -> Vec<String> {
+    files
+        .into_iter()
+        .flat_map(|file| {
+            file.tests
+                .iter()
+                .filter(|test| test.empty_sql)
+                .map(|test| format!("{}:{}: {}", file.name, test.line, test.header))
+        })
+        .collect()
+}
+
+/// A file can only be run on the shared stateless database if none of its
+/// tests leave state behind for the tests that follow.
+fn is_stateless(tests: &[Test]) -> bool {
+    tests.iter().all(|t| t.transactional && t.capture.is_none())
+}
+
+/// Prints a warning for each attribute of the code blocks in `contents`, a
+/// part of `path` starting at `first_line`, that isn't written as usual.
+fn warn_nonstandard_casing(path: &str, contents: &str, first_line: usize) {
+    for (line, written, standard) in parser::nonstandard_casing(contents) {
+        let line = first_line + line - 1;
+        warning!("`{path}:{line}`: the attribute `{written}` is usually written `{standard}`");
+    }
+}
+
+fn find_marked_tests_blocks<'f>(
+    file: &'f str,
+    start_marker: &str,
+    end_marker: &str,
+    anchored_end: bool,
+    case_insensitive: bool,
+) -> Result<Vec<(usize, &'f str)>, ParseError> {
+    // ASCII case changes keep every byte offset the same
+    let (searched, start_marker, end_marker) = match case_insensitive {
+        true => (
+            Cow::Owned(file.to_ascii_lowercase()),
+            Cow::Owned(start_marker.to_ascii_lowercase()),
+            Cow::Owned(end_marker.to_ascii_lowercase()),
+        ),
+        false => (
+            Cow::Borrowed(file),
+            Cow::Borrowed(start_marker),
+            Cow::Borrowed(end_marker),
+        ),
+    };
+    searched
+        .match_indices(&*start_marker)
+        .map(|(start, _)| {
+            let after_start = &searched[start..];
+            let end = find_end_marker(after_start, &end_marker, anchored_end).ok_or_else(|| {
+                let (line, col) = line_col(file, start);
+                let end_marker = end_marker.to_string();
+                ParseError::new(line, col, UnterminatedMarker { end_marker })
+            })?;
+            let test = &file[start + start_marker.len()..start + end];
+            Ok((start, test))
+        })
+        .collect()
+}
+
+/// Finds the end marker, which if `anchored` must only be preceded by
+/// whitespace on its line.
+fn find_end_marker(s: &str, end_marker: &str, anchored: bool) -> Option<usize> {
+    if !anchored {
+        return s.find(end_marker);
+    }
+    s.match_indices(end_marker)
+        .map(|(i, _)| i)
+        .find(|&i| s[..i].rsplit('\n').next().unwrap_or("").trim().is_empty())
+}
+
+/// Converts a byte offset into `contents` to a 1-based line and column.
+fn line_col(contents: &str, offset: usize) -> (usize, usize) {
+    let before = &contents[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    (line, before[line_start..].chars().count() + 1)
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[must_use]
+pub struct TestFile {
+    name: String,
+    stateless: bool,
+    /// run the tests one at a time on a single connection, set with a
+    /// `<!-- serial -->` line
+    serial: bool,
+    /// commit transactional tests instead of rolling them back, set with a
+    /// `<!-- commit-transactional -->` line
+    commit_transactional: bool,
+    /// run the tests one after the other in a transaction they share, rolled
+    /// back after the last one, set with a `<!-- single-transaction -->` line
+    single_transaction: bool,
+    /// `SET` statements run on the connection before the tests, from
+    /// `<!-- set name = value -->` lines
+    settings: Vec<String>,
+    /// statements run in each transaction of a stateless file's tests, right
+    /// after it begins, from `<!-- prelude statement -->` lines
+    prelude: Vec<String>,
+    /// SQL committed once before the tests of a stateless file, from
+    /// `SQL,before-all` blocks
+    before_all: Vec<String>,
+    /// SQL committed once after the tests of a stateless file, from
+    /// `SQL,after-all` blocks
+    after_all: Vec<String>,
+    /// the first `# heading` of a markdown file, its name in reports with
+    /// `--suite-name-from-h1`
+    title: Option<String>,
+    tests: Vec<Test>,
+}
+
+impl TestFile {
+    /// The tests' SQL as a single script, with transactional tests rolled back,
+    /// or committed, as they are when run.
+    fn to_script(&self) -> String {
+        use std::fmt::Write;
+
+        let mut script = format!("-- file: {}\n", self.name);
+        for sql in &self.before_all {
+            let _ = writeln!(
+                script,
+                "\n-- before-all\n{};",
+                sql.trim_end().trim_end_matches(';')
+            );
+        }
+        let end_transaction = match self.commit_transactional {
+            true => "COMMIT",
+            false => "ROLLBACK",
+        };
+        // the tests share the one transaction instead of each having their own
+        let single_transaction = self.single_transaction && self.stateless;
+        let prelude: String = match self.stateless {
+            true => self.prelude.iter().map(|sql| format!("{sql};\n")).collect(),
+            false => String::new(),
+        };
+        if single_transaction {
+            script += &format!("BEGIN;\n{prelude}");
+        }
+        for test in &self.tests {
+            let Test {
+                line,
+                header,
+                text,
+                transactional,
+                capture,
+                ..
+            } = test;
+            let _ = writeln!(script, "\n-- test: {header} (line {line})");
+            if let Some(name) = capture {
+                let _ = writeln!(script, "-- the first value is used as :{name} below");
+            }
+            let text = text.trim_end();
+            let terminator = if text.ends_with(';') { "" } else { ";" };
+            if *transactional && !single_transaction {
+                let _ = writeln!(
+                    script,
+                    "BEGIN;\n{prelude}{text}{terminator}\n{end_transaction};"
+                );
+            } else {
+                let _ = writeln!(script, "{text}{terminator}");
+            }
+        }
+        if single_transaction {
+            script += "ROLLBACK;\n";
+        }
+        for sql in &self.after_all {
+            let _ = writeln!(
+                script,
+                "\n-- after-all\n{};",
+                sql.trim_end().trim_end_matches(';')
+            );
+        }
+        script
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[must_use]
+pub struct Test {
+    line: usize,
+    /// bytes of the file holding the SQL block, fences included
+    sql_span: Range<usize>,
+    /// bytes of the file holding the output block, fences included
+    output_span: Option<Range<usize>>,
+    header: String,
+    text: String,
+    output: Vec<Vec<String>>,
+    transactional: bool,
+    ignore_output: bool,
+    /// name under which to store the first value of the result, for use by
+    /// later tests in the same file as `:name`
+    capture: Option<String>,
+    /// column names from the expected output
+    output_headers: Vec<String>,
+    /// whether to compare column names, overriding `--check-headers`
+    check_headers: Option<bool>,
+    /// 0-based columns whose values only need to be approximately equal
+    tolerances: Vec<(usize, db_output::Tolerance)>,
+    /// 0-based columns to sort the expected and received rows by before
+    /// comparing them
+    sort_by: Vec<usize>,
+    /// 0-based columns whose values group the rows, set with the `grouped-by`
+    /// output attribute. The rows of each group are compared in order but
+    /// the groups may come in any order
+    grouped_by: Vec<usize>,
+    /// compare the rows as sets, in any order and ignoring duplicates, set
+    /// with the `set` output attribute
+    as_set: bool,
+    /// 0-based columns of booleans, set with the `bool` output attribute,
+    /// where `t`, `true` and `1`, and `f`, `false` and `0`, are equal
+    bool_cols: Vec<usize>,
+    /// 0-based columns of the received output left out before comparing it,
+    /// set with the `ignore-cols` output attribute
+    ignore_cols: Vec<usize>,
+    /// the number of columns the query must return, whatever their values,
+    /// set with the `cols` output attribute
+    column_count: Option<usize>,
+    /// must return no rows, without needing an empty output block
+    expect_empty: bool,
+    /// the column, by name or 1-based index, that must be true in every row
+    /// instead of comparing the output, set with the `assert-true` attribute
+    assert_true: Option<String>,
+    /// a query whose result the test's must equal, run after it in the same
+    /// transaction, from a `SQL,compare-with` block following the test
+    compare_with: Option<String>,
+    /// labels for selecting tests with `--tag` and `--skip-tag`
+    tags: Vec<String>,
+    /// outputs, from `output, alt` blocks, accepted in place of `output`
+    alternative_outputs: Vec<AlternativeOutput>,
+    /// the notices the query should raise, from a `notices` block
+    notices: Option<db_output::ExpectedNotices>,
+    /// the command tags of the query's statements, in order, from a `tags`
+    /// block. Only the row counts ending them are checked.
+    command_tags: Option<Vec<String>>,
+    /// an expected value that matches any received one, set with the
+    /// `wildcards` output attribute
+    wildcard: Option<String>,
+    /// how NULLs are written in the expected output, instead of as empty
+    /// values, set with the `null-as` output attribute
+    null_as: Option<String>,
+    /// the column separator of the expected output, set with the `sep`
+    /// output attribute, that failures are shown with
+    separator: Option<String>,
+    /// the lines the query's error must contain, from an `output, error`
+    /// block
+    expected_error: Option<String>,
+    /// the SQL is only whitespace and comments, which usually means a block
+    /// was pasted or fenced wrong
+    empty_sql: bool,
+}
+
+impl Test {
+    /// The line the test's SQL block starts on.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// The test's name, from the headings above it.
+    pub fn header(&self) -> &str {
+        &self.header
+    }
+
+    /// The SQL the test runs.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+/// An expected output that's as acceptable as a test's own, compared using
+/// the attributes of the test's first output block.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct AlternativeOutput {
+    output_headers: Vec<String>,
+    output: Vec<Vec<String>>,
+}
+
+#[cfg(test)]
+mod test {
+    //! Tests that need PostgreSQL are ignored unless run with
+    //! `cargo test -- --ignored`. Those that need `TEST_DATABASE_URL` run
+    //! against the database it gives, such as the one from `--serve-only`.
+    //! Those that start a server of their own need `pg_config` on the `PATH`
+    //! and, as PostgreSQL won't run as root, a user of their own.
+
+    use super::*;
+    use clap::Parser;
+    use pretty_assertions::assert_eq;
+
+    /// A file of `select 1` tests at `lines`, named after the file.
+    pub(crate) fn test_file(name: &str, stateless: bool, lines: &[usize]) -> TestFile {
+        TestFile {
+            name: name.to_string(),
+            stateless,
+            tests: lines
+                .iter()
+                .map(|&line| Test {
+                    line,
+                    header: format!("`{name}`"),
+                    text: "select 1".to_string(),
+                    transactional: stateless,
+                    ignore_output: true,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    // Example tests that will be tested
+    /*--[sql-tests]
+    Single test
+    # Test Parsing is correct
+    ```SQL
+    select * from foo
+    ```
+    ```output
+    ```
+    */
+    /*--[sql-tests]
+    Multiple tests in one block
+    # Test Parsing is correct
+    ```SQL
+    select * from foo
+    ```
+    ```output
+    ```
+
+    ```SQL
+    select * from multiline
+    ```
+    ```output
+    ?column?
+    ----------
+        value
+    ```
+
+    ## ignored
+    ```SQL,ignore
+    select * from foo
+    ```
+
+    ## non-transactional
+    ```SQL,non-transactional
+    select * from bar
+    ```
+    ```output, precision(1: 3)
+    a | b
+    ---+---
+    1 | 2
+    ```
+
+    ## no output
+    ```SQL,ignore-output
+    select * from baz
+    ```
+
+    ## end by header
+    ```SQL
+    select * from quz
+    ```
+
+    ## end by file
+    ```SQL
+    select * from qat
+    ```
+    */
+
+    #[test]
+    fn test_block_finding_finds_tests() {
+        let this_file = std::fs::read_to_string(file!()).unwrap_or_else(|e| {
+            panic!("could not read the source '{}' file due to: {}", file!(), e)
+        });
+        let blocks: Vec<_> =
+            find_marked_tests_blocks(&this_file, "/*--[sql-tests]", "*/", false, false)
+                .expect("could not parse file")
+                .into_iter()
+                .map(|(_, s)| s)
+                .collect();
+        let args = "\")]
+    start_marker: String,
+
+    #[clap(short, long, default_value = \"";
+        let first_test = "
+    Single test
+    # Test Parsing is correct
+    ```SQL
+    select * from foo
+    ```
+    ```output
+    ```
+    ";
+        let second_test = "
+    Multiple tests in one block
+    # Test Parsing is correct
+    ```SQL
+    select * from foo
+    ```
+    ```output
+    ```
+
+    ```SQL
+    select * from multiline
+    ```
+    ```output
+    ?column?
+    ----------
+        value
+    ```
+
+    ## ignored
+    ```SQL,ignore
+    select * from foo
+    ```
+
+    ## non-transactional
+    ```SQL,non-transactional
+    select * from bar
+    ```
+    ```output, precision(1: 3)
+    a | b
+    ---+---
+    1 | 2
+    ```
+
+    ## no output
+    ```SQL,ignore-output
+    select * from baz
+    ```
+
+    ## end by header
+    ```SQL
+    select * from quz
+    ```
+
+    ## end by file
+    ```SQL
+    select * from qat
+    ```
+    ";
+        // we see the markers in the tests as well, this is probably fine since it
+        // won't occur in user code
+        let expected_blocks = [args, first_test, second_test, r#"", ""#, r#"", ""#];
+        assert_eq!(blocks, expected_blocks);
+        // assert!(
+        //     blocks == expected_blocks,
+        //     "  left: {}\n right: {}",
+        //     blocks.join("\n"),
+        //     expected_blocks.join("\n")
+        // )
+    }
+
+    #[test]
+    fn no_cluster_is_started_without_tests() {
+        use super::{has_tests_to_run, Args};
+        use clap::Parser;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("empty.md");
+        std::fs::write(&path, "# No tests here\n").unwrap();
+        let file = |path| super::extract_tests_from_file(path, &Default::default()).unwrap();
+        let files = vec![file(&path)];
+
+        let args = Args::parse_from(["test", "."]);
+        assert!(!has_tests_to_run(&args, &files, &files).unwrap());
+        let args = Args::parse_from(["test", "--fail-on-empty", "."]);
+        assert_eq!(
+            has_tests_to_run(&args, &files, &files).unwrap_err().to_string(),
+            "no tests found in 1 files; check the start markers and file extensions"
+        );
+        let args = Args::parse_from(["test", "--watch", "."]);
+        assert!(has_tests_to_run(&args, &files, &files).unwrap());
+
+        std::fs::write(&path, "```SQL\nSELECT 1\n```\n").unwrap();
+        let files = vec![file(&path)];
+        let args = Args::parse_from(["test", "--fail-on-empty", "."]);
+        assert!(has_tests_to_run(&args, &files, &files).unwrap());
+
+        // tests are looked for after `--tag` and `--shard` pick theirs
+        let args = Args::parse_from(["test", "--fail-on-empty", "--tag", "slow", "."]);
+        let selected = crate::runner::select_tests(&args, files.clone());
+        assert_eq!(
+            has_tests_to_run(&args, &files, &selected)
+                .unwrap_err()
+                .to_string(),
+            "no tests selected by `--tag`, `--skip-tag` or `--shard`"
+        );
+    }
+
+    #[test]
+    fn init_writes_an_example_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let written = super::init(dir.path()).unwrap();
+        let example = dir.path().join("example_test.md");
+        assert_eq!(written, [dir.path().join("example_test.md")]);
+
+        let file = super::extract_tests_from_file(&example, &Default::default()).unwrap();
+        assert_eq!(file.tests.len(), 5);
+        assert!(!file.stateless);
+
+        std::fs::write(&example, "edited").unwrap();
+        let error = super::init(dir.path()).unwrap_err();
+        assert!(error
+            .to_string()
+            .ends_with("already exists, not overwriting it"));
+        assert_eq!(std::fs::read_to_string(&example).unwrap(), "edited");
+    }
+
+    #[test]
+    fn tests_without_assertions_are_found() {
+        let contents = [
+            "# Checked",
+            "```SQL",
+            "select 1",
+            "```",
+            "```output",
+            "```",
+            "# Ignored",
+            "```SQL,ignore-output",
+            "select 2",
+            "```",
+            "# Empty",
+            "```SQL,expect-empty",
+            "select 3 where false",
+            "```",
+            "# Missing",
+            "```SQL",
+            "select 4",
+            "```",
+            "# Notices",
+            "```SQL",
+            "do $$ begin raise notice 'hi'; end $$",
+            "```",
+            "```notices",
+            "hi",
+            "```",
+            "# Tags",
+            "```SQL",
+            "select 5",
+            "```",
+            "```tags",
+            "SELECT 1",
+            "```",
+            "# Columns",
+            "```SQL",
+            "select 6",
+            "```",
+            "```output, cols(1)",
+            "```",
+            "# Compared",
+            "```SQL",
+            "select 7",
+            "```",
+            "```SQL,compare-with",
+            "select 7",
+            "```",
+        ]
+        .join("\n");
+        let file = extract_all_tests_from_file("a.md", &contents, Default::default()).unwrap();
+        assert_eq!(
+            unasserted_tests([&file]),
+            ["a.md:8: `Ignored`", "a.md:16: `Missing`"]
+        );
+    }
+
+    #[test]
+    fn tests_without_sql_are_listed() {
+        let contents = "# Pasted\n```SQL\n```\n# Kept\n```SQL\nselect 1\n```\n";
+        let file = extract_all_tests_from_file("a.md", contents, Default::default()).unwrap();
+        assert_eq!(empty_sql_tests([&file]), ["a.md:2: `Pasted`"]);
+    }
+
+    #[test]
+    fn files_without_tests_are_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let marked = dir.path().join("marked.rs");
+        fs::write(&marked, "/*<<tests\n```SQL\nselect 1\n```\n*/\nfn a() {}\n").unwrap();
+        let unmarked = dir.path().join("unmarked.rs");
+        fs::write(
+            &unmarked,
+            "/* tests\n```SQL\nselect 1\n```\n*/\nfn b() {}\n",
+        )
+        .unwrap();
+
+        let files: Vec<TestFile> = [&marked, &unmarked]
+            .into_iter()
+            .map(|path| {
+                extract_tests_from_file(
+                    path,
+                    &ExtractOptions {
+                        start_marker: "<<tests",
+                        end_marker: "*/",
+                        ..Default::default()
+                    },
+                )
+                .unwrap()
+            })
+            .collect();
+        assert_eq!(empty_files(&files), [&*unmarked.to_string_lossy()]);
+    }
+
+    #[test]
+    fn strict_markers_rejects_unmarked_sql_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        let contents =
+            "# Notes\n\n```SQL\nselect 1\n```\n```output\n ?column?\n----------\n 1\n```\n";
+        fs::write(&path, contents).unwrap();
+
+        let lenient = extract_tests_from_file(
+            &path,
+            &ExtractOptions {
+                start_marker: "<<tests",
+                end_marker: ">>",
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(lenient.tests.is_empty());
+
+        let error = extract_tests_from_file(
+            &path,
+            &ExtractOptions {
+                start_marker: "<<tests",
+                end_marker: ">>",
+                strict_markers: true,
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        let error = error.downcast::<ParseError>().unwrap();
+        assert_eq!(
+            error,
+            ParseError {
+                file: path.to_string_lossy().into_owned(),
+                line: 3,
+                col: 1,
+                kind: parser::ParseErrorKind::UnmarkedTests {
+                    start_marker: "<<tests".to_string()
+                },
+            }
+        );
+
+        let marked = format!("<<tests\n{contents}>>\n");
+        fs::write(&path, marked).unwrap();
+        let file = extract_tests_from_file(
+            &path,
+            &ExtractOptions {
+                start_marker: "<<tests",
+                end_marker: ">>",
+                strict_markers: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(file.tests.len(), 1);
+    }
+
+    #[test]
+    fn parse_errors_report_file_locations() {
+        use parser::ParseErrorKind::*;
+
+        let unterminated = extract_marked_tests_from_file(
+            "a.rs",
+            "fn a() {}\n  <<tests\n",
+            &ExtractOptions {
+                start_marker: "<<tests",
+                end_marker: ">>",
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            unterminated,
+            Err(ParseError {
+                file: "a.rs".to_string(),
+                line: 2,
+                col: 3,
+                kind: UnterminatedMarker {
+                    end_marker: ">>".to_string()
+                },
+            })
+        );
+
+        let contents = "fn a() {}\n\n<<tests\n# Test\n```output\n```\n>>\n";
+        let stray = extract_marked_tests_from_file(
+            "b.rs",
+            contents,
+            &ExtractOptions {
+                start_marker: "<<tests",
+                end_marker: ">>",
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            stray,
+            Err(ParseError {
+                file: "b.rs".to_string(),
+                line: 5,
+                col: 1,
+                kind: StrayOutput,
+            })
+        );
+    }
+
+    #[test]
+    fn warning_count_fails_above_the_limit() {
+        use super::check_warning_count;
+
+        let args = Args::parse_from(["test", "--fail-on-warning-count", "2", "."]);
+        let limit = args.fail_on_warning_count;
+        assert!(check_warning_count(limit, 2).is_ok());
+        assert_eq!(
+            check_warning_count(limit, 3).unwrap_err().to_string(),
+            "3 warnings were printed, more than the 2 allowed by `--fail-on-warning-count`"
+        );
+        assert!(check_warning_count(None, 100).is_ok());
+
+        let before = crate::colors::warnings();
+        crate::warning!("counted");
+        assert!(crate::colors::warnings() > before);
+    }
+
+    #[test]
+    fn case_insensitive_markers_match_any_case() {
+        let contents = "fn a() {}\n<<Tests\n```SQL\nSELECT 'KEEP'\n```\nEND>>";
+        let blocks = |case_insensitive| {
+            find_marked_tests_blocks(contents, "<<tests", "end>>", false, case_insensitive).unwrap()
+        };
+        assert!(blocks(false).is_empty());
+        // the block is taken from the file as written
+        let [(start, block)] = blocks(true)[..] else {
+            panic!("expected a single block");
+        };
+        assert_eq!(start, 10);
+        assert_eq!(block, "\n```SQL\nSELECT 'KEEP'\n```\n");
+    }
+
+    #[test]
+    fn anchored_end_markers_ignore_inline_markers() {
+        let contents = "fn a() {}\n<<tests\n```SQL\nselect 1 << comment >> 2\n```\n  >>\n";
+        let (_, block) =
+            find_marked_tests_blocks(contents, "<<tests", ">>", true, false).unwrap()[0];
+        assert_eq!(block, "\n```SQL\nselect 1 << comment >> 2\n```\n  ");
+
+        let (_, block) =
+            find_marked_tests_blocks(contents, "<<tests", ">>", false, false).unwrap()[0];
+        assert_eq!(block, "\n```SQL\nselect 1 << comment ");
+
+        let file = extract_marked_tests_from_file(
+            "a.rs",
+            contents,
+            &ExtractOptions {
+                start_marker: "<<tests",
+                end_marker: ">>",
+                anchored_end_marker: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(file.tests[0].text, "select 1 << comment >> 2");
+    }
+
+    #[test]
+    fn tests_in_different_marked_blocks_have_distinct_lines() {
+        // both tests are on the second line of their block, `--out-dir` and
+        // the rerun ids tell them apart by their line in the file
+        let contents =
+            "<<tests\n```SQL\nSELECT 1\n```\n>>\nfn a() {}\n<<tests\n```SQL\nSELECT 2\n```\n>>\n";
+        let file = extract_marked_tests_from_file(
+            "a.rs",
+            contents,
+            &ExtractOptions {
+                start_marker: "<<tests",
+                end_marker: ">>",
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let lines: Vec<_> = file.tests.iter().map(|test| test.line).collect();
+        assert_eq!(lines, [2, 8]);
+    }
+
+    #[test]
+    fn test_parsing_this_file_works() {
+        let path = Path::new(file!());
+        let (start_marker, end_marker) = ("/*--[sql-tests]", "*/");
+        let options = ExtractOptions {
+            start_marker,
+            end_marker,
+            ..Default::default()
+        };
+        let tests: Result<Vec<_>> = extract_tests_from_path(path, &options)
+            .into_iter()
+            .collect();
+        let mut tests = tests.expect("could not parse file");
+        // the spans and lines move whenever this file changes, so check what
+        // they hold instead of where they are
+        let this_file = fs::read_to_string(path).unwrap();
+        for test in &mut tests[0].tests {
+            let line = std::mem::take(&mut test.line);
+            assert_eq!(line, line_col(&this_file, test.sql_span.start).0);
+            let block = &this_file[std::mem::take(&mut test.sql_span)];
+            assert!(block.starts_with("```SQL"), "{block}");
+            assert!(block.contains(&test.text), "{block}");
+            if let Some(span) = test.output_span.take() {
+                assert!(this_file[span].starts_with("```output"));
+            }
+        }
+        let expected = vec![TestFile {
+            name: file!().to_string(),
+            stateless: false,
+            serial: false,
+            commit_transactional: false,
+            single_transaction: false,
+            settings: vec![],
+            prelude: vec![],
+            before_all: vec![],
+            after_all: vec![],
+            title: None,
+            tests: vec![
+                Test {
+                    header: "`Test Parsing is correct`".to_string(),
+                    text: "select * from foo".to_string(),
+                    output: vec![],
+                    transactional: true,
+                    ignore_output: false,
+                    ..Default::default()
+                },
+                Test {
+                    header: "`Test Parsing is correct`".to_string(),
+                    text: "select * from foo".to_string(),
+                    output: vec![],
+                    transactional: true,
+                    ignore_output: false,
+                    ..Default::default()
+                },
+                Test {
+                    header: "`Test Parsing is correct`".to_string(),
+                    text: "select * from multiline".to_string(),
+                    output: vec![vec!["value".to_string()]],
+                    transactional: true,
+                    ignore_output: false,
+                    output_headers: vec!["?column?".to_string()],
+                    ..Default::default()
+                },
+                Test {
+                    header: "`Test Parsing is correct``non-transactional`".to_string(),
+                    text: "select * from bar".to_string(),
+                    output: vec![vec!["1".to_string(), "2".to_string()]],
+                    transactional: false,
+                    ignore_output: false,
+                    output_headers: vec!["a".to_string(), "b".to_string()],
+                    tolerances: vec![(0, db_output::Tolerance::Precision(3))],
+                    ..Default::default()
+                },
+                Test {
+                    header: "`Test Parsing is correct``no output`".to_string(),
+                    text: "select * from baz".to_string(),
+                    output: vec![],
+                    transactional: true,
+                    ignore_output: true,
+                    ..Default::default()
+                },
+                Test {
+                    header: "`Test Parsing is correct``end by header`".to_string(),
+                    text: "select * from quz".to_string(),
+                    output: vec![],
+                    transactional: true,
+                    ignore_output: true,
+                    ..Default::default()
+                },
+                Test {
+                    header: "`Test Parsing is correct``end by file`".to_string(),
+                    text: "select * from qat".to_string(),
+                    output: vec![],
+                    transactional: true,
+                    ignore_output: true,
+                    ..Default::default()
+                },
+            ],
+        }];
+        assert_eq!(tests, expected)
+    }
+
+    #[test]
+    fn source_files_are_discovered() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir(root.join("sub")).unwrap();
+        for file in [
+            "a.rs",
+            "b.md",
+            "notes.txt",
+            "sub/c.h",
+            "sub/d.c",
+            "sub/skipped.rs",
+        ] {
+            fs::write(root.join(file), "").unwrap();
+        }
+        fs::write(root.join(".ignore"), "skipped.rs\n").unwrap();
+
+        let files: Vec<PathBuf> = find_source_files(root)
+            .into_iter()
+            .map(|f| f.unwrap().strip_prefix(root).unwrap().to_path_buf())
+            .collect();
+        let expected: Vec<PathBuf> = ["a.rs", "b.md", "sub/c.h", "sub/d.c"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        assert_eq!(files, expected);
+
+        // explicitly named files are kept regardless of extension
+        let files: Vec<PathBuf> = find_source_files(&root.join("notes.txt"))
+            .into_iter()
+            .map(|f| f.unwrap())
+            .collect();
+        assert_eq!(files, vec![root.join("notes.txt")]);
+    }
+
+    #[test]
+    fn scripts_contain_tests_in_order() {
+        let file = TestFile {
+            name: "story.md".to_string(),
+            stateless: false,
+            serial: false,
+            commit_transactional: false,
+            single_transaction: false,
+            settings: vec![],
+            prelude: vec![],
+            before_all: vec![],
+            after_all: vec![],
+            title: None,
+            tests: vec![
+                Test {
+                    line: 3,
+                    header: "`Story``setup`".to_string(),
+                    text: "create table t(id int);\ninsert into t values (1);".to_string(),
+                    capture: Some("id".to_string()),
+                    ..Default::default()
+                },
+                Test {
+                    line: 9,
+                    header: "`Story``query`".to_string(),
+                    text: "select * from t where id = :id".to_string(),
+                    transactional: true,
+                    ..Default::default()
+                },
+            ],
+        };
+        let expected = "\
+-- file: story.md
+
+-- test: `Story``setup` (line 3)
+-- the first value is used as :id below
+create table t(id int);
+insert into t values (1);
+
+-- test: `Story``query` (line 9)
+BEGIN;
+select * from t where id = :id;
+ROLLBACK;
+";
+        assert_eq!(file.to_script(), expected);
+
+        let file = TestFile {
+            commit_transactional: true,
+            single_transaction: false,
+            ..file
+        };
+        assert!(file
+            .to_script()
+            .ends_with("BEGIN;\nselect * from t where id = :id;\nCOMMIT;\n"));
+    }
+
+    #[test]
+    fn single_transaction_files_share_one_in_scripts() {
+        let contents = "<!-- single-transaction -->\n# Walkthrough\n\
+            ```SQL\ncreate temp table t(id int)\n```\n```SQL\nselect * from t\n```\n";
+        let file =
+            extract_all_tests_from_file("walkthrough.md", contents, Default::default()).unwrap();
+        assert!(file.single_transaction);
+        assert!(file.stateless);
+        assert_eq!(
+            file.to_script(),
+            "-- file: walkthrough.md\nBEGIN;\n\n-- test: `Walkthrough` (line 3)\n\
+            create temp table t(id int);\n\n-- test: `Walkthrough` (line 6)\n\
+            select * from t;\nROLLBACK;\n"
+        );
+    }
+
+    #[test]
+    fn commit_transactional_files_are_stateful() {
+        let contents = "<!-- commit-transactional -->\n# Migration\n\
+            ```SQL\ncreate table t(id int)\n```\n```SQL\nselect * from t\n```\n";
+        let file =
+            extract_all_tests_from_file("migration.md", contents, Default::default()).unwrap();
+        assert!(file.commit_transactional);
+        assert!(!file.stateless);
+        assert!(file.tests.iter().all(|test| test.transactional));
+
+        let contents = contents.replace("<!-- commit-transactional -->", "");
+        let file = extract_all_tests_from_file("plain.md", &contents, Default::default()).unwrap();
+        assert!(!file.commit_transactional);
+        assert!(file.stateless);
+    }
+
+    #[test]
+    fn changed_since_lists_modified_and_new_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let sh = Shell::new().unwrap();
+        sh.change_dir(dir.path());
+        assert!(changed_since(&sh, "HEAD").is_err());
+
+        let git = ["-c", "user.name=test", "-c", "user.email=test@example.com"];
+        cmd!(sh, "git init -q").run().unwrap();
+        for file in ["a.md", "b.md", "c.md"] {
+            fs::write(dir.path().join(file), "").unwrap();
+        }
+        cmd!(sh, "git {git...} add .").run().unwrap();
+        cmd!(sh, "git {git...} commit -qm initial").run().unwrap();
+
+        fs::write(dir.path().join("b.md"), "# changed").unwrap();
+        fs::write(dir.path().join("d.md"), "").unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        let mut changed = changed_since(&sh, "HEAD").unwrap();
+        changed.sort();
+        assert_eq!(changed, vec![root.join("b.md"), root.join("d.md")]);
+    }
+
+    #[test]
+    fn parsed_tests_round_trip_through_json() {
+        let contents = "# Numbers\n```SQL\nselect 1.0 a\n```\n\
+            ```output, approx(a: 0.1), sort-by(1)\n a\n---\n 1\n```\n\
+            ```SQL,stateful,capture(id)\nselect 2\n```\n";
+        let file = extract_all_tests_from_file("numbers.md", contents, Default::default()).unwrap();
+        let json = serde_json::to_string(&file).unwrap();
+        let parsed: TestFile = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, file);
+    }
+
+    #[test]
+    fn environment_sets_defaults_flags_override() {
+        use clap::Parser;
+        use std::process::Command;
+
+        let values = |args: &Args| {
+            (
+                args.jobs.get(),
+                args.timeout.map(|t| t.get()),
+                args.startup_timeout.get(),
+            )
+        };
+        // the variables are only set for a copy of this test, run in a process
+        // of its own so the other tests don't see them
+        if std::env::var_os("SQL_TESTER_ENV_TEST").is_some() {
+            let from_env = Args::parse_from(["test", "."]);
+            let from_flags = Args::parse_from([
+                "test",
+                "--jobs",
+                "2",
+                "--timeout",
+                "7",
+                "--startup-timeout",
+                "30",
+                ".",
+            ]);
+            assert_eq!(values(&from_env), (3, Some(5), 90));
+            assert_eq!(values(&from_flags), (2, Some(7), 30));
+            return;
+        }
+        let test = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "test::environment_sets_defaults_flags_override"])
+            .env("SQL_TESTER_ENV_TEST", "1")
+            .env("SQL_TESTER_JOBS", "3")
+            .env("SQL_TESTER_TIMEOUT", "5")
+            .env("SQL_TESTER_STARTUP_TIMEOUT", "90")
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&test.stdout);
+        assert!(test.status.success(), "{stdout}");
+        assert!(stdout.contains("1 passed"), "{stdout}");
+
+        let defaults = Args::parse_from(["test", "."]);
+        assert_eq!(values(&defaults), (default_jobs().get(), None, 60));
+    }
+
+    #[test]
+    fn failures_are_recorded_by_default() {
+        let args = Args::parse_from(["test", "--only-failed", "."]);
+        assert_eq!(args.failures_file, Path::new("failed-tests.txt"));
+        let args = Args::parse_from(["test", "--list", "--with-status", "."]);
+        assert!(args.with_status);
+        let args = Args::parse_from(["test", "--only-failed", "--failures-file", "f.txt", "."]);
+        assert_eq!(args.failures_file, Path::new("f.txt"));
+    }
+
+    #[test]
+    fn dump_conf_only_takes_a_path_after_an_equals_sign() {
+        let args = Args::parse_from(["test", "--dump-conf", "a.md"]);
+        assert_eq!(args.dump_conf, Some(None));
+        assert_eq!(args.input_paths, [Path::new("a.md")]);
+
+        let args = Args::parse_from(["test", "--dump-conf=conf.txt", "a.md"]);
+        assert_eq!(args.dump_conf, Some(Some(PathBuf::from("conf.txt"))));
+        assert_eq!(args.input_paths, [Path::new("a.md")]);
+    }
+
+    #[test]
+    #[ignore = "starts a server"]
+    fn embedded_tests_are_classified() {
+        use crate::db_output::FailureInfo::*;
+        use crate::runner::TestResultRecord;
+
+        let dir = tempfile::tempdir().unwrap();
+        let failures_file = dir.path().join("failed-tests.txt");
+        let args = Args::parse_from([
+            "test".as_ref(),
+            "--failures-file".as_ref(),
+            failures_file.as_os_str(),
+            "src/more_sql_tests.rs".as_ref(),
+            "src/captured_variables_tests.rs".as_ref(),
+        ]);
+        let options = (&args).into();
+        let files: Result<Vec<TestFile>> = args
+            .input_paths
+            .iter()
+            .flat_map(|path| extract_tests_from_path(path, &options))
+            .collect();
+
+        let mut outcomes = vec![];
+        let mut on_result = |record: &TestResultRecord| {
+            let outcome = match record.failure {
+                None => "passed",
+                Some(WrongNumberOfRows { .. }) => "wrong number of rows",
+                Some(MismatchedValues { .. }) => "mismatched values",
+                Some(_) => "failed otherwise",
+            };
+            outcomes.push((record.header.to_string(), outcome));
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let run = runner::run(&args, files.unwrap().into_iter(), Some(&mut on_result));
+        runtime.block_on(run).unwrap();
+
+        // the `Success` and `Failure` blocks share their headers
+        outcomes.sort();
+        let outcomes: Vec<(&str, &str)> = outcomes
+            .iter()
+            .map(|(header, outcome)| (&**header, *outcome))
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                ("`Captured variables`", "passed"),
+                ("`Captured variables`", "passed"),
+                ("`Captured variables`", "passed"),
+                ("`No rows`", "passed"),
+                ("`No rows`", "wrong number of rows"),
+                ("`many rows`", "mismatched values"),
+                ("`many rows`", "passed"),
+                ("`multi col`", "mismatched values"),
+                ("`multi col`", "passed"),
+                ("`one row`", "mismatched values"),
+                ("`one row`", "passed"),
+            ]
+        );
+        let failed = rerun::read_failed(&failures_file).unwrap();
+        assert_eq!(failed.len(), 4);
+    }
+}
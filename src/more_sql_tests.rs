@@ -103,4 +103,4 @@ select i, j from generate_series(1, 3) i, generate_series(1, 3) j;
  2 | 3
  3 | 3
 ```
-*/
\ No newline at end of file
+*/
@@ -1,129 +1,1157 @@
+use std::ops::Range;
 use std::str::Lines;
 
-use crate::Test;
+use crate::db_output::{ExpectedNotices, Tolerance};
+use crate::statements;
+use crate::{AlternativeOutput, Test};
 
-pub fn extract_tests_from_string(s: &str) -> Vec<crate::Test> {
+/// How a test's name is built from the headings it's under.
+#[derive(clap::ArgEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NameStyle {
+    /// Each heading in backticks, one after the other: `` `A``B` ``
+    #[default]
+    Nested,
+    /// The headings joined by ` > `: `A > B`
+    Path,
+    /// Only the innermost heading: `B`
+    Leaf,
+}
+
+impl NameStyle {
+    fn name(self, headings: &[&str]) -> String {
+        match self {
+            NameStyle::Nested => headings.iter().map(|h| format!("`{h}`")).collect(),
+            NameStyle::Path => headings.join(" > "),
+            NameStyle::Leaf => headings.last().copied().unwrap_or("").to_string(),
+        }
+    }
+}
+
+pub fn extract_tests_from_string(s: &str) -> Result<Vec<crate::Test>, ParseError> {
+    extract_tests_with_name_style(s, NameStyle::Nested)
+}
+
+/// Extracts the tests from a markdown string, naming them from their
+/// headings in the given style.
+pub fn extract_tests_with_name_style(
+    s: &str,
+    name_style: NameStyle,
+) -> Result<Vec<crate::Test>, ParseError> {
     use self::BlockKind::*;
     use self::Event::*;
     let block_parser = BlockParser::new(s);
-    let mut heading_stack = vec!["".to_string()];
+    // the headings enclosing the current position, by level
+    let mut heading_stack = vec![""];
 
     let mut tests = vec![];
 
     let mut current_test: Option<Test> = None;
+    // whether the last test pushed got its output from the latest blocks, so
+    // that an alternative output can follow
+    let mut has_output = false;
     for event in block_parser {
         match event {
             Heading { level, text } => {
                 heading_stack.truncate(level);
-                heading_stack.push(format!("`{}`", text));
+                heading_stack.push(text);
             }
             CodeBlock {
                 starting_line,
+                column,
                 attributes,
                 contents,
+                span,
             } => {
-                let header = heading_stack.join("");
-                match parse_code_block_attrs(attributes) {
+                let error = |kind| ParseError::new(starting_line, column, kind);
+                let header = name_style.name(&heading_stack[1..]);
+                match parse_code_block_attrs(attributes).map_err(error)? {
                     Sql {
                         ignore_output,
                         stateless,
+                        capture,
+                        expect_empty,
+                        assert_true,
+                        tags,
                     } => {
                         if let Some(mut test) = current_test.take() {
-                            test.ignore_output = true;
+                            test.ignore_output = !test.expect_empty
+                                && test.assert_true.is_none()
+                                && test.compare_with.is_none();
                             tests.push(test);
                         }
+                        has_output = false;
+                        let empty_sql = statements::is_empty(&contents);
                         let test = Test {
                             line: starting_line,
+                            sql_span: span,
                             header,
                             text: contents,
                             output: Vec::new(),
                             transactional: stateless,
                             ignore_output,
+                            capture: capture.map(str::to_string),
+                            expect_empty,
+                            assert_true: assert_true.map(str::to_string),
+                            check_headers: expect_empty.then_some(false),
+                            tags: tags.into_iter().map(str::to_string).collect(),
+                            empty_sql,
+                            ..Default::default()
                         };
                         current_test = Some(test)
                     }
-                    Output { ignore } => {
-                        let mut test = current_test.take().unwrap_or_else(|| todo!());
-                        test.output = parse_output(contents);
+                    Output {
+                        error: true,
+                        alt,
+                        stateful,
+                        ..
+                    } => {
+                        if alt {
+                            return Err(error(ParseErrorKind::MalformedAttribute {
+                                attribute: "error".to_string(),
+                                reason: "an alternative output can't be an error".to_string(),
+                            }));
+                        }
+                        let mut test = current_test
+                            .take()
+                            .ok_or_else(|| error(ParseErrorKind::StrayOutput))?;
+                        check_stateful_output(stateful, &test).map_err(error)?;
+                        let lines: Vec<&str> = contents
+                            .lines()
+                            .map(str::trim)
+                            .filter(|line| !line.is_empty())
+                            .collect();
+                        test.expected_error = Some(lines.join("\n"));
+                        test.output_span = Some(span);
+                        tests.push(test);
+                        has_output = true;
+                    }
+                    Output {
+                        separator,
+                        csv,
+                        preserve_leading_space,
+                        alt: true,
+                        stateful,
+                        ..
+                    } => {
+                        let test = match (has_output, tests.last_mut()) {
+                            (true, Some(test)) => test,
+                            _ => return Err(error(ParseErrorKind::StrayAlternative)),
+                        };
+                        check_stateful_output(stateful, test).map_err(error)?;
+                        let separator = separator.as_deref();
+                        let (output_headers, output) =
+                            parse_output_block(contents, separator, csv, preserve_leading_space)
+                                .map_err(error)?;
+                        test.alternative_outputs.push(AlternativeOutput {
+                            output_headers,
+                            output,
+                        });
+                    }
+                    Output {
+                        ignore,
+                        check_headers,
+                        tolerances,
+                        sort_by,
+                        grouped_by,
+                        set,
+                        bool_cols,
+                        ignore_cols,
+                        column_count,
+                        separator,
+                        csv,
+                        preserve_leading_space,
+                        alt: false,
+                        wildcard,
+                        null_as,
+                        stateful,
+                        error: false,
+                    } => {
+                        let mut test = current_test
+                            .take()
+                            .ok_or_else(|| error(ParseErrorKind::StrayOutput))?;
+                        check_stateful_output(stateful, &test).map_err(error)?;
+                        if test.expect_empty {
+                            return Err(error(ParseErrorKind::MalformedAttribute {
+                                attribute: "expect-empty".to_string(),
+                                reason:
+                                    "a test expecting an empty result can't have an output block"
+                                        .to_string(),
+                            }));
+                        }
+                        if test.assert_true.is_some() {
+                            return Err(error(ParseErrorKind::MalformedAttribute {
+                                attribute: "assert-true".to_string(),
+                                reason:
+                                    "a test asserting a column is true can't have an output block"
+                                        .to_string(),
+                            }));
+                        }
+                        if test.compare_with.is_some() {
+                            return Err(error(ParseErrorKind::MalformedAttribute {
+                                attribute: "compare-with".to_string(),
+                                reason:
+                                    "a test compared with another query can't have an output block"
+                                        .to_string(),
+                            }));
+                        }
+                        let (headers, output) = parse_output_block(
+                            contents,
+                            separator.as_deref(),
+                            csv,
+                            preserve_leading_space,
+                        )
+                        .map_err(error)?;
+                        test.output_headers = headers;
+                        test.tolerances =
+                            resolve_tolerances(tolerances, &test.output_headers).map_err(error)?;
+                        test.sort_by = sort_by
+                            .into_iter()
+                            .map(|column| resolve_column(column, &test.output_headers))
+                            .collect::<Result<_, _>>()
+                            .map_err(error)?;
+                        test.grouped_by = grouped_by
+                            .into_iter()
+                            .map(|column| resolve_column(column, &test.output_headers))
+                            .collect::<Result<_, _>>()
+                            .map_err(error)?;
+                        test.as_set = set;
+                        test.bool_cols = bool_cols
+                            .into_iter()
+                            .map(|column| resolve_column(column, &test.output_headers))
+                            .collect::<Result<_, _>>()
+                            .map_err(error)?;
+                        test.ignore_cols = ignore_cols;
+                        test.column_count = column_count;
+                        test.output = output;
                         test.ignore_output = ignore;
+                        test.check_headers = check_headers;
+                        test.wildcard = wildcard;
+                        test.null_as = null_as;
+                        test.separator = separator.filter(|_| !csv);
+                        test.output_span = Some(span);
                         tests.push(test);
+                        has_output = true;
+                    }
+                    Notices {
+                        unordered,
+                        contains,
+                    } => {
+                        let test = match (&mut current_test, has_output) {
+                            (Some(test), _) => test,
+                            (None, true) => tests.last_mut().unwrap(),
+                            (None, false) => return Err(error(ParseErrorKind::StrayNotices)),
+                        };
+                        test.notices = Some(ExpectedNotices {
+                            messages: contents
+                                .lines()
+                                .map(str::trim)
+                                .filter(|line| !line.is_empty())
+                                .map(str::to_string)
+                                .collect(),
+                            unordered,
+                            contains,
+                        });
                     }
-                    Other => continue,
+                    CommandTags => {
+                        let test = match (&mut current_test, has_output) {
+                            (Some(test), _) => test,
+                            (None, true) => tests.last_mut().unwrap(),
+                            (None, false) => return Err(error(ParseErrorKind::StrayCommandTags)),
+                        };
+                        if crate::psql::has_meta_commands(&test.text) {
+                            return Err(error(ParseErrorKind::MalformedAttribute {
+                                attribute: "tags".to_string(),
+                                reason: "tests with meta-commands run through psql, which \
+                                    doesn't report command tags"
+                                    .to_string(),
+                            }));
+                        }
+                        test.command_tags = Some(
+                            contents
+                                .lines()
+                                .map(str::trim)
+                                .filter(|line| !line.is_empty())
+                                .map(str::to_string)
+                                .collect(),
+                        );
+                    }
+                    Reference => {
+                        let test = current_test
+                            .as_mut()
+                            .ok_or_else(|| error(ParseErrorKind::StrayReference))?;
+                        test.compare_with = Some(contents);
+                    }
+                    // run around the file's tests rather than as one, see
+                    // `file_hooks`
+                    FileHook { .. } | Other => continue,
                 }
             }
         }
     }
     if let Some(mut test) = current_test.take() {
-        test.ignore_output = true;
+        test.ignore_output =
+            !test.expect_empty && test.assert_true.is_none() && test.compare_with.is_none();
         tests.push(test);
     }
-    tests
+    Ok(tests)
+}
+
+/// Whether the tests should be run one at a time on a single connection, as
+/// requested by a `<!-- serial -->` line.
+pub fn is_serial(s: &str) -> bool {
+    has_directive(s, "serial")
+}
+
+/// Whether transactional tests should be committed instead of rolled back,
+/// so that later tests in the file see their effects, as requested by a
+/// `<!-- commit-transactional -->` line.
+pub fn commits_transactional(s: &str) -> bool {
+    has_directive(s, "commit-transactional")
+}
+
+/// Whether a stateless file's tests should share a single transaction,
+/// rolled back after the last of them, so that later tests see what earlier
+/// ones did, as requested by a `<!-- single-transaction -->` line.
+pub fn is_single_transaction(s: &str) -> bool {
+    has_directive(s, "single-transaction")
+}
+
+/// The `SET` statements for the `<!-- set name = value -->` lines, applied to
+/// the connection each of the file's tests runs on.
+pub fn file_settings(s: &str) -> Vec<String> {
+    directives(s)
+        .filter_map(|directive| {
+            let setting = directive.strip_prefix("set ")?.trim();
+            Some(format!("SET {setting}"))
+        })
+        .collect()
+}
+
+/// The statements of the `<!-- prelude statement -->` lines, run in each of a
+/// stateless file's tests' transactions right after it begins.
+pub fn file_prelude(s: &str) -> Vec<String> {
+    directives(s)
+        .filter_map(|directive| {
+            let statement = directive.strip_prefix("prelude ")?.trim();
+            Some(statement.to_string())
+        })
+        .collect()
+}
+
+/// The SQL of the `SQL,before-all` and `SQL,after-all` blocks in `s`, run
+/// once before and after all of a stateless file's tests.
+pub fn file_hooks(s: &str) -> (Vec<String>, Vec<String>) {
+    let (mut before_all, mut after_all) = (vec![], vec![]);
+    for event in BlockParser::new(s) {
+        let Event::CodeBlock {
+            attributes,
+            contents,
+            ..
+        } = event
+        else {
+            continue;
+        };
+        match parse_code_block_attrs(attributes) {
+            Ok(BlockKind::FileHook { after_all: false }) => before_all.push(contents),
+            Ok(BlockKind::FileHook { after_all: true }) => after_all.push(contents),
+            _ => continue,
+        }
+    }
+    (before_all, after_all)
+}
+
+/// The attributes code blocks understand, as they're usually written. `SQL`
+/// may also be written `sql`.
+const ATTRIBUTES: &[&str] = &[
+    "SQL",
+    "output",
+    "ignore",
+    "stateful",
+    "non-transactional",
+    "ignore-output",
+    "expect-empty",
+    "headers",
+    "ignore-headers",
+    "csv",
+    "preserve-leading-space",
+    "alt",
+    "notices",
+    "tags",
+    "unordered",
+    "contains",
+    "error",
+    "compare-with",
+    "before-all",
+    "after-all",
+    "wildcards",
+    "capture",
+    "assert-true",
+    "approx",
+    "approx-rel",
+    "precision",
+    "sort-by",
+    "grouped-by",
+    "set",
+    "bool",
+    "ignore-cols",
+    "cols",
+    "sep",
+    "null-as",
+];
+
+/// The attributes of the code blocks in `s` that aren't written as they
+/// usually are, with the 1-based line of their block and their usual
+/// spelling. Attributes are read whatever their case, but consistently
+/// written files are easier to search.
+pub fn nonstandard_casing(s: &str) -> Vec<(usize, &str, &'static str)> {
+    let mut found = vec![];
+    for event in BlockParser::new(s) {
+        let Event::CodeBlock {
+            starting_line,
+            attributes,
+            ..
+        } = event
+        else {
+            continue;
+        };
+        for token in split_attrs(attributes) {
+            let name = split_attr_args(token).map_or(token, |(name, _)| name);
+            let standard = ATTRIBUTES
+                .iter()
+                .find(|standard| standard.eq_ignore_ascii_case(name));
+            match standard {
+                Some(&"SQL") if name == "sql" => (),
+                Some(&standard) if standard != name => found.push((starting_line, name, standard)),
+                _ => (),
+            }
+        }
+    }
+    found
+}
+
+/// The text of the first top-level `# heading` in `s`.
+pub fn first_h1(s: &str) -> Option<String> {
+    BlockParser::new(s).find_map(|event| match event {
+        Event::Heading { level: 1, text } if !text.trim().is_empty() => {
+            Some(text.trim().to_string())
+        }
+        _ => None,
+    })
+}
+
+/// The 1-based line and column of the first ```` ```SQL ```` block in `s`.
+pub fn first_sql_block(s: &str) -> Option<(usize, usize)> {
+    BlockParser::new(s).find_map(|event| match event {
+        Event::CodeBlock {
+            starting_line,
+            column,
+            attributes,
+            ..
+        } if attributes.get(..3)?.eq_ignore_ascii_case("sql") => Some((starting_line, column)),
+        _ => None,
+    })
+}
+
+fn has_directive(s: &str, name: &str) -> bool {
+    directives(s).any(|directive| directive == name)
+}
+
+/// The contents of the lines of `s` that are only an HTML comment, leaving
+/// out those within code blocks, which are part of a test.
+fn directives(s: &str) -> impl Iterator<Item = &str> {
+    let mut in_code_block = false;
+    s.lines().filter_map(move |line| {
+        if line.trim_start().starts_with("```") {
+            in_code_block = !in_code_block;
+        }
+        match in_code_block {
+            true => None,
+            false => directive(line),
+        }
+    })
+}
+
+/// The contents of a line that's only an HTML comment.
+fn directive(line: &str) -> Option<&str> {
+    line.trim()
+        .strip_prefix("<!--")
+        .and_then(|line| line.strip_suffix("-->"))
+        .map(str::trim)
+}
+
+/// An error in the tests embedded in a file, located by 1-based line and
+/// column.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub file: String,
+    pub line: usize,
+    pub col: usize,
+    pub kind: ParseErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// a start marker without a matching end marker
+    UnterminatedMarker {
+        end_marker: String,
+    },
+    /// an `output` block that doesn't follow a SQL block
+    StrayOutput,
+    /// an `output, alt` block that doesn't follow an `output` block
+    StrayAlternative,
+    /// a `notices` block that doesn't follow a SQL block
+    StrayNotices,
+    /// a `SQL,compare-with` block that doesn't follow a SQL block
+    StrayReference,
+    /// a `tags` block that doesn't follow a SQL block
+    StrayCommandTags,
+    MalformedAttribute {
+        attribute: String,
+        reason: String,
+    },
+    MalformedOutput {
+        reason: String,
+    },
+    /// a SQL block in a file with no start markers, as found by
+    /// `--strict-markers`
+    UnmarkedTests {
+        start_marker: String,
+    },
+}
+
+impl ParseError {
+    pub fn new(line: usize, col: usize, kind: ParseErrorKind) -> Self {
+        Self {
+            file: String::new(),
+            line,
+            col,
+            kind,
+        }
+    }
+
+    /// Attributes an error found in a snippet of `file` starting at
+    /// `first_line` to its location in the whole file.
+    pub fn in_file(mut self, file: &str, first_line: usize) -> Self {
+        self.file = file.to_string();
+        self.line += first_line - 1;
+        self
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use ParseErrorKind::*;
+        let Self {
+            file, line, col, ..
+        } = self;
+        write!(f, "{file}:{line}:{col}: ")?;
+        match &self.kind {
+            UnterminatedMarker { end_marker } => {
+                write!(f, "could not find test end `{end_marker}`")
+            }
+            StrayOutput => write!(f, "output block without a preceding SQL block"),
+            StrayAlternative => write!(
+                f,
+                "alternative output block without a preceding output block"
+            ),
+            StrayNotices => write!(f, "notices block without a preceding SQL block"),
+            StrayReference => write!(f, "compare-with block without a preceding SQL block"),
+            StrayCommandTags => write!(f, "tags block without a preceding SQL block"),
+            MalformedAttribute { attribute, reason } => {
+                write!(f, "malformed attribute `{attribute}`: {reason}")
+            }
+            MalformedOutput { reason } => write!(f, "malformed output: {reason}"),
+            UnmarkedTests { start_marker } => write!(
+                f,
+                "SQL block in a file without any `{start_marker}` markers, only `.md` files are parsed whole"
+            ),
+        }
+    }
 }
 
-enum BlockKind {
+impl std::error::Error for ParseError {}
+
+enum BlockKind<'a> {
     Sql {
         ignore_output: bool,
         stateless: bool,
+        capture: Option<&'a str>,
+        expect_empty: bool,
+        /// the column, by name or 1-based index, that must be true in every row
+        assert_true: Option<&'a str>,
+        tags: Vec<&'a str>,
     },
     Output {
         ignore: bool,
+        check_headers: Option<bool>,
+        /// the columns, by name or 1-based index, compared approximately
+        tolerances: Vec<(&'a str, Tolerance)>,
+        /// the columns, by name or 1-based index, to sort the rows by
+        sort_by: Vec<&'a str>,
+        /// the columns, by name or 1-based index, whose values group rows
+        /// that are compared in order, the groups being in any order
+        grouped_by: Vec<&'a str>,
+        /// whether the rows are compared as sets, in any order and ignoring
+        /// duplicates
+        set: bool,
+        /// the columns, by name or 1-based index, holding booleans that
+        /// match however they're written
+        bool_cols: Vec<&'a str>,
+        /// the 0-based columns of the received output that aren't compared
+        ignore_cols: Vec<usize>,
+        /// the number of columns the output must have, its values aren't
+        /// compared
+        column_count: Option<usize>,
+        /// the column delimiter, `|` if not set
+        separator: Option<String>,
+        /// whether the output is CSV, with the column names as the first record
+        csv: bool,
+        /// whether the values keep their leading spaces, after the one
+        /// padding them from the separator
+        preserve_leading_space: bool,
+        /// whether the output is an alternative to the preceding one
+        alt: bool,
+        /// the value that matches any received one, `...` for `wildcards`
+        wildcard: Option<String>,
+        /// how received NULLs are written, empty if not set
+        null_as: Option<String>,
+        /// whether the block holds the error the query should fail with
+        error: bool,
+        /// whether the block is marked stateful, as the SQL block it follows
+        /// must then be
+        stateful: bool,
+    },
+    /// the notices the preceding SQL block should raise
+    Notices {
+        unordered: bool,
+        contains: bool,
+    },
+    /// a query whose result the preceding SQL block's must equal
+    Reference,
+    /// the command tags of the preceding SQL block's statements
+    CommandTags,
+    /// SQL run once before, or after, all of the file's tests
+    FileHook {
+        after_all: bool,
     },
     Other,
 }
 
-fn parse_code_block_attrs(attrs: &str) -> BlockKind {
+fn parse_code_block_attrs(attrs: &str) -> Result<BlockKind<'_>, ParseErrorKind> {
     // TODO incomplete, look at the doctester for the full version
-    // TODO error handling
     let mut is_sql = false;
     let mut is_stateful = false;
-    let mut is_ignoring_output = false;
+    let mut is_expecting_empty = false;
     let mut is_output = false;
     let mut is_ignored = false;
-    attrs.split(',').for_each(|token| {
-        let token = &*token.trim().to_ascii_lowercase();
+    let mut capture = None;
+    let mut assert_true = None;
+    let mut check_headers = None;
+    let mut tolerances = vec![];
+    let mut sort_by = vec![];
+    let mut grouped_by = vec![];
+    let mut is_set = false;
+    let mut bool_cols = vec![];
+    let mut ignore_cols = vec![];
+    let mut column_count = None;
+    let mut separator = None;
+    let mut is_csv = false;
+    let mut is_preserving_leading_space = false;
+    let mut is_alternative = false;
+    let mut is_notices = false;
+    let mut is_command_tags = false;
+    let mut is_unordered = false;
+    let mut is_contains = false;
+    let mut tags = vec![];
+    let mut wildcard = None;
+    let mut null_as = None;
+    let mut is_error = false;
+    let mut is_reference = false;
+    let mut is_before_all = false;
+    let mut is_after_all = false;
+    for token in split_attrs(attrs) {
+        let malformed = |reason: &str| ParseErrorKind::MalformedAttribute {
+            attribute: token.to_string(),
+            reason: reason.to_string(),
+        };
+        if let Some((name, args)) = split_attr_args(token) {
+            if name.eq_ignore_ascii_case("capture") {
+                let name = args.trim();
+                let is_identifier =
+                    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+                if !is_identifier {
+                    return Err(malformed("variable names may only contain [a-zA-Z0-9_]"));
+                }
+                capture = Some(name);
+            } else if name.eq_ignore_ascii_case("assert-true") {
+                let column = args.trim();
+                if column.is_empty() || column.contains(',') {
+                    return Err(malformed("expected a single column"));
+                }
+                assert_true = Some(column);
+            } else if name.eq_ignore_ascii_case("approx") {
+                tolerances.extend(parse_tolerances(args, Tolerance::Absolute).map_err(malformed)?);
+            } else if name.eq_ignore_ascii_case("approx-rel") {
+                tolerances.extend(parse_tolerances(args, Tolerance::Relative).map_err(malformed)?);
+            } else if name.eq_ignore_ascii_case("precision") {
+                tolerances.extend(parse_precisions(args).map_err(malformed)?);
+            } else if name.eq_ignore_ascii_case("sort-by") {
+                sort_by = args.split(',').map(str::trim).collect();
+                if sort_by.iter().any(|column| column.is_empty()) {
+                    return Err(malformed("expected a list of columns"));
+                }
+            } else if name.eq_ignore_ascii_case("grouped-by") {
+                grouped_by = args.split(',').map(str::trim).collect();
+                if grouped_by.iter().any(|column| column.is_empty()) {
+                    return Err(malformed("expected a list of columns"));
+                }
+            } else if name.eq_ignore_ascii_case("bool") {
+                bool_cols = args.split(',').map(str::trim).collect();
+                if bool_cols.iter().any(|column| column.is_empty()) {
+                    return Err(malformed("expected a list of columns"));
+                }
+            } else if name.eq_ignore_ascii_case("cols") {
+                let count = args.trim().parse::<usize>();
+                column_count = Some(count.map_err(|_| malformed("expected a number of columns"))?);
+            } else if name.eq_ignore_ascii_case("ignore-cols") {
+                ignore_cols = args
+                    .split(',')
+                    .map(|column| match column.trim().parse::<usize>() {
+                        Ok(0) => Err(malformed("columns are numbered from 1")),
+                        Ok(n) => Ok(n - 1),
+                        Err(_) => Err(malformed("expected a list of column numbers")),
+                    })
+                    .collect::<Result<_, _>>()?;
+            } else if name.eq_ignore_ascii_case("sep") {
+                let sep = parse_separator(args.trim()).ok_or_else(|| {
+                    malformed("expected a non-empty separator such as `sep(\"\\t\")`")
+                })?;
+                separator = Some(sep);
+            } else if name.eq_ignore_ascii_case("tags") {
+                tags = args.split(',').map(str::trim).collect();
+                if tags.iter().any(|tag| tag.is_empty()) {
+                    return Err(malformed("expected a list of tags"));
+                }
+            } else if name.eq_ignore_ascii_case("wildcards") {
+                let token = parse_separator(args.trim()).ok_or_else(|| {
+                    malformed("expected a non-empty value such as `wildcards(\"*\")`")
+                })?;
+                wildcard = Some(token);
+            } else if name.eq_ignore_ascii_case("null-as") {
+                let token = parse_separator(args.trim()).ok_or_else(|| {
+                    malformed("expected a non-empty value such as `null-as(\"∅\")`")
+                })?;
+                null_as = Some(token);
+            }
+            continue;
+        }
+        let token = &*token.to_ascii_lowercase();
         match token {
             "output" => is_output = true,
             "sql" => is_sql = true,
             "ignore" => is_ignored = true,
             "stateful" | "non-transactional" => is_stateful = true,
-            "ignore-output" => is_ignoring_output = true,
+            "expect-empty" => is_expecting_empty = true,
+            "headers" => check_headers = check_headers.or(Some(true)),
+            "ignore-headers" => check_headers = Some(false),
+            "csv" => is_csv = true,
+            "preserve-leading-space" => is_preserving_leading_space = true,
+            "alt" => is_alternative = true,
+            "notices" => is_notices = true,
+            "tags" => is_command_tags = true,
+            "unordered" => is_unordered = true,
+            "set" => is_set = true,
+            "contains" => is_contains = true,
+            "error" => is_error = true,
+            "compare-with" => is_reference = true,
+            "before-all" => is_before_all = true,
+            "after-all" => is_after_all = true,
+            "wildcards" => wildcard = wildcard.or_else(|| Some("...".to_string())),
             _ => (),
         }
-    });
+    }
 
     if is_ignored {
+        return Ok(BlockKind::Other);
+    }
 
-        return BlockKind::Other;
+    if is_command_tags && !is_sql && !is_output {
+        return Ok(BlockKind::CommandTags);
+    }
+
+    if is_notices {
+        return Ok(BlockKind::Notices {
+            unordered: is_unordered,
+            contains: is_contains,
+        });
     }
 
     if is_output {
-        if is_stateful {
-            todo!()
+        if !sort_by.is_empty() && !grouped_by.is_empty() {
+            return Err(ParseErrorKind::MalformedAttribute {
+                attribute: attrs.to_string(),
+                reason: "`sort-by` and `grouped-by` can't be used together".to_string(),
+            });
+        }
+        if is_set && !(sort_by.is_empty() && grouped_by.is_empty()) {
+            return Err(ParseErrorKind::MalformedAttribute {
+                attribute: attrs.to_string(),
+                reason: "`set` already compares the rows in any order, it can't be used with \
+                    `sort-by` or `grouped-by`"
+                    .to_string(),
+            });
         }
-        return BlockKind::Output { ignore: is_ignored };
+        return Ok(BlockKind::Output {
+            ignore: is_ignored,
+            check_headers,
+            tolerances,
+            sort_by,
+            grouped_by,
+            set: is_set,
+            bool_cols,
+            ignore_cols,
+            column_count,
+            separator,
+            csv: is_csv,
+            preserve_leading_space: is_preserving_leading_space,
+            alt: is_alternative,
+            wildcard,
+            null_as,
+            error: is_error,
+            stateful: is_stateful,
+        });
+    }
+
+    if is_sql && is_reference {
+        return Ok(BlockKind::Reference);
+    }
+
+    if is_sql && (is_before_all || is_after_all) {
+        return Ok(BlockKind::FileHook {
+            after_all: is_after_all,
+        });
     }
 
     if is_sql {
-        return BlockKind::Sql {
+        return Ok(BlockKind::Sql {
             ignore_output: is_ignored,
             stateless: !is_stateful,
-        };
+            capture,
+            expect_empty: is_expecting_empty,
+            assert_true,
+            tags,
+        });
     }
 
     // TODO warn on other attributes?
-    BlockKind::Other
+    Ok(BlockKind::Other)
+}
+
+/// Splits a code block's attributes on the commas that aren't nested within
+/// an attribute's arguments, so `output, sort-by(2, 1)` is two attributes.
+/// Quoted arguments, such as `sep(",")`, are skipped over whole.
+fn split_attrs(attrs: &str) -> impl Iterator<Item = &str> {
+    let (mut depth, mut quoted, mut escaped) = (0usize, false, false);
+    attrs
+        .split(move |c| {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if quoted => escaped = true,
+                '"' => quoted = !quoted,
+                _ if quoted => (),
+                '(' => depth += 1,
+                ')' => depth = depth.saturating_sub(1),
+                _ => (),
+            }
+            c == ',' && depth == 0 && !quoted
+        })
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+}
+
+/// Splits an attribute of the form `name(args)` into its name and arguments.
+fn split_attr_args(token: &str) -> Option<(&str, &str)> {
+    let (name, args) = token.split_once('(')?;
+    let args = args.trim_end().strip_suffix(')')?;
+    Some((name.trim(), args))
+}
+
+/// Parses the `column: tolerance` pairs of an `approx` or `approx-rel`
+/// attribute.
+fn parse_tolerances(
+    args: &str,
+    kind: fn(f64) -> Tolerance,
+) -> Result<Vec<(&str, Tolerance)>, &'static str> {
+    args.split(',')
+        .map(|arg| {
+            let (column, tolerance) = arg
+                .split_once(':')
+                .ok_or("expected `column: tolerance` pairs")?;
+            let tolerance: f64 = tolerance
+                .trim()
+                .parse()
+                .map_err(|_| "tolerances must be numbers")?;
+            if !tolerance.is_finite() || tolerance < 0.0 {
+                return Err("tolerances must be finite and non-negative");
+            }
+            Ok((column.trim(), kind(tolerance)))
+        })
+        .collect()
+}
+
+/// Parses the `column: digits` pairs of a `precision` attribute.
+fn parse_precisions(args: &str) -> Result<Vec<(&str, Tolerance)>, &'static str> {
+    args.split(',')
+        .map(|arg| {
+            let (column, digits) = arg
+                .split_once(':')
+                .ok_or("expected `column: digits` pairs")?;
+            let digits = digits
+                .trim()
+                .parse()
+                .map_err(|_| "digits must be non-negative integers")?;
+            Ok((column.trim(), Tolerance::Precision(digits)))
+        })
+        .collect()
+}
+
+/// Converts the columns of tolerances into 0-based indices into the output.
+fn resolve_tolerances(
+    tolerances: Vec<(&str, Tolerance)>,
+    headers: &[String],
+) -> Result<Vec<(usize, Tolerance)>, ParseErrorKind> {
+    let mut resolved: Vec<(usize, Tolerance)> = Vec::with_capacity(tolerances.len());
+    for (column, tolerance) in tolerances {
+        let index = resolve_column(column, headers)?;
+        if resolved.iter().any(|(i, _)| *i == index) {
+            return Err(ParseErrorKind::MalformedAttribute {
+                attribute: column.to_string(),
+                reason: "a column can only have one tolerance".to_string(),
+            });
+        }
+        resolved.push((index, tolerance));
+    }
+    Ok(resolved)
 }
 
-fn parse_output(s: String) -> Vec<Vec<String>> {
-    s.split('\n') // parse by-line
+/// Converts a column, by name or 1-based index, into a 0-based index into
+/// the output.
+fn resolve_column(column: &str, headers: &[String]) -> Result<usize, ParseErrorKind> {
+    let malformed = |reason: &str| ParseErrorKind::MalformedAttribute {
+        attribute: column.to_string(),
+        reason: reason.to_string(),
+    };
+    match column.parse::<usize>() {
+        Ok(0) => Err(malformed("columns are numbered from 1")),
+        Ok(n) => Ok(n - 1),
+        Err(_) => headers
+            .iter()
+            .position(|h| h == column)
+            .ok_or_else(|| malformed("no such column in the output")),
+    }
+}
+
+/// Parses the argument of a `sep` attribute, either a bare separator or a
+/// quoted one which may contain the escapes `\t`, `\\` and `\"`.
+fn parse_separator(arg: &str) -> Option<String> {
+    let separator = match arg.strip_prefix('"').and_then(|a| a.strip_suffix('"')) {
+        None => arg.to_string(),
+        Some(quoted) => {
+            let mut separator = String::with_capacity(quoted.len());
+            let mut chars = quoted.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => match chars.next()? {
+                        't' => separator.push('\t'),
+                        c @ ('\\' | '"') => separator.push(c),
+                        _ => return None,
+                    },
+                    c => separator.push(c),
+                }
+            }
+            separator
+        }
+    };
+    (!separator.is_empty() && !separator.contains('\n')).then_some(separator)
+}
+
+/// An output block marked `stateful` is the output of a stateful or
+/// non-transactional test, so it can't follow a transactional one.
+fn check_stateful_output(stateful: bool, test: &Test) -> Result<(), ParseErrorKind> {
+    if stateful && test.transactional {
+        return Err(ParseErrorKind::MalformedAttribute {
+            attribute: "stateful".to_string(),
+            reason: "a stateful output block must follow a stateful or non-transactional SQL block"
+                .to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn parse_output_headers(s: &str, separator: &str) -> Vec<String> {
+    match s.lines().next() {
+        Some(line) if !line.trim().is_empty() => line
+            .split(separator)
+            .map(|s| s.trim().to_string())
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Parses the rows of a table. Values are trimmed, or with
+/// `preserve_leading_space` only lose the space padding them from the
+/// separator and those after them.
+fn parse_output(
+    s: String,
+    separator: &str,
+    preserve_leading_space: bool,
+) -> Result<Vec<Vec<String>>, ParseErrorKind> {
+    if !s.trim().is_empty() && s.lines().count() < 2 {
+        return Err(ParseErrorKind::MalformedOutput {
+            reason: "expected column names followed by a separator line".to_string(),
+        });
+    }
+    if let Some(line) = (s.lines().nth(1)).filter(|line| !is_separator_line(line, separator)) {
+        let line = line.trim();
+        return Err(ParseErrorKind::MalformedOutput {
+            reason: format!(
+                "expected a separator line such as `---+---` after the column names, found `{line}`"
+            ),
+        });
+    }
+    let rows: Vec<Vec<String>> = s
+        .split('\n') // parse by-line
         .skip(2) // first two lines are column names and a separator
         // .filter(|s| !s.is_empty()) TODO why was this in the original?
         .map(|s| {
-            s.split('|')
-                .map(|s| s.trim().to_string())
+            s.split(separator)
+                .map(|s| match preserve_leading_space {
+                    true => s.strip_prefix(' ').unwrap_or(s).trim_end().to_string(),
+                    false => s.trim().to_string(),
+                })
                 .collect::<Vec<_>>()
         })
-        .collect()
+        .collect();
+    check_row_widths(&s, &rows)?;
+    Ok(rows)
+}
+
+/// Checks that every row of a table has as many cells as the first, since a
+/// ragged row is usually a stray separator. Blank lines are left out, as
+/// they're how psql prints a row of a single empty value.
+fn check_row_widths(s: &str, rows: &[Vec<String>]) -> Result<(), ParseErrorKind> {
+    let mut lines = (s.split('\n').skip(2).zip(rows)).filter(|(line, _)| !line.trim().is_empty());
+    let width = match lines.next() {
+        Some((_, row)) => row.len(),
+        None => return Ok(()),
+    };
+    match lines.find(|(_, row)| row.len() != width) {
+        None => Ok(()),
+        Some((line, row)) => {
+            let (line, cells) = (line.trim(), row.len());
+            Err(ParseErrorKind::MalformedOutput {
+                reason: format!(
+                    "the row `{line}` has {cells} columns but the rows before it have {width}"
+                ),
+            })
+        }
+    }
+}
+
+/// Whether `line` is drawn from the characters a table's separator line is,
+/// such as psql's `---+---`, `===` or `---|---` with the table's own column
+/// `separator`, rather than being a row of values.
+fn is_separator_line(line: &str, separator: &str) -> bool {
+    const RULES: &[char] = &['-', '=', '─', '═'];
+    const JOINS: &[char] = &['+', '┼', '╪'];
+    line.contains(RULES)
+        && line.chars().all(|c| {
+            RULES.contains(&c) || JOINS.contains(&c) || separator.contains(c) || c.is_whitespace()
+        })
+}
+
+/// Parses the column names and rows of an output block, written as a table
+/// unless `csv` is set.
+fn parse_output_block(
+    contents: String,
+    separator: Option<&str>,
+    csv: bool,
+    preserve_leading_space: bool,
+) -> Result<(Vec<String>, Vec<Vec<String>>), ParseErrorKind> {
+    if csv {
+        return parse_csv_output(&contents, separator);
+    }
+    let separator = separator.unwrap_or("|");
+    let contents = strip_row_count(trim_blank_lines(&contents)).to_string();
+    let headers = parse_output_headers(&contents, separator);
+    Ok((
+        headers,
+        parse_output(contents, separator, preserve_leading_space)?,
+    ))
+}
+
+/// Drops the blank lines before a table's column names and the empty lines
+/// after its last row, so they aren't read as rows. Trailing lines holding
+/// only whitespace are kept, as that's how psql prints a row of a single
+/// empty value.
+fn trim_blank_lines(s: &str) -> &str {
+    let start = s
+        .split_inclusive('\n')
+        .take_while(|line| line.trim().is_empty())
+        .map(str::len)
+        .sum();
+    s[start..].trim_end_matches('\n')
+}
+
+/// Drops the `(N rows)` line psql prints after a table, along with the empty
+/// lines before it, so output pasted from psql can be used as is. The count
+/// must be the last line and, unlike the values above it, unindented.
+fn strip_row_count(s: &str) -> &str {
+    let (table, last_line) = s.rsplit_once('\n').unwrap_or(("", s));
+    let count = last_line
+        .trim_end()
+        .strip_prefix('(')
+        .and_then(|line| line.strip_suffix(')'))
+        .and_then(|line| {
+            line.strip_suffix(" rows")
+                .or_else(|| line.strip_suffix(" row"))
+        });
+    match count.map(|count| count.parse::<usize>()) {
+        Some(Ok(_)) => table.trim_end_matches('\n'),
+        _ => s,
+    }
+}
+
+/// Parses an `output, csv` block, whose first record holds the column names.
+/// Fields are kept exactly as written, so quoted ones may contain the
+/// delimiter, quotes or newlines. The delimiter defaults to `,` and may be
+/// set to another single byte with `sep`.
+fn parse_csv_output(
+    s: &str,
+    separator: Option<&str>,
+) -> Result<(Vec<String>, Vec<Vec<String>>), ParseErrorKind> {
+    let malformed = |reason: String| ParseErrorKind::MalformedOutput { reason };
+    let delimiter = match separator {
+        None => b',',
+        Some(sep) if sep.len() == 1 => sep.as_bytes()[0],
+        Some(sep) => {
+            return Err(malformed(format!(
+                "CSV separators must be one byte, found `{sep}`"
+            )))
+        }
+    };
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .delimiter(delimiter)
+        .from_reader(s.as_bytes());
+    let mut records = reader.records().map(|record| {
+        record
+            .map(|record| record.iter().map(str::to_string).collect())
+            .map_err(|e| malformed(format!("invalid CSV: {e}")))
+    });
+    let headers = records.next().transpose()?.unwrap_or_default();
+    let rows = records.collect::<Result<_, _>>()?;
+    Ok((headers, rows))
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -134,13 +1162,18 @@ enum Event<'s> {
     },
     CodeBlock {
         starting_line: usize,
+        column: usize,
         attributes: &'s str,
         contents: String,
+        /// the bytes of the whole block, from its opening fence to the end of
+        /// its closing one
+        span: Range<usize>,
     },
 }
 
 struct BlockParser<'s> {
     line_num: usize,
+    source: &'s str,
     lines: Lines<'s>,
 }
 
@@ -148,11 +1181,17 @@ impl<'s> BlockParser<'s> {
     fn new(s: &'s str) -> Self {
         Self {
             line_num: 0,
+            source: s,
             lines: s.lines(),
         }
     }
 }
 
+/// The byte offset of `line`, a slice of `source`, within it.
+fn offset_in(source: &str, line: &str) -> usize {
+    line.as_ptr() as usize - source.as_ptr() as usize
+}
+
 impl<'s> Iterator for BlockParser<'s> {
     type Item = Event<'s>;
 
@@ -178,15 +1217,23 @@ impl<'s> Iterator for BlockParser<'s> {
                 let indent = &line[..indent_len];
                 let starting_line = self.line_num;
                 let attributes = trimmed.get(3..).unwrap_or("").trim_start();
-                let contents: Vec<_> = (&mut self.lines)
-                    .take_while(|line| !line.trim_start().starts_with("```"))
-                    .map(|l| l.trim_start_matches(indent))
-                    .collect();
+                let start = offset_in(self.source, line) + indent_len;
+                let mut end = offset_in(self.source, line) + line.len();
+                let mut contents = vec![];
+                for line in &mut self.lines {
+                    end = offset_in(self.source, line) + line.len();
+                    if line.trim_start().starts_with("```") {
+                        break;
+                    }
+                    contents.push(line.trim_start_matches(indent));
+                }
                 self.line_num += contents.len() + 1;
                 return Some(CodeBlock {
                     starting_line,
+                    column: indent_len + 1,
                     attributes,
                     contents: contents.join("\n"),
+                    span: start..end,
                 });
             }
         }
@@ -270,23 +1317,31 @@ select * from qat
             },
             CodeBlock {
                 starting_line: 3,
+                column: 1,
                 attributes: "SQL",
                 contents: "select * from foo".to_string(),
+                span: 16..44,
             },
             CodeBlock {
                 starting_line: 6,
+                column: 1,
                 attributes: "output",
                 contents: "".to_string(),
+                span: 45..58,
             },
             CodeBlock {
                 starting_line: 9,
+                column: 1,
                 attributes: "SQL",
                 contents: "select * from multiline;\nselect * from multiline;".to_string(),
+                span: 60..120,
             },
             CodeBlock {
                 starting_line: 13,
+                column: 1,
                 attributes: "output",
                 contents: " ?column?\n----------\n    value".to_string(),
+                span: 121..165,
             },
             Heading {
                 level: 2,
@@ -294,8 +1349,10 @@ select * from qat
             },
             CodeBlock {
                 starting_line: 20,
+                column: 1,
                 attributes: "SQL,ignore",
                 contents: "select * from foo".to_string(),
+                span: 178..213,
             },
             Heading {
                 level: 2,
@@ -303,13 +1360,17 @@ select * from qat
             },
             CodeBlock {
                 starting_line: 25,
+                column: 1,
                 attributes: "SQL,non-transactional",
                 contents: "select * from bar".to_string(),
+                span: 236..282,
             },
             CodeBlock {
                 starting_line: 28,
+                column: 1,
                 attributes: "output, precision(1: 3)",
                 contents: " a | b\n---+---\n 1 | 2".to_string(),
+                span: 283..335,
             },
             Heading {
                 level: 2,
@@ -317,13 +1378,17 @@ select * from qat
             },
             CodeBlock {
                 starting_line: 36,
+                column: 5,
                 attributes: "SQL",
                 contents: "select indented;\n  select keeps_whitespace;".to_string(),
+                span: 354..416,
             },
             CodeBlock {
                 starting_line: 40,
+                column: 5,
                 attributes: "output",
                 contents: " ???\n-----\na | b".to_string(),
+                span: 421..467,
             },
             Heading {
                 level: 2,
@@ -331,8 +1396,10 @@ select * from qat
             },
             CodeBlock {
                 starting_line: 47,
+                column: 1,
                 attributes: "SQL,ignore-output",
                 contents: "select * from baz".to_string(),
+                span: 482..524,
             },
             Heading {
                 level: 2,
@@ -340,8 +1407,10 @@ select * from qat
             },
             CodeBlock {
                 starting_line: 52,
+                column: 1,
                 attributes: "SQL",
                 contents: "select * from quz".to_string(),
+                span: 543..571,
             },
             Heading {
                 level: 2,
@@ -349,76 +1418,879 @@ select * from qat
             },
             CodeBlock {
                 starting_line: 57,
+                column: 1,
                 attributes: "SQL",
                 contents: "select * from qat".to_string(),
+                span: 588..616,
             },
         ];
         assert_eq!(events, expected);
+        // the spans cover the blocks fences and all
+        for event in events {
+            if let CodeBlock { span, .. } = event {
+                let block = &TEST_CONTENTS[span];
+                assert!(block.starts_with("```"), "{block}");
+                assert!(block.ends_with("```"), "{block}");
+            }
+        }
     }
 
     #[test]
     fn extract_tests_extracts() {
         use crate::Test;
 
-        let tests = super::extract_tests_from_string(TEST_CONTENTS);
+        let tests = super::extract_tests_from_string(TEST_CONTENTS).unwrap();
         let expected = vec![
             Test {
                 line: 3,
+                sql_span: 16..44,
+                output_span: Some(45..58),
                 header: "`Test Parsing`".to_string(),
                 text: "select * from foo".to_string(),
                 output: vec![],
                 transactional: true,
                 ignore_output: false,
+                ..Default::default()
             },
             Test {
                 line: 9,
+                sql_span: 60..120,
+                output_span: Some(121..165),
                 header: "`Test Parsing`".to_string(),
                 text: "select * from multiline;\nselect * from multiline;".to_string(),
                 output: vec![vec!["value".to_string()]],
                 transactional: true,
                 ignore_output: false,
+                output_headers: vec!["?column?".to_string()],
+                ..Default::default()
             },
             Test {
                 line: 25,
+                sql_span: 236..282,
+                output_span: Some(283..335),
                 header: "`Test Parsing``non-transactional`".to_string(),
                 text: "select * from bar".to_string(),
                 output: vec![vec!["1".to_string(), "2".to_string()]],
                 transactional: false,
                 ignore_output: false,
+                output_headers: vec!["a".to_string(), "b".to_string()],
+                tolerances: vec![(0, super::Tolerance::Precision(3))],
+                ..Default::default()
             },
             Test {
                 line: 36,
+                sql_span: 354..416,
+                output_span: Some(421..467),
                 header: "`Test Parsing``indented`".to_string(),
                 text: "select indented;\n  select keeps_whitespace;".to_string(),
                 output: vec![vec!["a".to_string(), "b".to_string()]],
                 transactional: true,
                 ignore_output: false,
+                output_headers: vec!["???".to_string()],
+                ..Default::default()
             },
             Test {
                 line: 47,
+                sql_span: 482..524,
                 header: "`Test Parsing``no output`".to_string(),
                 text: "select * from baz".to_string(),
                 output: vec![],
                 transactional: true,
                 ignore_output: true,
+                ..Default::default()
             },
             Test {
                 line: 52,
+                sql_span: 543..571,
                 header: "`Test Parsing``end by header`".to_string(),
                 text: "select * from quz".to_string(),
                 output: vec![],
                 transactional: true,
                 ignore_output: true,
+                ..Default::default()
             },
             Test {
                 line: 57,
+                sql_span: 588..616,
                 header: "`Test Parsing``end by file`".to_string(),
                 text: "select * from qat".to_string(),
                 output: vec![],
                 transactional: true,
                 ignore_output: true,
+                ..Default::default()
             },
         ];
         assert_eq!(tests, expected);
+        assert_eq!(
+            &TEST_CONTENTS[tests[1].sql_span.clone()],
+            "```SQL\nselect * from multiline;\nselect * from multiline;\n```"
+        );
+        assert_eq!(
+            &TEST_CONTENTS[tests[3].output_span.clone().unwrap()],
+            "```output\n     ???\n    -----\n    a | b\n    ```"
+        );
+    }
+
+    #[test]
+    fn capture_attribute_is_parsed() {
+        let tests = super::extract_tests_from_string(
+            "```SQL,non-transactional,capture(id)\nINSERT INTO t VALUES (1) RETURNING id\n```",
+        )
+        .unwrap();
+        assert_eq!(tests[0].capture.as_deref(), Some("id"));
+        assert!(!tests[0].transactional);
+    }
+
+    #[test]
+    fn blank_sql_blocks_are_found() {
+        let contents = "# Blank\n```SQL\n\n   \n```\n```output\n```\n\
+            # Commented\n```SQL\n-- select 1;\n```\n\
+            # Query\n```SQL\n-- the answer\nselect 42\n```";
+        let tests = super::extract_tests_from_string(contents).unwrap();
+        let empty: Vec<_> = tests.iter().map(|test| test.empty_sql).collect();
+        assert_eq!(empty, [true, true, false]);
+    }
+
+    #[test]
+    fn parse_errors_are_located() {
+        use super::{extract_tests_from_string, ParseError, ParseErrorKind::*};
+
+        let stray = extract_tests_from_string("# a\n\n  ```output\n a\n---\n```\n");
+        assert_eq!(stray, Err(ParseError::new(3, 3, StrayOutput)));
+
+        let attribute = extract_tests_from_string("```SQL,capture(not a name)\nselect 1\n```");
+        assert_eq!(
+            attribute,
+            Err(ParseError::new(
+                1,
+                1,
+                MalformedAttribute {
+                    attribute: "capture(not a name)".to_string(),
+                    reason: "variable names may only contain [a-zA-Z0-9_]".to_string(),
+                }
+            ))
+        );
+
+        let output = extract_tests_from_string("```SQL\nselect 1\n```\n```output\n 1\n```");
+        let error = output.unwrap_err().in_file("test.md", 10);
+        assert_eq!((&*error.file, error.line, error.col), ("test.md", 13, 1));
+        assert!(matches!(error.kind, MalformedOutput { .. }));
+        assert_eq!(
+            error.to_string(),
+            "test.md:13:1: malformed output: expected column names followed by a separator line"
+        );
+    }
+
+    #[test]
+    fn header_attributes_are_parsed() {
+        let contents =
+            "```SQL\nselect 1\n```\n```output, headers, ignore-headers\n ?column?\n---\n 1\n```";
+        let tests = super::extract_tests_from_string(contents).unwrap();
+        assert_eq!(tests[0].output_headers, ["?column?"]);
+        assert_eq!(tests[0].check_headers, Some(false));
+    }
+
+    #[test]
+    fn tolerance_attributes_are_parsed() {
+        use super::ParseErrorKind;
+        use crate::db_output::Tolerance::*;
+        let contents = "```SQL\nselect 1.0 a, 2.0 b\n```\n\
+            ```output, approx(b: 1e-6), approx-rel(1: 0.5)\n a | b\n---|---\n 1 | 2\n```";
+        let tests = super::extract_tests_from_string(contents).unwrap();
+        assert_eq!(
+            tests[0].tolerances,
+            [(1, Absolute(1e-6)), (0, Relative(0.5))]
+        );
+
+        let error = |attrs: &str| {
+            let contents = format!("```SQL\nselect 1 a\n```\n```{attrs}\n a\n---\n 1\n```");
+            match super::extract_tests_from_string(&contents)
+                .unwrap_err()
+                .kind
+            {
+                ParseErrorKind::MalformedAttribute { reason, .. } => reason,
+                kind => panic!("unexpected error {kind:?}"),
+            }
+        };
+        assert_eq!(
+            error("output, approx(a: 0.1), approx-rel(a: 0.1)"),
+            "a column can only have one tolerance"
+        );
+        assert_eq!(
+            error("output, approx(c: 0.1)"),
+            "no such column in the output"
+        );
+        assert_eq!(
+            error("output, approx(a: -1)"),
+            "tolerances must be finite and non-negative"
+        );
+        assert_eq!(
+            error("output, approx(a)"),
+            "expected `column: tolerance` pairs"
+        );
+        assert_eq!(
+            error("output, precision(a: -1)"),
+            "digits must be non-negative integers"
+        );
+    }
+
+    #[test]
+    fn precision_columns_are_named() {
+        use super::ParseErrorKind;
+        use crate::db_output::Tolerance::Precision;
+        let contents = "```SQL\nselect 'a' item, 1.234 price\n```\n\
+            ```output, precision(price: 2)\n item | price\n------|------\n a    | 1.23\n```";
+        let tests = super::extract_tests_from_string(contents).unwrap();
+        assert_eq!(tests[0].tolerances, [(1, Precision(2))]);
+
+        let contents = contents.replace("precision(price", "precision(cost");
+        let error = super::extract_tests_from_string(&contents).unwrap_err();
+        assert_eq!(
+            error.kind,
+            ParseErrorKind::MalformedAttribute {
+                attribute: "cost".to_string(),
+                reason: "no such column in the output".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn alternative_outputs_are_parsed() {
+        use super::ParseErrorKind;
+        use crate::AlternativeOutput;
+
+        let contents = [
+            "```SQL",
+            "select 1.0 x",
+            "```",
+            "```output",
+            " x",
+            "---",
+            " 1.0",
+            "```",
+            "```output, alt",
+            " x",
+            "---",
+            " 1",
+            "```",
+            "```output, alt, csv",
+            "y",
+            "1.00",
+            "```",
+        ]
+        .join("\n");
+        let tests = super::extract_tests_from_string(&contents).unwrap();
+        assert_eq!(tests.len(), 1);
+        assert_eq!(tests[0].output, [["1.0"]]);
+        assert_eq!(
+            tests[0].alternative_outputs,
+            [
+                AlternativeOutput {
+                    output_headers: vec!["x".to_string()],
+                    output: vec![vec!["1".to_string()]],
+                },
+                AlternativeOutput {
+                    output_headers: vec!["y".to_string()],
+                    output: vec![vec!["1.00".to_string()]],
+                },
+            ]
+        );
+
+        for contents in [
+            "```SQL\nselect 1\n```\n```output, alt\n a\n---\n 1\n```",
+            "```output, alt\n a\n---\n 1\n```",
+        ] {
+            let error = super::extract_tests_from_string(contents).unwrap_err();
+            assert_eq!(error.kind, ParseErrorKind::StrayAlternative);
+        }
+    }
+
+    #[test]
+    fn notices_blocks_are_parsed() {
+        use super::ParseErrorKind;
+        use crate::db_output::ExpectedNotices;
+
+        let contents = [
+            "```SQL",
+            "select f()",
+            "```",
+            "```notices, unordered",
+            "first",
+            "",
+            "  second  ",
+            "```",
+            "```output",
+            " f",
+            "---",
+            " 1",
+            "```",
+            "```SQL",
+            "select g()",
+            "```",
+            "```output, ignore-headers",
+            "",
+            "```",
+            "```notices",
+            "third",
+            "```",
+        ]
+        .join("\n");
+        let tests = super::extract_tests_from_string(&contents).unwrap();
+        assert_eq!(tests.len(), 2);
+        assert_eq!(
+            tests[0].notices,
+            Some(ExpectedNotices {
+                messages: vec!["first".to_string(), "second".to_string()],
+                unordered: true,
+                contains: false,
+            })
+        );
+        assert_eq!(tests[0].output, [["1"]]);
+        assert_eq!(
+            tests[1].notices.as_ref().unwrap().messages,
+            ["third".to_string()]
+        );
+
+        let error = super::extract_tests_from_string("```notices\nfirst\n```").unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::StrayNotices);
+    }
+
+    #[test]
+    fn tags_are_parsed() {
+        let contents = "```SQL, tags(slow, regression)\nselect 1\n```\n```SQL\nselect 2\n```";
+        let tests = super::extract_tests_from_string(contents).unwrap();
+        assert_eq!(tests[0].tags, ["slow", "regression"]);
+        assert!(tests[1].tags.is_empty());
+
+        let contents = "```SQL, tags(slow,)\nselect 1\n```";
+        assert!(super::extract_tests_from_string(contents).is_err());
+    }
+
+    #[test]
+    fn csv_output_is_parsed() {
+        use super::ParseErrorKind;
+
+        let contents = [
+            "```SQL",
+            "select 'Smith, John' name, 'said \"hi\"' quote, E'two\\nlines' text, NULL n",
+            "```",
+            "```output, csv",
+            "name,quote,text,n",
+            "\"Smith, John\",\"said \"\"hi\"\"\",\"two",
+            "lines\",",
+            "```",
+        ]
+        .join("\n");
+        let tests = super::extract_tests_from_string(&contents).unwrap();
+        assert_eq!(tests[0].output_headers, ["name", "quote", "text", "n"]);
+        assert_eq!(
+            tests[0].output,
+            [["Smith, John", "said \"hi\"", "two\nlines", ""]]
+        );
+
+        let contents = "```SQL\nselect 1 a, 2 b\n```\n```output, csv, sep(\";\")\na;b\n1;2\n```";
+        let tests = super::extract_tests_from_string(contents).unwrap();
+        assert_eq!(tests[0].output, [["1", "2"]]);
+
+        let contents = "```SQL\nselect 1 a, 2 b\n```\n```output, csv\na,b\n1,2,3\n```";
+        let error = super::extract_tests_from_string(contents).unwrap_err();
+        assert!(matches!(error.kind, ParseErrorKind::MalformedOutput { .. }));
+    }
+
+    #[test]
+    fn surrounding_blank_lines_are_not_rows() {
+        let contents =
+            "```SQL\nselect 1 a, '' b\n```\n```output\n\n   \n a | b\n---+---\n 1 |\n\n\n```";
+        let tests = super::extract_tests_from_string(contents).unwrap();
+        assert_eq!(tests[0].output_headers, ["a", "b"]);
+        assert_eq!(tests[0].output, [["1", ""]]);
+
+        let contents = "```SQL\nselect ''\n```\n```output\n ?column?\n----------\n \n```";
+        let tests = super::extract_tests_from_string(contents).unwrap();
+        assert_eq!(tests[0].output, [[""]]);
+    }
+
+    #[test]
+    fn psql_row_counts_are_not_rows() {
+        let pasted = "```SQL\nselect * from fruit\n```\n```output\n name  | count\n-------+-------\n apple |     3\n pear  |     5\n(2 rows)\n\n```";
+        let tests = super::extract_tests_from_string(pasted).unwrap();
+        assert_eq!(tests[0].output, [["apple", "3"], ["pear", "5"]]);
+
+        let one = "```SQL\nselect 1 a\n```\n```output\n a\n---\n 1\n\n(1 row)\n```";
+        let tests = super::extract_tests_from_string(one).unwrap();
+        assert_eq!(tests[0].output, [["1"]]);
+
+        let none = "```SQL\nselect 1 a where false\n```\n```output\n a\n---\n(0 rows)\n```";
+        let tests = super::extract_tests_from_string(none).unwrap();
+        assert!(tests[0].output.is_empty());
+
+        // only a count is a footer
+        let value = "```SQL\nselect '(many rows)' a\n```\n```output\n a\n---\n (many rows)\n```";
+        let tests = super::extract_tests_from_string(value).unwrap();
+        assert_eq!(tests[0].output, [["(many rows)"]]);
+        // nor are values that look like one
+        let value = "```SQL\nselect '(2 rows)' a\n```\n```output\n a\n---\n (2 rows)\n```";
+        let tests = super::extract_tests_from_string(value).unwrap();
+        assert_eq!(tests[0].output, [["(2 rows)"]]);
+        let value = "```SQL\nselect 1 a\n```\n```output\n a\n---\n(1 row)\n 1\n```";
+        let tests = super::extract_tests_from_string(value).unwrap();
+        assert_eq!(tests[0].output, [["(1 row)"], ["1"]]);
+    }
+
+    #[test]
+    fn set_attribute_is_parsed() {
+        let contents = "```SQL\nselect 1 a\n```\n```output, set\n a\n---\n 1\n 1\n```";
+        let tests = super::extract_tests_from_string(contents).unwrap();
+        assert!(tests[0].as_set);
+
+        let contents = "```SQL\nselect 1 a\n```\n```output, set, sort-by(a)\n a\n---\n 1\n```";
+        let error = super::extract_tests_from_string(contents).unwrap_err();
+        assert!(matches!(
+            error.kind,
+            super::ParseErrorKind::MalformedAttribute { .. }
+        ));
+    }
+
+    #[test]
+    fn sort_by_attribute_is_parsed() {
+        let contents =
+            "```SQL\nselect 1 a, 2 b\n```\n```output, sort-by(b, 1)\n a | b\n---|---\n 1 | 2\n```";
+        let tests = super::extract_tests_from_string(contents).unwrap();
+        assert_eq!(tests[0].sort_by, [1, 0]);
+    }
+
+    #[test]
+    fn null_as_attribute_is_parsed() {
+        let contents = "```SQL\nselect null a\n```\n```output, null-as(\"∅\")\n a\n---\n ∅\n```";
+        let tests = super::extract_tests_from_string(contents).unwrap();
+        assert_eq!(tests[0].null_as.as_deref(), Some("∅"));
+        assert_eq!(tests[0].output, [["∅"]]);
+
+        let empty = contents.replace("(\"∅\")", "(\"\")");
+        assert!(super::extract_tests_from_string(&empty).is_err());
+    }
+
+    #[test]
+    fn bool_attribute_is_parsed() {
+        let contents =
+            "```SQL\nselect true a, 1 b, false c\n```\n```output, bool(a, 3)\n a | b | c\n---|---|---\n true | 1 | false\n```";
+        let tests = super::extract_tests_from_string(contents).unwrap();
+        assert_eq!(tests[0].bool_cols, [0, 2]);
+
+        let unknown = contents.replace("bool(a, 3)", "bool(d)");
+        assert!(super::extract_tests_from_string(&unknown).is_err());
+    }
+
+    #[test]
+    fn leading_spaces_can_be_preserved() {
+        use crate::db_output::{validate_rows, TestResult};
+        use crate::Args;
+        use clap::Parser;
+
+        let contents = "```SQL\nselect '  x' a, 'y' b\n```\n```output, preserve-leading-space\n  a  | b\n-----+---\n   x | y\n```";
+        let test = super::extract_tests_from_string(contents)
+            .unwrap()
+            .remove(0);
+        assert_eq!(test.output, [["  x", "y"]]);
+
+        let args = Args::parse_from(["test", "."]);
+        let received = |value: &str| vec![vec![value.to_string(), "y".to_string()]];
+        let headers = || vec!["a".to_string(), "b".to_string()];
+        let passed = |test, value| {
+            matches!(
+                validate_rows(test, headers(), received(value), &args),
+                TestResult::Passed
+            )
+        };
+        assert!(passed(&test, "  x"));
+        assert!(!passed(&test, "x"));
+
+        // the values are trimmed without the attribute
+        let trimmed = contents.replace(", preserve-leading-space", "");
+        let test = super::extract_tests_from_string(&trimmed)
+            .unwrap()
+            .remove(0);
+        assert_eq!(test.output, [["x", "y"]]);
+        assert!(!passed(&test, "  x"));
+    }
+
+    #[test]
+    fn stateful_output_follows_non_transactional_sql() {
+        let contents = "```SQL,non-transactional\nINSERT INTO t VALUES (1) RETURNING a\n```\n\
+            ```output, stateful\n a\n---\n 1\n```";
+        let tests = super::extract_tests_from_string(contents).unwrap();
+        assert_eq!(tests.len(), 1);
+        assert!(!tests[0].transactional);
+        assert!(!tests[0].ignore_output);
+        assert_eq!(tests[0].output, [["1"]]);
+
+        let transactional = contents.replace("SQL,non-transactional", "SQL");
+        let error = super::extract_tests_from_string(&transactional).unwrap_err();
+        assert!(matches!(
+            error.kind,
+            super::ParseErrorKind::MalformedAttribute { attribute, .. } if attribute == "stateful"
+        ));
+    }
+
+    #[test]
+    fn cols_attribute_is_parsed() {
+        let contents = "```SQL\nselect 1, 2, 3\n```\n```output, cols(3)\n```";
+        let tests = super::extract_tests_from_string(contents).unwrap();
+        assert_eq!(tests[0].column_count, Some(3));
+
+        let not_a_number = contents.replace("cols(3)", "cols(a)");
+        assert!(super::extract_tests_from_string(&not_a_number).is_err());
+    }
+
+    #[test]
+    fn command_tags_blocks_are_parsed() {
+        let contents = "```SQL\ninsert into t values (1)\n```\n```tags\n INSERT 0 1\n\n```";
+        let tests = super::extract_tests_from_string(contents).unwrap();
+        assert_eq!(tests[0].command_tags, Some(vec!["INSERT 0 1".to_string()]));
+
+        let stray = "```tags\nINSERT 0 1\n```";
+        assert!(super::extract_tests_from_string(stray).is_err());
+        let psql = contents.replace("insert", "\\set x 1\ninsert");
+        assert!(super::extract_tests_from_string(&psql).is_err());
+    }
+
+    #[test]
+    fn grouped_by_attribute_is_parsed() {
+        let contents =
+            "```SQL\nselect 1 a, 2 b\n```\n```output, grouped-by(b)\n a | b\n---|---\n 1 | 2\n```";
+        let tests = super::extract_tests_from_string(contents).unwrap();
+        assert_eq!(tests[0].grouped_by, [1]);
+
+        let both = contents.replace("grouped-by(b)", "grouped-by(b), sort-by(a)");
+        assert!(super::extract_tests_from_string(&both).is_err());
+    }
+
+    #[test]
+    fn name_styles_compose_headings() {
+        use super::NameStyle::{self, *};
+
+        let contents = "```SQL\nselect 0\n```\n# A\n## B\n```SQL\nselect 1\n```\n#### D\n```SQL\nselect 2\n```\n## C\n```SQL\nselect 3\n```";
+        let names = |style: NameStyle| -> Vec<String> {
+            super::extract_tests_with_name_style(contents, style)
+                .unwrap()
+                .into_iter()
+                .map(|test| test.header)
+                .collect()
+        };
+        assert_eq!(names(Nested), ["", "`A``B`", "`A``B``D`", "`A``C`"]);
+        assert_eq!(names(Path), ["", "A > B", "A > B > D", "A > C"]);
+        assert_eq!(names(Leaf), ["", "B", "D", "C"]);
+        assert_eq!(
+            super::extract_tests_from_string(contents).unwrap()[1].header,
+            "`A``B`"
+        );
+    }
+
+    #[test]
+    fn separator_lines_are_recognized_by_shape() {
+        use super::{extract_tests_from_string, ParseErrorKind::MalformedOutput};
+
+        let output = |table: &str| {
+            let contents = format!("```SQL\nselect 1 a, 2 b\n```\n```output\n{table}\n```");
+            extract_tests_from_string(&contents).map(|mut tests| tests.remove(0).output)
+        };
+        let rows = vec![vec!["1".to_string(), "2".to_string()]];
+        for separator in [
+            "---+---",
+            "=======",
+            "===+===",
+            " ═══╪═══ ",
+            "---|---",
+            "- -",
+        ] {
+            let table = format!(" a | b\n{separator}\n 1 | 2");
+            assert_eq!(output(&table), Ok(rows.clone()), "{separator}");
+        }
+
+        let error = output(" a | b\n 1 | 2\n 3 | 4").unwrap_err();
+        assert_eq!(
+            error.kind,
+            MalformedOutput {
+                reason: "expected a separator line such as `---+---` after the column names, \
+                    found `1 | 2`"
+                    .to_string()
+            }
+        );
+        assert!(output(" a | b\n -1 | 2").is_err());
+        assert!(output(" a\n   \n").is_err());
+    }
+
+    #[test]
+    fn ragged_tables_are_rejected() {
+        use super::{extract_tests_from_string, ParseErrorKind::MalformedOutput};
+
+        let contents =
+            "```SQL\nselect 1 a, 2 b\n```\n```output\n a | b\n---+---\n 1 | 2\n 3 | 4 |\n```";
+        let error = extract_tests_from_string(contents).unwrap_err();
+        assert_eq!(error.line, 4);
+        assert_eq!(
+            error.kind,
+            MalformedOutput {
+                reason: "the row `3 | 4 |` has 3 columns but the rows before it have 2".to_string()
+            }
+        );
+
+        // a blank line is a row of a single empty value, not a ragged row
+        let contents =
+            "```SQL\nselect 1 a, 2 b\n```\n```output\n a | b\n---+---\n 1 | 2\n  \n 3 | 4\n```";
+        assert!(extract_tests_from_string(contents).is_ok());
+    }
+
+    #[test]
+    fn error_output_is_parsed() {
+        use super::{ParseError, ParseErrorKind::MalformedAttribute};
+
+        let contents = "```SQL\nselect 1/0\n```\n```output, error\nERROR:  division by zero\n\n```\n```SQL\nselect 1\n```";
+        let tests = super::extract_tests_from_string(contents).unwrap();
+        assert_eq!(
+            tests[0].expected_error.as_deref(),
+            Some("ERROR:  division by zero")
+        );
+        assert!(tests[0].output.is_empty());
+        assert_eq!(tests[1].expected_error, None);
+
+        let contents = "```SQL\nselect 1/0\n```\n```output\n```\n```output, alt, error\nERROR:  division by zero\n```";
+        assert_eq!(
+            super::extract_tests_from_string(contents),
+            Err(ParseError::new(
+                6,
+                1,
+                MalformedAttribute {
+                    attribute: "error".to_string(),
+                    reason: "an alternative output can't be an error".to_string(),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn wildcards_attribute_is_parsed() {
+        let sql = "```SQL\nselect now(), 1\n```\n";
+        let tests = super::extract_tests_from_string(&format!(
+            "{sql}```output, wildcards\n now | one\n---\n ... | 1\n```"
+        ))
+        .unwrap();
+        assert_eq!(tests[0].wildcard.as_deref(), Some("..."));
+        assert_eq!(tests[0].output, [["...", "1"]]);
+
+        let tests = super::extract_tests_from_string(&format!(
+            "{sql}```output, wildcards(\"*\")\n now | one\n---\n * | 1\n```"
+        ))
+        .unwrap();
+        assert_eq!(tests[0].wildcard.as_deref(), Some("*"));
+
+        let tests = super::extract_tests_from_string(&format!("{sql}```output\n```")).unwrap();
+        assert_eq!(tests[0].wildcard, None);
+    }
+
+    #[test]
+    fn first_h1_is_found() {
+        let contents = "## Not this\n```SQL\n# nor this\n```\n#  Orders \n# Later\n";
+        assert_eq!(super::first_h1(contents).as_deref(), Some("Orders"));
+        assert_eq!(super::first_h1("## Only\n### Lower"), None);
+    }
+
+    #[test]
+    fn file_settings_are_parsed() {
+        let contents = "<!-- set search_path = app, public -->\n<!-- serial -->\n\
+            ```SQL\nselect 1\n```\n  <!--set statement_timeout=0-->";
+        assert_eq!(
+            super::file_settings(contents),
+            ["SET search_path = app, public", "SET statement_timeout=0"]
+        );
+        assert!(super::is_serial(contents));
+    }
+
+    #[test]
+    fn nonstandard_attribute_casing_is_found() {
+        let contents = "```SQL,stateful\nselect 1\n```\n\
+            ```Output, Sort-By(a)\n a\n---\n 1\n```\n\
+            ```sql, Ignore-Output\nselect 2\n```\n\
+            ```Sql\nselect 3\n```\n\
+            ```Rust\nfn main() {}\n```";
+        assert_eq!(
+            super::nonstandard_casing(contents),
+            [
+                (4, "Output", "output"),
+                (4, "Sort-By", "sort-by"),
+                (9, "Ignore-Output", "ignore-output"),
+                (12, "Sql", "SQL"),
+            ]
+        );
+        // however they're written, they're still understood
+        let tests = super::extract_tests_from_string(contents).unwrap();
+        assert_eq!(tests.len(), 3);
+        assert!(tests[1].ignore_output);
+    }
+
+    #[test]
+    fn file_preludes_are_parsed() {
+        let contents = "<!-- prelude SET LOCAL timezone = 'UTC' -->\n\
+            # Title\n\
+            <!-- prelude  SET LOCAL search_path = a  -->\n\
+            ```SQL\nshow timezone\n```";
+        assert_eq!(
+            super::file_prelude(contents),
+            ["SET LOCAL timezone = 'UTC'", "SET LOCAL search_path = a"]
+        );
+        assert!(super::file_prelude("# Title").is_empty());
+    }
+
+    #[test]
+    fn file_hooks_are_parsed() {
+        let contents = "```SQL,before-all\ncreate table fixture(a int);\n```\n\
+            ```SQL\nselect * from fixture\n```\n\
+            ```SQL,after-all\ndrop table fixture;\n```\n\
+            ```SQL,before-all\ninsert into fixture values (1);\n```";
+        let (before_all, after_all) = super::file_hooks(contents);
+        assert_eq!(
+            before_all,
+            [
+                "create table fixture(a int);",
+                "insert into fixture values (1);"
+            ]
+        );
+        assert_eq!(after_all, ["drop table fixture;"]);
+
+        let tests = super::extract_tests_from_string(contents).unwrap();
+        assert_eq!(tests.len(), 1);
+        assert_eq!(tests[0].text, "select * from fixture");
+    }
+
+    #[test]
+    fn assert_true_attribute_is_parsed() {
+        use super::ParseErrorKind;
+        let sql = "```SQL, assert-true(ok)\nselect 1 = 1 AS ok\n```\n";
+        let tests = super::extract_tests_from_string(sql).unwrap();
+        assert_eq!(tests[0].assert_true.as_deref(), Some("ok"));
+        assert!(!tests[0].ignore_output);
+
+        let error = super::extract_tests_from_string(&format!("{sql}```output\n ok\n---\n t\n```"))
+            .unwrap_err();
+        assert!(matches!(
+            error.kind,
+            ParseErrorKind::MalformedAttribute { attribute, .. } if attribute == "assert-true"
+        ));
+    }
+
+    #[test]
+    fn compare_with_blocks_are_parsed() {
+        use super::ParseErrorKind;
+        let sql = "```SQL\nselect a from t where a < 5\n```\n\
+            ```SQL, compare-with\nselect a from t where a + 0 < 5\n```\n";
+        let tests = super::extract_tests_from_string(sql).unwrap();
+        assert_eq!(tests.len(), 1);
+        assert_eq!(
+            tests[0].compare_with.as_deref(),
+            Some("select a from t where a + 0 < 5")
+        );
+        assert!(!tests[0].ignore_output);
+
+        let error = super::extract_tests_from_string(&format!("{sql}```output\n a\n---\n 1\n```"))
+            .unwrap_err();
+        assert!(matches!(
+            error.kind,
+            ParseErrorKind::MalformedAttribute { attribute, .. } if attribute == "compare-with"
+        ));
+
+        let stray = "```SQL, compare-with\nselect 1\n```\n";
+        let error = super::extract_tests_from_string(stray).unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::StrayReference);
+    }
+
+    #[test]
+    fn ignored_columns_are_parsed() {
+        use super::ParseErrorKind;
+        let sql = "```SQL\nselect 1 a, now(), 2 b, random()\n```\n";
+        let tests = super::extract_tests_from_string(&format!(
+            "{sql}```output, ignore-cols(2, 4)\n a | b\n---\n 1 | 2\n```"
+        ))
+        .unwrap();
+        assert_eq!(tests[0].ignore_cols, [1, 3]);
+
+        let error = super::extract_tests_from_string(&format!(
+            "{sql}```output, ignore-cols(now)\n a | b\n---\n 1 | 2\n```"
+        ))
+        .unwrap_err();
+        assert!(matches!(
+            error.kind,
+            ParseErrorKind::MalformedAttribute { reason, .. }
+                if reason == "expected a list of column numbers"
+        ));
+    }
+
+    #[test]
+    fn separator_attribute_is_parsed() {
+        let contents =
+            "```SQL\nselect 'a|b', 1\n```\n```output, sep(\"\\t\")\n ?column?\t?column?\n---\n a|b\t 1\n```";
+        let tests = super::extract_tests_from_string(contents).unwrap();
+        assert_eq!(tests[0].output_headers, ["?column?", "?column?"]);
+        assert_eq!(tests[0].output, [["a|b", "1"]]);
+        assert_eq!(tests[0].separator.as_deref(), Some("\t"));
+
+        // quoted separators can be the characters attributes are split on
+        for sep in [",", ")", "("] {
+            let contents = format!(
+                "```SQL\nselect 1, 2\n```\n```output, sep(\"{sep}\"), sort-by(1)\n \
+                a {sep} b\n---\n 1 {sep} 2\n```"
+            );
+            let tests = super::extract_tests_from_string(&contents).unwrap();
+            assert_eq!(tests[0].output, [["1", "2"]]);
+            assert_eq!(tests[0].sort_by, [0]);
+            assert_eq!(tests[0].separator.as_deref(), Some(sep));
+        }
+        let attrs: Vec<_> = super::split_attrs(r#"output, sep("\",("), set"#).collect();
+        assert_eq!(attrs, ["output", r#"sep("\",(")"#, "set"]);
+
+        assert_eq!(super::parse_separator(";").as_deref(), Some(";"));
+        assert_eq!(super::parse_separator(r#""\"""#).as_deref(), Some("\""));
+        assert_eq!(super::parse_separator(r#""""#), None);
+        assert_eq!(super::parse_separator(r#""\n""#), None);
+    }
+
+    #[test]
+    fn expect_empty_attribute_is_parsed() {
+        use super::{ParseError, ParseErrorKind::MalformedAttribute};
+
+        let tests = super::extract_tests_from_string("```SQL,expect-empty\nselect 1\n```").unwrap();
+        assert!(tests[0].expect_empty);
+        assert!(!tests[0].ignore_output);
+        assert!(tests[0].output.is_empty());
+
+        let contents = "```SQL,expect-empty\nselect 1\n```\n```output\n a\n---\n```";
+        assert_eq!(
+            super::extract_tests_from_string(contents),
+            Err(ParseError::new(
+                4,
+                1,
+                MalformedAttribute {
+                    attribute: "expect-empty".to_string(),
+                    reason: "a test expecting an empty result can't have an output block"
+                        .to_string(),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn serial_marker_is_found() {
+        assert!(super::is_serial(
+            "# Locks\n  <!-- serial -->\n```SQL\nselect 1\n```"
+        ));
+        assert!(!super::is_serial("# Locks\n<!-- serial --> not alone\n"));
+        // nor is one that's part of a test
+        let in_block = "```SQL\nselect '\n<!-- serial -->\n'\n```\n```output\n<!-- set a = 1 -->\n```\n";
+        assert!(!super::is_serial(in_block));
+        assert!(super::file_settings(in_block).is_empty());
+        assert!(super::is_serial(&format!("{in_block}<!-- serial -->\n")));
+        assert!(!super::is_serial("<!-- commit-transactional -->"));
+        assert!(super::commits_transactional(
+            "# Migrations\n<!-- commit-transactional -->\n"
+        ));
     }
 }
@@ -0,0 +1,71 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+/// Where `--progress-json` events are written, one JSON object per line, as
+/// the run goes on.
+pub(crate) struct Progress {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+/// Something that happened during a run, serialized with its name in the
+/// `event` field.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum Event<'a> {
+    FileStarted {
+        file: &'a str,
+    },
+    TestStarted {
+        file: &'a str,
+        line: usize,
+        header: &'a str,
+    },
+    TestFinished {
+        file: &'a str,
+        line: usize,
+        header: &'a str,
+        passed: bool,
+        duration_ms: f64,
+    },
+    RunFinished {
+        passed: usize,
+        failed: usize,
+    },
+}
+
+impl Progress {
+    /// Writes the events to the file at `path`, or stderr for `-`, keeping
+    /// them apart from the results printed to stdout.
+    pub(crate) fn open(path: &Path) -> Result<Self> {
+        let out: Box<dyn Write + Send> = match path.as_os_str() == "-" {
+            true => Box::new(std::io::stderr()),
+            false => Box::new(File::create(path).with_context(|| {
+                format!("could not create the progress file `{}`", path.display())
+            })?),
+        };
+        Ok(Self {
+            out: Mutex::new(out),
+        })
+    }
+
+    /// Writes `event` straight away. Failing to write it doesn't fail the
+    /// run, since the events are only there to follow along with it.
+    pub(crate) fn emit(&self, event: Event) {
+        let Ok(line) = serde_json::to_string(&event) else {
+            return;
+        };
+        let mut out = self.out.lock().unwrap_or_else(PoisonError::into_inner);
+        let _ = writeln!(out, "{line}").and_then(|()| out.flush());
+    }
+}
+
+/// A duration in milliseconds, as the events give them.
+pub(crate) fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
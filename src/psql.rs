@@ -0,0 +1,178 @@
+//! Running the tests that use psql's meta-commands, such as `\d`, through
+//! `psql` itself, since the server doesn't understand them.
+
+use std::path::Path;
+use std::process::Stdio;
+
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// Whether any line of `sql` is a meta-command.
+pub(crate) fn has_meta_commands(sql: &str) -> bool {
+    sql.lines().any(|line| line.trim_start().starts_with('\\'))
+}
+
+/// The script a test is run with: the file's settings followed by the test,
+/// in a transaction ended with `end_transaction` if it's given. NULLs are
+/// printed as `null`.
+pub(crate) fn script(
+    settings: &[String],
+    sql: &str,
+    end_transaction: Option<&str>,
+    null: &str,
+) -> String {
+    let mut script = String::new();
+    if !null.is_empty() {
+        let null = null.replace('\\', "\\\\").replace('\'', "''");
+        script += &format!("\\pset null '{null}'\n");
+    }
+    for setting in settings {
+        script += &format!("{setting};\n");
+    }
+    if end_transaction.is_some() {
+        script += "BEGIN;\n";
+    }
+    // ends the test's last statement if it's missing a `;`
+    script += &format!("{sql}\n;\n");
+    if let Some(end) = end_transaction {
+        script += &format!("{end};\n");
+    }
+    script
+}
+
+/// Runs `script` with `psql`, connected with `conninfo`, returning the column
+/// names and rows it printed, or the error it stopped at. The results of
+/// every statement are read as a single table, so tests run this way should
+/// only have one.
+pub(crate) async fn run(
+    psql: &Path,
+    conninfo: &str,
+    script: &str,
+) -> Result<(Vec<String>, Vec<Vec<String>>), String> {
+    let mut child = Command::new(psql)
+        .args([
+            "-X",
+            "-q",
+            "--csv",
+            "-v",
+            "ON_ERROR_STOP=1",
+            "-f",
+            "-",
+            "-d",
+        ])
+        .arg(conninfo)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("could not run `{}`: {e}", psql.display()))?;
+    let mut stdin = child.stdin.take().expect("psql's stdin is piped");
+    let written = stdin.write_all(script.as_bytes()).await;
+    drop(stdin);
+    let output = child
+        .wait_with_output()
+        .await
+        .map_err(|e| format!("psql failed: {e}"))?;
+    if !output.status.success() {
+        return Err(error_message(&String::from_utf8_lossy(&output.stderr)));
+    }
+    written.map_err(|e| format!("could not write the test to psql: {e}"))?;
+    parse_csv(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// The errors psql printed, without the location in the script it adds.
+fn error_message(stderr: &str) -> String {
+    stderr
+        .lines()
+        .map(|line| {
+            line.strip_prefix("psql:<stdin>:")
+                .and_then(|rest| rest.split_once(": "))
+                .map_or(line, |(_, message)| message)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn parse_csv(output: &str) -> Result<(Vec<String>, Vec<Vec<String>>), String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(output.as_bytes());
+    let mut records = reader.records().map(|record| {
+        record
+            .map(|record| record.iter().map(str::to_string).collect())
+            .map_err(|e| format!("could not read psql's output: {e}"))
+    });
+    let headers = records.next().transpose()?.unwrap_or_default();
+    let rows = records.collect::<Result<_, _>>()?;
+    Ok((headers, rows))
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn meta_commands_are_detected() {
+        use super::has_meta_commands;
+
+        assert!(has_meta_commands("\\dt"));
+        assert!(has_meta_commands("CREATE TABLE t();\n  \\d t"));
+        assert!(!has_meta_commands("SELECT E'\\\\d'"));
+    }
+
+    #[test]
+    fn scripts_wrap_tests() {
+        use super::script;
+
+        let settings = ["SET search_path = app".to_string()];
+        assert_eq!(
+            script(&settings, "CREATE TABLE t();\n\\dt", Some("ROLLBACK"), ""),
+            "SET search_path = app;\nBEGIN;\nCREATE TABLE t();\n\\dt\n;\nROLLBACK;\n"
+        );
+        assert_eq!(script(&[], "\\dt", None, ""), "\\dt\n;\n");
+        assert_eq!(
+            script(&[], "\\dt", None, "it's"),
+            "\\pset null 'it''s'\n\\dt\n;\n"
+        );
+    }
+
+    #[test]
+    fn errors_and_tables_are_read() {
+        let stderr = "psql:<stdin>:3: ERROR:  relation \"t\" does not exist\n";
+        assert_eq!(
+            super::error_message(stderr),
+            "ERROR:  relation \"t\" does not exist"
+        );
+
+        let stdout = "Schema,Name,Type,Owner\npublic,t,table,postgres\n";
+        let (headers, rows) = super::parse_csv(stdout).unwrap();
+        assert_eq!(headers, ["Schema", "Name", "Type", "Owner"]);
+        assert_eq!(rows, [["public", "t", "table", "postgres"]]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn dt_output_is_captured() {
+        use std::path::PathBuf;
+
+        let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL is not set");
+        let bindir = std::process::Command::new("pg_config")
+            .arg("--bindir")
+            .output()
+            .unwrap();
+        let psql = PathBuf::from(String::from_utf8(bindir.stdout).unwrap().trim()).join("psql");
+
+        let sql = "CREATE TABLE meta_command_test(a int);\n\\dt meta_command_test";
+        let script = super::script(&[], sql, Some("ROLLBACK"), "");
+        let (headers, rows) = super::run(&psql, &url, &script).await.unwrap();
+        assert_eq!(headers, ["Schema", "Name", "Type", "Owner"]);
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0][..3], ["public", "meta_command_test", "table"]);
+
+        let error = super::run(&psql, &url, "SELECT * FROM meta_command_test")
+            .await
+            .unwrap_err();
+        assert!(error.contains("does not exist"), "{error}");
+    }
+}
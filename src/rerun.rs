@@ -0,0 +1,275 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::{Test, TestFile};
+
+/// Identifies a test from run to run, as `file:line:header`.
+pub(crate) fn test_id(file: &str, test: &Test) -> String {
+    format!("{file}:{}:{}", test.line, test.header)
+}
+
+/// Records which of the tests that `ran` failed, one `failed <id>` or
+/// `passed <id>` per line, replacing the last run's record.
+pub(crate) fn write_results(path: &Path, ran: &[String], failed: &[String]) -> Result<()> {
+    let mut contents: String = failed.iter().map(|id| format!("failed {id}\n")).collect();
+    for id in ran.iter().filter(|id| !failed.contains(id)) {
+        contents += &format!("passed {id}\n");
+    }
+    fs::write(path, contents)
+        .with_context(|| format!("could not write the failed tests to `{}`", path.display()))
+}
+
+/// Whether each test the last run recorded passed. Lines that are only an
+/// identifier are failures, as they were recorded before passes were.
+pub(crate) fn read_results(path: &Path) -> Result<HashMap<String, bool>> {
+    let contents = fs::read_to_string(path).with_context(|| {
+        format!(
+            "could not read the failed tests from `{}`, has a run recorded them?",
+            path.display()
+        )
+    })?;
+    let results = contents.lines().map(|line| match line.split_once(' ') {
+        Some(("passed", id)) => (id.to_string(), true),
+        Some(("failed", id)) => (id.to_string(), false),
+        _ => (line.to_string(), false),
+    });
+    Ok(results.collect())
+}
+
+/// The tests the last run recorded as failed.
+pub(crate) fn read_failed(path: &Path) -> Result<HashSet<String>> {
+    let results = read_results(path)?;
+    Ok(results
+        .into_iter()
+        .filter(|(_, passed)| !passed)
+        .map(|(id, _)| id)
+        .collect())
+}
+
+/// The identifiers of `files`' tests, one per line, followed by whether they
+/// `passed` or `failed` in the last run if there are `results`, or `unknown`
+/// if it didn't run them.
+pub(crate) fn listing(files: &[TestFile], results: Option<&HashMap<String, bool>>) -> String {
+    let mut listing = String::new();
+    for file in files {
+        for test in &file.tests {
+            let id = test_id(&file.name, test);
+            let status = match results.map(|results| results.get(&id)) {
+                None => None,
+                Some(Some(true)) => Some("passed"),
+                Some(Some(false)) => Some("failed"),
+                Some(None) => Some("unknown"),
+            };
+            match status {
+                Some(status) => listing += &format!("{id}: {status}\n"),
+                None => listing += &format!("{id}\n"),
+            }
+        }
+    }
+    listing
+}
+
+/// Keeps the tests that are in `failed`. Stateful files are kept as a whole
+/// if any of their tests failed, since their tests depend on each other.
+pub(crate) fn select_failed(files: Vec<TestFile>, failed: &HashSet<String>) -> Vec<TestFile> {
+    files
+        .into_iter()
+        .filter_map(|mut file| {
+            let name = file.name.clone();
+            let has_failed = |test: &Test| failed.contains(&test_id(&name, test));
+            if file.stateless {
+                file.tests.retain(has_failed);
+                (!file.tests.is_empty()).then_some(file)
+            } else {
+                file.tests.iter().any(has_failed).then_some(file)
+            }
+        })
+        .collect()
+}
+
+/// A test picked out by `--only`, as the `file:line` failures report it at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    file: PathBuf,
+    line: usize,
+}
+
+impl FromStr for Location {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let expected = || anyhow!("expected a test as `file:line`, found `{s}`");
+        let (file, line) = s.rsplit_once(':').ok_or_else(expected)?;
+        let line = line.trim().parse().map_err(|_| expected())?;
+        if file.is_empty() {
+            return Err(expected());
+        }
+        Ok(Self {
+            file: file.into(),
+            line,
+        })
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file.display(), self.line)
+    }
+}
+
+/// Keeps only the test at `location`, along with the tests before it if its
+/// file is stateful, since they set up its state. It's an error for no test,
+/// or more than one, to be there.
+pub(crate) fn select_only(files: Vec<TestFile>, location: &Location) -> Result<Vec<TestFile>> {
+    // `./a.md` and `a.md` are the same file
+    let components = |path: &Path| -> Vec<PathBuf> {
+        path.components()
+            .filter(|component| *component != Component::CurDir)
+            .map(|component| PathBuf::from(component.as_os_str()))
+            .collect()
+    };
+    let file = components(&location.file);
+    let at_location = |test: &Test| test.line == location.line;
+    let selected: Vec<TestFile> = files
+        .into_iter()
+        .filter(|test_file| components(Path::new(&test_file.name)) == file)
+        .filter_map(|mut test_file| {
+            match test_file.stateless {
+                true => test_file.tests.retain(at_location),
+                false => {
+                    let last = test_file.tests.iter().rposition(at_location)?;
+                    test_file.tests.truncate(last + 1);
+                }
+            }
+            test_file.tests.iter().any(at_location).then_some(test_file)
+        })
+        .collect();
+    let found = selected.iter().flat_map(|file| &file.tests);
+    match found.filter(|test| at_location(test)).count() {
+        0 => bail!("no test found at `{location}`"),
+        1 => Ok(selected),
+        n => bail!("{n} tests found at `{location}`, expected one"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use crate::test::test_file;
+
+    #[test]
+    fn only_failed_tests_are_rerun() {
+        use super::{read_failed, select_failed, test_id, write_results};
+
+        let stateless = test_file("a.md", true, &[3, 9]);
+        let stateful = test_file("b.md", false, &[4, 8]);
+        let passing = test_file("c.md", true, &[5]);
+        let failed = [
+            test_id("a.md", &stateless.tests[1]),
+            test_id("b.md", &stateful.tests[1]),
+        ];
+        assert_eq!(failed, ["a.md:9:`a.md`", "b.md:8:`b.md`"]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("failed-tests.txt");
+        write_results(&path, &[], &failed).unwrap();
+        let failed = read_failed(&path).unwrap();
+
+        let rerun = select_failed(vec![stateless, stateful, passing], &failed);
+        let rerun: Vec<_> = rerun
+            .iter()
+            .map(|file| {
+                let lines: Vec<_> = file.tests.iter().map(|test| test.line).collect();
+                (&*file.name, lines)
+            })
+            .collect();
+        assert_eq!(rerun, [("a.md", vec![9]), ("b.md", vec![4, 8])]);
+
+        write_results(&path, &["c.md:5:`c.md`".to_string()], &[]).unwrap();
+        assert!(read_failed(&path).unwrap().is_empty());
+        assert!(read_failed(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn listing_shows_the_last_results() {
+        use super::{listing, read_results, test_id, write_results};
+
+        let files = [
+            test_file("a.md", true, &[3, 9]),
+            test_file("b.md", false, &[4]),
+        ];
+        let ran = [
+            test_id("a.md", &files[0].tests[0]),
+            test_id("a.md", &files[0].tests[1]),
+        ];
+        let failed = [ran[1].clone()];
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("failed-tests.txt");
+        write_results(&path, &ran, &failed).unwrap();
+        let results = read_results(&path).unwrap();
+
+        assert_eq!(
+            listing(&files, Some(&results)),
+            "a.md:3:`a.md`: passed\na.md:9:`a.md`: failed\nb.md:4:`b.md`: unknown\n"
+        );
+        assert_eq!(
+            listing(&files, None),
+            "a.md:3:`a.md`\na.md:9:`a.md`\nb.md:4:`b.md`\n"
+        );
+
+        // records from before passes were kept only have the failures
+        std::fs::write(&path, "a.md:9:`a.md`\n").unwrap();
+        assert_eq!(read_results(&path).unwrap()["a.md:9:`a.md`"], false);
+    }
+
+    #[test]
+    fn only_the_test_at_a_location_is_run() {
+        use super::{select_only, Location};
+
+        let files = || {
+            vec![
+                test_file("fixture.md", true, &[3, 12, 20]),
+                test_file("other.md", false, &[12]),
+            ]
+        };
+        let location: Location = "fixture.md:12".parse().unwrap();
+        let selected = select_only(files(), &location).unwrap();
+        let selected: Vec<_> = selected
+            .iter()
+            .map(|file| {
+                let lines: Vec<_> = file.tests.iter().map(|test| test.line).collect();
+                (&*file.name, lines)
+            })
+            .collect();
+        assert_eq!(selected, [("fixture.md", vec![12])]);
+
+        let stateful = select_only(files(), &"./other.md:12".parse().unwrap()).unwrap();
+        assert_eq!(stateful[0].name, "other.md");
+        // a stateful file's earlier tests set up the state the test runs in
+        let stateful = vec![test_file("other.md", false, &[3, 7, 12, 20])];
+        let stateful = select_only(stateful, &"other.md:12".parse().unwrap()).unwrap();
+        let lines: Vec<_> = stateful[0].tests.iter().map(|test| test.line).collect();
+        assert_eq!(lines, [3, 7, 12]);
+
+        let missing = select_only(files(), &"fixture.md:13".parse().unwrap()).unwrap_err();
+        assert_eq!(missing.to_string(), "no test found at `fixture.md:13`");
+        let twice = [files(), files()].concat();
+        assert!(select_only(twice, &location).is_err());
+        let same_line = vec![test_file("fixture.md", true, &[12, 12])];
+        let same_line = select_only(same_line, &location).unwrap_err();
+        assert_eq!(
+            same_line.to_string(),
+            "2 tests found at `fixture.md:12`, expected one"
+        );
+
+        assert!("fixture.md".parse::<Location>().is_err());
+        assert!(":12".parse::<Location>().is_err());
+    }
+}
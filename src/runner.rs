@@ -1,19 +1,43 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
 use std::io::Write;
 use std::mem::ManuallyDrop;
 use std::path::{Path, PathBuf};
-use std::process::Child;
+use std::process::{Child, ExitStatus};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
 use std::thread;
+use std::time::Duration;
 use std::{fs::OpenOptions, time::Instant};
 
+use crate::db_output::validate_output;
 use crate::db_output::FailureInfo;
-use crate::db_output::{validate_output, FailureInfo::QueryError};
-use crate::{cprintln, db_output, ecprint, ecprintln, Args, Test, TestFile};
+use crate::explain;
+use crate::progress::{self, Event, Progress};
+use crate::psql;
+use crate::rerun;
+use crate::snapshot::{self, Outcome};
+use crate::statements::{self, split_statements};
+use crate::timing;
+use crate::watch::{changed_source_files, FileWatcher};
+use crate::xfail::ExpectedFailures;
+use crate::{cprint, cprintln, db_output, ecprint, ecprintln, warning, Args, Test, TestFile};
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use futures::stream::FuturesOrdered;
 use futures::StreamExt;
+use once_cell::sync::OnceCell;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
 use tempfile::{tempdir, TempDir};
-use xshell::{cmd, Shell};
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio_postgres::error::SqlState;
+use tokio_postgres::tls::NoTlsStream;
+use tokio_postgres::{AsyncMessage, Client, SimpleQueryMessage, Socket, Transaction};
+use xshell::{cmd, Cmd, Shell};
 
 macro_rules! path {
     ($start:ident $(/ $segment: literal)*) => {
@@ -36,518 +60,4680 @@ struct TestsEnv<'a> {
     sh: &'a Shell,
     temp_dir: ManuallyDrop<TempDir>,
     bindir: &'a str,
-    data_dir: &'a Path,
-    postmaster: Child,
-    port: &'a str,
+    data_dir: PathBuf,
+    postmaster: Mutex<Child>,
+    port: String,
     out_path: PathBuf,
     err_path: PathBuf,
+    /// whether the last run of the suite passed, with `--quiet-server` the
+    /// postmaster's logs are only kept if it didn't
+    passed: AtomicBool,
+    /// the databases kept by the last run until the tests are next run, such
+    /// as the stateless database after failing tests committed their state
+    /// with `--no-rollback-on-failure`
+    kept_dbs: Mutex<Vec<DbDropper>>,
+    /// the databases the tests are done with, dropped or kept by `--teardown`
+    /// once it's known whether the run passed
+    finished_dbs: Mutex<Vec<DbDropper>>,
+    /// the names reports use for files instead of their paths, by path
+    suite_names: Mutex<HashMap<String, String>>,
+    /// set once the role the tests connect as exists in the cluster
+    role_created: OnceCell<()>,
+    /// a permit for each of the `--max-connections`, held by every connection
+    /// and command connecting to the server while it's open
+    connections: Arc<Semaphore>,
+    run: RunContext<'a>,
 }
 
-pub(crate) async fn run(args: &Args, tests: impl Iterator<Item = TestFile>) -> Result<()> {
-    let sh = Shell::new()?;
-    // TODO allow configurable pg_config
-    let pg_config = "pg_config";
-    let bindir = cmd!(sh, "{pg_config} --bindir").read()?;
+/// What every cluster started by a run shares.
+#[derive(Clone)]
+struct RunContext<'a> {
+    /// where the `--progress-json` events are written
+    progress: Option<Arc<Progress>>,
+    /// the tests `--expected-failures` lists
+    expected_failures: &'a ExpectedFailures,
+    /// called with each test's result as soon as it finishes
+    on_result: &'a OnResult<'a>,
+}
 
-    // TODO allow existing DB
-    let temp_dir = tempdir()?;
-    let data_dir = path!(temp_dir / "data");
-    let db_init_location = data_dir.to_string_lossy();
+/// Given each test's result as soon as the test finishes.
+type OnResult<'f> = dyn Fn(&TestResultRecord) + 'f;
 
-    ecprintln!("Initializing DB" bold blue, " at {db_init_location}");
+/// The result of a test, as given to the callback passed to [`run`].
+pub struct TestResultRecord<'r> {
+    pub file: &'r str,
+    pub header: &'r str,
+    pub line: usize,
+    pub status: TestStatus,
+    pub duration: Duration,
+    /// why the test failed, `None` if it passed
+    pub failure: Option<&'r FailureInfo>,
+}
 
-    let initdb = path!(bindir / "initdb");
-    let init_output = cmd!(sh, "{initdb} -D {data_dir} --no-clean --no-sync")
-        .quiet()
-        .ignore_status()
-        .output();
-    match init_output {
-        Ok(output) if output.status.success() => {}
-        Ok(output) => {
-            let out = String::from_utf8(output.stdout)?;
-            let err = String::from_utf8(output.stderr)?;
-            bail!("initdb failed with\nout:\n{out}\nerr:\n{err}")
+/// How a test finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestStatus {
+    Passed,
+    Failed,
+    /// failed as `--expected-failures` said it would
+    XFailed,
+    /// passed though `--expected-failures` said it would fail
+    XPassed,
+}
+
+impl TestStatus {
+    /// Whether the test leaves the run passing.
+    pub fn passed(self) -> bool {
+        self != TestStatus::Failed
+    }
+}
+
+/// When the scratch databases and the cluster's directory are removed.
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Teardown {
+    Always,
+    /// Keep everything if any test failed.
+    OnSuccess,
+    Never,
+}
+
+impl Teardown {
+    /// Whether what the tests used is kept after a run that `passed`.
+    fn keeps(self, passed: bool) -> bool {
+        match self {
+            Teardown::Always => false,
+            Teardown::OnSuccess => !passed,
+            Teardown::Never => true,
         }
-        Err(e) => return Err(e)?,
     }
+}
 
-    let conf_path = path!(data_dir / "postgresql.conf");
-    let mut db_conf = OpenOptions::new().append(true).open(&conf_path)?;
-    writeln!(
-        &mut db_conf,
-        "\n# Configuration added by test runner\n\
-        log_autovacuum_min_duration = 0\n\
-        log_checkpoints = on\n\
-        log_line_prefix = '%m %b[%p] %q%a '\n\
-        log_lock_waits = on\n\
-        log_temp_files = 128kB\n\
-        max_prepared_transactions = 2"
-    )
-    .map_err(|e| {
-        anyhow!(
-            "failed to write to db_conf at `{}` due to {e}",
-            conf_path.display()
-        )
-    })?;
+// TODO better port picking
+/// The port the postmaster listens on.
+pub(crate) const PGPORT: &str = "1763";
+
+/// Runs `tests` as `args` say, calling `on_result`, if given, with the result
+/// of each test as soon as it finishes.
+pub async fn run(
+    args: &Args,
+    tests: impl Iterator<Item = TestFile>,
+    on_result: Option<&mut dyn FnMut(&TestResultRecord)>,
+) -> Result<()> {
+    if args.max_connections.get() < 2 {
+        bail!("`--max-connections` needs to be at least 2, one is kept for the `psql`, `createdb` and `dropdb` commands")
+    }
+    let tests = expand_defines(&args.defines, select_tests(args, tests.collect()))?;
+    let ran: Vec<String> = tests
+        .iter()
+        .flat_map(|file| {
+            file.tests
+                .iter()
+                .map(|test| rerun::test_id(&file.name, test))
+        })
+        .collect();
+    let progress = match &args.progress_json {
+        Some(path) => Some(Arc::new(Progress::open(path)?)),
+        None => None,
+    };
+    let expected_failures = ExpectedFailures::read(args.expected_failures.as_deref())?;
+    let on_result = on_result.map(Mutex::new);
+    let on_result = |record: &TestResultRecord| {
+        if let Some(on_result) = &on_result {
+            (on_result.lock().unwrap_or_else(PoisonError::into_inner))(record)
+        }
+    };
+    let run = RunContext {
+        progress,
+        expected_failures: &expected_failures,
+        on_result: &on_result,
+    };
 
-    // TODO allow user configs
+    let pg_configs = match &*args.pg_configs {
+        [] => vec!["pg_config".to_string()],
+        pg_configs => pg_configs.to_vec(),
+    };
+    if let [pg_config] = &*pg_configs {
+        let failed = run_against(args, pg_config, tests, run).await?;
+        if !args.serve_only {
+            rerun::write_results(&args.failures_file, &ran, &failed)?;
+        }
+        return Ok(());
+    }
+    if args.serve_only || args.watch {
+        bail!("`--serve-only` and `--watch` need a single `--pg-config`")
+    }
 
-    // TODO better port picking
-    let pgport = "1763";
-    sh.set_var("PGPORT", pgport);
+    let sh = Shell::new()?;
+    let mut outcomes = Vec::with_capacity(pg_configs.len());
+    let mut all_failed = vec![];
+    for pg_config in &pg_configs {
+        let version = cmd!(sh, "{pg_config} --version").read()?;
+        cprintln!("\n{version}" bold blue, " from `{pg_config}`\n");
+        let failed = run_against(args, pg_config, tests.clone(), run.clone()).await?;
+        outcomes.push((version, failed.is_empty()));
+        for id in failed {
+            if !all_failed.contains(&id) {
+                all_failed.push(id);
+            }
+        }
+    }
+    rerun::write_results(&args.failures_file, &ran, &all_failed)?;
 
-    ecprint!("Starting postmaster" bold blue, "... ");
+    print_rule();
+    let report = versions_report(&outcomes);
+    cprint!("{report}");
+    let num_failed = outcomes.iter().filter(|(_, passed)| !passed).count();
+    if num_failed > 0 {
+        let num_versions = outcomes.len();
+        bail!("tests failed against {num_failed} of {num_versions} versions")
+    }
+    Ok(())
+}
 
-    let mut tester = start_postgres(args, &sh, temp_dir, &bindir, &data_dir, pgport)?;
+/// The results of running the tests against each version, grouped by
+/// version.
+fn versions_report(outcomes: &[(String, bool)]) -> String {
+    let mut report = "\nversions:\n".to_string();
+    for (version, passed) in outcomes {
+        let result = match passed {
+            true => "ok",
+            false => "FAILED",
+        };
+        report += &format!("{version}: {result}\n");
+    }
+    report
+}
 
-    tester.wait_for_postmaster_start()?;
+/// Runs `tests` against the PostgreSQL of `pg_config` in a cluster of its
+/// own, returning the identifiers of the tests that failed.
+async fn run_against(
+    args: &Args,
+    pg_config: &str,
+    tests: Vec<TestFile>,
+    run: RunContext<'_>,
+) -> Result<Vec<String>> {
+    let sh = Shell::new()?;
+    let bindir = cmd!(sh, "{pg_config} --bindir").read()?;
+    let cache = match args.no_cache {
+        true => None,
+        false => {
+            let version = cmd!(sh, "{pg_config} --version").read()?;
+            cache_home().map(|cache_home| cluster_cache_dir(&cache_home, &version))
+        }
+    };
+
+    let mut tester = start_with_retries(args.startup_retries, PGPORT, |port| {
+        start_cluster(args, &sh, &bindir, cache.as_deref(), port, run.clone())
+    })?;
 
     // TODO user-specified DBs
 
-    let postmaster_id = tester.postmaster.id();
+    let postmaster_id = tester.postmaster.get_mut().unwrap().id();
+    let pgport = &tester.port;
     eprintln!("running on port {pgport} with PID {postmaster_id}\n");
 
-    let (stateless_tests, stateful_tests): (Vec<_>, Vec<_>) =
-        tests.partition(|tests| tests.stateless);
+    if args.serve_only {
+        tester.serve().await?;
+        return Ok(vec![]);
+    }
+
+    let failed = tester.run_suite(tests).await?;
 
-    let t1: usize = stateless_tests.iter().map(|file| file.tests.len()).sum();
-    let t2: usize = stateful_tests.iter().map(|file| file.tests.len()).sum();
-    let num_tests = t1 + t2;
-    println!("running {num_tests} tests");
+    if args.watch {
+        tester.watch().await?;
+    }
 
-    let failures1 = tester.run_stateless_tests(stateless_tests).await?;
-    let failures2 = tester.run_stateful_tests(stateful_tests).await?;
+    Ok(failed)
+}
 
-    if !failures1.is_empty() || !failures2.is_empty() {
-        cprintln!("\n", "Failures" bold blue, ":");
-        let mut current_file = "";
-        for (file_name, test, failure) in failures1.iter().chain(failures2.iter()) {
-            if file_name != current_file {
-                current_file = file_name;
-                cprintln!("\n", "File" bold blue, ": {current_file}\n");
+/// Runs `start` on `first_port`, then on a new free port each time it fails,
+/// until it succeeds or has been retried `retries` times.
+fn start_with_retries<T>(
+    retries: u32,
+    first_port: &str,
+    mut start: impl FnMut(String) -> Result<T>,
+) -> Result<T> {
+    let mut port = first_port.to_string();
+    for retry in 1..=retries {
+        match start(port) {
+            Ok(started) => return Ok(started),
+            Err(e) => {
+                port = free_port()?.to_string();
+                let error = format!("{e:#}");
+                warning!(
+                    "the server didn't start due to {error}\nretrying on port {port} ({retry}/{retries})"
+                );
             }
-            failure.print(test)
         }
     }
+    start(port)
+}
 
-    let num_failed = failures1.len() + failures2.len();
-    let num_passed = num_tests - num_failed;
-    if failures1.is_empty() && failures2.is_empty() {
-        cprintln!("\ntest result: ", "ok" green, ". {num_passed} passed; {num_failed} failed\n");
-        // TODO timing
-    } else {
-        cprintln!("\ntest result: ", "FAILED" bold red, ". {num_passed} passed; {num_failed} failed\n");
-        // TODO timing
-    }
-
-    Ok(())
+/// A port nothing is listening on, for now.
+fn free_port() -> Result<u16> {
+    let listener = std::net::TcpListener::bind(("localhost", 0))?;
+    Ok(listener.local_addr()?.port())
 }
 
-fn start_postgres<'a>(
+/// Creates a cluster in a new temporary directory and starts its postmaster
+/// on `port`, waiting until it accepts connections.
+fn start_cluster<'a>(
     args: &'a Args,
     sh: &'a Shell,
-    temp_dir: TempDir,
     bindir: &'a str,
-    data_dir: &'a Path,
-    port: &'a str,
+    cache: Option<&Path>,
+    port: String,
+    run: RunContext<'a>,
 ) -> Result<TestsEnv<'a>> {
-    let mut redirect_options = OpenOptions::new();
-    redirect_options.create(true).write(true).read(true);
-    let out_path = PathBuf::from("postmaster-stdout.temp.log");
-    let out = redirect_options.open(&out_path)?;
-
-    let err_path = PathBuf::from("postmaster-stderr.temp.log");
-    let err = redirect_options.open(&err_path)?;
+    // TODO allow existing DB
+    let temp_dir = tempdir()?;
+    let data_dir = path!(temp_dir / "data");
+    let db_init_location = data_dir.to_string_lossy();
 
-    let postgres = path!(bindir / "postgres");
-    // TODO shut down postmaster on drop?
-    let postmaster = std::process::Command::new(postgres)
-        .arg("-D")
-        .arg(data_dir)
-        .arg("-F")
-        // TODO user config debug flag -d 5
-        .arg("-c")
-        .arg(format!("port={port}"))
-        .stdout(out)
-        .stderr(err)
-        // .arg("-k")
-        // .arg(todo!())
-        .spawn()?;
+    ecprintln!("Initializing DB" bold blue, " at {db_init_location}");
 
-    let tester = TestsEnv {
-        args,
-        sh,
-        temp_dir: ManuallyDrop::new(temp_dir),
-        bindir,
-        data_dir,
-        postmaster,
-        port,
-        out_path,
-        err_path,
+    let initdb = path!(bindir / "initdb");
+    let run_initdb = |data_dir: &Path| -> Result<()> {
+        let init_output = cmd!(sh, "{initdb} -D {data_dir} --no-clean --no-sync")
+            .quiet()
+            .ignore_status()
+            .output();
+        match init_output {
+            Ok(output) if output.status.success() => Ok(()),
+            Ok(output) => {
+                let out = String::from_utf8(output.stdout)?;
+                let err = String::from_utf8(output.stderr)?;
+                bail!("initdb failed with\nout:\n{out}\nerr:\n{err}")
+            }
+            Err(e) => Err(e)?,
+        }
     };
-    Ok(tester)
-}
-
-impl<'a> TestsEnv<'a> {
-    fn wait_for_postmaster_start(&mut self) -> Result<()> {
-        use std::time::Duration;
-        let TestsEnv {
-            sh,
-            bindir,
-            postmaster,
-            port,
-            ..
-        } = self;
-
-        let psql = path!(bindir / "psql");
+    init_data_dir(sh, cache, &data_dir, run_initdb)?;
 
-        let wait_start = Instant::now();
-        while wait_start.elapsed().as_secs() < 60 {
-            let out = cmd!(sh, "{psql} -X postgres -p {port}")
-                .quiet()
-                .ignore_stderr()
-                .ignore_status()
-                .output()?;
-            if out.status.success() {
-                return Ok(());
-            }
-            if let Some(status) = postmaster.try_wait()? {
-                bail!("postmaster failed with {status}")
-            }
+    let conf_path = path!(data_dir / "postgresql.conf");
+    let mut db_conf = OpenOptions::new().append(true).open(&conf_path)?;
+    writeln!(&mut db_conf, "{}", conf_block(&conf_settings(args))).map_err(|e| {
+        anyhow!(
+            "failed to write to db_conf at `{}` due to {e}",
+            conf_path.display()
+        )
+    })?;
 
-            thread::sleep(Duration::from_micros(1000000))
+    if let Some(path) = &args.dump_conf {
+        let conf = resolved_conf(&std::fs::read_to_string(&conf_path)?);
+        match path {
+            Some(path) => std::fs::write(path, conf)
+                .with_context(|| format!("could not write the conf to `{}`", path.display()))?,
+            None => eprint!("{conf}"),
         }
-        bail!("postmaster did no respond within 60 seconds")
     }
 
-    async fn run_stateless_tests(
-        &self,
-        tests: Vec<TestFile>,
-    ) -> Result<Vec<(String, Test, FailureInfo)>> {
-        use tokio::sync::{mpsc, oneshot};
-        let TestsEnv { port, .. } = self;
-
-        cprintln!("Stateless tests" bold blue);
+    sh.set_var("PGPORT", &port);
 
-        let db = self.createdb(format!("stateless_test_db"))?;
+    ecprint!("Starting postmaster" bold blue, "... ");
 
-        // TODO make size user-configurable
-        let (unused_clients, mut clients) = mpsc::channel(4);
+    let mut tester = start_postgres(args, sh, temp_dir, bindir, data_dir, port, run)?;
 
-        let mut conns: FuturesOrdered<_> = (0..4)
-            .map(|_| async {
-                tokio_postgres::connect(
-                    &format!("host=localhost port={port} user=postgres dbname=stateless_test_db application_name=tests"),
-                    tokio_postgres::NoTls,
-                )
-                .await
-            })
-            .collect();
+    tester.wait_for_postmaster_start()?;
+    Ok(tester)
+}
 
-        for conn in (&mut conns).next().await {
-            let (client, connection) = conn?;
-            tokio::spawn(async move {
-                if let Err(e) = connection.await {
-                    cprintln!("Error" bold red, " in postgres connection: {e}");
-                }
-            });
-            unused_clients.try_send(client)?;
+impl TestsEnv<'_> {
+    /// Runs `tests` against the cluster and prints a summary of the results,
+    /// returning the identifiers of the tests that failed.
+    async fn run_suite(&self, tests: Vec<TestFile>) -> Result<Vec<String>> {
+        let args = self.args;
+        self.passed.store(false, Ordering::SeqCst);
+        let kept_dbs =
+            std::mem::take(&mut *self.kept_dbs.lock().unwrap_or_else(PoisonError::into_inner));
+        for db in kept_dbs {
+            db.drop().await;
         }
+        if args.suite_name_from_h1 {
+            *self
+                .suite_names
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = tests
+                .iter()
+                .filter_map(|file| Some((file.name.clone(), file.title.clone()?)))
+                .collect();
+        }
+        let (stateless_tests, mut stateful_tests): (Vec<_>, Vec<_>) =
+            tests.into_iter().partition(|tests| tests.stateless);
 
-        let num_tests: usize = tests.iter().map(|file| file.tests.len()).sum();
-
-        let mut results = Vec::with_capacity(num_tests);
-        let mut tests = tests.into_iter().flat_map(|file| {
-            file.tests
-                .into_iter()
-                .map(move |test| (file.name.clone(), test))
-        });
-
-        while let Some(mut client) = (&mut clients).recv().await {
-            let (file, test) = match tests.next() {
-                None => break,
-                Some(test) => test,
-            };
-            let (send, recv) = oneshot::channel();
-            results.push(recv);
-            let unused_clients = unused_clients.clone();
-            tokio::spawn(async move {
-                let result = {
-                    let txn = client.transaction().await;
-                    match txn {
-                        Err(e) => Err(e),
-                        Ok(txn) => {
-                            let result = txn.simple_query(&test.text).await;
-                            let _ = txn.rollback().await;
-                            result
-                        }
-                    }
-                };
-                send.send((file, test, result))
-                    .unwrap_or_else(|_| unreachable!());
-                unused_clients.send(client).await.unwrap();
-            });
+        let seed = args.shuffle.then(|| args.seed.unwrap_or_else(rand::random));
+        if let Some(seed) = seed {
+            cprintln!("shuffling the tests, repeat the order with `--shuffle --seed {seed}`");
+            shuffle(&mut stateful_tests, seed);
         }
 
-        let mut current_file = "".to_string();
-        let mut failures = vec![];
-        for result in results {
-            let (file_name, test, result) = result.await?;
-            if file_name != current_file {
-                current_file = file_name.clone();
-                cprintln!("\n", "File" bold blue, ": {current_file}\n");
+        for file in &stateful_tests {
+            if let Some(test) = lone_non_transactional_test(file) {
+                let (name, line, num_tests) = (&file.name, test.line, file.tests.len());
+                warning!(
+                    "non-transactional test at `{name}:{line}` forces all {num_tests} \
+                    tests in the file to run statefully, consider moving it to its own file"
+                );
             }
-
-            print_test_result(file_name, test, result, &mut failures);
         }
 
-        drop(unused_clients);
-        drop(clients);
-
-        db.drop()?;
-
-        Ok(failures)
-    }
+        if args.validate_sql {
+            self.validate_sql(stateless_tests.iter().chain(&stateful_tests))
+                .await?;
+        }
 
-    async fn run_stateful_tests(
-        &self,
-        tests: Vec<TestFile>,
-    ) -> Result<Vec<(String, Test, FailureInfo)>> {
-        let TestsEnv { port, .. } = self;
+        // the tests that will run, for the snapshot's report
+        let mut snapshot_tests: Vec<(String, usize, String)> = vec![];
+        if args.snapshot.is_some() {
+            for file in stateless_tests.iter().chain(&stateful_tests) {
+                for test in &file.tests {
+                    snapshot_tests.push((file.name.clone(), test.line, test.header.clone()));
+                }
+            }
+            if let Some(sql) = &args.assert_after_file {
+                let assertion = after_file_assertion(sql);
+                for file in &stateful_tests {
+                    snapshot_tests.push((
+                        file.name.clone(),
+                        assertion.line,
+                        assertion.header.clone(),
+                    ));
+                }
+            }
+        }
 
-        cprintln!("\nStateful tests" bold blue);
+        let t1: usize = stateless_tests.iter().map(|file| file.tests.len()).sum();
+        let t2: usize = stateful_tests.iter().map(|file| file.tests.len()).sum();
+        let t2 = match args.assert_after_file {
+            Some(_) => t2 + stateful_tests.len(),
+            None => t2,
+        };
+        let num_tests = t1 + t2;
+        cprintln!("running {num_tests} tests");
 
-        let mut running_tests = FuturesOrdered::new();
-        let mut files = tests.into_iter().rev();
+        let expected_to_fail = stateless_tests
+            .iter()
+            .chain(&stateful_tests)
+            .flat_map(|file| file.tests.iter().map(move |test| (&file.name, test)))
+            .filter(|(file, test)| self.run.expected_failures.contains(file, test))
+            .count();
 
-        let test_runner = |test_file: TestFile, db_num: usize| async move {
-            let dbname = format!("stateful-tests-{db_num}");
-            let db = self.createdb(dbname)?;
-            let dbname = &*db;
-            let (mut client, connection) = tokio_postgres::connect(
-                &format!("host=localhost port={port} user=postgres dbname={dbname} application_name=tests"),
-                tokio_postgres::NoTls,
-            )
-            .await?;
+        let run_tests = async {
+            let (failures1, mut durations) =
+                self.run_stateless_tests(stateless_tests, seed).await?;
+            let (failures2, durations2) = self.run_stateful_tests(stateful_tests).await?;
+            durations.extend(durations2);
+            Ok::<_, anyhow::Error>((failures1, failures2, durations))
+        };
+        // if the postmaster crashes every test still running will fail with a
+        // connection error, so stop the run and report the crash instead
+        let (failures1, failures2, durations) = tokio::select! {
+            failures = run_tests => failures?,
+            status = postmaster_exited(&self.postmaster) => return Err(self.postmaster_crash(status?)),
+        };
+        let is_expected = |(file, test, _): &(String, Test, FailureInfo)| {
+            self.run.expected_failures.contains(file, test)
+        };
+        let (xfailed1, failures1): (Vec<_>, Vec<_>) = failures1.into_iter().partition(is_expected);
+        let (xfailed2, failures2): (Vec<_>, Vec<_>) = failures2.into_iter().partition(is_expected);
+        let num_xfailed = xfailed1.len() + xfailed2.len();
+        let num_xpassed = expected_to_fail - num_xfailed;
 
-            tokio::spawn(async move {
-                if let Err(e) = connection.await {
-                    cprintln!("Error" bold red, " in postgres connection: {e}");
+        if args.compact_failures && (!failures1.is_empty() || !failures2.is_empty()) {
+            cprintln!("\n", "Failures" bold blue, ":");
+            for (file_name, test, failure) in failures1.iter().chain(failures2.iter()) {
+                let summary = failure.summary_line(test);
+                cprintln!("FAIL" bold red, " {file_name}:{summary}");
+            }
+        } else if !failures1.is_empty() || !failures2.is_empty() {
+            cprintln!("\n", "Failures" bold blue, ":");
+            let changed_cols = args
+                .diff_only_changed_cols
+                .then_some(db_output::ChangedCols {
+                    key: args.diff_key_col.as_deref(),
+                });
+            let mut current_file = "";
+            for (file_name, test, failure) in failures1.iter().chain(failures2.iter()) {
+                if file_name != current_file {
+                    current_file = file_name;
+                    self.print_file_banner(current_file);
                 }
-            });
-
-            let mut results = Vec::with_capacity(test_file.tests.len());
-
-            for test in test_file.tests {
-                let result = if test.transactional {
-                    let txn = client.transaction().await?;
-                    let result = txn.simple_query(&test.text).await;
-                    let _ = txn.rollback().await;
-                    result
-                } else {
-                    // TODO if a stateful test fails to probably invalidates future tests
-                    //      abort here and mark them as skipped somehow?
-                    client.simple_query(&test.text).await
-                };
-                results.push((test, result));
+                failure.print(test, args.diff_style, changed_cols)
             }
+        }
 
-            drop(client);
-            // TODO do something on error?
-            let _ = db.drop();
-            Ok::<_, anyhow::Error>((test_file.name, results))
+        if args.timing_report {
+            print_rule();
+            timing::print_report(&durations);
+        }
+
+        let num_failed = failures1.len() + failures2.len();
+        let num_passed = num_tests - num_failed - num_xfailed;
+        let xfails = match args.expected_failures {
+            Some(_) => format!("; {num_xfailed} xfailed; {num_xpassed} xpassed"),
+            None => String::new(),
         };
+        print_rule();
+        if failures1.is_empty() && failures2.is_empty() {
+            cprintln!("\ntest result: ", "ok" green, ". {num_passed} passed; {num_failed} failed{xfails}\n");
+            // TODO timing
+        } else {
+            cprintln!("\ntest result: ", "FAILED" bold red, ". {num_passed} passed; {num_failed} failed{xfails}\n");
+            // TODO timing
+        }
+        self.passed.store(num_failed == 0, Ordering::SeqCst);
+        self.emit(Event::RunFinished {
+            passed: num_passed,
+            failed: num_failed,
+        });
+        self.tear_down_dbs(num_failed == 0).await;
 
-        // TODO make size user-configurable
-        // TODO max client
-        let mut i = 0;
-        for file in (&mut files).take(4) {
-            i += 1;
-            running_tests.push(test_runner(file, i))
+        if let Some(path) = &args.snapshot {
+            let failed: Vec<_> = failures1
+                .iter()
+                .chain(&failures2)
+                .map(|(file, test, _)| (file, test.line, &test.header))
+                .collect();
+            let outcomes = snapshot_tests
+                .into_iter()
+                .map(|(file, line, header)| Outcome {
+                    passed: !failed.contains(&(&file, line, &header)),
+                    file,
+                    line,
+                    header,
+                })
+                .collect();
+            snapshot::check(path, &snapshot::report(outcomes), args.bless)?;
         }
 
-        let mut failures = vec![];
+        let failed = failures1
+            .iter()
+            .chain(&failures2)
+            .map(|(file, test, _)| rerun::test_id(file, test))
+            .collect();
+        Ok(failed)
+    }
+
+    /// Keeps the server running with a scratch database to connect to until
+    /// interrupted.
+    async fn serve(&self) -> Result<()> {
+        let db = self.createdb("scratch".to_string()).await?;
+        let connection_string = connection_string(self.db_user(), &self.port, &db);
+        cprintln!("Serving" bold blue, " {connection_string}");
+        cprintln!("connect with `psql {connection_string}`");
+        ecprintln!("\nPress Ctrl-C to stop");
+        tokio::select! {
+            status = postmaster_exited(&self.postmaster) => return Err(self.postmaster_crash(status?)),
+            _ = tokio::signal::ctrl_c() => (),
+        };
+        // the database goes along with the rest of the cluster
+        Ok(())
+    }
 
+    /// Reruns the tests of source files as they are edited, reusing the
+    /// running cluster, until interrupted.
+    async fn watch(&self) -> Result<()> {
+        let args = self.args;
+        let mut watcher = FileWatcher::new(&args.input_paths)?;
         loop {
-            let result = (&mut running_tests).next().await;
-            if let Some(results) = result {
-                for result in results {
-                    let (current_file, result) = result;
-                    cprintln!("\n", "File" bold blue, ": {current_file}\n");
-                    for (test, result) in result {
-                        print_test_result(current_file.clone(), test, result, &mut failures);
+            ecprintln!("\nWatching for changes" bold blue, ", press Ctrl-C to stop");
+            let changed = tokio::select! {
+                changed = watcher.changed_files() => changed?,
+                status = postmaster_exited(&self.postmaster) => return Err(self.postmaster_crash(status?)),
+                _ = tokio::signal::ctrl_c() => return Ok(()),
+            };
+
+            let mut tests = vec![];
+            let mut errors = vec![];
+            for path in changed_source_files(&args.input_paths, &changed) {
+                for file in crate::extract_tests_from_path(&path, &args.into()) {
+                    match file {
+                        Ok(file) => tests.push(file),
+                        Err(e) => errors.push(e),
                     }
                 }
             }
-            match (&mut files).next() {
-                Some(file) => {
-                    i += 1;
-                    running_tests.push(test_runner(file, i))
-                }
-                None => {
-                    if running_tests.is_empty() {
-                        break;
-                    }
-                }
+            for error in &errors {
+                ecprintln!("Error" bold red, ": {error}");
+            }
+            if tests.is_empty() || !errors.is_empty() {
+                continue;
             }
-        }
 
-        Ok(failures)
+            let tests = match expand_defines(&args.defines, select_tests(args, tests)) {
+                Ok(tests) => tests,
+                Err(error) => {
+                    ecprintln!("Error" bold red, ": {error}");
+                    continue;
+                }
+            };
+            print_rule();
+            self.run_suite(tests).await?;
+        }
     }
+}
 
-    fn createdb(&self, dbname: String) -> Result<DbDropper> {
-        use once_cell::sync::OnceCell;
-
-        let Self { bindir, port, .. } = self;
+/// The tests selected by `--tag`, `--skip-tag` and `--shard`.
+pub(crate) fn select_tests(args: &Args, tests: Vec<TestFile>) -> Vec<TestFile> {
+    let tests = select_tagged(&args.tags, &args.skip_tags, tests);
+    match args.shard {
+        Some(shard) => shard.select(tests),
+        None => tests,
+    }
+}
 
-        let sh = Shell::new()?;
+/// Keeps the tests that have any of the `include` tags, or all of them if
+/// there are none, unless they also have any of the `exclude` tags. Stateful
+/// files are selected as a whole, having the tags of all their tests, since
+/// their tests depend on each other.
+fn select_tagged(include: &[String], exclude: &[String], files: Vec<TestFile>) -> Vec<TestFile> {
+    if include.is_empty() && exclude.is_empty() {
+        return files;
+    }
+    let is_selected = |tags: &mut dyn Iterator<Item = &String>| {
+        let mut included = include.is_empty();
+        for tag in tags {
+            if exclude.contains(tag) {
+                return false;
+            }
+            included |= include.contains(tag);
+        }
+        included
+    };
+    files
+        .into_iter()
+        .filter_map(|mut file| {
+            if file.stateless {
+                file.tests.retain(|test| is_selected(&mut test.tags.iter()));
+                (!file.tests.is_empty()).then_some(file)
+            } else {
+                is_selected(&mut file.tests.iter().flat_map(|test| &test.tags)).then_some(file)
+            }
+        })
+        .collect()
+}
 
-        let createdb = path!(bindir / "createdb");
-        cmd!(sh, "{createdb} -p {port} {dbname}").quiet().run()?;
+/// One of `count` disjoint partitions of the suite, written `index/count` on
+/// the command line with `index` starting at 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    index: usize,
+    count: usize,
+}
 
-        let psql = path!(bindir / "psql");
+impl FromStr for Shard {
+    type Err = anyhow::Error;
 
-        static CREATE_ROLE_ONCE: OnceCell<()> = OnceCell::new();
+    fn from_str(s: &str) -> Result<Self> {
+        let (index, count) = s
+            .split_once('/')
+            .ok_or_else(|| anyhow!("shard must be of the form `k/n`, found `{s}`"))?;
+        let index: usize = index.trim().parse()?;
+        let count: usize = count.trim().parse()?;
+        if count == 0 || index == 0 || index > count {
+            bail!("shard `{s}` out of range, `k` must be between 1 and `n`")
+        }
+        Ok(Self { index, count })
+    }
+}
 
-        CREATE_ROLE_ONCE.get_or_try_init(|| {
-            let create_role = "CREATE ROLE postgres WITH LOGIN;";
-            // TODO print output only on error
-            cmd!(sh, "{psql} -X -p {port} -c {create_role} {dbname}")
-                .quiet()
-                .ignore_stdout()
-                .ignore_stderr()
-                .run()
-        })?;
+impl Shard {
+    /// Selects this shard's portion of the suite. Files are sorted by name and
+    /// their tests by line so every shard sees the same ordering, then the
+    /// units are dealt out round-robin. Stateless tests are distributed
+    /// individually, stateful files are kept whole since their tests depend
+    /// on each other.
+    fn select(self, mut files: Vec<TestFile>) -> Vec<TestFile> {
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut unit = 0;
+        let mut in_shard = || {
+            let selected = unit % self.count == self.index - 1;
+            unit += 1;
+            selected
+        };
+        files
+            .into_iter()
+            .filter_map(|mut file| {
+                if file.stateless {
+                    file.tests.sort_by_key(|test| test.line);
+                    file.tests.retain(|_| in_shard());
+                    (!file.tests.is_empty()).then_some(file)
+                } else {
+                    in_shard().then_some(file)
+                }
+            })
+            .collect()
+    }
+}
 
-        Ok(DbDropper {
-            dbname,
-            sh,
-            bindir: bindir.to_string(),
-            port: port.to_string(),
-        })
+impl TestsEnv<'_> {
+    /// Writes a `--progress-json` event, if they're wanted.
+    fn emit(&self, event: Event) {
+        if let Some(progress) = &self.run.progress {
+            progress.emit(event);
+        }
+    }
+
+    /// Reports a finished test to `--progress-json` and the callback passed
+    /// to [`run`].
+    fn test_finished(&self, record: &TestResultRecord) {
+        self.emit(Event::TestFinished {
+            file: record.file,
+            line: record.line,
+            header: record.header,
+            passed: record.status.passed(),
+            duration_ms: progress::millis(record.duration),
+        });
+        (self.run.on_result)(record);
+    }
+
+    fn print_file_banner(&self, path: &str) {
+        let suite_names = self
+            .suite_names
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let cwd = std::env::current_dir().unwrap_or_default();
+        let name = report_name(path, suite_names.get(path), &cwd);
+        print_rule();
+        cprintln!("File" bold blue, ": {name}\n");
     }
 }
 
-fn print_test_result(
-    file_name: String,
-    test: Test,
-    result: Result<Vec<tokio_postgres::SimpleQueryMessage>, tokio_postgres::Error>,
-    failures: &mut Vec<(String, Test, FailureInfo)>,
-) {
-    let header = &test.header;
-    match result {
-        Err(e) => {
-            cprintln!("test {header}... ", "FAILED" bold red);
-            failures.push((file_name, test, QueryError(e)))
-        }
-        Ok(query_result) => {
-            print!("test {header}... ");
-            match validate_output(query_result, &test) {
-                db_output::TestResult::Passed => cprintln!("ok" green),
-                db_output::TestResult::Failed(failure) => {
-                    failures.push((file_name, test, failure));
-                    cprintln!("FAILED" bold red)
-                }
-            }
+/// How reports name the file at `path`, by its suite name if it has one.
+fn report_name<'p>(path: &'p str, suite_name: Option<&'p String>, cwd: &Path) -> Cow<'p, str> {
+    match suite_name {
+        Some(name) => Cow::Borrowed(name),
+        None => display_path(path, cwd),
+    }
+}
+
+/// Draws a horizontal rule across the terminal, or just a blank line when
+/// stdout isn't one or isn't being colored.
+fn print_rule() {
+    use termcolor::WriteColor;
+    let width = terminal_size::terminal_size().map(|(terminal_size::Width(width), _)| width);
+    crate::colors::write_stdout(|buffer| match width {
+        Some(width) if buffer.supports_color() => {
+            let rule = "─".repeat(width.into());
+            let _ = writeln!(buffer);
+            let _ = buffer.set_color(termcolor::ColorSpec::new().set_dimmed(true));
+            let _ = write!(buffer, "{rule}");
+            let _ = buffer.reset();
+            let _ = writeln!(buffer);
+        }
+        _ => {
+            let _ = writeln!(buffer);
+        }
+    });
+}
+
+/// Shortens a test file's path for display, making it relative to `cwd`
+/// when it's within it.
+fn display_path<'p>(path: &'p str, cwd: &Path) -> Cow<'p, str> {
+    let path = Path::new(path);
+    let relative = path.strip_prefix(cwd).or_else(|_| path.strip_prefix("."));
+    match relative {
+        Ok(relative) if !relative.as_os_str().is_empty() => {
+            Cow::Owned(relative.to_string_lossy().into_owned())
         }
+        _ => path.to_string_lossy(),
     }
 }
 
-impl<'a> Drop for TestsEnv<'a> {
-    fn drop(&mut self) {
-        #[cfg(unix)]
-        use nix::{
-            sys::signal::{kill, SIGTERM},
-            unistd::Pid,
-        };
+/// Resolves once the postmaster exits, checking for it periodically.
+async fn postmaster_exited(postmaster: &Mutex<Child>) -> Result<ExitStatus> {
+    loop {
+        if let Some(status) = postmaster.lock().unwrap().try_wait()? {
+            return Ok(status);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
 
-        let pid = self.postmaster.id();
-        let copy_output_locally = || {
-            use std::fs::rename;
+/// The user's cache directory, `$XDG_CACHE_HOME` or `~/.cache`.
+fn cache_home() -> Option<PathBuf> {
+    std::env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| path!(home / ".cache")))
+}
 
-            let out_file = format!("postmaster-out.log");
-            match rename(&self.out_path, &out_file) {
-                Ok(_) => ecprintln!("Postmaster stdout" bold blue, " can be found in {out_file}"),
-                Err(err) => cprintln!(
-                    "Error" bold red,
-                    " could not copy postmaster stdout from `postmaster-stdout.temp.log` due to {err}"
-                ),
-            };
+/// Where the cluster initialized for `pg_version`, the output of
+/// `pg_config --version`, is cached between runs under `cache_home`.
+fn cluster_cache_dir(cache_home: &Path, pg_version: &str) -> PathBuf {
+    let version: String = pg_version
+        .trim()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    path!(cache_home / "embedded-sql-tester").join(version)
+}
 
-            let err_file = format!("postmaster-err.log");
-            let _ = std::fs::rename(&self.err_path, &err_file).map_err(|err| {
-                ecprintln!(
-                    "Error" bold red,
-                    " could not copy postmaster stderr from `postmaster-stderr.temp.log` due to {err}"
-                )
+/// Creates the cluster at `data_dir` by copying the one cached in `cache`,
+/// running `initdb` and populating the cache if there isn't one yet.
+fn init_data_dir(
+    sh: &Shell,
+    cache: Option<&Path>,
+    data_dir: &Path,
+    initdb: impl FnOnce(&Path) -> Result<()>,
+) -> Result<()> {
+    let cache = match cache {
+        Some(cache) => cache,
+        None => return initdb(data_dir),
+    };
+
+    // a cluster is only cached once it's complete, see below
+    let cached = path!(cache / "data");
+    if path!(cached / "PG_VERSION").is_file() {
+        let cached_location = cached.display();
+        ecprintln!("Using cached cluster" bold blue, " from {cached_location}");
+        if cmd!(sh, "cp -a {cached} {data_dir}").quiet().run().is_ok() {
+            return Ok(());
+        }
+        warning!("could not copy the cached cluster, running initdb");
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+
+    initdb(data_dir)?;
+
+    // copy then rename so that concurrent runs never see a partial cache
+    let populate = || -> Result<()> {
+        std::fs::create_dir_all(cache)?;
+        let partial = path!(cache / format!("data.{}.partial", std::process::id()));
+        cmd!(sh, "cp -a {data_dir} {partial}").quiet().run()?;
+        if std::fs::rename(&partial, &cached).is_err() {
+            std::fs::remove_dir_all(&partial)?;
+        }
+        Ok(())
+    };
+    if let Err(e) = populate() {
+        warning!("could not cache the cluster: {e}");
+    }
+    Ok(())
+}
+
+/// Splits stateless files into the batches of tests each run on a single
+/// connection. Tests are run individually, except those of serial and
+/// single-transaction files which are run in order as one batch so that they
+/// never overlap.
+fn stateless_batches(files: Vec<TestFile>, connection_per_file: bool) -> Vec<Batch> {
+    let mut batches = vec![];
+    let mut first = 0;
+    for file in files {
+        let settings = Arc::new(file.settings);
+        let prelude = Arc::new(file.prelude);
+        let one_batch = file.serial || file.single_transaction || connection_per_file;
+        let hooks = (!file.before_all.is_empty() || !file.after_all.is_empty()).then(|| {
+            Arc::new(FileHooks {
+                before_all: file.before_all,
+                after_all: file.after_all,
+                started: AtomicBool::new(false),
+                unfinished: AtomicUsize::new(match one_batch {
+                    true => 1,
+                    false => file.tests.len(),
+                }),
+            })
+        });
+        if one_batch {
+            let num_tests = file.tests.len();
+            batches.push(Batch {
+                file: file.name,
+                settings,
+                prelude,
+                single_transaction: file.single_transaction,
+                hooks,
+                first,
+                tests: file.tests,
             });
-            ecprintln!("Postmaster stderr" bold blue, " can be found in {err_file}");
-        };
-        match self.postmaster.try_wait() {
-            // TODO log output location?
-            Ok(Some(_)) => copy_output_locally(),
-            Ok(None) => {
-                ecprint!("Stopping postmaster" bold blue, "... ");
-                #[cfg(unix)]
-                let result = kill(Pid::from_raw(self.postmaster.id() as i32), SIGTERM);
+            first += num_tests;
+        } else {
+            let name = file.name;
+            batches.extend(file.tests.into_iter().map(|test| {
+                let batch = Batch {
+                    file: name.clone(),
+                    settings: settings.clone(),
+                    prelude: prelude.clone(),
+                    single_transaction: false,
+                    hooks: hooks.clone(),
+                    first,
+                    tests: vec![test],
+                };
+                first += 1;
+                batch
+            }));
+        }
+    }
+    batches
+}
 
-                // TODO do this on unix also if the term fails?
-                #[cfg(not(unix))]
-                let result = self.postmaster.kill();
+/// Puts `items`, stateless batches or stateful files, in the random order
+/// given by `seed`. Unlike `StdRng`, whose algorithm may change between
+/// releases of `rand`, ChaCha8 gives a seed the same order in every build.
+fn shuffle<T>(items: &mut [T], seed: u64) {
+    items.shuffle(&mut ChaCha8Rng::seed_from_u64(seed));
+}
 
-                // FIXME anything to do with this error?
-                match result {
-                    Err(error) => {
-                        ecprintln!(
-                            "ERROR:" bold red,
-                            " could not kill postmaster at pid {pid} due to {error}",
-                        );
-                        // TODO terminate if kill doesn't work?
-                    }
-                    Ok(_) => {
-                        // TODO timeout
-                        let _ = self.postmaster.wait();
-                        eprintln!("stopped");
-                        copy_output_locally();
-                        unsafe {
-                            ManuallyDrop::drop(&mut self.temp_dir);
-                        }
-                    } // TODO only on test error?
-                }
-            }
-            Err(err) => ecprintln!(
-                "Error" bold red, " could not shut down postgres due to {err}"
-            ),
+/// Stateless tests run one after the other on the same connection.
+struct Batch {
+    file: String,
+    /// the file's `SET` statements, applied before the tests are run
+    settings: Arc<Vec<String>>,
+    /// the file's prelude, run in each of the tests' transactions
+    prelude: Arc<Vec<String>>,
+    /// run the tests in a transaction they share instead of one each
+    single_transaction: bool,
+    /// the file's `before-all` and `after-all` blocks, if it has any
+    hooks: Option<Arc<FileHooks>>,
+    /// the position of the first of the tests among all of the suite's, in
+    /// source order
+    first: usize,
+    tests: Vec<Test>,
+}
+
+/// The `before-all` and `after-all` blocks of a stateless file, committed
+/// before the first of its batches starts and after the last one finishes.
+struct FileHooks {
+    before_all: Vec<String>,
+    after_all: Vec<String>,
+    /// set once the `before-all` blocks have been run
+    started: AtomicBool,
+    /// the number of the file's batches that haven't finished yet
+    unfinished: AtomicUsize,
+}
+
+/// Holds back results until those before them in source order have been
+/// released, for `--ordered-output`. Tests are identified by their position
+/// in source order, as lines alone aren't unique.
+struct ReorderBuffer<T> {
+    /// the position of the next result to release
+    next: usize,
+    held: BTreeMap<usize, T>,
+}
+
+impl<T> ReorderBuffer<T> {
+    fn new() -> Self {
+        Self {
+            next: 0,
+            held: BTreeMap::new(),
+        }
+    }
+
+    /// Adds the result of the test at `position`, returning the results that
+    /// can now be released, in order.
+    fn push(&mut self, position: usize, result: T) -> Vec<T> {
+        self.held.insert(position, result);
+        let mut ready = vec![];
+        while let Some(result) = self.held.remove(&self.next) {
+            ready.push(result);
+            self.next += 1;
         }
+        ready
+    }
+
+    fn is_empty(&self) -> bool {
+        self.held.is_empty()
     }
 }
 
-#[must_use]
-struct DbDropper {
-    dbname: String,
-    sh: Shell,
-    bindir: String,
+/// Runs a test of a single-transaction file, starting the transaction the
+/// file's tests share if `began` isn't set yet. The test runs in a savepoint
+/// so that an error only undoes the test rather than aborting the
+/// transaction.
+async fn run_in_shared_transaction(
+    client: &Client,
+    sql: &str,
+    prelude: &str,
+    began: &mut bool,
+) -> Result<Vec<SimpleQueryMessage>, tokio_postgres::Error> {
+    if !*began {
+        client.batch_execute("BEGIN").await?;
+        *began = true;
+        if !prelude.is_empty() {
+            client.batch_execute(prelude).await?;
+        }
+    }
+    client.batch_execute("SAVEPOINT test").await?;
+    let result = client.simple_query(sql).await;
+    let end = match result {
+        Ok(_) => "RELEASE SAVEPOINT test",
+        Err(_) => "ROLLBACK TO SAVEPOINT test",
+    };
+    client.batch_execute(end).await?;
+    result
+}
+
+/// [`with_description`] for a test run in a transaction, such as that of a
+/// single-transaction file or one a test's effects are committed with. The
+/// statements are described in a savepoint, as one that no longer prepares,
+/// such as a query of a table the test went on to drop, would otherwise
+/// abort the transaction.
+async fn with_savepoint_description(
+    client: &Client,
+    test: &Test,
+    first_result_only: bool,
+    result: Result<Vec<SimpleQueryMessage>, tokio_postgres::Error>,
+) -> Ran {
+    if test.column_count.is_none() || result.is_err() {
+        return Ran::Query(result);
+    }
+    let _ = client.batch_execute("SAVEPOINT describe").await;
+    let ran = with_description(client, test, first_result_only, result).await;
+    let _ = client
+        .batch_execute("ROLLBACK TO SAVEPOINT describe; RELEASE SAVEPOINT describe")
+        .await;
+    ran
+}
+
+/// Runs a stateless test in the transaction `client` has just begun, after
+/// the `prelude`.
+async fn run_after_prelude(
+    client: &Transaction<'_>,
+    sql: &str,
+    prelude: &str,
+) -> (
+    Result<Vec<SimpleQueryMessage>, tokio_postgres::Error>,
+    Duration,
+) {
+    if !prelude.is_empty() {
+        if let Err(e) = client.batch_execute(prelude).await {
+            return (Err(e), Duration::ZERO);
+        }
+    }
+    timed(client.simple_query(sql)).await
+}
+
+/// Whether a file has a prelude but there's no transaction to run it in.
+fn prelude_without_transaction(args: &Args, files: &[TestFile]) -> bool {
+    args.stateless_no_transaction && files.iter().any(|file| !file.prelude.is_empty())
+}
+
+/// The statements run in each of a stateless batch's transactions, those of
+/// `--stateless-prelude` followed by the file's.
+fn stateless_prelude(args: &Args, prelude: &[String]) -> String {
+    let statements: Vec<&str> = (args.stateless_prelude.iter())
+        .chain(prelude)
+        .map(|sql| sql.trim_end().trim_end_matches(';'))
+        .collect();
+    statements.join(";\n")
+}
+
+/// Runs a file's `SET` statements on `client` unless they've already been.
+async fn apply_settings(
+    client: &Client,
+    settings: &[String],
+    applied: &mut bool,
+) -> Result<(), tokio_postgres::Error> {
+    if *applied || settings.is_empty() {
+        return Ok(());
+    }
+    client.batch_execute(&settings.join(";\n")).await?;
+    *applied = true;
+    Ok(())
+}
+
+/// Commits a stateless file's `before-all` or `after-all` SQL, with the
+/// file's settings applied while it runs.
+async fn run_file_hooks(
+    client: &Client,
+    settings: &[String],
+    hooks: &[String],
+) -> Result<(), tokio_postgres::Error> {
+    let mut settings_applied = false;
+    apply_settings(client, settings, &mut settings_applied).await?;
+    let mut result = Ok(());
+    for sql in hooks {
+        result = client.batch_execute(sql).await;
+        if result.is_err() {
+            break;
+        }
+    }
+    if settings_applied {
+        let _ = client.batch_execute("RESET ALL").await;
+    }
+    result
+}
+
+/// Runs `query`, measuring how long it takes.
+async fn timed<T>(query: impl Future<Output = T>) -> (T, Duration) {
+    let start = Instant::now();
+    let output = query.await;
+    (output, start.elapsed())
+}
+
+/// Moves a file, copying it if it's on another filesystem.
+fn move_file(from: &Path, to: impl AsRef<Path>) -> std::io::Result<()> {
+    if std::fs::rename(from, &to).is_ok() {
+        return Ok(());
+    }
+    std::fs::copy(from, to)?;
+    std::fs::remove_file(from)
+}
+
+fn last_lines(s: &str, n: usize) -> &str {
+    let s = s.trim_end();
+    match s.rmatch_indices('\n').nth(n.saturating_sub(1)) {
+        Some((i, _)) if n > 0 => &s[i + 1..],
+        _ if n > 0 => s,
+        _ => "",
+    }
+}
+
+/// Finds the test responsible when a single non-transactional test is all that
+/// keeps an otherwise transactional file off the stateless path.
+fn lone_non_transactional_test(file: &TestFile) -> Option<&Test> {
+    let mut non_transactional = file.tests.iter().filter(|test| !test.transactional);
+    match (non_transactional.next(), non_transactional.next()) {
+        (Some(test), None) if file.tests.len() > 1 => Some(test),
+        _ => None,
+    }
+}
+
+fn start_postgres<'a>(
+    args: &'a Args,
+    sh: &'a Shell,
+    temp_dir: TempDir,
+    bindir: &'a str,
+    data_dir: PathBuf,
     port: String,
+    run: RunContext<'a>,
+) -> Result<TestsEnv<'a>> {
+    let mut redirect_options = OpenOptions::new();
+    redirect_options.create(true).write(true).read(true);
+    // with `--quiet-server` the logs are kept out of the way in the cluster's
+    // directory, and only copied out if something went wrong
+    let log_dir = match args.quiet_server {
+        true => temp_dir.path(),
+        false => Path::new(""),
+    };
+    let out_path = path!(log_dir / "postmaster-stdout.temp.log");
+    let out = redirect_options.open(&out_path)?;
+
+    let err_path = path!(log_dir / "postmaster-stderr.temp.log");
+    let err = redirect_options.open(&err_path)?;
+
+    let postgres = path!(bindir / "postgres");
+    // TODO shut down postmaster on drop?
+    let postmaster = std::process::Command::new(postgres)
+        .arg("-D")
+        .arg(&data_dir)
+        .arg("-F")
+        // TODO user config debug flag -d 5
+        .arg("-c")
+        .arg(format!("port={port}"))
+        .stdout(out)
+        .stderr(err)
+        // .arg("-k")
+        // .arg(todo!())
+        .spawn()?;
+
+    let tester = TestsEnv {
+        args,
+        sh,
+        temp_dir: ManuallyDrop::new(temp_dir),
+        bindir,
+        data_dir,
+        postmaster: Mutex::new(postmaster),
+        port,
+        out_path,
+        err_path,
+        passed: AtomicBool::new(false),
+        kept_dbs: Mutex::new(vec![]),
+        finished_dbs: Mutex::new(vec![]),
+        suite_names: Mutex::new(HashMap::new()),
+        role_created: OnceCell::new(),
+        connections: Arc::new(Semaphore::new(args.max_connections.get())),
+        run,
+    };
+    Ok(tester)
 }
 
-impl DbDropper {
-    fn drop(self) -> Result<()> {
-        let DbDropper {
-            dbname,
+impl<'a> TestsEnv<'a> {
+    fn wait_for_postmaster_start(&mut self) -> Result<()> {
+        let timeout = self.args.startup_timeout.get();
+        let TestsEnv {
             sh,
             bindir,
+            postmaster,
             port,
+            ..
         } = self;
-        let dropdb = path!(bindir / "dropdb");
-        cmd!(sh, "{dropdb} -f -p {port} {dbname}").quiet().run()?;
-        Ok(())
+
+        let psql = path!(bindir / "psql");
+
+        let wait_start = Instant::now();
+        while wait_start.elapsed().as_secs() < timeout {
+            let out = cmd!(sh, "{psql} -X postgres -p {port}")
+                .quiet()
+                .ignore_stderr()
+                .ignore_status()
+                .output()?;
+            if out.status.success() {
+                return Ok(());
+            }
+            if let Some(status) = postmaster.get_mut().unwrap().try_wait()? {
+                bail!("postmaster failed with {status}")
+            }
+
+            thread::sleep(Duration::from_micros(1000000))
+        }
+        bail!("postmaster did no respond within {timeout} seconds, see `--startup-timeout`")
     }
-}
 
-impl std::ops::Deref for DbDropper {
-    type Target = str;
+    async fn run_stateless_tests(
+        &self,
+        tests: Vec<TestFile>,
+        shuffle_seed: Option<u64>,
+    ) -> Result<(Vec<(String, Test, FailureInfo)>, Vec<Duration>)> {
+        use tokio::sync::oneshot;
+        let TestsEnv { port, .. } = self;
+        let user = self.db_user();
 
-    fn deref(&self) -> &Self::Target {
-        &self.dbname
+        cprintln!("Stateless tests" bold blue);
+
+        if prelude_without_transaction(self.args, &tests) {
+            bail!("a file's `<!-- prelude ... -->` needs the transaction `--stateless-no-transaction` skips")
+        }
+
+        let db = self.createdb("stateless_test_db".to_string()).await?;
+        let args = Arc::new(self.args.clone());
+        let committed = Arc::new(AtomicBool::new(false));
+        let psql = Arc::new(self.psql());
+        let conninfo = Arc::new(format!(
+            "host=localhost port={port} user={user} dbname=stateless_test_db"
+        ));
+
+        let jobs = self.jobs();
+        let (unused_clients, mut clients) = mpsc::channel(jobs);
+
+        let mut conns: FuturesOrdered<_> = (0..jobs)
+            .map(|_| async {
+                let permit = acquire(&self.connections).await;
+                let conn = tokio_postgres::connect(
+                    &format!("host=localhost port={port} user={user} dbname=stateless_test_db application_name=tests"),
+                    tokio_postgres::NoTls,
+                )
+                .await;
+                conn.map(|conn| (conn, permit))
+            })
+            .collect();
+
+        while let Some(conn) = conns.next().await {
+            let ((client, connection), permit) = conn?;
+            let notices = spawn_connection(connection);
+            unused_clients.try_send((client, notices, permit))?;
+        }
+
+        let num_tests: usize = tests.iter().map(|file| file.tests.len()).sum();
+
+        // shuffled batches of different files are interleaved, so their
+        // results are put back in file order to print each file's together
+        let reorder = args.ordered_output || shuffle_seed.is_some();
+        let mut reorder = reorder.then(ReorderBuffer::new);
+        let mut results = Vec::with_capacity(num_tests);
+        let mut batches = stateless_batches(tests, args.connection_per_file);
+        if let Some(seed) = shuffle_seed {
+            shuffle(&mut batches, seed);
+        }
+        let mut batches = batches.into_iter();
+        let mut started_files = HashSet::new();
+
+        while let Some((mut client, mut notices, permit)) = clients.recv().await {
+            let Batch {
+                file,
+                settings,
+                prelude,
+                single_transaction,
+                hooks,
+                first,
+                tests: batch,
+            } = match batches.next() {
+                None => break,
+                Some(batch) => batch,
+            };
+            // a file's fixtures are set up before any of its tests start, and
+            // torn down once they're all done
+            if let Some(hooks) = &hooks {
+                if !hooks.started.swap(true, Ordering::SeqCst) {
+                    run_file_hooks(&client, &settings, &hooks.before_all)
+                        .await
+                        .with_context(|| format!("the `before-all` blocks of `{file}` failed"))?;
+                }
+            }
+            if started_files.insert(file.clone()) {
+                self.emit(Event::FileStarted { file: &file });
+            }
+            let mut sends = Vec::with_capacity(batch.len());
+            for position in first..first + batch.len() {
+                let (send, recv) = oneshot::channel();
+                sends.push(send);
+                results.push((position, recv));
+            }
+            let prelude = stateless_prelude(&args, &prelude);
+            let unused_clients = unused_clients.clone();
+            let (args, committed) = (args.clone(), committed.clone());
+            let (psql, conninfo) = (psql.clone(), conninfo.clone());
+            let (progress, connections) = (self.run.progress.clone(), self.connections.clone());
+            tokio::spawn(async move {
+                let mut settings_applied = false;
+                let mut began = false;
+                for (test, send) in batch.into_iter().zip(sends) {
+                    if let Some(progress) = &progress {
+                        let (line, header) = (test.line, &test.header);
+                        progress.emit(Event::TestStarted {
+                            file: &file,
+                            line,
+                            header,
+                        });
+                    }
+                    drain_notices(&mut notices);
+                    let applied = apply_settings(&client, &settings, &mut settings_applied).await;
+                    let (ran, raised, plan, duration) = if psql::has_meta_commands(&test.text) {
+                        let end = (!args.stateless_no_transaction).then_some("ROLLBACK");
+                        let null = db_output::null_text(&test);
+                        let sql = match (end, prelude.is_empty()) {
+                            (Some(_), false) => format!("{prelude};\n{}", test.text),
+                            _ => test.text.clone(),
+                        };
+                        let script = psql::script(&settings, &sql, end, null);
+                        let _permit = acquire(&connections).await;
+                        let (result, duration) = timed(psql::run(&psql, &conninfo, &script)).await;
+                        (Ran::Psql(result), vec![], vec![], duration)
+                    } else if let Err(e) = applied {
+                        (Ran::Query(Err(e)), vec![], vec![], Duration::ZERO)
+                    } else if single_transaction {
+                        let run =
+                            run_in_shared_transaction(&client, &test.text, &prelude, &mut began);
+                        let (result, duration) = timed(run).await;
+                        let raised = drain_notices(&mut notices);
+                        let plan = explain_test(&args, &client, &test.text, true, &result).await;
+                        let ran = match (&test.compare_with, result) {
+                            (Some(reference), Ok(output)) => {
+                                let run = run_in_shared_transaction(
+                                    &client, reference, &prelude, &mut began,
+                                );
+                                let reference = run.await;
+                                Ran::Compared { output, reference }
+                            }
+                            (_, result) => {
+                                let first_only = args.first_result_only;
+                                let describe =
+                                    with_savepoint_description(&client, &test, first_only, result);
+                                describe.await
+                            }
+                        };
+                        (ran, raised, plan, duration)
+                    } else if args.stateless_no_transaction {
+                        let (result, duration) = timed(client.simple_query(&test.text)).await;
+                        let raised = drain_notices(&mut notices);
+                        let plan = explain_test(&args, &client, &test.text, false, &result).await;
+                        let first_only = args.first_result_only;
+                        let ran = with_reference(&client, &test, first_only, false, result).await;
+                        (ran, raised, plan, duration)
+                    } else {
+                        let txn = client.transaction().await;
+                        match txn {
+                            Err(e) => (Ran::Query(Err(e)), vec![], vec![], Duration::ZERO),
+                            Ok(txn) => {
+                                let (result, duration) =
+                                    run_after_prelude(&txn, &test.text, &prelude).await;
+                                let raised = drain_notices(&mut notices);
+                                let plan =
+                                    explain_test(&args, txn.client(), &test.text, true, &result)
+                                        .await;
+                                let ran = with_reference(
+                                    txn.client(),
+                                    &test,
+                                    args.first_result_only,
+                                    true,
+                                    result,
+                                )
+                                .await;
+                                if commits_failure(&args, &test, &ran) {
+                                    if txn.commit().await.is_ok() {
+                                        committed.store(true, Ordering::SeqCst);
+                                    }
+                                } else {
+                                    let _ = txn.rollback().await;
+                                }
+                                (ran, raised, plan, duration)
+                            }
+                        }
+                    };
+                    if let Ran::Query(Err(_)) = ran {
+                        let _ = reset_aborted_transaction(&client).await;
+                    }
+                    // the receivers are gone if the run was cut short
+                    let _ = send.send((file.clone(), test, ran, raised, plan, duration));
+                }
+                if began {
+                    let _ = client.batch_execute("ROLLBACK").await;
+                }
+                // the next file shouldn't see this one's settings
+                if settings_applied {
+                    let _ = client.batch_execute("RESET ALL").await;
+                }
+                if let Some(hooks) = hooks {
+                    if hooks.unfinished.fetch_sub(1, Ordering::SeqCst) == 1 {
+                        let after_all = run_file_hooks(&client, &settings, &hooks.after_all);
+                        if let Err(e) = after_all.await {
+                            warning!("the `after-all` blocks of `{file}` failed: {e}");
+                        }
+                    }
+                }
+                let _ = unused_clients.send((client, notices, permit)).await;
+            });
+        }
+
+        let mut current_file = "".to_string();
+        let mut failures = vec![];
+        let mut durations = Vec::with_capacity(num_tests);
+        for (position, result) in results {
+            let result = result.await?;
+            if let (_, _, Ran::Query(Err(error)), ..) = &result {
+                self.check_postmaster(error).await?;
+            }
+            let ready = match &mut reorder {
+                Some(reorder) => reorder.push(position, result),
+                None => vec![result],
+            };
+            for (file_name, test, result, notices, plan, duration) in ready {
+                durations.push(duration);
+                if file_name != current_file {
+                    current_file = file_name.clone();
+                    self.print_file_banner(&current_file);
+                }
+
+                let (line, header) = (test.line, test.header.clone());
+                let status = print_test_result(
+                    self.args,
+                    file_name.clone(),
+                    test,
+                    result,
+                    Diagnostics { notices, plan },
+                    self.run.expected_failures,
+                    &mut failures,
+                );
+                self.test_finished(&TestResultRecord {
+                    file: &file_name,
+                    header: &header,
+                    line,
+                    status,
+                    duration,
+                    failure: last_failure(status, &failures),
+                });
+            }
+        }
+        // every test's result has been released by the last one's
+        assert!(reorder.iter().all(ReorderBuffer::is_empty));
+
+        // the clients come back once their batches, and the `after-all`
+        // blocks of the files they finished, are done
+        drop(unused_clients);
+        while clients.recv().await.is_some() {}
+
+        if committed.load(Ordering::SeqCst) {
+            let (dbname, port) = (&*db, &self.port);
+            ecprintln!(
+                "Kept" bold yellow,
+                " the state of failed stateless tests in `{dbname}`, \
+                inspect it with `psql -p {port} {dbname}`"
+            );
+            self.kept_dbs
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(db);
+        } else {
+            self.finish_db(db).await;
+        }
+
+        Ok((failures, durations))
+    }
+
+    async fn run_stateful_tests(
+        &self,
+        tests: Vec<TestFile>,
+    ) -> Result<(Vec<(String, Test, FailureInfo)>, Vec<Duration>)> {
+        let TestsEnv { port, .. } = self;
+        let user = self.db_user();
+
+        cprintln!("\nStateful tests" bold blue);
+
+        let mut running_tests = FuturesOrdered::new();
+        let mut files = tests.into_iter().rev();
+
+        let test_runner = |test_file: TestFile, db_num: usize| async move {
+            let dbname = format!("stateful-tests-{db_num}");
+            // a file that can't connect fails on its own, the others still run
+            let connected = async {
+                let db = self.createdb(dbname).await?;
+                let dbname = &*db;
+                let permit = acquire(&self.connections).await;
+                let connected = tokio_postgres::connect(
+                    &format!(
+                        "host=localhost port={port} user={user} dbname={dbname} application_name=tests"
+                    ),
+                    tokio_postgres::NoTls,
+                )
+                .await;
+                match connected {
+                    Ok((client, connection)) => Ok((db, permit, client, connection)),
+                    Err(e) => {
+                        drop(permit);
+                        self.finish_db(db).await;
+                        Err(anyhow::Error::from(e))
+                    }
+                }
+            };
+            let (db, permit, mut client, connection) = match connected.await {
+                Ok(connected) => connected,
+                Err(error) => {
+                    let results = unconnected_results(test_file.tests, &error);
+                    return Ok((test_file.name, results));
+                }
+            };
+            let dbname = &*db;
+            let mut notices = spawn_connection(connection);
+            let psql = self.psql();
+            let conninfo = format!("host=localhost port={port} user={user} dbname={dbname}");
+
+            self.emit(Event::FileStarted {
+                file: &test_file.name,
+            });
+            let mut results = Vec::with_capacity(test_file.tests.len());
+            let mut variables = HashMap::new();
+            let mut settings_applied = false;
+
+            for test in test_file.tests {
+                self.emit(Event::TestStarted {
+                    file: &test_file.name,
+                    line: test.line,
+                    header: &test.header,
+                });
+                let text = substitute_variables(&test.text, &variables);
+                drain_notices(&mut notices);
+                let args = self.args;
+                let applied =
+                    apply_settings(&client, &test_file.settings, &mut settings_applied).await;
+                let (result, duration, raised, plan) = if psql::has_meta_commands(&text) {
+                    let end = match test_file.commit_transactional {
+                        true => "COMMIT",
+                        false => "ROLLBACK",
+                    };
+                    let end = test.transactional.then_some(end);
+                    let null = db_output::null_text(&test);
+                    let script = psql::script(&test_file.settings, &text, end, null);
+                    let _permit = acquire(&self.connections).await;
+                    let (result, duration) = timed(psql::run(&psql, &conninfo, &script)).await;
+                    (Ran::Psql(result), duration, vec![], vec![])
+                } else if let Err(e) = applied {
+                    (Ran::Query(Err(e)), Duration::ZERO, vec![], vec![])
+                } else if test.transactional {
+                    let txn = client.transaction().await?;
+                    let (result, duration) = timed(txn.simple_query(&text)).await;
+                    let raised = drain_notices(&mut notices);
+                    let plan = explain_test(args, txn.client(), &text, true, &result).await;
+                    let first_only = args.first_result_only;
+                    let ran = with_reference(txn.client(), &test, first_only, true, result).await;
+                    // later tests see a committed test's effects
+                    if test_file.commit_transactional {
+                        let _ = txn.commit().await;
+                    } else {
+                        let _ = txn.rollback().await;
+                    }
+                    (ran, duration, raised, plan)
+                } else {
+                    // TODO if a stateful test fails to probably invalidates future tests
+                    //      abort here and mark them as skipped somehow?
+                    let (result, duration) = timed(client.simple_query(&text)).await;
+                    let raised = drain_notices(&mut notices);
+                    let plan = explain_test(args, &client, &text, false, &result).await;
+                    let first_only = args.first_result_only;
+                    let ran = with_reference(&client, &test, first_only, false, result).await;
+                    (ran, duration, raised, plan)
+                };
+                if let (Some(name), Some(value)) = (&test.capture, result.first_value()) {
+                    variables.insert(name.clone(), value);
+                }
+                results.push((test, result, raised, plan, duration));
+            }
+
+            if let Some(sql) = &self.args.assert_after_file {
+                let assertion = after_file_assertion(sql);
+                self.emit(Event::TestStarted {
+                    file: &test_file.name,
+                    line: assertion.line,
+                    header: &assertion.header,
+                });
+                let (result, duration) = timed(client.simple_query(sql)).await;
+                let raised = drain_notices(&mut notices);
+                let result = Ran::Query(result);
+                results.push((after_file_assertion(sql), result, raised, vec![], duration));
+            }
+
+            drop(client);
+            drop(permit);
+            self.finish_db(db).await;
+            Ok::<_, anyhow::Error>((test_file.name, results))
+        };
+
+        let mut i = 0;
+        for file in (&mut files).take(self.jobs()) {
+            i += 1;
+            running_tests.push(test_runner(file, i))
+        }
+
+        let mut failures = vec![];
+        let mut durations = vec![];
+
+        loop {
+            let result = running_tests.next().await.transpose()?;
+            if let Some((current_file, result)) = result {
+                self.print_file_banner(&current_file);
+                for (test, result, notices, plan, duration) in result {
+                    durations.push(duration);
+                    if let Ran::Query(Err(error)) = &result {
+                        self.check_postmaster(error).await?;
+                    }
+                    let file = current_file.clone();
+                    let (line, header) = (test.line, test.header.clone());
+                    let status = print_test_result(
+                        self.args,
+                        file,
+                        test,
+                        result,
+                        Diagnostics { notices, plan },
+                        self.run.expected_failures,
+                        &mut failures,
+                    );
+                    self.test_finished(&TestResultRecord {
+                        file: &current_file,
+                        header: &header,
+                        line,
+                        status,
+                        duration,
+                        failure: last_failure(status, &failures),
+                    });
+                }
+            }
+            match files.next() {
+                Some(file) => {
+                    i += 1;
+                    running_tests.push(test_runner(file, i))
+                }
+                None => {
+                    if running_tests.is_empty() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok((failures, durations))
+    }
+
+    /// Prepares every statement of the tests without running them, failing
+    /// with all the syntax errors found. Other errors, such as missing
+    /// tables, aren't reported since the statements creating them haven't
+    /// run.
+    async fn validate_sql(&self, files: impl Iterator<Item = &TestFile>) -> Result<()> {
+        let TestsEnv { port, .. } = self;
+        let user = self.db_user();
+
+        let db = self.createdb("validate_sql_db".to_string()).await?;
+        let permit = acquire(&self.connections).await;
+        let (client, connection) = tokio_postgres::connect(
+            &format!(
+                "host=localhost port={port} user={user} dbname=validate_sql_db application_name=tests"
+            ),
+            tokio_postgres::NoTls,
+        )
+        .await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                cprintln!("Error" bold red, " in postgres connection: {e}");
+            }
+        });
+
+        let mut errors = vec![];
+        for file in files {
+            for (line, statement) in statements_to_validate(file) {
+                let error = match client.prepare(&statement).await {
+                    Ok(_) => continue,
+                    Err(error) => error,
+                };
+                match error.as_db_error() {
+                    Some(e) if *e.code() == SqlState::SYNTAX_ERROR => {
+                        errors.push(format!("{}:{line}: {}", file.name, e.message()))
+                    }
+                    Some(_) => (),
+                    None => return Err(error.into()),
+                }
+            }
+        }
+        drop(client);
+        drop(permit);
+        self.finish_db(db).await;
+
+        if errors.is_empty() {
+            return Ok(());
+        }
+        for error in &errors {
+            ecprintln!("Error" bold red, ": {error}");
+        }
+        let num_errors = errors.len();
+        bail!("{num_errors} statements failed to parse, not running the tests")
+    }
+
+    /// Checks whether a query failed because the postmaster died, in which
+    /// case every following query would fail as well.
+    async fn check_postmaster(&self, error: &tokio_postgres::Error) -> Result<()> {
+        if !error.is_closed() {
+            return Ok(());
+        }
+        // the connection may notice the crash a little before we can
+        let exit = postmaster_exited(&self.postmaster);
+        match tokio::time::timeout(Duration::from_secs(1), exit).await {
+            Ok(status) => Err(self.postmaster_crash(status?)),
+            Err(_) => Ok(()),
+        }
+    }
+
+    fn postmaster_crash(&self, status: ExitStatus) -> anyhow::Error {
+        let log = std::fs::read_to_string(&self.err_path).unwrap_or_default();
+        let tail = last_lines(&log, 10);
+        anyhow!("postmaster exited unexpectedly ({status}); see postmaster-err.log\n{tail}")
+    }
+
+    async fn createdb(&self, dbname: String) -> Result<DbDropper> {
+        let Self { bindir, port, .. } = self;
+
+        let sh = Shell::new()?;
+        let _permit = acquire(&self.connections).await;
+
+        let psql = path!(bindir / "psql");
+
+        if let Some(role) = role_to_create(self.args) {
+            self.role_created.get_or_try_init(|| {
+                let create_role = create_role_if_missing(role);
+                let maintenance_db = &self.args.maintenance_db;
+                cmd!(sh, "{psql} -X -p {port} -c {create_role} {maintenance_db}")
+                    .quiet()
+                    .ignore_stdout()
+                    .run()
+            })?;
+        }
+
+        let createdb = path!(bindir / "createdb");
+        let owner = self.args.db_owner.as_deref();
+        let owner_args: Vec<_> = owner.into_iter().flat_map(|owner| ["-O", owner]).collect();
+        let maintenance_args = maintenance_args(self.args);
+        retry_transient(CREATEDB_ATTEMPTS, RETRY_DELAY, || {
+            let owner_args = &owner_args;
+            run_for_stderr(cmd!(
+                sh,
+                "{createdb} -p {port} {maintenance_args...} {owner_args...} {dbname}"
+            ))
+        })
+        .await
+        .with_context(|| format!("could not create database `{dbname}`"))?;
+
+        Ok(DbDropper {
+            dbname,
+            sh,
+            bindir: bindir.to_string(),
+            port: port.to_string(),
+            maintenance_db: self.args.maintenance_db.clone(),
+            attempts: self.args.drop_attempts.get(),
+            connections: self.connections.clone(),
+        })
+    }
+}
+
+impl TestsEnv<'_> {
+    /// Hands a database the tests are done with to `--teardown`, which
+    /// drops it right away unless it could be kept.
+    async fn finish_db(&self, db: DbDropper) {
+        if self.args.teardown == Teardown::Always {
+            return db.drop().await;
+        }
+        self.finished_dbs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(db);
+    }
+
+    /// Drops the databases the tests are done with, or keeps them until the
+    /// next run if `--teardown` says so.
+    async fn tear_down_dbs(&self, passed: bool) {
+        let finished = std::mem::take(
+            &mut *self
+                .finished_dbs
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        if !self.args.teardown.keeps(passed) {
+            for db in finished {
+                db.drop().await;
+            }
+            return;
+        }
+        if !finished.is_empty() {
+            let names: Vec<_> = finished.iter().map(|db| format!("`{}`", &**db)).collect();
+            let (names, port) = (names.join(", "), &self.port);
+            ecprintln!(
+                "Kept" bold yellow,
+                " the databases {names}, inspect them with `psql -p {port} <database>`"
+            );
+        }
+        self.kept_dbs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .extend(finished);
+    }
+
+    /// The `psql` tests with meta-commands are run through.
+    fn psql(&self) -> PathBuf {
+        let bindir = self.bindir;
+        path!(bindir / "psql")
+    }
+
+    /// The role tests connect as, the owner of the scratch databases if one
+    /// was requested.
+    fn db_user(&self) -> &str {
+        self.args.db_owner.as_deref().unwrap_or("postgres")
+    }
+
+    /// How many connections run tests at once, leaving one of the
+    /// `--max-connections` for the commands run alongside them.
+    fn jobs(&self) -> usize {
+        jobs_within(self.args.jobs.get(), self.args.max_connections.get())
+    }
+}
+
+/// `jobs` capped to leave one of `max_connections` free.
+fn jobs_within(jobs: usize, max_connections: usize) -> usize {
+    jobs.min(max_connections - 1).max(1)
+}
+
+/// Waits for one of the `--max-connections` to be free, keeping it until the
+/// permit is dropped.
+async fn acquire(connections: &Arc<Semaphore>) -> OwnedSemaphorePermit {
+    connections
+        .clone()
+        .acquire_owned()
+        .await
+        .expect("the connections are never closed")
+}
+
+/// The arguments telling `createdb` which database to connect to.
+fn maintenance_args(args: &Args) -> [&str; 2] {
+    ["--maintenance-db", &args.maintenance_db]
+}
+
+/// How many times `createdb` is tried before giving up.
+const CREATEDB_ATTEMPTS: u32 = 5;
+
+/// How long to wait before retrying a database's creation or removal, which
+/// doubles after each attempt.
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Parts of the errors of database creation or removal that can go away on
+/// their own, such as when another session is briefly connected to it.
+const TRANSIENT_ERRORS: &[&str] = &[
+    "is being accessed by other users",
+    "the database system is starting up",
+    "could not connect to server",
+    "connection to server",
+];
+
+/// Runs `attempt` until it succeeds, fails with an error that isn't one of
+/// the `TRANSIENT_ERRORS`, or has been tried `attempts` times,
+/// waiting twice as long after each failure as the one before.
+async fn retry_transient<T>(
+    attempts: u32,
+    first_delay: Duration,
+    mut attempt: impl FnMut() -> Result<T, String>,
+) -> Result<T> {
+    let mut delay = first_delay;
+    for remaining in (0..attempts).rev() {
+        match attempt() {
+            Ok(value) => return Ok(value),
+            Err(e) if remaining > 0 && TRANSIENT_ERRORS.iter().any(|t| e.contains(t)) => {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            Err(e) => bail!("{e}"),
+        }
+    }
+    bail!("no attempts were made")
+}
+
+/// Runs `command` quietly, failing with what it wrote to stderr if it exits
+/// unsuccessfully, so [`retry_transient`] can tell why.
+fn run_for_stderr(command: Cmd<'_>) -> Result<(), String> {
+    let output = command
+        .quiet()
+        .ignore_status()
+        .output()
+        .map_err(|e| e.to_string())?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(String::from_utf8_lossy(&output.stderr).trim().to_string()),
+    }
+}
+
+/// A libpq connection URI for `dbname` on the local server.
+fn connection_string(user: &str, port: &str, dbname: &str) -> String {
+    format!("postgresql://{user}@localhost:{port}/{dbname}")
+}
+
+/// Configuration the runner adds to `postgresql.conf` unless overridden by
+/// `--set`.
+const DEFAULT_SETTINGS: &[(&str, &str)] = &[
+    ("log_autovacuum_min_duration", "0"),
+    ("log_checkpoints", "on"),
+    ("log_line_prefix", "'%m %b[%p] %q%a '"),
+    ("log_lock_waits", "on"),
+    ("log_temp_files", "128kB"),
+    ("max_prepared_transactions", "2"),
+];
+
+/// A `postgresql.conf` parameter set with `--set NAME=VALUE`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Setting {
+    name: String,
+    value: String,
+}
+
+impl FromStr for Setting {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, value) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("setting must be of the form `NAME=VALUE`, found `{s}`"))?;
+        let name = name.trim();
+        if name.is_empty() {
+            bail!("setting `{s}` is missing a name")
+        }
+        Ok(Self {
+            name: name.to_string(),
+            value: value.trim().to_string(),
+        })
+    }
+}
+
+/// The settings added to `postgresql.conf` besides the defaults: `--set`'s,
+/// after the `statement_timeout` of `--timeout` so that they can replace it.
+fn conf_settings(args: &Args) -> Vec<Setting> {
+    let timeout = args.timeout.map(|secs| Setting {
+        name: "statement_timeout".to_string(),
+        value: format!("'{secs}s'"),
+    });
+    timeout.into_iter().chain(args.settings.clone()).collect()
+}
+
+/// The configuration appended to `postgresql.conf`: the defaults, with any
+/// the user set replaced in place, followed by the user's other settings.
+/// Parameter names are case-insensitive, and the last `--set` of one wins.
+fn conf_block(settings: &[Setting]) -> String {
+    let mut merged: Vec<(&str, &str)> = DEFAULT_SETTINGS.to_vec();
+    for Setting { name, value } in settings {
+        match merged
+            .iter_mut()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        {
+            Some(existing) => existing.1 = value,
+            None => merged.push((name, value)),
+        }
+    }
+    let mut block = "\n# Configuration added by test runner".to_string();
+    for (name, value) in merged {
+        block.push_str(&format!("\n{name} = {value}"));
+    }
+    block
+}
+
+/// The settings of a `postgresql.conf`, one `name = value` per line in the
+/// order they first appear, with the value of their last appearance as the
+/// server takes it. Comments and blank lines are left out.
+fn resolved_conf(conf: &str) -> String {
+    let mut settings: Vec<(&str, &str)> = vec![];
+    for line in conf.lines() {
+        let line = strip_conf_comment(line).trim();
+        let Some(split) = line.find(|c: char| c == '=' || c.is_whitespace()) else {
+            continue;
+        };
+        let (name, value) = line.split_at(split);
+        let value = value.trim_start().trim_start_matches('=').trim();
+        match settings
+            .iter_mut()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        {
+            Some(existing) => existing.1 = value,
+            None => settings.push((name, value)),
+        }
+    }
+    settings
+        .into_iter()
+        .map(|(name, value)| format!("{name} = {value}\n"))
+        .collect()
+}
+
+/// `line` up to its `#` comment, if it has one outside of a quoted value.
+fn strip_conf_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '\'' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => (),
+        }
+    }
+    line
+}
+
+/// The role to create before the first database, unless it already exists,
+/// so that tests can connect as it.
+fn role_to_create(args: &Args) -> Option<&str> {
+    match args.no_create_role {
+        true => None,
+        false => Some(args.db_owner.as_deref().unwrap_or("postgres")),
+    }
+}
+
+/// SQL creating a login role unless one of that name already exists.
+fn create_role_if_missing(role: &str) -> String {
+    let ident = role.replace('"', "\"\"");
+    let literal = role.replace('\'', "''");
+    format!(
+        "DO $$ BEGIN \
+            IF NOT EXISTS (SELECT FROM pg_roles WHERE rolname = '{literal}') THEN \
+                CREATE ROLE \"{ident}\" WITH LOGIN; \
+            END IF; \
+        END $$;"
+    )
+}
+
+/// Returns the first column of the first row in a query's output.
+pub(crate) fn first_value(messages: &[SimpleQueryMessage]) -> Option<String> {
+    messages.iter().find_map(|message| match message {
+        SimpleQueryMessage::Row(row) => Some(row.try_get(0).ok()??.to_string()),
+        _ => None,
+    })
+}
+
+/// A value for the `{{name}}` placeholders of the tests' SQL, set with
+/// `--define NAME=VALUE`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Define {
+    name: String,
+    value: String,
+}
+
+impl FromStr for Define {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, value) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("define must be of the form `NAME=VALUE`, found `{s}`"))?;
+        let name = name.trim();
+        if !is_placeholder_name(name) {
+            bail!("`{name}` isn't a valid name, names may only contain [a-zA-Z0-9_] and can't start with a digit")
+        }
+        Ok(Self {
+            name: name.to_string(),
+            value: value.trim().to_string(),
+        })
+    }
+}
+
+fn is_placeholder_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Replaces the `{{name}}` placeholders of the tests' SQL, and of their
+/// `compare-with` queries, with the values of `defines`, failing with every
+/// placeholder that isn't defined, whether or not any defines were given.
+fn expand_defines(defines: &[Define], mut files: Vec<TestFile>) -> Result<Vec<TestFile>> {
+    let mut undefined = vec![];
+    for file in &mut files {
+        for test in &mut file.tests {
+            let sql = std::iter::once(&mut test.text).chain(test.compare_with.as_mut());
+            for sql in sql {
+                match expand_placeholders(sql, defines) {
+                    Ok(expanded) => *sql = expanded,
+                    Err(name) => {
+                        let (file, line) = (&file.name, test.line);
+                        undefined.push(format!("`{file}:{line}`: `{{{{{name}}}}}`"));
+                    }
+                }
+            }
+        }
+    }
+    if !undefined.is_empty() {
+        let undefined = undefined.join("\n");
+        bail!("placeholders without a `--define`:\n{undefined}")
+    }
+    Ok(files)
+}
+
+/// `sql` with each `{{name}}` replaced by the value of the last define of
+/// `name`, or the name of the first placeholder that has none.
+fn expand_placeholders(sql: &str, defines: &[Define]) -> Result<String, String> {
+    let mut output = String::with_capacity(sql.len());
+    let mut rest = sql;
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let placeholder = after
+            .split_once("}}")
+            .map(|(name, _)| name.trim())
+            .filter(|name| is_placeholder_name(name));
+        let Some(name) = placeholder else {
+            output.push_str("{{");
+            rest = after;
+            continue;
+        };
+        let define = defines.iter().rev().find(|define| define.name == name);
+        match define {
+            Some(define) => output.push_str(&define.value),
+            None => return Err(name.to_string()),
+        }
+        rest = &after[after.find("}}").unwrap() + 2..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Interpolates captured variables into a test's SQL in the manner of psql;
+/// `:name` is replaced by the value verbatim and `:'name'` by the value as a
+/// string literal. Type casts, unknown names, and anything within strings,
+/// quoted identifiers, comments or dollar-quoted bodies are left untouched.
+pub(crate) fn substitute_variables<'s>(
+    sql: &'s str,
+    variables: &HashMap<String, String>,
+) -> Cow<'s, str> {
+    if variables.is_empty() {
+        return Cow::Borrowed(sql);
+    }
+
+    let mut output = String::with_capacity(sql.len());
+    let mut pos = 0;
+    while let Some(c) = sql[pos..].chars().next() {
+        let rest = &sql[pos..];
+        let len = match c {
+            ':' if rest.starts_with("::") => 2,
+            ':' => match substitution(&rest[1..], variables) {
+                Some((len, value)) => {
+                    output.push_str(&value);
+                    pos += 1 + len;
+                    continue;
+                }
+                None => 1,
+            },
+            c => statements::quoted_or_comment_len(sql, pos).unwrap_or(c.len_utf8()),
+        };
+        output.push_str(&rest[..len]);
+        pos += len;
+    }
+    Cow::Owned(output)
+}
+
+/// What the `name` or `'name'` following a `:` in `reference` is replaced
+/// by, and how long it is, if `name` is one of the `variables`.
+fn substitution(reference: &str, variables: &HashMap<String, String>) -> Option<(usize, String)> {
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let (quoted, name_start) = match reference.strip_prefix('\'') {
+        Some(name_start) => (true, name_start),
+        None => (false, reference),
+    };
+    let name_len = name_start
+        .find(|c| !is_name_char(c))
+        .unwrap_or(name_start.len());
+    let value = variables.get(&name_start[..name_len])?;
+    match (quoted, name_start[name_len..].starts_with('\'')) {
+        (false, _) => Some((name_len, value.clone())),
+        (true, true) => Some((name_len + 2, format!("'{}'", value.replace('\'', "''")))),
+        (true, false) => None,
+    }
+}
+
+/// Each statement of a file's tests along with the line it starts on, for
+/// `--validate-sql`. Captured variables are replaced by `NULL` since their
+/// values are only known once the tests run, and tests run through `psql`
+/// are skipped since the server can't parse their meta-commands.
+fn statements_to_validate(file: &TestFile) -> Vec<(usize, String)> {
+    let variables: HashMap<String, String> = file
+        .tests
+        .iter()
+        .filter_map(|test| test.capture.clone())
+        .map(|name| (name, "NULL".to_string()))
+        .collect();
+    let mut statements = vec![];
+    for test in &file.tests {
+        let text = substitute_variables(&test.text, &variables);
+        if psql::has_meta_commands(&text) {
+            continue;
+        }
+        for statement in split_statements(&text) {
+            // the SQL starts on the line after the code block's opening fence
+            let line = test.line + 1 + statement.line;
+            statements.push((line, statement.text.to_string()));
+        }
+    }
+    statements
+}
+
+/// Whether a stateless test's transaction should be committed instead of
+/// rolled back, which `--no-rollback-on-failure` does when the test received
+/// the wrong output. Transactions of tests with errors are aborted and have
+/// no state to commit.
+fn commits_failure(args: &Args, test: &Test, result: &Ran) -> bool {
+    let failed = |result| matches!(result, db_output::TestResult::Failed(_));
+    match result {
+        Ran::Query(Ok(messages)) if args.no_rollback_on_failure => {
+            failed(validate_output(messages, test, args))
+        }
+        Ran::Compared {
+            output,
+            reference: Ok(reference),
+        } if args.no_rollback_on_failure => {
+            let mut test = test.clone();
+            expect_reference(args, &mut test, reference);
+            failed(validate_output(output, &test, args))
+        }
+        _ => false,
+    }
+}
+
+/// The pseudo-test for `--assert-after-file`, which passes when its query
+/// returns no rows.
+fn after_file_assertion(sql: &str) -> Test {
+    Test {
+        header: "`--assert-after-file`".to_string(),
+        text: sql.to_string(),
+        check_headers: Some(false),
+        ..Default::default()
+    }
+}
+
+/// What running a test returned, from the server, or from `psql` for tests
+/// with meta-commands.
+pub(crate) enum Ran {
+    Query(Result<Vec<SimpleQueryMessage>, tokio_postgres::Error>),
+    Psql(Result<(Vec<String>, Vec<Vec<String>>), String>),
+    /// a successful test with a `compare-with` query, and what that returned
+    Compared {
+        output: Vec<SimpleQueryMessage>,
+        reference: Result<Vec<SimpleQueryMessage>, tokio_postgres::Error>,
+    },
+    /// a test that didn't run since its file couldn't connect to its
+    /// database, with the error
+    Unconnected(String),
+    /// a successful test with `cols` that returned no rows, and the number
+    /// of columns its statement is described as returning
+    Described {
+        output: Vec<SimpleQueryMessage>,
+        columns: Result<usize, tokio_postgres::Error>,
+    },
+}
+
+impl Ran {
+    /// The first column of the first row, for `capture`.
+    pub(crate) fn first_value(&self) -> Option<String> {
+        match self {
+            Ran::Query(Ok(messages))
+            | Ran::Compared {
+                output: messages, ..
+            }
+            | Ran::Described {
+                output: messages, ..
+            } => first_value(messages),
+            Ran::Psql(Ok((_, rows))) => rows.first()?.first().cloned(),
+            Ran::Query(Err(_)) | Ran::Psql(Err(_)) | Ran::Unconnected(_) => None,
+        }
+    }
+}
+
+/// A stateful test with what it returned, the notices it raised, its plan
+/// and how long it took.
+type StatefulResult = (Test, Ran, Vec<String>, Vec<String>, Duration);
+
+/// The results of a file's tests when it couldn't connect to its database,
+/// each failing with the error.
+fn unconnected_results(tests: Vec<Test>, error: &anyhow::Error) -> Vec<StatefulResult> {
+    let error = format!("{error:#}");
+    tests
+        .into_iter()
+        .map(|test| {
+            let ran = Ran::Unconnected(error.clone());
+            (test, ran, vec![], vec![], Duration::ZERO)
+        })
+        .collect()
+}
+
+/// What a test returned, running its `compare-with` query on `client` after
+/// it if it succeeded, so that the query sees the same state the test did.
+pub(crate) async fn with_reference(
+    client: &Client,
+    test: &Test,
+    first_result_only: bool,
+    in_transaction: bool,
+    result: Result<Vec<SimpleQueryMessage>, tokio_postgres::Error>,
+) -> Ran {
+    match (&test.compare_with, result) {
+        (Some(reference), Ok(output)) => Ran::Compared {
+            output,
+            reference: client.simple_query(reference).await,
+        },
+        (_, result) if in_transaction => {
+            with_savepoint_description(client, test, first_result_only, result).await
+        }
+        (_, result) => with_description(client, test, first_result_only, result).await,
+    }
+}
+
+/// What a test returned, with the number of columns its statements are
+/// described as returning if it has `cols` but returned no rows, since the
+/// simple query protocol only gives the columns of a row. That's the first
+/// statement returning any, or only the first statement's with
+/// `first_result_only`.
+async fn with_description(
+    client: &Client,
+    test: &Test,
+    first_result_only: bool,
+    result: Result<Vec<SimpleQueryMessage>, tokio_postgres::Error>,
+) -> Ran {
+    let output = match result {
+        Ok(output) if test.column_count.is_some() => output,
+        result => return Ran::Query(result),
+    };
+    if output
+        .iter()
+        .any(|message| matches!(message, SimpleQueryMessage::Row(_)))
+    {
+        return Ran::Query(Ok(output));
+    }
+    let statements = split_statements(&test.text);
+    let statements = match first_result_only {
+        true => &statements[..statements.len().min(1)],
+        false => &statements[..],
+    };
+    let mut columns = Ok(0);
+    for statement in statements {
+        match client.prepare(statement.text).await {
+            Ok(prepared) if prepared.columns().is_empty() => continue,
+            Ok(prepared) => {
+                columns = Ok(prepared.columns().len());
+                break;
+            }
+            Err(e) => {
+                columns = Err(e);
+                break;
+            }
+        }
+    }
+    Ran::Described { output, columns }
+}
+
+/// Makes what a test's `compare-with` query returned its expected output.
+fn expect_reference(args: &Args, test: &mut Test, reference: &[SimpleQueryMessage]) {
+    let null = db_output::null_text(test);
+    let (headers, rows) = db_output::received_rows(reference, args.first_result_only, null);
+    test.output_headers = headers;
+    test.output = rows;
+}
+
+/// What's shown of a test besides whether it passed.
+#[derive(Default)]
+struct Diagnostics {
+    /// the notices the test raised, checked against those it expects
+    notices: Vec<String>,
+    /// the plan `--explain` printed under the test's result
+    plan: Vec<String>,
+}
+
+/// Checks what a test returned against what it expects, other than the
+/// notices it raised. What a `compare-with` query returned becomes its
+/// expected output.
+pub(crate) fn validate_ran(args: &Args, test: &mut Test, result: Ran) -> db_output::TestResult {
+    if let Ran::Compared {
+        reference: Ok(reference),
+        ..
+    } = &result
+    {
+        expect_reference(args, test, reference);
+    }
+    let test = &*test;
+    let tags = match &result {
+        Ran::Query(Ok(output)) | Ran::Compared { output, .. } | Ran::Described { output, .. } => {
+            db_output::validate_command_tags(test, output)
+        }
+        Ran::Query(Err(_)) | Ran::Psql(_) | Ran::Unconnected(_) => db_output::TestResult::Passed,
+    };
+    let result = match result {
+        Ran::Query(Err(e)) => db_output::validate_error(test, e),
+        Ran::Query(Ok(query_result)) => validate_output(&query_result, test, args),
+        Ran::Psql(Err(e)) => db_output::validate_psql_error(test, e),
+        Ran::Psql(Ok((headers, rows))) => db_output::validate_rows(test, headers, rows, args),
+        Ran::Compared {
+            output,
+            reference: Ok(_),
+        } => validate_output(&output, test, args),
+        Ran::Compared {
+            reference: Err(e), ..
+        } => db_output::TestResult::Failed(FailureInfo::ReferenceError(e)),
+        Ran::Described {
+            columns: Ok(columns),
+            ..
+        } => db_output::check_column_count(test, columns),
+        Ran::Described {
+            columns: Err(e), ..
+        } => db_output::TestResult::Failed(FailureInfo::QueryError(e)),
+        Ran::Unconnected(e) => db_output::TestResult::Failed(FailureInfo::ConnectionError(e)),
+    };
+    match result {
+        db_output::TestResult::Passed => tags,
+        failed => failed,
+    }
+}
+
+fn print_test_result(
+    args: &Args,
+    file_name: String,
+    mut test: Test,
+    result: Ran,
+    Diagnostics { notices, plan }: Diagnostics,
+    expected_failures: &ExpectedFailures,
+    failures: &mut Vec<(String, Test, FailureInfo)>,
+) -> TestStatus {
+    if let Some(out_dir) = &args.out_dir {
+        let path = out_file_path(out_dir, &file_name, test.line);
+        if let Err(e) = write_out_file(&path, &received_output(args, &test, &result)) {
+            let path = path.display();
+            warning!("could not write `{path}`: {e}");
+        }
+    }
+
+    let result = validate_ran(args, &mut test, result);
+    let result = match result {
+        db_output::TestResult::Passed => db_output::validate_notices(&test, notices),
+        failed => failed,
+    };
+    let header = &test.header;
+    cprint!("test {header}... ");
+    // expected failures are still recorded, the summary leaves them out
+    let expected_to_fail = expected_failures.contains(&file_name, &test);
+    let status = match result {
+        db_output::TestResult::Passed if expected_to_fail => {
+            cprintln!("XPASS" bold yellow);
+            let line = test.line;
+            warning!("`{file_name}:{line}` passed but was expected to fail");
+            TestStatus::XPassed
+        }
+        db_output::TestResult::Passed => {
+            cprintln!("ok" green);
+            TestStatus::Passed
+        }
+        db_output::TestResult::Failed(failure) if expected_to_fail => {
+            failures.push((file_name, test, failure));
+            cprintln!("XFAIL" yellow);
+            TestStatus::XFailed
+        }
+        db_output::TestResult::Failed(failure) => {
+            failures.push((file_name, test, failure));
+            cprintln!("FAILED" bold red);
+            TestStatus::Failed
+        }
+    };
+    for line in plan {
+        cprintln!("    {line}");
+    }
+    status
+}
+
+/// Why the test `print_test_result` last gave the `status` of failed, the
+/// failure it pushed, if it did.
+fn last_failure(
+    status: TestStatus,
+    failures: &[(String, Test, FailureInfo)],
+) -> Option<&FailureInfo> {
+    match status {
+        TestStatus::Failed | TestStatus::XFailed => failures.last().map(|(.., failure)| failure),
+        TestStatus::Passed | TestStatus::XPassed => None,
+    }
+}
+
+/// The plan of a test that ran successfully, if `--explain` or
+/// `--explain-analyze` asked for it, or why it couldn't be explained.
+async fn explain_test(
+    args: &Args,
+    client: &Client,
+    sql: &str,
+    in_transaction: bool,
+    result: &Result<Vec<SimpleQueryMessage>, tokio_postgres::Error>,
+) -> Vec<String> {
+    if !(args.explain || args.explain_analyze) || result.is_err() {
+        return vec![];
+    }
+    match explain::explain(client, sql, in_transaction, args.explain_analyze).await {
+        Ok(plan) => plan,
+        Err(e) => vec![format!("could not explain: {e}")],
+    }
+}
+
+/// Rolls back the transaction `client` is in if it was aborted by an error,
+/// so the connection can run the next test. This happens when a test that
+/// failed opened its own transaction, or the rollback after it failed.
+async fn reset_aborted_transaction(client: &Client) -> Result<(), tokio_postgres::Error> {
+    match client.simple_query("SELECT 1").await {
+        Err(e) if e.code() == Some(&SqlState::IN_FAILED_SQL_TRANSACTION) => {
+            client.simple_query("ROLLBACK").await?;
+            Ok(())
+        }
+        result => result.map(drop),
+    }
+}
+
+/// Drives a connection, forwarding the messages of the notices it receives.
+/// A query's notices arrive before its result, so they've all been
+/// forwarded by the time the query returns.
+fn spawn_connection(
+    mut connection: tokio_postgres::Connection<Socket, NoTlsStream>,
+) -> mpsc::UnboundedReceiver<String> {
+    let (send, notices) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut messages = futures::stream::poll_fn(move |cx| connection.poll_message(cx));
+        while let Some(message) = messages.next().await {
+            match message {
+                Ok(AsyncMessage::Notice(notice)) => {
+                    let _ = send.send(notice.message().to_string());
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    cprintln!("Error" bold red, " in postgres connection: {e}");
+                    break;
+                }
+            }
+        }
+    });
+    notices
+}
+
+/// The notices forwarded since the last time they were drained.
+fn drain_notices(notices: &mut mpsc::UnboundedReceiver<String>) -> Vec<String> {
+    let mut drained = vec![];
+    while let Ok(notice) = notices.try_recv() {
+        drained.push(notice);
+    }
+    drained
+}
+
+/// Where `--out-dir` puts the output received by the test at `line` of
+/// `file_name`. Parent directories in the name are replaced by `_` and the
+/// root is dropped, keeping every file within `out_dir`.
+fn out_file_path(out_dir: &Path, file_name: &str, line: usize) -> PathBuf {
+    use std::path::Component;
+
+    let mut path = out_dir.to_path_buf();
+    for component in Path::new(file_name).components() {
+        match component {
+            Component::Normal(name) => path.push(name),
+            Component::ParentDir => path.push("_"),
+            Component::RootDir | Component::Prefix(_) | Component::CurDir => (),
+        }
+    }
+    path.join(format!("{line}.out"))
+}
+
+/// The output a test received as psql would show it, or the error it failed
+/// with.
+fn received_output(args: &Args, test: &Test, result: &Ran) -> String {
+    let (headers, rows) = match result {
+        Ran::Query(Ok(messages))
+        | Ran::Compared {
+            output: messages, ..
+        }
+        | Ran::Described {
+            output: messages, ..
+        } => db_output::received_rows(messages, args.first_result_only, db_output::null_text(test)),
+        Ran::Psql(Ok((headers, rows))) => (headers.clone(), rows.clone()),
+        Ran::Query(Err(e)) => return format!("{e}\n"),
+        Ran::Psql(Err(e)) | Ran::Unconnected(e) => return format!("{e}\n"),
+    };
+    // without rows there are no column names, use the expected ones instead
+    let headers = match headers.is_empty() {
+        true => &test.output_headers,
+        false => &headers,
+    };
+    let table = db_output::render_psql_table(&rows, headers);
+    match rows.len() {
+        1 => format!("{table}(1 row)\n"),
+        n => format!("{table}({n} rows)\n"),
+    }
+}
+
+fn write_out_file(path: &Path, contents: &str) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, contents)
+}
+
+impl<'a> Drop for TestsEnv<'a> {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        use nix::{
+            sys::signal::{kill, SIGTERM},
+            unistd::Pid,
+        };
+
+        let postmaster = self
+            .postmaster
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        let pid = postmaster.id();
+        let passed = *self.passed.get_mut();
+        let discard_output = self.args.quiet_server && passed;
+        let copy_output_locally = || {
+            if discard_output {
+                return;
+            }
+
+            let out_file = "postmaster-out.log".to_string();
+            match move_file(&self.out_path, &out_file) {
+                Ok(_) => ecprintln!("Postmaster stdout" bold blue, " can be found in {out_file}"),
+                Err(err) => cprintln!(
+                    "Error" bold red,
+                    " could not copy postmaster stdout from `postmaster-stdout.temp.log` due to {err}"
+                ),
+            };
+
+            let err_file = "postmaster-err.log".to_string();
+            let _ = move_file(&self.err_path, &err_file).map_err(|err| {
+                ecprintln!(
+                    "Error" bold red,
+                    " could not copy postmaster stderr from `postmaster-stderr.temp.log` due to {err}"
+                )
+            });
+            ecprintln!("Postmaster stderr" bold blue, " can be found in {err_file}");
+        };
+        match postmaster.try_wait() {
+            // TODO log output location?
+            Ok(Some(_)) => copy_output_locally(),
+            Ok(None) => {
+                ecprint!("Stopping postmaster" bold blue, "... ");
+                #[cfg(unix)]
+                let result = kill(Pid::from_raw(pid as i32), SIGTERM);
+
+                // TODO do this on unix also if the term fails?
+                #[cfg(not(unix))]
+                let result = postmaster.kill();
+
+                // FIXME anything to do with this error?
+                match result {
+                    Err(error) => {
+                        ecprintln!(
+                            "ERROR:" bold red,
+                            " could not kill postmaster at pid {pid} due to {error}",
+                        );
+                        // TODO terminate if kill doesn't work?
+                    }
+                    Ok(_) => {
+                        // TODO timeout
+                        let _ = postmaster.wait();
+                        eprintln!("stopped");
+                        copy_output_locally();
+                        if self.args.teardown.keeps(passed) {
+                            let dir = self.temp_dir.path().display();
+                            let port = &self.port;
+                            ecprintln!(
+                                "Kept" bold yellow,
+                                " the cluster's directory at {dir}, restart it with \
+                                `postgres -D {dir}/data -p {port}`"
+                            );
+                        } else {
+                            unsafe {
+                                ManuallyDrop::drop(&mut self.temp_dir);
+                            }
+                        }
+                    }
+                }
+            }
+            Err(err) => ecprintln!(
+                "Error" bold red, " could not shut down postgres due to {err}"
+            ),
+        }
+    }
+}
+
+#[must_use]
+struct DbDropper {
+    dbname: String,
+    sh: Shell,
+    bindir: String,
+    port: String,
+    maintenance_db: String,
+    /// how many times `dropdb` is tried, from `--drop-attempts`
+    attempts: u32,
+    /// the `--max-connections` `dropdb` waits for one of
+    connections: Arc<Semaphore>,
+}
+
+impl DbDropper {
+    /// Drops the database, retrying while a connection that's closing still
+    /// holds it. Failing to is only a warning, so it doesn't hide the tests'
+    /// results.
+    async fn drop(self) {
+        let DbDropper {
+            dbname,
+            sh,
+            bindir,
+            port,
+            maintenance_db,
+            attempts,
+            connections,
+        } = self;
+        let _permit = acquire(&connections).await;
+        let dropdb = path!(bindir / "dropdb");
+        let result = retry_transient(attempts, RETRY_DELAY, || {
+            run_for_stderr(cmd!(
+                sh,
+                "{dropdb} -f -p {port} --maintenance-db {maintenance_db} {dbname}"
+            ))
+        })
+        .await;
+        if let Err(e) = result {
+            warning!("could not drop database `{dbname}`: {e}");
+        }
+    }
+}
+
+impl std::ops::Deref for DbDropper {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        &self.dbname
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Shard;
+    use crate::test::test_file;
+    use crate::TestFile;
+    use pretty_assertions::assert_eq;
+
+    fn suite() -> Vec<TestFile> {
+        vec![
+            test_file("b.md", true, &[9, 3, 6]),
+            test_file("a.md", true, &[1, 2]),
+            test_file("c.rs", false, &[4, 8]),
+            test_file("d.rs", false, &[5]),
+        ]
+    }
+
+    fn test_ids(files: &[TestFile]) -> Vec<(String, usize)> {
+        files
+            .iter()
+            .flat_map(|file| file.tests.iter().map(|t| (file.name.clone(), t.line)))
+            .collect()
+    }
+
+    /// Runs the `files`, given by name and contents, on a server of its own
+    /// with the `flags`, returning the run's result and the file, line and
+    /// status of each test in the order they finished. What the tests using
+    /// it need is in [`crate::test`].
+    fn run_files(
+        flags: &[&str],
+        files: &[(&str, &str)],
+    ) -> (anyhow::Result<()>, Vec<(String, usize, super::TestStatus)>) {
+        use super::{run, TestResultRecord};
+        use crate::{extract_all_tests_from_file, Args};
+        use clap::Parser;
+
+        // failures are recorded with the files, not in the working directory
+        let dir = tempfile::tempdir().unwrap();
+        let failures_file = dir.path().join("failed-tests.txt");
+        let mut args = vec!["test", "--failures-file", failures_file.to_str().unwrap()];
+        args.extend(flags);
+        args.push(dir.path().to_str().unwrap());
+        let args = Args::parse_from(args);
+        let files = files.iter().map(|(name, contents)| {
+            extract_all_tests_from_file(name, contents, Default::default()).unwrap()
+        });
+
+        let mut records = vec![];
+        let mut on_result = |record: &TestResultRecord| {
+            records.push((record.file.to_string(), record.line, record.status));
+        };
+        // the server's stopped with the runtime blocked on it, so connections
+        // still to be closed need another thread to close them
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let result = runtime.block_on(run(&args, files, Some(&mut on_result)));
+        (result, records)
+    }
+
+    #[test]
+    fn warns_on_lone_non_transactional_test() {
+        use super::lone_non_transactional_test;
+
+        let mut file = test_file("a.md", true, &[1, 5, 9]);
+        assert!(lone_non_transactional_test(&file).is_none());
+
+        file.tests[1].transactional = false;
+        file.stateless = false;
+        let test = lone_non_transactional_test(&file).expect("no warning");
+        assert_eq!(test.line, 5);
+
+        file.tests[2].transactional = false;
+        assert!(lone_non_transactional_test(&file).is_none());
+
+        let story = test_file("b.md", false, &[1]);
+        assert!(lone_non_transactional_test(&story).is_none());
+    }
+
+    #[test]
+    fn captured_variables_are_substituted() {
+        use super::substitute_variables;
+        use std::collections::HashMap;
+
+        let variables: HashMap<_, _> = [
+            ("id".to_string(), "42".to_string()),
+            ("name".to_string(), "O'Brien".to_string()),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(
+            substitute_variables("SELECT * FROM users WHERE id = :id;", &variables),
+            "SELECT * FROM users WHERE id = 42;"
+        );
+        assert_eq!(
+            substitute_variables("SELECT :'name', :id::text, :missing", &variables),
+            "SELECT 'O''Brien', 42::text, :missing"
+        );
+        assert_eq!(
+            substitute_variables("SELECT ':id', '1'::int, now()::date", &variables),
+            "SELECT ':id', '1'::int, now()::date"
+        );
+        assert_eq!(
+            substitute_variables(
+                "SELECT \"a:id\", $$ :id $$, $f$ :'name' $f$ -- :id\n/* :id */ FROM t",
+                &variables
+            ),
+            "SELECT \"a:id\", $$ :id $$, $f$ :'name' $f$ -- :id\n/* :id */ FROM t"
+        );
+        assert_eq!(
+            substitute_variables("SELECT E'\\':id', :id", &variables),
+            "SELECT E'\\':id', 42"
+        );
+    }
+
+    #[test]
+    fn paths_are_displayed_relative_to_cwd() {
+        use super::display_path;
+        use std::path::Path;
+
+        let cwd = Path::new("/home/user/project");
+        assert_eq!(
+            display_path("/home/user/project/src/lib.rs", cwd),
+            "src/lib.rs"
+        );
+        assert_eq!(display_path("./docs/tests.md", cwd), "docs/tests.md");
+        assert_eq!(display_path("docs/tests.md", cwd), "docs/tests.md");
+        assert_eq!(
+            display_path("/elsewhere/tests.md", cwd),
+            "/elsewhere/tests.md"
+        );
+        assert_eq!(
+            display_path("/home/user/project", cwd),
+            "/home/user/project"
+        );
+    }
+
+    #[test]
+    fn owner_role_creation_is_quoted() {
+        assert_eq!(
+            super::create_role_if_missing(r#"o'wn"er"#),
+            "DO $$ BEGIN \
+                IF NOT EXISTS (SELECT FROM pg_roles WHERE rolname = 'o''wn\"er') THEN \
+                    CREATE ROLE \"o'wn\"\"er\" WITH LOGIN; \
+                END IF; \
+            END $$;"
+        );
+    }
+
+    #[test]
+    fn log_tail_keeps_last_lines() {
+        use super::last_lines;
+
+        let log = "one\ntwo\nthree\nfour\n";
+        assert_eq!(last_lines(log, 2), "three\nfour");
+        assert_eq!(last_lines(log, 10), "one\ntwo\nthree\nfour");
+        assert_eq!(last_lines(log, 0), "");
+        assert_eq!(last_lines("", 3), "");
+    }
+
+    #[test]
+    fn banners_use_the_suite_name() {
+        use super::report_name;
+        use std::path::Path;
+
+        let cwd = Path::new("/home/user/project");
+        let contents = "# Order Totals\n```SQL\nselect 1\n```\n";
+        let title = crate::parser::first_h1(contents).unwrap();
+        let path = "/home/user/project/docs/orders.md";
+        assert_eq!(report_name(path, Some(&title), cwd), "Order Totals");
+        assert_eq!(report_name(path, None, cwd), "docs/orders.md");
+    }
+
+    #[test]
+    fn on_success_teardown_keeps_failed_runs() {
+        use super::Teardown;
+        use clap::Parser;
+
+        let args = crate::Args::parse_from(["test", "--teardown", "on-success", "."]);
+        assert_eq!(args.teardown, Teardown::OnSuccess);
+        // a run with a failing test keeps its databases and cluster
+        assert!(Teardown::OnSuccess.keeps(false));
+        assert!(!Teardown::OnSuccess.keeps(true));
+
+        assert!(!Teardown::Always.keeps(false));
+        assert!(Teardown::Never.keeps(true));
+        let args = crate::Args::parse_from(["test", "."]);
+        assert_eq!(args.teardown, Teardown::Always);
+    }
+
+    #[test]
+    fn moved_files_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("postmaster-stderr.temp.log");
+        let to = dir.path().join("postmaster-err.log");
+        std::fs::write(&from, "log").unwrap();
+        super::move_file(&from, &to).unwrap();
+        assert!(!from.exists());
+        assert_eq!(std::fs::read_to_string(&to).unwrap(), "log");
+    }
+
+    #[test]
+    fn serial_files_run_as_one_batch() {
+        use super::stateless_batches;
+
+        let mut serial = test_file("serial.md", true, &[1, 5, 9]);
+        serial.serial = true;
+        let files = vec![test_file("a.md", true, &[1, 5]), serial];
+        let batches: Vec<(String, Vec<usize>)> = stateless_batches(files, false)
+            .into_iter()
+            .map(|batch| (batch.file, batch.tests.iter().map(|t| t.line).collect()))
+            .collect();
+        let expected = vec![
+            ("a.md".to_string(), vec![1]),
+            ("a.md".to_string(), vec![5]),
+            ("serial.md".to_string(), vec![1, 5, 9]),
+        ];
+        assert_eq!(batches, expected);
+    }
+
+    #[test]
+    fn shuffles_repeat_with_the_same_seed() {
+        use super::{shuffle, stateless_batches};
+
+        let shuffled_batches = |seed| {
+            let mut serial = test_file("serial.md", true, &[1, 5, 9]);
+            serial.serial = true;
+            let files = vec![test_file("a.md", true, &[1, 2, 3, 4, 5, 6]), serial];
+            let mut batches = stateless_batches(files, false);
+            shuffle(&mut batches, seed);
+            let batches: Vec<(String, Vec<usize>)> = batches
+                .into_iter()
+                .map(|batch| (batch.file, batch.tests.iter().map(|t| t.line).collect()))
+                .collect();
+            batches
+        };
+        let batches = shuffled_batches(7);
+        assert_eq!(batches, shuffled_batches(7));
+        assert_ne!(batches, shuffled_batches(8));
+        // a serial file is still run in order
+        assert!(batches.contains(&("serial.md".to_string(), vec![1, 5, 9])));
+
+        let shuffled_files = |seed| {
+            let mut files: Vec<TestFile> = (0..8)
+                .map(|i| test_file(&format!("{i}.md"), false, &[3, 1, 2]))
+                .collect();
+            shuffle(&mut files, seed);
+            files
+        };
+        let files = shuffled_files(7);
+        let names: Vec<&str> = files.iter().map(|file| &*file.name).collect();
+        let repeated = shuffled_files(7);
+        assert_eq!(
+            names,
+            repeated.iter().map(|file| &*file.name).collect::<Vec<_>>()
+        );
+        // the order's the same whichever version of `rand` is built
+        assert_eq!(
+            names,
+            ["4.md", "3.md", "2.md", "0.md", "5.md", "6.md", "7.md", "1.md"]
+        );
+        // the tests of a stateful file keep their order
+        for file in &files {
+            let lines: Vec<usize> = file.tests.iter().map(|test| test.line).collect();
+            assert_eq!(lines, [3, 1, 2]);
+        }
+    }
+
+    #[test]
+    fn files_can_have_a_connection_each() {
+        use super::stateless_batches;
+
+        let mut a = test_file("a.md", true, &[1, 5]);
+        a.settings = vec!["SET search_path = a".to_string()];
+        let files = vec![a, test_file("b.md", true, &[2, 3])];
+        let batches: Vec<(String, Vec<String>, Vec<usize>)> = stateless_batches(files, true)
+            .into_iter()
+            .map(|batch| {
+                let lines = batch.tests.iter().map(|t| t.line).collect();
+                (batch.file, batch.settings.to_vec(), lines)
+            })
+            .collect();
+        let expected = vec![
+            (
+                "a.md".to_string(),
+                vec!["SET search_path = a".to_string()],
+                vec![1, 5],
+            ),
+            ("b.md".to_string(), vec![], vec![2, 3]),
+        ];
+        assert_eq!(batches, expected);
+    }
+
+    #[test]
+    fn single_transaction_files_are_one_batch() {
+        use super::stateless_batches;
+
+        let mut a = test_file("a.md", true, &[1, 5]);
+        a.single_transaction = true;
+        let files = vec![a, test_file("b.md", true, &[2, 3])];
+        let batches: Vec<(String, bool, Vec<usize>)> = stateless_batches(files, false)
+            .into_iter()
+            .map(|batch| {
+                let lines = batch.tests.iter().map(|t| t.line).collect();
+                (batch.file, batch.single_transaction, lines)
+            })
+            .collect();
+        let expected = vec![
+            ("a.md".to_string(), true, vec![1, 5]),
+            ("b.md".to_string(), false, vec![2]),
+            ("b.md".to_string(), false, vec![3]),
+        ];
+        assert_eq!(batches, expected);
+    }
+
+    #[test]
+    #[ignore = "starts a server"]
+    fn shuffled_files_are_started_and_printed_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let progress_file = dir.path().join("progress.jsonl");
+        let contents = "# One\n```SQL\nselect 1\n```\n".repeat(4);
+        let progress = progress_file.to_str().unwrap();
+        let flags = ["--shuffle", "--seed", "7", "--progress-json", progress];
+        let (result, records) = run_files(&flags, &[("a.md", &contents), ("b.md", &contents)]);
+        result.unwrap();
+
+        // each file's results are together, however its tests were run
+        let files: Vec<_> = records.into_iter().map(|(file, ..)| file).collect();
+        let mut grouped = files.clone();
+        grouped.dedup();
+        assert_eq!(files.len(), 8);
+        assert_eq!(grouped.len(), 2);
+
+        let mut started: Vec<String> = std::fs::read_to_string(&progress_file)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .filter(|event| event["event"] == "file_started")
+            .map(|event| event["file"].as_str().unwrap().to_string())
+            .collect();
+        started.sort();
+        assert_eq!(started, ["a.md", "b.md"]);
+    }
+
+    #[test]
+    fn ordered_output_is_in_source_order() {
+        use super::{stateless_batches, ReorderBuffer};
+
+        // tests are told apart by where they are in the suite, not their lines
+        let files = vec![
+            test_file("a.md", true, &[1, 4, 4]),
+            test_file("b.md", true, &[2, 9]),
+        ];
+        let batches = stateless_batches(files, false);
+        let mut reorder = ReorderBuffer::new();
+        // finishing in an order of their own
+        let released: Vec<Vec<String>> = [2, 4, 0, 3, 1]
+            .iter()
+            .map(|&i| {
+                let batch = &batches[i];
+                let test = format!("{}:{}", batch.file, batch.tests[0].line);
+                reorder.push(batch.first, test)
+            })
+            .collect();
+        assert_eq!(
+            released,
+            [
+                vec![],
+                vec![],
+                vec!["a.md:1".to_string()],
+                vec![],
+                vec![
+                    "a.md:4".to_string(),
+                    "a.md:4".to_string(),
+                    "b.md:2".to_string(),
+                    "b.md:9".to_string(),
+                ],
+            ]
+        );
+        assert!(reorder.is_empty());
+    }
+
+    #[test]
+    fn preludes_from_the_args_run_first() {
+        use clap::Parser;
+
+        let args = crate::Args::parse_from([
+            "test",
+            "--stateless-prelude",
+            "SET LOCAL timezone = 'UTC';",
+            ".",
+        ]);
+        let file = ["SET LOCAL search_path = a".to_string()];
+        assert_eq!(
+            super::stateless_prelude(&args, &file),
+            "SET LOCAL timezone = 'UTC';\nSET LOCAL search_path = a"
+        );
+        assert_eq!(
+            super::stateless_prelude(&crate::Args::parse_from(["test", "."]), &[]),
+            ""
+        );
+        assert!(crate::Args::try_parse_from([
+            "test",
+            "--stateless-prelude",
+            "SELECT 1",
+            "--stateless-no-transaction",
+            "."
+        ])
+        .is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn preludes_are_rolled_back_with_the_test() {
+        use super::{first_value, run_after_prelude};
+
+        let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL is not set");
+        let (mut client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+
+        let before = client.simple_query("SHOW timezone").await.unwrap();
+        let prelude = "SET LOCAL timezone = 'Pacific/Chatham'";
+        let txn = client.transaction().await.unwrap();
+        let (result, _) = run_after_prelude(&txn, "SHOW timezone", prelude).await;
+        assert_eq!(
+            first_value(&result.unwrap()).as_deref(),
+            Some("Pacific/Chatham")
+        );
+        txn.rollback().await.unwrap();
+
+        let after = client.simple_query("SHOW timezone").await.unwrap();
+        assert_eq!(first_value(&after), first_value(&before));
+
+        let txn = client.transaction().await.unwrap();
+        let (result, _) = run_after_prelude(&txn, "SELECT 1", "SELECT 1/0").await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn single_transaction_tests_see_earlier_ones() {
+        use super::{first_value, run_in_shared_transaction};
+
+        let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL is not set");
+        let (client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+
+        let mut began = false;
+        let create = "CREATE TEMP TABLE shared(a int); INSERT INTO shared VALUES (1)";
+        run_in_shared_transaction(&client, create, "", &mut began)
+            .await
+            .unwrap();
+        assert!(began);
+        let failing = run_in_shared_transaction(&client, "SELECT 1/0", "", &mut began).await;
+        assert!(failing.is_err());
+        let select = run_in_shared_transaction(&client, "SELECT a FROM shared", "", &mut began)
+            .await
+            .unwrap();
+        assert_eq!(first_value(&select).as_deref(), Some("1"));
+
+        client.batch_execute("ROLLBACK").await.unwrap();
+        assert!(client.simple_query("SELECT a FROM shared").await.is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn failed_descriptions_leave_the_shared_transaction_usable() {
+        use super::{first_value, run_in_shared_transaction, with_savepoint_description, Ran};
+
+        let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL is not set");
+        let (client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+
+        // the table is gone by the time the query is described
+        let mut test = test_file("a.md", false, &[1]).tests.remove(0);
+        test.text = "CREATE TEMP TABLE gone(a int); SELECT a FROM gone; DROP TABLE gone".into();
+        test.column_count = Some(1);
+        let mut began = false;
+        let result = run_in_shared_transaction(&client, &test.text, "", &mut began).await;
+        let ran = with_savepoint_description(&client, &test, false, result).await;
+        assert!(matches!(
+            ran,
+            Ran::Described {
+                columns: Err(_),
+                ..
+            }
+        ));
+
+        let select = run_in_shared_transaction(&client, "SELECT 1", "", &mut began)
+            .await
+            .unwrap();
+        assert_eq!(first_value(&select).as_deref(), Some("1"));
+        client.batch_execute("ROLLBACK").await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn before_all_fixtures_are_visible_to_tests() {
+        use super::{first_value, run_file_hooks};
+
+        let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL is not set");
+        let connect = || async {
+            let (client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+                .await
+                .unwrap();
+            tokio::spawn(connection);
+            client
+        };
+        let (hooks, mut tests) = (connect().await, connect().await);
+
+        let settings = ["SET search_path = public".to_string()];
+        let before_all = [
+            "CREATE TABLE before_all_fixture(a int)".to_string(),
+            "INSERT INTO before_all_fixture VALUES (1)".to_string(),
+        ];
+        run_file_hooks(&hooks, &settings, &before_all)
+            .await
+            .unwrap();
+
+        // the tests each run in a transaction of their own
+        let txn = tests.transaction().await.unwrap();
+        let select = txn
+            .simple_query("SELECT a FROM public.before_all_fixture")
+            .await
+            .unwrap();
+        assert_eq!(first_value(&select).as_deref(), Some("1"));
+        txn.rollback().await.unwrap();
+
+        let after_all = ["DROP TABLE before_all_fixture".to_string()];
+        run_file_hooks(&hooks, &settings, &after_all).await.unwrap();
+        assert!(tests
+            .simple_query("SELECT a FROM public.before_all_fixture")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn tests_are_compared_with_their_reference_query() {
+        use super::{expect_reference, with_reference, Ran};
+        use crate::db_output::{validate_output, TestResult};
+        use crate::Args;
+        use clap::Parser;
+
+        let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL is not set");
+        let (mut client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        let txn = client.transaction().await.unwrap();
+        txn.batch_execute(
+            "CREATE TEMP TABLE t AS SELECT a FROM generate_series(1, 1000) a;\
+            CREATE INDEX ON t(a); ANALYZE t; SET LOCAL enable_seqscan = off",
+        )
+        .await
+        .unwrap();
+
+        let args = Args::parse_from(["test", "."]);
+        let check = |reference: &str| {
+            let mut test = test_file("a.md", true, &[1]).tests.remove(0);
+            test.text = "SELECT a FROM t WHERE a < 5 ORDER BY a".to_string();
+            test.compare_with = Some(reference.to_string());
+            test.ignore_output = false;
+            let (txn, args) = (&txn, &args);
+            async move {
+                let result = txn.simple_query(&test.text).await;
+                let Ran::Compared { output, reference } =
+                    with_reference(txn.client(), &test, args.first_result_only, true, result).await
+                else {
+                    panic!("the reference query wasn't run")
+                };
+                expect_reference(args, &mut test, &reference.unwrap());
+                matches!(validate_output(&output, &test, args), TestResult::Passed)
+            }
+        };
+        // `a + 0` can't use the index so it's found with a sequential scan
+        assert!(check("SELECT a FROM t WHERE a + 0 < 5 ORDER BY a").await);
+        assert!(!check("SELECT a FROM t WHERE a + 0 < 6 ORDER BY a").await);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn columns_of_empty_results_are_described() {
+        use super::{with_description, Ran};
+
+        let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL is not set");
+        let (client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+
+        let columns = |text: &str, first_result_only| {
+            let mut test = test_file("a.md", true, &[1]).tests.remove(0);
+            test.text = text.to_string();
+            test.column_count = Some(3);
+            let client = &client;
+            async move {
+                let result = client.simple_query(&test.text).await;
+                match with_description(client, &test, first_result_only, result).await {
+                    Ran::Described { columns, .. } => Some(columns.unwrap()),
+                    _ => None,
+                }
+            }
+        };
+        assert_eq!(columns("SELECT 1, 2, 3 WHERE false", false).await, Some(3));
+        assert_eq!(columns("SELECT 1, 2 WHERE false", false).await, Some(2));
+        assert_eq!(
+            columns("SET work_mem = '4MB'; SELECT 1 WHERE false", false).await,
+            Some(1)
+        );
+        assert_eq!(
+            columns("SET work_mem = '4MB'; SELECT 1 WHERE false", true).await,
+            Some(0)
+        );
+        // rows have their columns already
+        assert_eq!(columns("SELECT 1, 2, 3", false).await, None);
+    }
+
+    #[test]
+    fn role_creation_can_be_skipped() {
+        use super::role_to_create;
+        use crate::Args;
+        use clap::Parser;
+
+        let args = Args::parse_from(["test", "."]);
+        assert_eq!(role_to_create(&args), Some("postgres"));
+
+        let args = Args::parse_from(["test", "--db-owner", "owner", "."]);
+        assert_eq!(role_to_create(&args), Some("owner"));
+
+        let args = Args::parse_from(["test", "--db-owner", "owner", "--no-create-role", "."]);
+        assert_eq!(role_to_create(&args), None);
+    }
+
+    #[test]
+    #[ignore = "starts a server"]
+    fn tests_connect_as_the_db_owner() {
+        use super::TestStatus::*;
+
+        let contents = "# Owner\n```SQL\nselect current_user, rolsuper from pg_roles \
+            where rolname = current_user\n```\n```output\n \
+            current_user | rolsuper\n--------------+----------\n tester | f\n```\n";
+        let (result, records) = run_files(&["--db-owner", "tester"], &[("a.md", contents)]);
+        result.unwrap();
+        assert_eq!(records, [("a.md".to_string(), 2, Passed)]);
+    }
+
+    #[test]
+    #[ignore = "starts a server"]
+    fn committed_tests_are_seen_by_later_ones() {
+        use super::TestStatus::*;
+
+        let contents = "<!-- commit-transactional -->\n# Migrate\n\
+            ```SQL\ncreate table migrated as select 1 as a\n```\n\
+            # Migrated\n```SQL\nselect a from migrated\n```\n```output\n a\n---\n 1\n```\n";
+        let (result, records) = run_files(&[], &[("a.md", contents)]);
+        result.unwrap();
+        let a = |line| ("a.md".to_string(), line, Passed);
+        assert_eq!(records, [a(3), a(7)]);
+
+        // rolled back, the table's gone by the time it's read
+        let contents = contents.replace("<!-- commit-transactional -->\n", "");
+        let (_, records) = run_files(&[], &[("a.md", &contents)]);
+        let a = |line, status| ("a.md".to_string(), line, status);
+        assert_eq!(records, [a(2, Passed), a(6, Failed)]);
+    }
+
+    #[test]
+    #[ignore = "starts a server"]
+    fn failed_descriptions_dont_undo_committed_tests() {
+        use super::TestStatus::*;
+
+        // the table selected from is gone by the time the query is described
+        let contents = "<!-- commit-transactional -->\n# Migrate\n\
+            ```SQL\ncreate table migrated(a int); create table gone(a int); \
+            select a from gone; drop table gone\n```\n```output, cols(1)\n```\n\
+            # Migrated\n```SQL\nselect count(*) from migrated\n```\n\
+            ```output\n count\n-------\n 0\n```\n";
+        let (result, records) = run_files(&[], &[("a.md", contents)]);
+        result.unwrap();
+        let a = |line, status| ("a.md".to_string(), line, status);
+        assert_eq!(records, [a(3, Failed), a(9, Passed)]);
+    }
+
+    #[test]
+    #[ignore = "starts a server"]
+    fn notices_are_checked_in_the_order_raised() {
+        use super::TestStatus::*;
+
+        let test = |notices: &str| {
+            format!(
+                "```SQL\ncreate function f() returns int as $$ begin \
+                raise notice 'first'; raise notice 'second'; return 1; end $$ \
+                language plpgsql;\nselect f()\n```\n```{notices}\n```\n\
+                ```output\n f\n---\n 1\n```\n"
+            )
+        };
+        let contents = [
+            test("notices\nfirst\nsecond"),
+            test("notices\nsecond\nfirst"),
+            test("notices, unordered\nsecond\nfirst"),
+            test("notices\nfirst"),
+        ]
+        .concat();
+        let (result, mut records) = run_files(&[], &[("a.md", &contents)]);
+        result.unwrap();
+        records.sort_by_key(|(_, line, _)| *line);
+        let a = |line, status| ("a.md".to_string(), line, status);
+        assert_eq!(
+            records,
+            [a(1, Passed), a(14, Failed), a(27, Passed), a(40, Failed)]
+        );
+    }
+
+    #[test]
+    #[ignore = "starts a server"]
+    fn scratch_dbs_are_made_from_the_maintenance_db() {
+        use super::TestStatus::*;
+
+        let contents = "# Fine\n```SQL\nselect 1\n```\n```output\n ?column?\n----------\n 1\n```\n";
+        let flags = ["--maintenance-db", "template1", "--db-owner", "tester"];
+        let (result, records) = run_files(&flags, &[("a.md", contents)]);
+        result.unwrap();
+        assert_eq!(records, [("a.md".to_string(), 2, Passed)]);
+
+        // without a role to create first, it's `createdb` that connects
+        let sh = xshell::Shell::new().unwrap();
+        let user = xshell::cmd!(sh, "whoami").read().unwrap();
+        let flags = [
+            "--maintenance-db",
+            "missing",
+            "--no-create-role",
+            "--db-owner",
+            &user,
+        ];
+        let (result, records) = run_files(&flags, &[("a.md", contents)]);
+        let error = format!("{:#}", result.unwrap_err());
+        assert!(
+            error.contains(r#"database "missing" does not exist"#),
+            "{error}"
+        );
+        assert!(records.is_empty());
+    }
+
+    #[test]
+    #[ignore = "starts a server"]
+    fn no_role_is_created_with_no_create_role() {
+        use super::TestStatus::*;
+
+        // the cluster's only role is the user that created it
+        let sh = xshell::Shell::new().unwrap();
+        let user = xshell::cmd!(sh, "whoami").read().unwrap();
+        let contents =
+            "# Roles\n```SQL\nselect count(*) from pg_roles where rolname !~ '^pg_'\n```\n\
+            ```output\n count\n-------\n 1\n```\n";
+        let flags = ["--no-create-role", "--db-owner", &user];
+        let (result, records) = run_files(&flags, &[("a.md", contents)]);
+        result.unwrap();
+        assert_eq!(records, [("a.md".to_string(), 2, Passed)]);
+
+        let flags = ["--no-create-role", "--db-owner", "never_created"];
+        let (result, records) = run_files(&flags, &[("a.md", contents)]);
+        let error = format!("{:#}", result.unwrap_err());
+        assert!(
+            error.contains(r#"role "never_created" does not exist"#),
+            "{error}"
+        );
+        assert!(records.is_empty());
+    }
+
+    #[test]
+    #[ignore = "starts a server"]
+    fn serial_files_tests_never_overlap() {
+        use super::TestStatus;
+
+        // each test holds the lock while it sleeps, one overlapping it can't
+        // take the lock
+        let test = "```SQL\nselect pg_try_advisory_xact_lock(1) as alone, \
+            length(pg_sleep(0.5)::text) as slept\n```\n\
+            ```output\n alone | slept\n-------+-------\n t     |     0\n```\n";
+        let statuses = |directive: &str| {
+            let contents = format!("{directive}# Lock\n{}", test.repeat(4));
+            let (_, records) = run_files(&["--jobs", "4"], &[("a.md", &contents)]);
+            records
+                .into_iter()
+                .map(|(.., status)| status)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(statuses("<!-- serial -->\n"), [TestStatus::Passed; 4]);
+        assert!(statuses("").contains(&TestStatus::Failed));
+    }
+
+    #[test]
+    #[ignore = "starts a server"]
+    fn quiet_server_only_keeps_logs_of_failed_runs() {
+        use std::process::Command;
+
+        // the logs are kept in the working directory, so the runs are made by
+        // copies of this test, in processes of their own in a new directory
+        if let Some(expected) = std::env::var_os("SQL_TESTER_QUIET_TEST") {
+            let contents = format!(
+                "# Quiet\n```SQL\nselect 1\n```\n```output\n ?column?\n----------\n {}\n```\n",
+                expected.to_str().unwrap()
+            );
+            let _ = run_files(&["--quiet-server"], &[("a.md", &contents)]);
+            return;
+        }
+        let logs_kept = |expected: &str| {
+            let dir = tempfile::tempdir().unwrap();
+            let test = Command::new(std::env::current_exe().unwrap())
+                .args([
+                    "--ignored",
+                    "--exact",
+                    "runner::test::quiet_server_only_keeps_logs_of_failed_runs",
+                ])
+                .current_dir(dir.path())
+                .env("SQL_TESTER_QUIET_TEST", expected)
+                .output()
+                .unwrap();
+            let stdout = String::from_utf8_lossy(&test.stdout);
+            assert!(stdout.contains("1 passed"), "{stdout}");
+            let mut kept: Vec<String> = std::fs::read_dir(dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+            kept.sort();
+            kept
+        };
+        assert!(logs_kept("1").is_empty());
+        assert_eq!(logs_kept("2"), ["postmaster-err.log", "postmaster-out.log"]);
+    }
+
+    #[test]
+    #[ignore = "starts a server"]
+    fn served_databases_can_be_connected_to() {
+        use nix::{
+            sys::signal::{kill, SIGINT},
+            unistd::Pid,
+        };
+        use std::io::{BufRead, BufReader};
+        use std::process::{Command, Stdio};
+
+        // serving only stops on Ctrl-C, so it's done by a copy of this test
+        // in a process of its own
+        if std::env::var_os("SQL_TESTER_SERVE_TEST").is_some() {
+            run_files(&["--serve-only"], &[]).0.unwrap();
+            return;
+        }
+        let mut server = Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--ignored",
+                "--exact",
+                "runner::test::served_databases_can_be_connected_to",
+            ])
+            .env("SQL_TESTER_SERVE_TEST", "1")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut stdout = BufReader::new(server.stdout.take().unwrap()).lines();
+        let connection_string = stdout
+            .by_ref()
+            .map(Result::unwrap)
+            .find_map(|line| Some(line.split_once("Serving ")?.1.to_string()))
+            .expect("no connection string printed");
+        let (_, dbname) = connection_string.rsplit_once('/').unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let connected_to: String = runtime.block_on(async {
+            let (client, connection) =
+                tokio_postgres::connect(&connection_string, tokio_postgres::NoTls)
+                    .await
+                    .unwrap();
+            tokio::spawn(connection);
+            let row = client.query_one("select current_database()", &[]).await;
+            row.unwrap().get(0)
+        });
+        assert_eq!(connected_to, dbname);
+
+        // the server's stopped once its connections close, so close ours
+        drop(runtime);
+        kill(Pid::from_raw(server.id() as i32), SIGINT).unwrap();
+        let rest: Vec<String> = stdout.map(Result::unwrap).collect();
+        assert!(server.wait().unwrap().success());
+        assert!(
+            rest.iter().any(|line| line.contains("1 passed")),
+            "{rest:?}"
+        );
+    }
+
+    #[test]
+    #[ignore = "starts a server"]
+    fn failed_runs_are_kept_with_on_success_teardown() {
+        use crate::colors::capture_stderr;
+
+        let contents = |expected| {
+            format!("# Kept\n```SQL\nselect 1\n```\n```output\n ?column?\n----------\n {expected}\n```\n")
+        };
+        let printed = |contents: &str| {
+            let (_, printed) = capture_stderr(termcolor::Buffer::no_color(), || {
+                run_files(&["--teardown", "on-success"], &[("a.md", contents)])
+            });
+            String::from_utf8(printed.into_inner()).unwrap()
+        };
+
+        let passed = printed(&contents(1));
+        assert!(!passed.contains("Kept"), "{passed}");
+
+        let failed = printed(&contents(2));
+        assert!(
+            failed.contains("Kept the databases `stateless_test_db`"),
+            "{failed}"
+        );
+        let (_, dir) = failed
+            .split_once("Kept the cluster's directory at ")
+            .expect("the cluster wasn't kept");
+        let (dir, _) = dir.split_once(',').unwrap();
+        assert!(std::path::Path::new(dir).join("data/PG_VERSION").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[ignore = "starts a server"]
+    fn syntax_errors_are_caught_before_running() {
+        use crate::colors::capture_stderr;
+
+        // the missing table is only an error once the tests run
+        let contents = "# Fine\n```SQL\nselect * from missing\n```\n\
+            # Broken\n```SQL\nselect 1;\nselec 2\n```\n";
+        let ((result, records), printed) = capture_stderr(termcolor::Buffer::no_color(), || {
+            run_files(&["--validate-sql"], &[("a.md", contents)])
+        });
+        let error = result.unwrap_err().to_string();
+        assert_eq!(error, "1 statements failed to parse, not running the tests");
+        assert!(records.is_empty());
+        let printed = String::from_utf8(printed.into_inner()).unwrap();
+        assert!(
+            printed.contains("Error: a.md:8: syntax error at or near \"selec\"\n"),
+            "{printed}"
+        );
+
+        // meta-commands are left to psql
+        let contents = "# Psql\n```SQL\n\\set x 1\nselect :x\n```\n\
+            ```output\n ?column?\n----------\n 1\n```\n";
+        let (result, records) = run_files(&["--validate-sql"], &[("a.md", contents)]);
+        result.unwrap();
+        assert_eq!(
+            records,
+            [("a.md".to_string(), 2, super::TestStatus::Passed)]
+        );
+    }
+
+    #[test]
+    #[ignore = "starts a server"]
+    fn failed_tests_state_persists_with_no_rollback() {
+        use super::{run, TestResultRecord, TestStatus::*};
+        use crate::{extract_all_tests_from_file, Args};
+        use clap::Parser;
+
+        // the first test fails, the second only passes if it sees its table
+        let contents = "<!-- serial -->\n# Fails\n```SQL\ncreate table kept as select 1 as a; \
+            select a from kept\n```\n```output\n a\n---\n 2\n```\n\
+            # Sees\n```SQL\nselect a from kept\n```\n```output\n a\n---\n 1\n```\n";
+        let statuses = |flags: &[&str]| {
+            let dir = tempfile::tempdir().unwrap();
+            let failures_file = dir.path().join("failed-tests.txt");
+            let failures_file = failures_file.to_str().unwrap();
+            let mut args = vec!["test", "--watch", "--failures-file", failures_file];
+            args.extend(flags);
+            args.push(dir.path().to_str().unwrap());
+            let args = Args::parse_from(args);
+            let file = extract_all_tests_from_file("a.md", contents, Default::default()).unwrap();
+
+            // `--watch` runs until it's stopped, which it is once both tests
+            // have run
+            let (mut statuses, ran) = (vec![], tokio::sync::Notify::new());
+            let mut on_result = |record: &TestResultRecord| {
+                statuses.push(record.status);
+                if statuses.len() == 2 {
+                    ran.notify_one();
+                }
+            };
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                tokio::select! {
+                    _ = run(&args, [file].into_iter(), Some(&mut on_result)) => (),
+                    _ = ran.notified() => (),
+                }
+            });
+            statuses
+        };
+        assert_eq!(statuses(&["--no-rollback-on-failure"]), [Failed, Passed]);
+        assert_eq!(statuses(&[]), [Failed, Failed]);
+    }
+
+    #[test]
+    fn maintenance_db_is_configurable() {
+        use super::maintenance_args;
+        use crate::Args;
+        use clap::Parser;
+
+        let args = Args::parse_from(["test", "."]);
+        assert_eq!(maintenance_args(&args), ["--maintenance-db", "postgres"]);
+
+        let args = Args::parse_from(["test", "--maintenance-db", "admin", "."]);
+        assert_eq!(maintenance_args(&args), ["--maintenance-db", "admin"]);
+    }
+
+    #[test]
+    fn each_version_gets_a_result() {
+        use super::versions_report;
+        use crate::Args;
+        use clap::Parser;
+
+        let args = Args::parse_from([
+            "test",
+            "--pg-config",
+            "/usr/lib/postgresql/14/bin/pg_config",
+            "--pg-config",
+            "/usr/lib/postgresql/15/bin/pg_config",
+            ".",
+        ]);
+        assert_eq!(args.pg_configs.len(), 2);
+
+        let outcomes = [
+            ("PostgreSQL 14.9".to_string(), true),
+            ("PostgreSQL 15.4".to_string(), false),
+        ];
+        assert_eq!(
+            versions_report(&outcomes),
+            "\nversions:\nPostgreSQL 14.9: ok\nPostgreSQL 15.4: FAILED\n"
+        );
+    }
+
+    #[test]
+    fn jobs_leave_a_connection_free() {
+        use super::jobs_within;
+
+        assert_eq!(jobs_within(4, 90), 4);
+        assert_eq!(jobs_within(4, 3), 2);
+        assert_eq!(jobs_within(1, 2), 1);
+    }
+
+    #[test]
+    #[ignore = "starts a server"]
+    fn connections_never_exceed_the_limit() {
+        use super::TestStatus::*;
+
+        // the server turns away any connection past the two the runner may
+        // open, and the two it may still be closing
+        let stateless = "# Sleep\n```SQL\nselect pg_sleep(0.05)\n```\n".repeat(6)
+            + "# Psql\n```SQL\n\\set x 1\nselect :x\n```\n";
+        let stateful = "# Create\n```SQL,stateful\ncreate table t()\n```\n\
+            # Sleep\n```SQL,stateful\nselect pg_sleep(0.05)\n```\n";
+        let flags = [
+            "--jobs",
+            "8",
+            "--max-connections",
+            "2",
+            "--set",
+            "max_connections=4",
+            "--set",
+            "superuser_reserved_connections=0",
+        ];
+        let files = [
+            ("a.md", &*stateless),
+            ("b.md", stateful),
+            ("c.md", stateful),
+            ("d.md", stateful),
+        ];
+        let (result, records) = run_files(&flags, &files);
+        result.unwrap();
+        assert_eq!(records.len(), 13);
+        assert!(records.iter().all(|(.., status)| *status == Passed));
+    }
+
+    #[test]
+    fn startup_moves_off_a_taken_port() {
+        use super::start_with_retries;
+        use std::cell::Cell;
+        use std::net::TcpListener;
+
+        let taken = TcpListener::bind(("localhost", 0)).unwrap();
+        let taken_port = taken.local_addr().unwrap().port();
+        let attempts = Cell::new(0);
+        let listen = |port: String| {
+            attempts.set(attempts.get() + 1);
+            let listener = TcpListener::bind(("localhost", port.parse::<u16>()?))?;
+            Ok(listener.local_addr()?.port())
+        };
+
+        let port = start_with_retries(2, &taken_port.to_string(), listen).unwrap();
+        assert_ne!(port, taken_port);
+        assert_eq!(attempts.get(), 2);
+
+        assert!(start_with_retries(0, &taken_port.to_string(), listen).is_err());
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[test]
+    fn dumped_conf_has_each_setting_once() {
+        use super::{conf_block, resolved_conf};
+        use crate::Args;
+        use clap::Parser;
+
+        let args = Args::parse_from([
+            "test",
+            "--set",
+            "max_connections=20",
+            "--set",
+            "log_checkpoints=off",
+            ".",
+        ]);
+        // as initdb writes it
+        let initdb_conf = "\
+            # comment\n\
+            \n\
+            max_connections = 100\t\t\t# (change requires restart)\n\
+            #port = 5432\n\
+            log_timezone = 'Etc/UTC'\n";
+        let conf = format!("{initdb_conf}{}\n", conf_block(&args.settings));
+        assert_eq!(
+            resolved_conf(&conf),
+            "\
+            max_connections = 20\n\
+            log_timezone = 'Etc/UTC'\n\
+            log_autovacuum_min_duration = 0\n\
+            log_checkpoints = off\n\
+            log_line_prefix = '%m %b[%p] %q%a '\n\
+            log_lock_waits = on\n\
+            log_temp_files = 128kB\n\
+            max_prepared_transactions = 2\n"
+        );
+    }
+
+    #[test]
+    fn user_settings_replace_defaults() {
+        use super::conf_block;
+        use crate::Args;
+        use clap::Parser;
+
+        let args = Args::parse_from([
+            "test",
+            "--set",
+            "log_line_prefix='%m '",
+            "--set",
+            "work_mem = 64MB",
+            "--set",
+            "MAX_PREPARED_TRANSACTIONS=10",
+            "--set",
+            "max_prepared_transactions=5",
+            ".",
+        ]);
+        assert_eq!(args.input_paths, [std::path::PathBuf::from(".")]);
+        let expected = [
+            "",
+            "# Configuration added by test runner",
+            "log_autovacuum_min_duration = 0",
+            "log_checkpoints = on",
+            "log_line_prefix = '%m '",
+            "log_lock_waits = on",
+            "log_temp_files = 128kB",
+            "max_prepared_transactions = 5",
+            "work_mem = 64MB",
+        ];
+        assert_eq!(
+            conf_block(&args.settings).split('\n').collect::<Vec<_>>(),
+            expected
+        );
+
+        assert!("work_mem".parse::<super::Setting>().is_err());
+        assert!("=64MB".parse::<super::Setting>().is_err());
+    }
+
+    #[test]
+    fn validated_statements_are_split_and_located() {
+        use super::statements_to_validate;
+
+        let mut file = test_file("a.md", false, &[3, 10, 14]);
+        file.tests[0].text = "SELECT 1 AS id;\n\nSELCT 2".to_string();
+        file.tests[0].capture = Some("id".to_string());
+        file.tests[1].text = "SELECT * FROM t WHERE id = :id".to_string();
+        file.tests[2].text = "CREATE TABLE t();\n\\dt".to_string();
+        assert_eq!(
+            statements_to_validate(&file),
+            [
+                (4, "SELECT 1 AS id".to_string()),
+                (6, "SELCT 2".to_string()),
+                (11, "SELECT * FROM t WHERE id = NULL".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn defines_expand_placeholders() {
+        use super::{expand_defines, Define};
+
+        let defines: Vec<Define> = ["schema=reporting", "limit = 5", "schema=analytics"]
+            .iter()
+            .map(|define| define.parse().unwrap())
+            .collect();
+        let mut file = test_file("a.md", true, &[3, 7]);
+        file.tests[0].text =
+            "select '{{1,2}}'::int[] from {{schema}}.t limit {{ limit }}".to_string();
+        file.tests[1].compare_with = Some("select * from {{schema}}.expected".to_string());
+
+        let files = expand_defines(&defines, vec![file.clone()]).unwrap();
+        assert_eq!(
+            files[0].tests[0].text,
+            "select '{{1,2}}'::int[] from analytics.t limit 5"
+        );
+        assert_eq!(
+            files[0].tests[1].compare_with.as_deref(),
+            Some("select * from analytics.expected")
+        );
+        // without defines a placeholder is still reported
+        let error = expand_defines(&[], vec![file.clone()]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "placeholders without a `--define`:\n`a.md:3`: `{{schema}}`\n\
+            `a.md:7`: `{{schema}}`"
+        );
+        let mut plain = test_file("a.md", true, &[3]);
+        plain.tests[0].text = "select '{{1,2}}'::int[]".to_string();
+        assert_eq!(expand_defines(&[], vec![plain.clone()]).unwrap(), [plain]);
+
+        file.tests[1].text = "select {{missing}}".to_string();
+        let error = expand_defines(&defines, vec![file]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "placeholders without a `--define`:\n`a.md:7`: `{{missing}}`"
+        );
+        assert!("1x=a".parse::<Define>().is_err());
+        assert!("schema".parse::<Define>().is_err());
+    }
+
+    #[test]
+    fn unconnected_files_fail_alone() {
+        use super::{print_test_result, unconnected_results, Diagnostics, Ran};
+        use crate::db_output::FailureInfo;
+        use crate::xfail::ExpectedFailures;
+        use crate::Args;
+        use clap::Parser;
+
+        let args = Args::parse_from(["test", "."]);
+        let error = anyhow::anyhow!("connection refused").context("could not create database");
+        let unconnected = unconnected_results(test_file("a.md", false, &[1, 5]).tests, &error);
+        let ran = test_file("b.md", false, &[3])
+            .tests
+            .into_iter()
+            .map(|test| {
+                (
+                    test,
+                    Ran::Query(Ok(vec![])),
+                    vec![],
+                    vec![],
+                    Default::default(),
+                )
+            });
+
+        let mut failures = vec![];
+        let passed: Vec<bool> = [("a.md", unconnected), ("b.md", ran.collect())]
+            .into_iter()
+            .flat_map(|(file, results)| results.into_iter().map(move |result| (file, result)))
+            .map(|(file, (test, ran, notices, plan, _))| {
+                print_test_result(
+                    &args,
+                    file.to_string(),
+                    test,
+                    ran,
+                    Diagnostics { notices, plan },
+                    &ExpectedFailures::default(),
+                    &mut failures,
+                )
+                .passed()
+            })
+            .collect();
+        assert_eq!(passed, [false, false, true]);
+        let failed: Vec<_> = failures
+            .iter()
+            .map(|(file, test, failure)| match failure {
+                FailureInfo::ConnectionError(e) => (file.as_str(), test.line, e.as_str()),
+                _ => panic!("expected a connection error"),
+            })
+            .collect();
+        let error = "could not create database: connection refused";
+        assert_eq!(failed, [("a.md", 1, error), ("a.md", 5, error)]);
+    }
+
+    #[test]
+    fn rules_are_blank_lines_when_output_is_uncolored() {
+        use crate::colors::capture_stdout;
+
+        let ((), printed) = capture_stdout(termcolor::Buffer::no_color(), super::print_rule);
+        assert_eq!(printed.into_inner(), b"\n");
+    }
+
+    #[test]
+    fn expected_failures_are_xfail_and_their_passes_xpass() {
+        use super::TestStatus::*;
+        use super::{print_test_result, Ran};
+        use crate::colors::{capture_stderr, capture_stdout};
+        use crate::xfail::ExpectedFailures;
+        use crate::Args;
+        use clap::Parser;
+
+        let args = Args::parse_from(["test", "."]);
+        let expected_failures = ExpectedFailures::parse("a.md:1\na.md:3").unwrap();
+        let mut failures = vec![];
+        let mut results = vec![];
+        let capture = || {
+            for test in test_file("a.md", true, &[1, 2, 3]).tests {
+                let ran = match test.line {
+                    3 => Ran::Query(Ok(vec![])),
+                    _ => Ran::Unconnected("connection refused".to_string()),
+                };
+                let status = print_test_result(
+                    &args,
+                    "a.md".to_string(),
+                    test,
+                    ran,
+                    Default::default(),
+                    &expected_failures,
+                    &mut failures,
+                );
+                results.push(status);
+            }
+        };
+        let (((), printed), warned) = capture_stderr(termcolor::Buffer::no_color(), || {
+            capture_stdout(termcolor::Buffer::no_color(), capture)
+        });
+        let printed = String::from_utf8(printed.into_inner()).unwrap();
+        let outcomes: Vec<_> = printed
+            .lines()
+            .map(|line| line.rsplit(' ').next().unwrap())
+            .collect();
+        assert_eq!(outcomes, ["XFAIL", "FAILED", "XPASS"]);
+        assert_eq!(results, [XFailed, Failed, XPassed]);
+        let passed: Vec<bool> = results.iter().map(|status| status.passed()).collect();
+        assert_eq!(passed, [true, false, true]);
+        // the summary leaves out the expected failures
+        let failed: Vec<_> = failures.iter().map(|(_, test, _)| test.line).collect();
+        assert_eq!(failed, [1, 2]);
+        let warned = String::from_utf8(warned.into_inner()).unwrap();
+        assert_eq!(
+            warned,
+            "Warning: `a.md:3` passed but was expected to fail\n"
+        );
+    }
+
+    #[test]
+    #[ignore = "starts a server"]
+    fn file_hooks_run_around_their_files_tests() {
+        use super::TestStatus::*;
+
+        // each file's fixture is only there for its own tests, the second
+        // couldn't be created while the first is
+        let file = |name: &str| {
+            format!(
+                "```SQL,before-all\ncreate table fixture as select '{name}' as file\n```\n\
+                # Fixture\n```SQL\nselect file from fixture\n```\n\
+                ```output\n file\n------\n {name}\n```\n\
+                ```SQL,after-all\ndrop table fixture\n```\n"
+            )
+        };
+        let files = [("a.md", &*file("a.md")), ("b.md", &*file("b.md"))];
+        let (result, mut records) = run_files(&["--jobs", "1"], &files);
+        result.unwrap();
+        records.sort_by_key(|(file, ..)| file.clone());
+        let passed = |file: &str| (file.to_string(), 5, Passed);
+        assert_eq!(records, [passed("a.md"), passed("b.md")]);
+    }
+
+    #[test]
+    #[ignore = "starts a server"]
+    fn progress_events_follow_the_run() {
+        use super::run;
+        use crate::{extract_all_tests_from_file, Args};
+        use clap::Parser;
+
+        let dir = tempfile::tempdir().unwrap();
+        let failures_file = dir.path().join("failed-tests.txt");
+        let progress_file = dir.path().join("progress.jsonl");
+        let args = Args::parse_from([
+            "test".as_ref(),
+            "--progress-json".as_ref(),
+            progress_file.as_os_str(),
+            "--failures-file".as_ref(),
+            failures_file.as_os_str(),
+            dir.path().as_os_str(),
+        ]);
+        let stateful = "# Passing\n```SQL,stateful\nselect 1 as one\n```\n\
+            ```output\n one\n-----\n 1\n```\n\
+            # Failing\n```SQL\nselect 2 as two\n```\n```output\n two\n-----\n 3\n```\n";
+        let file = extract_all_tests_from_file("a.md", stateful, Default::default()).unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let _ = runtime.block_on(run(&args, [file].into_iter(), None));
+
+        let events: Vec<serde_json::Value> = std::fs::read_to_string(&progress_file)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let names: Vec<_> = events
+            .iter()
+            .map(|event| event["event"].as_str().unwrap())
+            .collect();
+        // a stateful file's tests are all started before their results are in
+        assert_eq!(
+            names,
+            [
+                "file_started",
+                "test_started",
+                "test_started",
+                "test_finished",
+                "test_finished",
+                "run_finished"
+            ]
+        );
+        assert_eq!(events[0]["file"], "a.md");
+        assert_eq!(events[2]["line"], 11);
+        let failing = &events[4];
+        assert_eq!(
+            (&failing["line"], &failing["header"], &failing["passed"]),
+            (&11.into(), &"`Failing`".into(), &false.into())
+        );
+        assert!(failing["duration_ms"].as_f64().unwrap() > 0.0);
+        assert_eq!(events[5]["passed"], 1);
+        assert_eq!(events[5]["failed"], 1);
+    }
+
+    #[test]
+    #[ignore = "starts a server"]
+    fn stateless_no_transaction_issues_no_begin() {
+        use super::TestStatus::*;
+
+        // a query started by itself is the first of its transaction, after a
+        // `BEGIN` it's started later than the transaction is
+        let contents = "# No BEGIN\n```SQL\nselect now() = statement_timestamp() as first\n```\n\
+            ```output\n first\n-------\n t\n```\n";
+        let status = |flags: &[&str]| {
+            let (_, records) = run_files(flags, &[("a.md", contents)]);
+            records
+                .into_iter()
+                .map(|(.., status)| status)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(status(&["--stateless-no-transaction"]), [Passed]);
+        assert_eq!(status(&[]), [Failed]);
+    }
+
+    #[test]
+    #[ignore = "starts a server"]
+    fn files_that_cant_connect_fail_alone() {
+        use super::{run, TestResultRecord, TestStatus};
+        use crate::db_output::FailureInfo;
+        use crate::{extract_all_tests_from_file, Args};
+        use clap::Parser;
+
+        // with one job at a time the files run from the last, which takes the
+        // database the one before it would be created as, so only `b.md` can't
+        // connect
+        let dir = tempfile::tempdir().unwrap();
+        let failures_file = dir.path().join("failed-tests.txt");
+        let args = Args::parse_from([
+            "test",
+            "--jobs",
+            "1",
+            "--failures-file",
+            failures_file.to_str().unwrap(),
+            dir.path().to_str().unwrap(),
+        ]);
+        let taking = "# Take\n```SQL,stateful\ncreate database \"stateful-tests-2\"\n```\n\
+            # After\n```SQL\nselect 1\n```\n";
+        let stateful = "# Create\n```SQL,stateful\ncreate table t(a int)\n```\n\
+            # Count\n```SQL\nselect count(*) from t\n```\n```output\n count\n-------\n 0\n```\n";
+        let files =
+            [("a.md", stateful), ("b.md", stateful), ("c.md", taking)].map(|(name, contents)| {
+                extract_all_tests_from_file(name, contents, Default::default()).unwrap()
+            });
+
+        let mut records = vec![];
+        let mut on_result = |record: &TestResultRecord| {
+            let unconnected = matches!(record.failure, Some(FailureInfo::ConnectionError(_)));
+            records.push((record.file.to_string(), record.status, unconnected));
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let _ = runtime.block_on(run(&args, files.into_iter(), Some(&mut on_result)));
+
+        records.sort_by_key(|(file, ..)| file.clone());
+        let record = |file: &str, status, unconnected| (file.to_string(), status, unconnected);
+        assert_eq!(
+            records,
+            [
+                record("a.md", TestStatus::Passed, false),
+                record("a.md", TestStatus::Passed, false),
+                record("b.md", TestStatus::Failed, true),
+                record("b.md", TestStatus::Failed, true),
+                record("c.md", TestStatus::Passed, false),
+                record("c.md", TestStatus::Passed, false),
+            ]
+        );
+    }
+
+    #[test]
+    #[ignore = "starts a server"]
+    fn results_are_given_to_the_callback() {
+        use super::{run, TestResultRecord, TestStatus};
+        use crate::db_output::FailureInfo;
+        use crate::{extract_all_tests_from_file, Args};
+        use clap::Parser;
+
+        let dir = tempfile::tempdir().unwrap();
+        let failures_file = dir.path().join("failed-tests.txt");
+        let args = Args::parse_from([
+            "test".as_ref(),
+            "--failures-file".as_ref(),
+            failures_file.as_os_str(),
+            dir.path().as_os_str(),
+        ]);
+        let stateless =
+            "# Passing\n```SQL\nselect 1 as one\n```\n```output\n one\n-----\n 1\n```\n\
+            # Failing\n```SQL\nselect 2 as two\n```\n```output\n two\n-----\n 3\n```\n";
+        let stateful = "# Create\n```SQL,stateful\ncreate temp table t(a int)\n```\n\
+            # Count\n```SQL\nselect count(*) from t\n```\n```output\n count\n-------\n 0\n```\n";
+        let files =
+            [("stateless.md", stateless), ("stateful.md", stateful)].map(|(name, contents)| {
+                extract_all_tests_from_file(name, contents, Default::default()).unwrap()
+            });
+
+        let mut records = vec![];
+        let mut on_result = |record: &TestResultRecord| {
+            let wrong_values = matches!(record.failure, Some(FailureInfo::MismatchedValues { .. }));
+            records.push((
+                record.file.to_string(),
+                record.line,
+                record.header.to_string(),
+                record.status,
+                wrong_values,
+            ));
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let _ = runtime.block_on(run(&args, files.into_iter(), Some(&mut on_result)));
+
+        records.sort_by_key(|(file, line, ..)| (file.clone(), *line));
+        let record = |file: &str, line, header: &str, status, wrong_values| {
+            (
+                file.to_string(),
+                line,
+                header.to_string(),
+                status,
+                wrong_values,
+            )
+        };
+        assert_eq!(
+            records,
+            [
+                record("stateful.md", 2, "`Create`", TestStatus::Passed, false),
+                record("stateful.md", 6, "`Count`", TestStatus::Passed, false),
+                record("stateless.md", 2, "`Passing`", TestStatus::Passed, false),
+                record("stateless.md", 11, "`Failing`", TestStatus::Failed, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn only_failed_tests_commit_with_no_rollback() {
+        use super::commits_failure;
+        use crate::Args;
+        use clap::Parser;
+
+        let mut failing = test_file("a.md", true, &[1]).tests.remove(0);
+        failing.ignore_output = false;
+        failing.output = vec![vec!["1".to_string()]];
+        let passing = test_file("a.md", true, &[2]).tests.remove(0);
+
+        let args = Args::parse_from(["test", "--watch", "--no-rollback-on-failure", "."]);
+        assert!(commits_failure(
+            &args,
+            &failing,
+            &super::Ran::Query(Ok(vec![]))
+        ));
+        assert!(!commits_failure(
+            &args,
+            &passing,
+            &super::Ran::Query(Ok(vec![]))
+        ));
+
+        let args = Args::parse_from(["test", "--watch", "."]);
+        assert!(!commits_failure(
+            &args,
+            &failing,
+            &super::Ran::Query(Ok(vec![]))
+        ));
+
+        assert!(Args::try_parse_from(["test", "--no-rollback-on-failure", "."]).is_err());
+        assert!(Args::try_parse_from([
+            "test",
+            "--watch",
+            "--no-rollback-on-failure",
+            "--stateless-no-transaction",
+            "."
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn received_outputs_are_written_per_test() {
+        use super::{out_file_path, received_output, write_out_file};
+        use crate::Args;
+        use clap::Parser;
+
+        let dir = tempfile::tempdir().unwrap();
+        let out_dir = dir.path();
+        assert_eq!(
+            out_file_path(out_dir, "tests/a.md", 12),
+            out_dir.join("tests/a.md/12.out")
+        );
+        assert_eq!(
+            out_file_path(out_dir, "/abs/../b.rs", 3),
+            out_dir.join("abs/_/b.rs/3.out")
+        );
+
+        let args = Args::parse_from(["test", "."]);
+        let mut test = test_file("tests/a.md", true, &[12]).tests.remove(0);
+        test.output_headers = vec!["id".to_string(), "name".to_string()];
+        let output = received_output(&args, &test, &super::Ran::Query(Ok(vec![])));
+        assert_eq!(output, " id | name\n----+------\n(0 rows)\n");
+
+        let path = out_file_path(out_dir, "tests/a.md", 12);
+        write_out_file(&path, &output).unwrap();
+        write_out_file(&out_file_path(out_dir, "tests/a.md", 20), "").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), output);
+        let mut written: Vec<String> = std::fs::read_dir(out_dir.join("tests/a.md"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        written.sort();
+        assert_eq!(written, ["12.out", "20.out"]);
+    }
+
+    #[test]
+    fn served_connection_string() {
+        assert_eq!(
+            super::connection_string("postgres", "1763", "scratch"),
+            "postgresql://postgres@localhost:1763/scratch"
+        );
+
+        use crate::Args;
+        use clap::Parser;
+        let args = Args::parse_from(["test", "--serve-only"]);
+        assert!(args.serve_only && args.input_paths.is_empty());
+    }
+
+    #[test]
+    fn after_file_assertion_expects_no_rows() {
+        let test = super::after_file_assertion("SELECT gid FROM pg_prepared_xacts");
+        assert_eq!(test.text, "SELECT gid FROM pg_prepared_xacts");
+        assert!(test.output.is_empty());
+        assert!(!test.ignore_output);
+        assert_eq!(test.check_headers, Some(false));
+    }
+
+    #[test]
+    #[ignore = "starts a server"]
+    fn leaked_prepared_transactions_fail_their_file() {
+        use super::TestStatus::*;
+
+        let leaking =
+            "# Leak\n```SQL,stateful\nbegin; create table t(); prepare transaction 'leak'\n```\n\
+            # After\n```SQL\nselect 1\n```\n";
+        let clean = "# Create\n```SQL,stateful\ncreate table t()\n```\n\
+            # After\n```SQL\nselect 1\n```\n";
+        let assertion = "select gid from pg_prepared_xacts where database = current_database()";
+        let (_, mut records) = run_files(
+            &["--assert-after-file", assertion],
+            &[("leaking.md", leaking), ("clean.md", clean)],
+        );
+
+        // the assertion is reported as a test of the file at line 0
+        records.sort_by_key(|(file, line, _)| (file.clone(), *line));
+        let record = |file: &str, line, status| (file.to_string(), line, status);
+        assert_eq!(
+            records,
+            [
+                record("clean.md", 0, Passed),
+                record("clean.md", 2, Passed),
+                record("clean.md", 6, Passed),
+                record("leaking.md", 0, Failed),
+                record("leaking.md", 2, Passed),
+                record("leaking.md", 6, Passed),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn warm_cluster_cache_skips_initdb() {
+        use super::init_data_dir;
+        use std::{cell::Cell, fs, path::Path};
+
+        let sh = xshell::Shell::new().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("cache");
+        let initdb_runs = Cell::new(0);
+        let initdb = |data_dir: &Path| {
+            initdb_runs.set(initdb_runs.get() + 1);
+            fs::create_dir_all(data_dir)?;
+            fs::write(data_dir.join("PG_VERSION"), "15\n")?;
+            Ok(())
+        };
+
+        let cold = dir.path().join("cold");
+        init_data_dir(&sh, Some(&cache), &cold, initdb).unwrap();
+        assert_eq!(initdb_runs.get(), 1);
+        assert!(cache.join("data/PG_VERSION").is_file());
+
+        let warm = dir.path().join("warm");
+        init_data_dir(&sh, Some(&cache), &warm, initdb).unwrap();
+        assert_eq!(initdb_runs.get(), 1);
+        assert_eq!(fs::read_to_string(warm.join("PG_VERSION")).unwrap(), "15\n");
+
+        let uncached = dir.path().join("uncached");
+        init_data_dir(&sh, None, &uncached, initdb).unwrap();
+        assert_eq!(initdb_runs.get(), 2);
+    }
+
+    #[test]
+    fn cluster_cache_is_per_version() {
+        assert_eq!(
+            super::cluster_cache_dir(
+                std::path::Path::new("/cache"),
+                "PostgreSQL 15.4 (Debian 15.4-1)\n"
+            ),
+            std::path::Path::new("/cache/embedded-sql-tester/PostgreSQL_15.4__Debian_15.4_1_")
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn postmaster_exit_is_detected() {
+        use super::postmaster_exited;
+        use std::os::unix::process::ExitStatusExt;
+        use std::{process::Command, sync::Mutex, time::Duration};
+
+        let child = Command::new("sleep").arg("60").spawn().unwrap();
+        let child = Mutex::new(child);
+        let not_exited =
+            tokio::time::timeout(Duration::from_millis(300), postmaster_exited(&child));
+        assert!(not_exited.await.is_err());
+
+        child.lock().unwrap().kill().unwrap();
+        let status = tokio::time::timeout(Duration::from_secs(5), postmaster_exited(&child))
+            .await
+            .expect("exit not detected")
+            .unwrap();
+        assert_eq!(status.signal(), Some(9));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[ignore = "starts a server"]
+    fn killing_the_postmaster_stops_the_run() {
+        // the backend's parent is the postmaster
+        let contents = "# Kill\n```SQL\ncopy (select 1) to program \
+            'kill -9 $(cut -d \" \" -f 4 /proc/$PPID/stat)'\n```\n\
+            # After\n```SQL\nselect 1\n```\n# Later\n```SQL\nselect 2\n```\n";
+        let (result, records) = run_files(&["--jobs", "1"], &[("a.md", contents)]);
+        let error = result.unwrap_err().to_string();
+        assert!(
+            error.starts_with("postmaster exited unexpectedly (signal: 9"),
+            "{error}"
+        );
+        // the run stops at the first query the crash cut off
+        assert_eq!(records.len(), 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn failed_tests_dont_poison_the_connection() {
+        use super::reset_aborted_transaction;
+
+        let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL is not set");
+        let (client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+
+        // a failing test that opened its own transaction
+        assert!(client.simple_query("BEGIN; SELECT 1/0").await.is_err());
+        assert!(client.simple_query("SELECT 1").await.is_err());
+
+        reset_aborted_transaction(&client).await.unwrap();
+        assert!(client.simple_query("SELECT 1").await.is_ok());
+        // a healthy connection is left alone
+        reset_aborted_transaction(&client).await.unwrap();
+        assert!(client.simple_query("SELECT 1").await.is_ok());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    async fn file_settings_apply_to_all_its_tests() {
+        use super::apply_settings;
+
+        let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL is not set");
+        let (client, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        // as a test sees it, from within its transaction
+        let show = "BEGIN; SHOW search_path; ROLLBACK";
+        let search_path =
+            || async { super::first_value(&client.simple_query(show).await.unwrap()).unwrap() };
+
+        let settings = ["SET search_path = pg_catalog, public".to_string()];
+        let mut applied = false;
+        for _ in 0..2 {
+            apply_settings(&client, &settings, &mut applied)
+                .await
+                .unwrap();
+            assert_eq!(search_path().await, "pg_catalog, public");
+        }
+        assert!(applied);
+
+        client.batch_execute("RESET ALL").await.unwrap();
+        assert_eq!(search_path().await, r#""$user", public"#);
+    }
+
+    #[tokio::test]
+    async fn transient_createdb_failures_are_retried() {
+        use super::retry_transient;
+        use std::time::Duration;
+
+        let mut calls = 0;
+        let result = retry_transient(5, Duration::ZERO, || {
+            calls += 1;
+            match calls {
+                1 | 2 => Err("createdb: error: database creation failed: ERROR:  source database \"template1\" is being accessed by other users".to_string()),
+                _ => Ok(calls),
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 3);
+
+        let mut calls = 0;
+        let result = retry_transient(5, Duration::ZERO, || -> Result<(), _> {
+            calls += 1;
+            Err(
+                "createdb: error: database creation failed: ERROR:  database \"a\" already exists"
+                    .to_string(),
+            )
+        })
+        .await;
+        assert!(result.unwrap_err().to_string().contains("already exists"));
+        assert_eq!(calls, 1);
+
+        let mut calls = 0;
+        let result = retry_transient(3, Duration::ZERO, || -> Result<(), _> {
+            calls += 1;
+            Err("ERROR:  source database is being accessed by other users".to_string())
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn shard_parsing() {
+        assert_eq!(
+            "2/3".parse::<Shard>().unwrap(),
+            Shard { index: 2, count: 3 }
+        );
+        assert!("0/3".parse::<Shard>().is_err());
+        assert!("4/3".parse::<Shard>().is_err());
+        assert!("1/0".parse::<Shard>().is_err());
+        assert!("1".parse::<Shard>().is_err());
+    }
+
+    #[test]
+    fn shards_cover_suite_exactly_once() {
+        let first = Shard { index: 1, count: 2 }.select(suite());
+        let second = Shard { index: 2, count: 2 }.select(suite());
+
+        let first = test_ids(&first);
+        let second = test_ids(&second);
+        assert_eq!(
+            first,
+            vec![
+                ("a.md".to_string(), 1),
+                ("b.md".to_string(), 3),
+                ("b.md".to_string(), 9),
+                ("d.rs".to_string(), 5),
+            ]
+        );
+        assert_eq!(
+            second,
+            vec![
+                ("a.md".to_string(), 2),
+                ("b.md".to_string(), 6),
+                ("c.rs".to_string(), 4),
+                ("c.rs".to_string(), 8),
+            ]
+        );
+
+        let mut all: Vec<_> = first.into_iter().chain(second).collect();
+        all.sort();
+        let mut expected = test_ids(&suite());
+        expected.sort();
+        assert_eq!(all, expected);
+    }
+
+    #[test]
+    fn tags_include_and_exclude_tests() {
+        use super::select_tagged;
+
+        let tags = |tags: &[&str]| tags.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        let suite = || {
+            let mut stateless = test_file("a.md", true, &[1, 2, 3]);
+            stateless.tests[0].tags = tags(&["slow"]);
+            stateless.tests[1].tags = tags(&["slow", "flaky"]);
+            let mut stateful = test_file("b.md", false, &[1, 2]);
+            stateful.tests[1].tags = tags(&["regression"]);
+            vec![stateless, stateful]
+        };
+        let selected = |include: &[&str], exclude: &[&str]| {
+            test_ids(&select_tagged(&tags(include), &tags(exclude), suite()))
+        };
+        let ids = |ids: &[(&str, usize)]| {
+            ids.iter()
+                .map(|(file, line)| (file.to_string(), *line))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(selected(&[], &[]), test_ids(&suite()));
+        assert_eq!(selected(&["slow"], &[]), ids(&[("a.md", 1), ("a.md", 2)]));
+        assert_eq!(
+            selected(&["flaky", "regression"], &[]),
+            ids(&[("a.md", 2), ("b.md", 1), ("b.md", 2)])
+        );
+        assert_eq!(
+            selected(&[], &["flaky", "regression"]),
+            ids(&[("a.md", 1), ("a.md", 3)])
+        );
+        assert_eq!(selected(&["slow"], &["flaky"]), ids(&[("a.md", 1)]));
+        assert!(selected(&["missing"], &[]).is_empty());
     }
 }
@@ -0,0 +1,123 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::db_output::unified_line_diff;
+use crate::{cprintln, ecprintln};
+
+/// The outcome of a single test in a run's report.
+pub(crate) struct Outcome {
+    pub file: String,
+    pub line: usize,
+    pub header: String,
+    pub passed: bool,
+}
+
+/// A report of which tests passed, without timings or colors, so that it's
+/// the same from run to run.
+pub(crate) fn report(mut outcomes: Vec<Outcome>) -> String {
+    outcomes.sort_by(|a, b| (&a.file, a.line).cmp(&(&b.file, b.line)));
+    let mut report = String::new();
+    let mut num_failed = 0;
+    for Outcome {
+        file,
+        line,
+        header,
+        passed,
+    } in &outcomes
+    {
+        let status = match passed {
+            true => "ok",
+            false => "FAILED",
+        };
+        num_failed += !passed as usize;
+        let _ = writeln!(report, "{file}:{line} {header} ... {status}");
+    }
+    let num_passed = outcomes.len() - num_failed;
+    let result = match num_failed {
+        0 => "ok",
+        _ => "FAILED",
+    };
+    let _ = writeln!(
+        report,
+        "\ntest result: {result}. {num_passed} passed; {num_failed} failed"
+    );
+    report
+}
+
+/// Compares the report to the snapshot at `path`, or replaces the snapshot
+/// with it if `bless` is set.
+pub(crate) fn check(path: &Path, report: &str, bless: bool) -> Result<()> {
+    let location = path.display();
+    if bless {
+        fs::write(path, report)
+            .with_context(|| format!("could not write snapshot `{location}`"))?;
+        ecprintln!("Updated snapshot" bold blue, " `{location}`");
+        return Ok(());
+    }
+
+    let snapshot = fs::read_to_string(path).with_context(|| {
+        format!("could not read snapshot `{location}`, it can be created with `--bless`")
+    })?;
+    if snapshot == report {
+        return Ok(());
+    }
+    let snapshot: Vec<_> = snapshot.lines().collect();
+    let report: Vec<_> = report.lines().collect();
+    let diff = unified_line_diff(&snapshot, &report);
+    cprintln!("Snapshot" bold blue, " `{location}` differs from this run:\n\n{diff}");
+    bail!("run differs from snapshot `{location}`, use `--bless` to update it")
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::{check, report, Outcome};
+
+    fn outcome(file: &str, line: usize, passed: bool) -> Outcome {
+        Outcome {
+            file: file.to_string(),
+            line,
+            header: format!("`Test {line}`"),
+            passed,
+        }
+    }
+
+    #[test]
+    fn reports_are_ordered() {
+        let outcomes = vec![
+            outcome("b.md", 3, true),
+            outcome("a.md", 10, false),
+            outcome("a.md", 2, true),
+        ];
+        let expected = [
+            "a.md:2 `Test 2` ... ok",
+            "a.md:10 `Test 10` ... FAILED",
+            "b.md:3 `Test 3` ... ok",
+            "",
+            "test result: FAILED. 2 passed; 1 failed",
+        ];
+        assert_eq!(report(outcomes).lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn snapshots_are_compared_and_blessed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run.snap");
+        let passing = report(vec![outcome("a.md", 1, true), outcome("a.md", 5, true)]);
+        let failing = report(vec![outcome("a.md", 1, true), outcome("a.md", 5, false)]);
+
+        assert!(check(&path, &passing, false).is_err());
+        check(&path, &passing, true).unwrap();
+        check(&path, &passing, false).unwrap();
+
+        let error = check(&path, &failing, false).unwrap_err();
+        assert!(error.to_string().contains("differs from snapshot"));
+
+        check(&path, &failing, true).unwrap();
+        check(&path, &failing, false).unwrap();
+    }
+}
@@ -0,0 +1,356 @@
+/// A single statement from a test's SQL.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Statement<'s> {
+    /// the statement, without the `;` ending it
+    pub text: &'s str,
+    /// 0-based line within the test's SQL the statement starts on
+    pub line: usize,
+}
+
+/// Splits SQL into its statements on the semicolons that aren't within
+/// strings, quoted identifiers, comments or dollar-quoted bodies. Statements
+/// that are only whitespace are dropped.
+pub(crate) fn split_statements(sql: &str) -> Vec<Statement<'_>> {
+    let mut statements = vec![];
+    let mut start = 0;
+    let mut rest = sql;
+    let mut push = |start: usize, end: usize| {
+        let text = &sql[start..end];
+        let trimmed = text.trim_start();
+        if !trimmed.trim_end().is_empty() {
+            let offset = end - trimmed.len();
+            statements.push(Statement {
+                text: trimmed.trim_end(),
+                line: sql[..offset].matches('\n').count(),
+            });
+        }
+    };
+    while let Some(c) = rest.chars().next() {
+        let pos = sql.len() - rest.len();
+        let skip = match c {
+            ';' => {
+                push(start, pos);
+                start = pos + 1;
+                1
+            }
+            c => quoted_or_comment_len(sql, pos).unwrap_or(c.len_utf8()),
+        };
+        rest = &rest[skip..];
+    }
+    push(start, sql.len());
+    statements
+}
+
+/// Whether `sql` is only whitespace, comments and semicolons, so that
+/// running it does nothing.
+pub(crate) fn is_empty(sql: &str) -> bool {
+    let mut rest = sql;
+    while let Some(c) = rest.chars().next() {
+        let skip = match c {
+            '-' if rest.starts_with("--") => rest.find('\n').unwrap_or(rest.len()),
+            '/' if rest.starts_with("/*") => block_comment_len(rest),
+            c if c.is_whitespace() || c == ';' => c.len_utf8(),
+            _ => return false,
+        };
+        rest = &rest[skip..];
+    }
+    true
+}
+
+/// The command tags the server would likely send for the statements of
+/// `sql`, given the number of rows each affected or returned. tokio-postgres
+/// only reports the number, so the rest of each tag is guessed from the
+/// statement's keywords, which is only good enough to show in failures.
+pub(crate) fn command_tags(sql: &str, rows: &[u64]) -> Vec<String> {
+    split_statements(sql)
+        .iter()
+        .zip(rows)
+        .map(|(statement, &rows)| command_tag(statement.text, rows))
+        .collect()
+}
+
+/// The command tag of `statement`, such as `INSERT 0 1` or `CREATE TABLE`.
+fn command_tag(statement: &str, rows: u64) -> String {
+    let words = top_level_words(statement);
+    let word = |i: usize| words.get(i).map_or("", String::as_str);
+    // the statement a `WITH` is for comes after its queries
+    let verb = match word(0) {
+        "WITH" => words
+            .iter()
+            .map(String::as_str)
+            .find(|w| matches!(*w, "SELECT" | "INSERT" | "UPDATE" | "DELETE" | "MERGE"))
+            .unwrap_or("SELECT"),
+        // a parenthesized query
+        "" => "SELECT",
+        verb => verb,
+    };
+    match verb {
+        "INSERT" => format!("INSERT 0 {rows}"),
+        "SELECT" | "VALUES" | "TABLE" => format!("SELECT {rows}"),
+        "UPDATE" | "DELETE" | "MERGE" | "FETCH" | "MOVE" | "COPY" => format!("{verb} {rows}"),
+        "CREATE" | "DROP" | "ALTER" => {
+            let object = object_type(&words[1..]);
+            // `CREATE TABLE ... AS` reports the rows it was created with
+            let creates_from_query = verb == "CREATE"
+                && (object == "TABLE" || object == "MATERIALIZED VIEW")
+                && words.iter().any(|w| w == "AS");
+            match creates_from_query {
+                true => format!("SELECT {rows}"),
+                false => format!("{verb} {object}"),
+            }
+        }
+        "ABORT" => "ROLLBACK".to_string(),
+        "END" => "COMMIT".to_string(),
+        "START" => "START TRANSACTION".to_string(),
+        "TRUNCATE" => "TRUNCATE TABLE".to_string(),
+        "REFRESH" => "REFRESH MATERIALIZED VIEW".to_string(),
+        "DISCARD" => format!("DISCARD {}", word(1)),
+        "COMMIT" | "ROLLBACK" | "PREPARE" if matches!(word(1), "PREPARED" | "TRANSACTION") => {
+            format!("{verb} {}", word(1))
+        }
+        verb => verb.to_string(),
+    }
+}
+
+/// The kind of object a `CREATE`, `DROP` or `ALTER` is for, from the words
+/// following it, such as `TABLE` for `CREATE TEMP TABLE`.
+fn object_type(words: &[String]) -> String {
+    const MODIFIERS: &[&str] = &[
+        "OR",
+        "REPLACE",
+        "TEMP",
+        "TEMPORARY",
+        "UNLOGGED",
+        "UNIQUE",
+        "GLOBAL",
+        "LOCAL",
+        "RECURSIVE",
+        "TRUSTED",
+        "PROCEDURAL",
+        "CONSTRAINT",
+    ];
+    let words: Vec<&str> = words
+        .iter()
+        .map(String::as_str)
+        .skip_while(|w| MODIFIERS.contains(w))
+        .collect();
+    let len = match words.as_slice() {
+        ["FOREIGN", "DATA", ..] | ["TEXT", "SEARCH", ..] => 3,
+        ["MATERIALIZED" | "EVENT" | "ACCESS" | "FOREIGN", ..]
+        | ["USER", "MAPPING", ..]
+        | ["OPERATOR", "CLASS" | "FAMILY", ..] => 2,
+        _ => 1,
+    };
+    words[..len.min(words.len())].join(" ")
+}
+
+/// The words of `statement` outside of parentheses, strings and comments,
+/// in upper case.
+fn top_level_words(statement: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut depth = 0usize;
+    let mut rest = statement;
+    while let Some(c) = rest.chars().next() {
+        let pos = statement.len() - rest.len();
+        let skip = match c {
+            '(' => {
+                depth += 1;
+                1
+            }
+            ')' => {
+                depth = depth.saturating_sub(1);
+                1
+            }
+            c => match quoted_or_comment_len(statement, pos) {
+                Some(len) => len,
+                None if c.is_alphabetic() || c == '_' => {
+                    let len = rest
+                        .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$'))
+                        .unwrap_or(rest.len());
+                    if depth == 0 {
+                        words.push(rest[..len].to_uppercase());
+                    }
+                    len
+                }
+                None => c.len_utf8(),
+            },
+        };
+        rest = &rest[skip..];
+    }
+    words
+}
+
+/// The length of the string, quoted identifier, comment or dollar-quoted
+/// body starting `pos` bytes into `sql`, if one does there.
+pub(crate) fn quoted_or_comment_len(sql: &str, pos: usize) -> Option<usize> {
+    let rest = &sql[pos..];
+    match rest.chars().next()? {
+        '\'' => Some(quoted_len(rest, '\'', is_escape_string(&sql[..pos]))),
+        '"' => Some(quoted_len(rest, '"', false)),
+        '-' if rest.starts_with("--") => Some(rest.find('\n').unwrap_or(rest.len())),
+        '/' if rest.starts_with("/*") => Some(block_comment_len(rest)),
+        '$' => dollar_quoted_len(rest),
+        _ => None,
+    }
+}
+
+/// Whether a string starting after `before` is an escape string, `E'...'`.
+fn is_escape_string(before: &str) -> bool {
+    let mut chars = before.chars().rev();
+    matches!(chars.next(), Some('E' | 'e'))
+        && !matches!(chars.next(), Some(c) if c.is_alphanumeric() || c == '_')
+}
+
+/// The length of the quoted string or identifier at the start of `s`, where
+/// a doubled quote is part of the contents, as is an escaped one if
+/// `escapes` is set.
+fn quoted_len(s: &str, quote: char, escapes: bool) -> usize {
+    let mut chars = s.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        if escapes && c == '\\' {
+            chars.next();
+        } else if c == quote {
+            match chars.clone().next() {
+                Some((_, next)) if next == quote => {
+                    chars.next();
+                }
+                _ => return i + 1,
+            }
+        }
+    }
+    s.len()
+}
+
+/// The length of the possibly nested `/* */` comment at the start of `s`.
+fn block_comment_len(s: &str) -> usize {
+    let mut depth = 0;
+    let mut i = 0;
+    while i < s.len() {
+        if s[i..].starts_with("/*") {
+            depth += 1;
+            i += 2;
+        } else if s[i..].starts_with("*/") {
+            depth -= 1;
+            i += 2;
+            if depth == 0 {
+                return i;
+            }
+        } else {
+            i += s[i..].chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    s.len()
+}
+
+/// The length of the dollar-quoted string, such as `$$ ... $$` or
+/// `$body$ ... $body$`, at the start of `s`, if there is one.
+fn dollar_quoted_len(s: &str) -> Option<usize> {
+    let name_len = s[1..].find(|c: char| !(c.is_alphanumeric() || c == '_'))?;
+    if !s[1 + name_len..].starts_with('$') || s[1..].starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    let tag_len = name_len + 2;
+    let tag = &s[..tag_len];
+    let end = s[tag_len..]
+        .find(tag)
+        .map_or(s.len(), |i| tag_len + i + tag_len);
+    Some(end)
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::{command_tags, is_empty, split_statements, Statement};
+
+    fn texts(sql: &str) -> Vec<&str> {
+        split_statements(sql).into_iter().map(|s| s.text).collect()
+    }
+
+    #[test]
+    fn statements_are_split_on_semicolons() {
+        let sql = "CREATE TABLE foo(a int);\n\nINSERT INTO foo VALUES (1);\n  SELECT * FROM foo";
+        assert_eq!(
+            split_statements(sql),
+            [
+                Statement {
+                    text: "CREATE TABLE foo(a int)",
+                    line: 0
+                },
+                Statement {
+                    text: "INSERT INTO foo VALUES (1)",
+                    line: 2
+                },
+                Statement {
+                    text: "SELECT * FROM foo",
+                    line: 3
+                },
+            ]
+        );
+        assert!(split_statements(" ;\n; ").is_empty());
+    }
+
+    #[test]
+    fn quoted_semicolons_are_kept() {
+        assert_eq!(
+            texts("SELECT 'a;''b', \"c;\"\"d\"; SELECT E'\\';', 2"),
+            ["SELECT 'a;''b', \"c;\"\"d\"", "SELECT E'\\';', 2"]
+        );
+        assert_eq!(
+            texts("SELECT some'\\'; SELECT 2"),
+            ["SELECT some'\\'", "SELECT 2"]
+        );
+        assert_eq!(
+            texts("SELECT 1 -- one; two\n; /* a /* b; */ c; */ SELECT 2"),
+            ["SELECT 1 -- one; two", "/* a /* b; */ c; */ SELECT 2"]
+        );
+        assert_eq!(
+            texts("DO $$ BEGIN PERFORM 1; END $$; SELECT $f$;$f$, $1; SELECT 3"),
+            [
+                "DO $$ BEGIN PERFORM 1; END $$",
+                "SELECT $f$;$f$, $1",
+                "SELECT 3"
+            ]
+        );
+    }
+
+    #[test]
+    fn command_tags_are_rebuilt_from_statements() {
+        let sql = "INSERT INTO t VALUES (1), (2);\n\
+            -- updates both\n\
+            UPDATE t SET a = a + 1;\n\
+            DELETE FROM t WHERE a > 5;\n\
+            WITH gone AS (DELETE FROM t RETURNING a) SELECT count(*) FROM gone;\n\
+            WITH new AS (SELECT 1 a) INSERT INTO t SELECT a FROM new;\n\
+            CREATE TEMP TABLE u (b int);\n\
+            CREATE TABLE v AS SELECT * FROM t;\n\
+            CREATE OR REPLACE FUNCTION f() RETURNS int AS $$ SELECT 1 $$ LANGUAGE sql;\n\
+            DROP MATERIALIZED VIEW IF EXISTS m;\n\
+            begin; rollback to savepoint s; end";
+        assert_eq!(
+            command_tags(sql, &[2, 2, 0, 1, 1, 0, 3, 0, 0, 0, 0, 0]),
+            [
+                "INSERT 0 2",
+                "UPDATE 2",
+                "DELETE 0",
+                "SELECT 1",
+                "INSERT 0 1",
+                "CREATE TABLE",
+                "SELECT 3",
+                "CREATE FUNCTION",
+                "DROP MATERIALIZED VIEW",
+                "BEGIN",
+                "ROLLBACK",
+                "COMMIT"
+            ]
+        );
+    }
+
+    #[test]
+    fn comments_alone_are_empty() {
+        assert!(is_empty(""));
+        assert!(is_empty("  \n-- TODO\n/* nested /* comment */ */;\n"));
+        assert!(!is_empty("-- a comment\nSELECT 1"));
+        assert!(!is_empty("/* a comment */ VALUES (1)"));
+    }
+}
@@ -0,0 +1,113 @@
+use std::time::Duration;
+
+use crate::cprintln;
+
+/// Prints the total and percentiles of how long queries took, followed by a
+/// histogram of them in power-of-two buckets.
+pub(crate) fn print_report(durations: &[Duration]) {
+    let mut durations = durations.to_vec();
+    durations.sort();
+
+    let num_queries = durations.len();
+    let total = format_duration(durations.iter().sum());
+    cprintln!("\nTiming" bold blue, ": {num_queries} queries took {total}");
+    if durations.is_empty() {
+        return;
+    }
+
+    let [p50, p90, p99] = [50, 90, 99].map(|p| format_duration(percentile(&durations, p)));
+    println!("p50 {p50}, p90 {p90}, p99 {p99}\n");
+
+    let buckets = histogram(&durations);
+    let most = buckets.iter().map(|(_, count)| *count).max().unwrap_or(0);
+    let labels: Vec<String> = buckets
+        .iter()
+        .map(|(bound, _)| format!("< {}", format_duration(*bound)))
+        .collect();
+    let label_width = labels.iter().map(String::len).max().unwrap_or(0);
+    for (label, (_, count)) in labels.iter().zip(&buckets) {
+        const BAR_WIDTH: usize = 40;
+        let bar = "#".repeat((count * BAR_WIDTH).div_ceil(most));
+        println!("{label:>label_width$} | {bar:BAR_WIDTH$} {count}");
+    }
+}
+
+/// The nearest-rank `p`th percentile of some sorted durations.
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    let rank = (p * sorted.len()).div_ceil(100);
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Counts the sorted durations into buckets, each bounded by twice the
+/// previous, from 1ms until the largest duration.
+fn histogram(sorted: &[Duration]) -> Vec<(Duration, usize)> {
+    let max = match sorted.last() {
+        Some(max) => *max,
+        None => return vec![],
+    };
+    let mut buckets = vec![];
+    let mut bound = Duration::from_millis(1);
+    let mut remaining = sorted;
+    loop {
+        let count = remaining.partition_point(|d| *d < bound);
+        remaining = &remaining[count..];
+        buckets.push((bound, count));
+        if max < bound {
+            return buckets;
+        }
+        bound *= 2;
+    }
+}
+
+fn format_duration(d: Duration) -> String {
+    if d < Duration::from_millis(1) {
+        format!("{}µs", d.as_micros())
+    } else if d < Duration::from_secs(1) {
+        format!("{:.1}ms", d.as_secs_f64() * 1000.0)
+    } else {
+        format!("{:.2}s", d.as_secs_f64())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use pretty_assertions::assert_eq;
+
+    use super::{histogram, percentile};
+
+    fn millis(ms: &[u64]) -> Vec<Duration> {
+        ms.iter().map(|ms| Duration::from_millis(*ms)).collect()
+    }
+
+    #[test]
+    fn percentiles_use_nearest_rank() {
+        let durations = millis(&(1..=100).collect::<Vec<_>>());
+        assert_eq!(percentile(&durations, 50), Duration::from_millis(50));
+        assert_eq!(percentile(&durations, 90), Duration::from_millis(90));
+        assert_eq!(percentile(&durations, 99), Duration::from_millis(99));
+        assert_eq!(percentile(&durations, 100), Duration::from_millis(100));
+
+        let durations = millis(&[3, 5, 7, 8, 9, 10, 11, 13, 15, 20]);
+        assert_eq!(percentile(&durations, 50), Duration::from_millis(9));
+        assert_eq!(percentile(&durations, 90), Duration::from_millis(15));
+        assert_eq!(percentile(&durations, 99), Duration::from_millis(20));
+        assert_eq!(percentile(&durations, 0), Duration::from_millis(3));
+
+        let durations = millis(&[4]);
+        assert_eq!(percentile(&durations, 50), Duration::from_millis(4));
+    }
+
+    #[test]
+    fn histogram_buckets_double() {
+        let mut durations = millis(&[0, 1, 3, 3, 9]);
+        durations.insert(2, Duration::from_micros(1500));
+        let buckets: Vec<(u128, usize)> = histogram(&durations)
+            .into_iter()
+            .map(|(bound, count)| (bound.as_millis(), count))
+            .collect();
+        assert_eq!(buckets, [(1, 1), (2, 2), (4, 2), (8, 0), (16, 1)]);
+        assert!(histogram(&[]).is_empty());
+    }
+}
@@ -0,0 +1,124 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use notify::{EventKind, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+
+/// Watches the input paths for changes to files.
+pub(crate) struct FileWatcher {
+    _watcher: notify::RecommendedWatcher,
+    events: mpsc::UnboundedReceiver<notify::Result<notify::Event>>,
+}
+
+impl FileWatcher {
+    pub(crate) fn new(paths: &[PathBuf]) -> Result<Self> {
+        let (send, events) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = send.send(event);
+        })?;
+        for path in paths {
+            watcher.watch(path, RecursiveMode::Recursive)?;
+        }
+        Ok(Self {
+            _watcher: watcher,
+            events,
+        })
+    }
+
+    /// Waits for files to be written, returning their paths once the writes
+    /// settle down.
+    pub(crate) async fn changed_files(&mut self) -> Result<Vec<PathBuf>> {
+        let mut changed = vec![];
+        let mut event = self.events.recv().await;
+        loop {
+            match event {
+                None => bail!("stopped receiving file changes"),
+                Some(event) => {
+                    let event = event?;
+                    if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                        changed.extend(event.paths)
+                    }
+                }
+            }
+            // editors often save a file in several steps, wait for the last
+            let settle = Duration::from_millis(100);
+            event = match tokio::time::timeout(settle, self.events.recv()).await {
+                Ok(event) => event,
+                Err(_) if changed.is_empty() => self.events.recv().await,
+                Err(_) => return Ok(changed),
+            };
+        }
+    }
+}
+
+/// The source files under `input_paths` that are among the `changed` paths.
+pub(crate) fn changed_source_files(input_paths: &[PathBuf], changed: &[PathBuf]) -> Vec<PathBuf> {
+    let changed: Vec<PathBuf> = changed
+        .iter()
+        .filter_map(|p| p.canonicalize().ok())
+        .collect();
+    let is_changed = |path: &Path| {
+        path.canonicalize()
+            .map(|path| changed.contains(&path))
+            .unwrap_or(false)
+    };
+    input_paths
+        .iter()
+        .flat_map(|path| crate::find_source_files(path))
+        .filter_map(Result::ok)
+        .filter(|path| is_changed(path))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use pretty_assertions::assert_eq;
+
+    use super::{changed_source_files, FileWatcher};
+
+    #[tokio::test]
+    async fn edits_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let roots = [dir.path().to_path_buf()];
+        let root = &roots[0];
+        fs::write(root.join("a.md"), "").unwrap();
+        fs::write(root.join("b.md"), "").unwrap();
+        fs::write(root.join("notes.txt"), "").unwrap();
+
+        let mut watcher = FileWatcher::new(&roots).unwrap();
+        fs::write(root.join("b.md"), "# edited").unwrap();
+        fs::write(root.join("notes.txt"), "edited").unwrap();
+
+        let changed = tokio::time::timeout(Duration::from_secs(10), watcher.changed_files())
+            .await
+            .expect("no changes reported")
+            .unwrap();
+        assert_eq!(
+            changed_source_files(&roots, &changed),
+            vec![root.join("b.md")]
+        );
+    }
+
+    #[test]
+    fn only_changed_source_files_rerun() {
+        let dir = tempfile::tempdir().unwrap();
+        let roots = [dir.path().to_path_buf()];
+        let root = &roots[0];
+        for file in ["a.md", "b.rs", "c.txt"] {
+            fs::write(root.join(file), "").unwrap();
+        }
+        let changed: Vec<PathBuf> = ["b.rs", "c.txt", "deleted.md"]
+            .iter()
+            .map(|file| root.join(file))
+            .collect();
+        assert_eq!(
+            changed_source_files(&roots, &changed),
+            vec![root.join("b.rs")]
+        );
+    }
+}
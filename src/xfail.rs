@@ -0,0 +1,110 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use regex::Regex;
+
+use crate::rerun::test_id;
+use crate::Test;
+
+/// The tests `--expected-failures` lists, whose failures aren't counted.
+#[derive(Debug, Default)]
+pub(crate) struct ExpectedFailures {
+    /// tests as `file:line`, or their full identifiers
+    ids: HashSet<String>,
+    /// matched against the headers of the tests
+    headers: Vec<Regex>,
+}
+
+impl ExpectedFailures {
+    /// Reads the list at `path`, an empty one if there's none.
+    pub(crate) fn read(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(Self::default()),
+        };
+        let contents = fs::read_to_string(path).with_context(|| {
+            format!(
+                "could not read the expected failures from `{}`",
+                path.display()
+            )
+        })?;
+        Self::parse(&contents)
+            .with_context(|| format!("invalid expected failures in `{}`", path.display()))
+    }
+
+    /// One test per line, as its `file:line` or its identifier from `--list`,
+    /// or a regex matched against the headers of the tests. Blank lines and
+    /// those starting with `#` are skipped.
+    pub(crate) fn parse(contents: &str) -> Result<Self> {
+        let mut expected = Self::default();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if is_test_id(line) {
+                expected.ids.insert(line.to_string());
+                continue;
+            }
+            let header = Regex::new(line).with_context(|| format!("on line {}", i + 1))?;
+            expected.headers.push(header);
+        }
+        Ok(expected)
+    }
+
+    pub(crate) fn contains(&self, file: &str, test: &Test) -> bool {
+        self.ids.contains(&format!("{file}:{}", test.line))
+            || self.ids.contains(&test_id(file, test))
+            || self
+                .headers
+                .iter()
+                .any(|header| header.is_match(&test.header))
+    }
+}
+
+/// Whether `line` is a `file:line`, optionally followed by `:header`.
+fn is_test_id(line: &str) -> bool {
+    let mut parts = line.splitn(3, ':');
+    let (file, line) = (parts.next(), parts.next());
+    matches!((file, line), (Some(file), Some(line))
+        if !file.is_empty() && !line.is_empty() && line.bytes().all(|b| b.is_ascii_digit()))
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::ExpectedFailures;
+    use crate::Test;
+
+    fn test(line: usize, header: &str) -> Test {
+        Test {
+            line,
+            header: header.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn tests_are_listed_by_location_or_header() {
+        let contents = "# not implemented yet\na.md:3\n\nb.md:7:`merge`\n^unsupported \\(\n";
+        let expected = ExpectedFailures::parse(contents).unwrap();
+        let listed = |file: &str, line, header: &str| expected.contains(file, &test(line, header));
+
+        assert!(listed("a.md", 3, ""));
+        assert!(!listed("a.md", 4, ""));
+        assert!(listed("b.md", 7, "`merge`"));
+        assert!(!listed("b.md", 7, "`merge into`"));
+        assert!(listed("c.md", 1, "unsupported (lateral)"));
+        assert!(!listed("c.md", 1, "`a.md:3`"));
+        assert_eq!(expected.headers.len(), 1);
+    }
+
+    #[test]
+    fn invalid_regexes_are_errors() {
+        let error = ExpectedFailures::parse("a.md:3\nunclosed (\n").unwrap_err();
+        assert_eq!(error.to_string(), "on line 2");
+    }
+}